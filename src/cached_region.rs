@@ -1,15 +1,18 @@
 use eth_types::Field;
 use halo2_proofs::{
-    circuit::{AssignedCell, Region, Value},
-    plonk::{Advice, Any, Assigned, Column, Error, Expression, Fixed},
+    circuit::{self, AssignedCell, Layouter, Region, Value},
+    plonk::{Advice, Any, Assigned, Column, Error, Expression, Fixed, Instance},
     poly::Rotation,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     hash::{Hash, Hasher},
+    ops::Range,
 };
-use super::cell_manager::{Cell, CellType, CellColumn};
-use super::constraint_builder::ConstraintBuilder;
+use super::cell_manager::{Cell, CellType, CellColumn, TableTag};
+use super::constraint_builder::{CellInfo, ConstraintBuilder};
+use super::util::{expr_nodes, expr_to_string, Challenges};
 
 
 pub trait ChallengeSet<F: Field> {
@@ -22,24 +25,121 @@ impl<F: Field, V: AsRef<[Value<F>]>> ChallengeSet<F> for V {
     }
 }
 
+impl<F: Field> ChallengeSet<F> for Challenges<Value<F>> {
+    fn indexed(&self) -> Vec<&Value<F>> {
+        Challenges::<Value<F>>::indexed(self)
+    }
+}
+
+/// How the unused rows of a column are padded
+#[derive(Clone, Copy, Debug)]
+pub enum Padding<F> {
+    /// Assign a constant value
+    Constant(F),
+    /// Assign the value of the previous row
+    CopyPrevious,
+    /// Leave the rows unassigned
+    Unassigned,
+    /// Assign the padding sentinel of the builder, a random looking value
+    /// derived from its seed (see `ConstraintBuilder::set_seed`)
+    Sentinel,
+}
+
+/// Padding of the unused rows of a region. Columns not listed explicitly
+/// are padded with `default` when they are managed by the cell manager.
+#[derive(Clone, Debug)]
+pub struct PaddingSpec<F> {
+    columns: Vec<(Column<Any>, Padding<F>)>,
+    default: Padding<F>,
+}
+
+impl<F: Field> Default for PaddingSpec<F> {
+    fn default() -> Self {
+        // Zero turns fixed selectors off and empties all cells
+        Self::with_default(Padding::Constant(F::ZERO))
+    }
+}
+
+impl<F: Field> PaddingSpec<F> {
+    pub fn with_default(default: Padding<F>) -> Self {
+        Self {
+            columns: Vec::new(),
+            default,
+        }
+    }
+
+    /// Sets the padding of a column
+    pub fn column<T: Into<Column<Any>>>(mut self, column: T, padding: Padding<F>) -> Self {
+        let column = column.into();
+        self.columns.retain(|(c, _)| *c != column);
+        self.columns.push((column, padding));
+        self
+    }
+
+    /// Sets the padding of the column of a cell
+    pub(crate) fn cell(self, cell: &Cell<F>, padding: Padding<F>) -> Self {
+        self.column(cell.column(), padding)
+    }
+
+    /// Returns all columns to pad with their padding
+    fn resolve<C: CellType, T: TableTag>(
+        &self,
+        cb: &ConstraintBuilder<F, C, T>,
+    ) -> Vec<(Column<Any>, Padding<F>)> {
+        let mut columns = self.columns.clone();
+        if let Some(cell_manager) = &cb.cell_manager {
+            for cell_column in cell_manager.columns() {
+                let column: Column<Any> = cell_column.column.into();
+                if !columns.iter().any(|(c, _)| *c == column) {
+                    columns.push((column, self.default));
+                }
+            }
+        }
+        for (_, padding) in columns.iter_mut() {
+            if let Padding::Sentinel = padding {
+                *padding = Padding::Constant(cb.padding_sentinel());
+            }
+        }
+        columns
+    }
+}
+
 pub struct CachedRegion<'r, 'b, F: Field> {
     region: &'r mut Region<'b, F>,
     pub advice: HashMap<(usize, usize), F>,
     pub fixed: HashMap<(usize, usize), F>,
+    /// The assigned advice cells, used to copy them into other columns
+    assigned: HashMap<(usize, usize), circuit::Cell>,
     disable_description: bool,
     regions: Vec<(usize, usize)>,
-    pub key_r: F,
-    pub keccak_r: F,
+    /// The region being assigned, between `push_region` and `pop_region`
+    current_region: Option<usize>,
+    /// The `(column, offset)` assigned in every region, in assignment order
+    region_assignments: HashMap<usize, Vec<(Column<Any>, usize)>>,
+    /// Assignments left out of the witness digests
+    volatile: HashSet<(Column<Any>, usize)>,
+    padding: Option<(Vec<(Column<Any>, Padding<F>)>, usize)>,
+    usable_rows: Option<(Range<usize>, u32)>,
+    /// Challenges to compress memory keys and keccak inputs with, unknown
+    /// during keygen
+    pub key_r: Value<F>,
+    pub keccak_r: Value<F>,
 }
 
 impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
-    pub(crate) fn new(region: &'r mut Region<'b, F>, keccak_r: F) -> Self {
+    pub(crate) fn new(region: &'r mut Region<'b, F>, keccak_r: Value<F>) -> Self {
         Self {
             region,
             advice: HashMap::new(),
             fixed: HashMap::new(),
+            assigned: HashMap::new(),
             disable_description: false,
             regions: Vec::new(),
+            current_region: None,
+            region_assignments: HashMap::new(),
+            volatile: HashSet::new(),
+            padding: None,
+            usable_rows: None,
             key_r: keccak_r,
             keccak_r,
         }
@@ -49,27 +149,205 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
         self.disable_description = disable_description;
     }
 
+    /// Makes assignments outside of `usable_rows` fail, so witnesses and
+    /// tables landing in the rows halo2 reserves for blinding are caught
+    /// before proving (see `ConstraintBuilder::usable_rows`). The rows are
+    /// relative to the start of this region.
+    pub(crate) fn set_usable_rows(&mut self, usable_rows: Range<usize>, k: u32) {
+        self.usable_rows = Some((usable_rows, k));
+    }
+
+    fn check_usable_row(&self, offset: usize) -> Result<(), Error> {
+        match &self.usable_rows {
+            Some((usable_rows, k)) if !usable_rows.contains(&offset) => {
+                Err(Error::NotEnoughRowsAvailable { current_k: *k })
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub(crate) fn push_region(&mut self, offset: usize, region_id: usize) {
         self.regions.push((offset, region_id));
+        self.current_region = Some(region_id);
     }
 
     pub(crate) fn pop_region(&mut self) {
-        // Nothing to do
+        self.current_region = None;
     }
 
-    pub(crate) fn assign_stored_expressions<C: CellType, S: ChallengeSet<F>>(
+    /// Pads the unassigned cells of all rows in `from_row..to_row` following
+    /// the spec. Cells that are already assigned are left untouched.
+    pub(crate) fn pad_region<C: CellType, T: TableTag>(
         &mut self,
-        cb: &ConstraintBuilder<F, C>,
+        cb: &ConstraintBuilder<F, C, T>,
+        from_row: usize,
+        to_row: usize,
+        spec: &PaddingSpec<F>,
+    ) -> Result<(), Error> {
+        self.pad_columns(&spec.resolve(cb), from_row, to_row)
+    }
+
+    /// Pads the unassigned rows of every region in `assign_stored_expressions`,
+    /// once the stored expressions are assigned. A region is padded up to the
+    /// start of the next region, the last one up to `to_row` (exclusive).
+    pub(crate) fn enable_auto_padding<C: CellType, T: TableTag>(
+        &mut self,
+        cb: &ConstraintBuilder<F, C, T>,
+        spec: &PaddingSpec<F>,
+        to_row: usize,
+    ) {
+        self.padding = Some((spec.resolve(cb), to_row));
+    }
+
+    fn pad_columns(
+        &mut self,
+        columns: &[(Column<Any>, Padding<F>)],
+        from_row: usize,
+        to_row: usize,
+    ) -> Result<(), Error> {
+        for &(column, padding) in columns.iter() {
+            for row in from_row..to_row {
+                self.pad_cell(column, row, padding)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn pad_cell(&mut self, column: Column<Any>, row: usize, padding: Padding<F>) -> Result<(), Error> {
+        let cache = match column.column_type() {
+            Any::Advice(_) => &self.advice,
+            Any::Fixed => &self.fixed,
+            Any::Instance => return Ok(()),
+        };
+        if cache.contains_key(&(column.index(), row)) {
+            return Ok(());
+        }
+        let value = match padding {
+            Padding::Constant(value) => value,
+            Padding::CopyPrevious => row
+                .checked_sub(1)
+                .and_then(|prev| cache.get(&(column.index(), prev)))
+                .copied()
+                .unwrap_or(F::ZERO),
+            Padding::Unassigned => return Ok(()),
+            Padding::Sentinel => unreachable!("sentinels are resolved with the builder"),
+        };
+        match column.column_type() {
+            Any::Advice(_) => {
+                let column: Column<Advice> = column.try_into().unwrap();
+                self.assign_advice(|| "padding", column, row, || Value::known(value))?;
+            }
+            Any::Fixed => {
+                let column: Column<Fixed> = column.try_into().unwrap();
+                self.assign_fixed(|| "padding", column, row, || Value::known(value))?;
+            }
+            Any::Instance => unreachable!(),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn assign_stored_expressions<C: CellType, T: TableTag, S: ChallengeSet<F>>(
+        &mut self,
+        cb: &ConstraintBuilder<F, C, T>,
         challenges: &S,
     ) -> Result<(), Error> {
         for (offset, region_id) in self.regions.clone() {
-            for stored_expression in cb.get_stored_expressions(region_id).iter() {
-                stored_expression.assign(self, challenges, offset)?;
+            self.assign_stored_expressions_at(cb, region_id, offset, challenges)?;
+        }
+        if let Some((columns, to_row)) = self.padding.clone() {
+            let mut offsets = self.regions.iter().map(|(offset, _)| *offset).collect::<Vec<_>>();
+            offsets.sort();
+            offsets.dedup();
+            for (idx, offset) in offsets.iter().enumerate() {
+                let end = offsets.get(idx + 1).copied().unwrap_or(to_row);
+                self.pad_columns(&columns, *offset, end)?;
             }
         }
         Ok(())
     }
 
+    /// Assigns the challenge power cells of the builder (see
+    /// `ConstraintBuilder::challenge_power`) at the offsets of the regions
+    /// using them, before the stored expressions that use them are assigned.
+    /// Powers used outside of the regions are assigned in all regions.
+    pub(crate) fn assign_challenge_powers<C: CellType, T: TableTag>(
+        &mut self,
+        cb: &ConstraintBuilder<F, C, T>,
+        challenges: &Challenges<Value<F>>,
+    ) -> Result<(), Error> {
+        for ((phase, i), (cell, region_ids)) in cb.challenge_power_cells().iter() {
+            let r = challenges
+                .first_after(*phase)
+                .unwrap_or_else(|| panic!("no challenge usable after phase {}", phase));
+            let power = r.map(|r| r.pow([*i as u64, 0, 0, 0]));
+            let mut offsets = self
+                .regions
+                .iter()
+                .filter(|(_, region_id)| region_ids.contains(&0) || region_ids.contains(region_id))
+                .map(|(offset, _)| *offset)
+                .collect::<Vec<_>>();
+            if self.regions.is_empty() && region_ids.contains(&0) {
+                offsets.push(0);
+            }
+            offsets.sort();
+            offsets.dedup();
+            for offset in offsets.iter() {
+                cell.assign_value(self, *offset, power)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns the stored expressions of a single instance of the region,
+    /// with the region starting at `instance_offset`. Used when the same
+    /// configured region is repeated at multiple offsets.
+    pub(crate) fn assign_stored_expressions_at<C: CellType, T: TableTag, S: ChallengeSet<F>>(
+        &mut self,
+        cb: &ConstraintBuilder<F, C, T>,
+        region_id: usize,
+        instance_offset: usize,
+        challenges: &S,
+    ) -> Result<(), Error> {
+        let stored_expressions = cb.get_stored_expressions(region_id);
+        if cb.column_grouped_assignment() {
+            self.assign_grouped(&stored_expressions, challenges, instance_offset)?;
+        } else {
+            for stored_expression in stored_expressions.iter() {
+                stored_expression.assign(self, challenges, instance_offset)?;
+            }
+        }
+        for decomposition in cb.range_decompositions(region_id) {
+            decomposition.assign(self, challenges, instance_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Assigns the stored expressions grouped by column, by row within a
+    /// column. Stored expressions can use the cells of the ones stored before
+    /// them, so all values are evaluated in order and cached before the
+    /// cells are written.
+    fn assign_grouped<C: CellType, S: ChallengeSet<F>>(
+        &mut self,
+        stored_expressions: &[StoredExpression<F, C>],
+        challenges: &S,
+        offset: usize,
+    ) -> Result<(), Error> {
+        let mut values = Vec::with_capacity(stored_expressions.len());
+        for stored_expression in stored_expressions.iter() {
+            let value = stored_expression.evaluate(self, challenges, offset);
+            let key = (stored_expression.column_index(), offset + stored_expression.cell.rotation());
+            value.map(|value| self.advice.insert(key, value));
+            values.push((stored_expression, key, value));
+        }
+        // The sort is stable, so a cell stored twice keeps its order
+        values.sort_by_key(|(_, key, _)| *key);
+        for (stored_expression, key, value) in values {
+            self.advice.remove(&key);
+            stored_expression.cell.assign_value(self, offset, value)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn annotate_columns<C: CellType>(&mut self, cell_columns: &[CellColumn<F, C>]) {
         for c in cell_columns {
             self.region
@@ -94,13 +372,16 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
         A: Fn() -> AR,
         AR: Into<String>,
     {
+        self.check_usable_row(offset)?;
         // Actually set the value
         let res = self.region.assign_advice(annotation, column, offset, &to);
         // Cache the value
         // Note that the `value_field` in `AssignedCell` might be `Value::unkonwn` if
         // the column has different phase than current one, so we call to `to`
         // again here to cache the value.
-        if res.is_ok() {
+        if let Ok(assigned) = &res {
+            self.assigned.insert((column.index(), offset), assigned.cell());
+            self.record_assignment(column.into(), offset);
             to().map(|f: VR| {
                 let existing = self
                     .advice
@@ -135,6 +416,7 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
         A: Fn() -> AR,
         AR: Into<String>,
     {
+        self.check_usable_row(offset)?;
         // Actually set the value
         let res = self.region.assign_fixed(annotation, column, offset, &to);
         // Cache the value
@@ -142,6 +424,7 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
         // the column has different phase than current one, so we call to `to`
         // again here to cache the value.
         if res.is_ok() {
+            self.record_assignment(column.into(), offset);
             to().map(|f: VR| {
                 let existing = self
                     .fixed
@@ -153,6 +436,60 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
         res
     }
 
+    fn record_assignment(&mut self, column: Column<Any>, offset: usize) {
+        if let Some(region_id) = self.current_region {
+            self.region_assignments
+                .entry(region_id)
+                .or_default()
+                .push((column, offset));
+        }
+    }
+
+    /// Leaves the assignment out of the witness digests, e.g. for values that
+    /// differ between runs without changing the witness in a meaningful way
+    pub(crate) fn mark_volatile(&mut self, column: impl Into<Column<Any>>, offset: usize) {
+        self.volatile.insert((column.into(), offset));
+    }
+
+    /// SHA-256 of the advice and fixed values assigned in all instances of the
+    /// region, as `(column type, column index, offset, value)` sorted by
+    /// column and offset. Stays the same across runs as long as the witness
+    /// of the region is the same, ignoring the volatile assignments.
+    #[cfg(feature = "sha2")]
+    pub(crate) fn witness_digest(&self, region_id: usize) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+        let mut entries = self
+            .region_assignments
+            .get(&region_id)
+            .map(|assignments| {
+                assignments
+                    .iter()
+                    .filter(|assignment| !self.volatile.contains(assignment))
+                    .map(|(column, offset)| {
+                        let (kind, values) = match column.column_type() {
+                            Any::Fixed => (1u8, &self.fixed),
+                            _ => (0u8, &self.advice),
+                        };
+                        let value = values
+                            .get(&(column.index(), *offset))
+                            .copied()
+                            .unwrap_or(F::ZERO);
+                        (kind, column.index(), *offset, value)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        entries.sort_by_key(|(kind, index, offset, _)| (*kind, *index, *offset));
+        let mut hasher = Sha256::new();
+        for (kind, index, offset, value) in entries {
+            hasher.update([kind]);
+            hasher.update((index as u64).to_le_bytes());
+            hasher.update((offset as u64).to_le_bytes());
+            hasher.update(value.to_repr().as_ref());
+        }
+        hasher.finalize().into()
+    }
+
     pub fn get_fixed(&self, row_index: usize, column_index: usize, rotation: Rotation) -> F {
         let zero = F::ZERO;
         *self
@@ -169,6 +506,76 @@ impl<'r, 'b, F: Field> CachedRegion<'r, 'b, F> {
             .unwrap_or(&zero)
     }
 
+    /// Evaluates the expression with the values assigned to the region at
+    /// `offset`, unassigned values are zero
+    pub(crate) fn evaluate<S: ChallengeSet<F>>(
+        &self,
+        expr: &Expression<F>,
+        challenges: &S,
+        offset: usize,
+    ) -> Value<F> {
+        expr.evaluate(
+            &|scalar| Value::known(scalar),
+            &|_| unimplemented!("selector column"),
+            &|fixed_query| {
+                Value::known(self.get_fixed(
+                    offset,
+                    fixed_query.column_index(),
+                    fixed_query.rotation(),
+                ))
+            },
+            &|advice_query| {
+                Value::known(self.get_advice(
+                    offset,
+                    advice_query.column_index(),
+                    advice_query.rotation(),
+                ))
+            },
+            &|_| unimplemented!("instance column"),
+            &|challenge| *challenges.indexed()[challenge.index()],
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, scalar| a * Value::known(scalar),
+        )
+    }
+
+    /// Panics listing the cells of the checklist (see
+    /// `ConstraintBuilder::assignment_checklist`) that weren't assigned for
+    /// the region at `offset`
+    pub(crate) fn assert_checklist_complete(&self, checklist: &[CellInfo], offset: usize) {
+        let missing = checklist
+            .iter()
+            .filter(|cell| !self.assigned.contains_key(&(cell.column.index(), offset + cell.rotation)))
+            .map(|cell| cell.to_string())
+            .collect::<Vec<_>>();
+        assert!(
+            missing.is_empty(),
+            "unassigned cells at offset {}: {}",
+            offset,
+            missing.join(", ")
+        );
+    }
+
+    /// The assigned cells of the public inputs exposed with
+    /// `ConstraintBuilder::expose_public`, for the region at `offset`, as
+    /// `(cell, instance column, row)`. Pass them to `constrain_publics`
+    /// once the region is assigned.
+    pub(crate) fn public_cells<C: CellType, T: TableTag>(
+        &self,
+        cb: &ConstraintBuilder<F, C, T>,
+        offset: usize,
+    ) -> Result<Vec<(circuit::Cell, Column<Instance>, usize)>, Error> {
+        cb.publics()
+            .iter()
+            .map(|public| {
+                let key = (public.cell.column().index(), offset + public.cell.rotation());
+                let cell = self.assigned.get(&key).ok_or(Error::Synthesis)?;
+                Ok((*cell, public.instance, public.row))
+            })
+            .collect()
+    }
+
     /// Constrains a cell to have a constant value.
     ///
     /// Returns an error if the cell is in a column where equality has not been
@@ -192,6 +599,9 @@ pub struct StoredExpression<F, C: CellType> {
     pub(crate) cell_type: C,
     pub(crate) expr: Expression<F>,
     pub(crate) expr_id: String,
+    /// The cell holds the inverse of the expression (`0` when the expression
+    /// is `0`) instead of the expression itself
+    pub(crate) inverse: bool,
 }
 
 impl<F, C: CellType> Hash for StoredExpression<F, C> {
@@ -201,38 +611,101 @@ impl<F, C: CellType> Hash for StoredExpression<F, C> {
     }
 }
 
+impl<F: Field, C: CellType> fmt::Display for StoredExpression<F, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} = {}{} (degree {}, {} nodes)",
+            self.name,
+            self.cell,
+            if self.inverse { "1 / " } else { "" },
+            expr_to_string(&self.expr, 3),
+            self.expr.degree(),
+            expr_nodes(&self.expr),
+        )
+    }
+}
+
 impl<F: Field, C: CellType> StoredExpression<F, C> {
+    /// Index of the advice column of the cell the expression is stored in
+    pub fn column_index(&self) -> usize {
+        self.cell.column().index()
+    }
+
+    /// The value of the cell at `offset`, without assigning it
+    pub fn evaluate<S: ChallengeSet<F>>(
+        &self,
+        region: &CachedRegion<'_, '_, F>,
+        challenges: &S,
+        offset: usize,
+    ) -> Value<F> {
+        let value = region.evaluate(&self.expr, challenges, offset);
+        if self.inverse {
+            value.map(|value| value.invert().unwrap_or(F::ZERO))
+        } else {
+            value
+        }
+    }
+
     pub fn assign<S: ChallengeSet<F>>(
         &self,
         region: &mut CachedRegion<'_, '_, F>,
         challenges: &S,
         offset: usize,
     ) -> Result<Value<F>, Error> {
-        let value = self.expr.evaluate(
-            &|scalar| Value::known(scalar),
-            &|_| unimplemented!("selector column"),
-            &|fixed_query| {
-                Value::known(region.get_fixed(
-                    offset,
-                    fixed_query.column_index(),
-                    fixed_query.rotation(),
-                ))
-            },
-            &|advice_query| {
-                Value::known(region.get_advice(
-                    offset,
-                    advice_query.column_index(),
-                    advice_query.rotation(),
-                ))
-            },
-            &|_| unimplemented!("instance column"),
-            &|challenge| *challenges.indexed()[challenge.index()],
-            &|a| -a,
-            &|a, b| a + b,
-            &|a, b| a * b,
-            &|a, scalar| a * Value::known(scalar),
-        );
+        let value = self.evaluate(region, challenges, offset);
         self.cell.assign_value(region, offset, value)?;
         Ok(value)
     }
 }
+
+/// A value decomposed into little-endian byte cells by
+/// `ConstraintBuilder::require_in_range`
+#[derive(Clone, Debug)]
+pub struct RangeDecomposition<F> {
+    pub(crate) name: &'static str,
+    pub(crate) value: Expression<F>,
+    pub(crate) bytes: Vec<Cell<F>>,
+}
+
+impl<F: Field> fmt::Display for RangeDecomposition<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in {} bytes",
+            self.name,
+            expr_to_string(&self.value, 3),
+            self.bytes.len()
+        )
+    }
+}
+
+impl<F: Field> RangeDecomposition<F> {
+    /// Assigns the lowest bytes of the value to the byte cells. The bytes
+    /// of a value out of range don't add up to it, so the constraint fails.
+    pub fn assign<S: ChallengeSet<F>>(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        challenges: &S,
+        offset: usize,
+    ) -> Result<(), Error> {
+        let value = region.evaluate(&self.value, challenges, offset);
+        for (idx, byte) in self.bytes.iter().enumerate() {
+            let byte_value = value.map(|value| F::from(value.to_repr().as_ref()[idx] as u64));
+            byte.assign_value(region, offset, byte_value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies the public cells returned by `CachedRegion::public_cells` into
+/// their instance columns
+pub(crate) fn constrain_publics<F: Field>(
+    layouter: &mut impl Layouter<F>,
+    cells: &[(circuit::Cell, Column<Instance>, usize)],
+) -> Result<(), Error> {
+    for (cell, instance, row) in cells.iter() {
+        layouter.constrain_instance(*cell, *instance, *row)?;
+    }
+    Ok(())
+}
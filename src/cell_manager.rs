@@ -1,9 +1,10 @@
 //! Cell manager
 use super::constraint_builder::ConstraintBuilder;
 use super::cached_region::CachedRegion;
-use super::util::{rlc, query_expression, Expr};
-use zkevm_circuits::table::LookupTable;
+use super::util::{from_bytes, intern, rlc, query_expression, Expr};
+use zkevm_circuits::{table::LookupTable, util::word::Word};
 use eth_types::Field;
+use itertools::Itertools;
 use halo2_proofs::{
     circuit::{AssignedCell, Value},
     plonk::{
@@ -12,23 +13,46 @@ use halo2_proofs::{
     },
     poly::Rotation,
 };
-use std::{cmp::Ordering, collections::BTreeMap, fmt::Debug, hash::Hash};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Debug},
+    hash::Hash,
+    ops::{Add, Mul, Neg, Sub},
+};
 
 #[derive(Clone, Debug, Default)]
-pub(crate) struct Cell<F> {
+pub struct Cell<F> {
     // expression for constraint
     expression: Option<Expression<F>>,
     pub column: Option<Column<Advice>>,
     // relative position to selector for synthesis
     pub rotation: usize,
+    // allocation round of the cell manager the cell was queried in
+    pub(crate) generation: usize,
+    // name of the column of the cell, shown by `Display`
+    pub(crate) annotation: Option<&'static str>,
 }
 
 impl<F: Field> Cell<F> {
-    pub(crate) fn new(meta: &mut VirtualCells<F>, column: Column<Advice>, rotation: usize) -> Self {
+    pub(crate) fn new(meta: &mut impl ColumnAllocator<F>, column: Column<Advice>, rotation: usize) -> Self {
         Self {
             expression: Some(meta.query_advice(column, Rotation(rotation as i32))),
             column: Some(column),
             rotation,
+            generation: 0,
+            annotation: None,
+        }
+    }
+
+    /// Cell returned by dummy builders, `0` and not in any column
+    pub(crate) fn placeholder() -> Self {
+        Self {
+            expression: Some(0.expr()),
+            column: None,
+            rotation: 0,
+            generation: 0,
+            annotation: None,
         }
     }
 
@@ -88,6 +112,19 @@ impl<F: Field> Cell<F> {
     }
 }
 
+impl<F> fmt::Display for Cell<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "advice[{}]@{}", column.index(), self.rotation)?,
+            None => write!(f, "cell@{}", self.rotation)?,
+        }
+        match self.annotation {
+            Some(annotation) => write!(f, " ({})", annotation),
+            None => Ok(()),
+        }
+    }
+}
+
 impl<F: Field> Expr<F> for Cell<F> {
     fn expr(&self) -> Expression<F> {
         self.expression.as_ref().unwrap().clone()
@@ -100,6 +137,126 @@ impl<F: Field> Expr<F> for &Cell<F> {
     }
 }
 
+/// Implements a binary operator on cell references, mixed with expressions
+/// and constants, so `&a + &b * 2` works without calling `expr()` on every
+/// operand. The result is always an `Expression<F>`.
+macro_rules! impl_cell_op {
+    ($trait:ident, $method:ident) => {
+        impl<F: Field> $trait<&Cell<F>> for &Cell<F> {
+            type Output = Expression<F>;
+
+            fn $method(self, rhs: &Cell<F>) -> Expression<F> {
+                $trait::$method(self.expr(), rhs.expr())
+            }
+        }
+
+        impl<F: Field> $trait<Expression<F>> for &Cell<F> {
+            type Output = Expression<F>;
+
+            fn $method(self, rhs: Expression<F>) -> Expression<F> {
+                $trait::$method(self.expr(), rhs)
+            }
+        }
+
+        impl<F: Field> $trait<&Cell<F>> for Expression<F> {
+            type Output = Expression<F>;
+
+            fn $method(self, rhs: &Cell<F>) -> Expression<F> {
+                $trait::$method(self, rhs.expr())
+            }
+        }
+
+        impl<F: Field> $trait<u64> for &Cell<F> {
+            type Output = Expression<F>;
+
+            fn $method(self, rhs: u64) -> Expression<F> {
+                $trait::$method(self.expr(), rhs.expr())
+            }
+        }
+    };
+}
+
+impl_cell_op!(Add, add);
+impl_cell_op!(Sub, sub);
+impl_cell_op!(Mul, mul);
+
+impl<F: Field> Neg for &Cell<F> {
+    type Output = Expression<F>;
+
+    fn neg(self) -> Expression<F> {
+        -self.expr()
+    }
+}
+
+/// A 256-bit word stored in two cells of 128-bit limbs
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WordCell<F> {
+    pub(crate) lo: Cell<F>,
+    pub(crate) hi: Cell<F>,
+}
+
+impl<F: Field> WordCell<F> {
+    pub(crate) fn new(lo: Cell<F>, hi: Cell<F>) -> Self {
+        Self { lo, hi }
+    }
+
+    /// The word of the limbs
+    pub(crate) fn expr(&self) -> Word<Expression<F>> {
+        Word::new([self.lo.expr(), self.hi.expr()])
+    }
+
+    /// `lo + hi ⋅ r`
+    pub(crate) fn rlc(&self, r: Expression<F>) -> Expression<F> {
+        rlc::expr(&[self.lo.expr(), self.hi.expr()], r)
+    }
+
+    /// Assigns the limbs of the word
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        word: eth_types::Word,
+    ) -> Result<(), Error> {
+        let mut bytes = [0u8; 32];
+        word.to_little_endian(&mut bytes);
+        self.lo.assign(region, offset, from_bytes::value(&bytes[..16]))?;
+        self.hi.assign(region, offset, from_bytes::value(&bytes[16..]))?;
+        Ok(())
+    }
+}
+
+/// Creates and queries the columns of a cell manager, so it can be configured
+/// against a `ConstraintSystem` as well as against a mock
+pub trait ColumnAllocator<F: Field> {
+    /// Creates a new advice column in the given phase
+    fn alloc_advice_column(&mut self, phase: u8) -> Column<Advice>;
+
+    /// Enables equality constraints on the column
+    fn enable_equality(&mut self, column: Column<Advice>);
+
+    /// Queries the column at the given rotation
+    fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F>;
+}
+
+impl<F: Field> ColumnAllocator<F> for ConstraintSystem<F> {
+    fn alloc_advice_column(&mut self, phase: u8) -> Column<Advice> {
+        match phase {
+            0 => self.advice_column_in(FirstPhase),
+            1 => self.advice_column_in(SecondPhase),
+            2 => self.advice_column_in(ThirdPhase),
+            _ => unreachable!(),
+        }
+    }
+
+    fn enable_equality(&mut self, column: Column<Advice>) {
+        ConstraintSystem::enable_equality(self, column);
+    }
+
+    fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
+        query_expression(self, |meta| meta.query_advice(column, at))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CellConfig<C: CellType> {
     pub cell_type: C,
@@ -116,13 +273,8 @@ impl<C: CellType> CellConfig<C> {
         }
     }
 
-    pub fn init_column<F: Field>(&self, meta: &mut ConstraintSystem<F>) -> Column<Advice> {
-        let column = match self.phase {
-            0 => meta.advice_column_in(FirstPhase),
-            1 => meta.advice_column_in(SecondPhase),
-            2 => meta.advice_column_in(ThirdPhase),
-            _ => unreachable!(),
-        };
+    pub fn init_column<F: Field>(&self, meta: &mut impl ColumnAllocator<F>) -> Column<Advice> {
+        let column = meta.alloc_advice_column(self.phase);
         if self.is_permute {
             meta.enable_equality(column);
         }
@@ -130,11 +282,22 @@ impl<C: CellType> CellConfig<C> {
     }
 }
 
+/// Tag of a table loaded into the constraint builder. Implemented for all
+/// types with the required bounds, so circuits can use their own enum of tags
+/// independently of the cell type (see `ConstraintBuilder`).
+pub trait TableTag:
+    Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Hash + 'static
+{
+}
+
+impl<T: Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Hash + 'static> TableTag for T {}
+
 pub trait CellType:
     Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Hash + Default
 {
-    /// This is the table type for lookups
-    type TableType: Clone + Copy + Debug + PartialEq + Eq + PartialOrd + Ord + Hash;
+    /// This is the table type for lookups of the cell types that are lookups,
+    /// and the default table tag of the constraint builder
+    type TableType: TableTag;
 
     fn byte_type() -> Option<Self>;
 
@@ -153,7 +316,10 @@ pub trait CellType:
     /// Return the storage phase of phase
     fn storage_for_phase(phase: u8) -> Self;
 
-    /// Creates a type from a unique id
+    /// Creates a type from a unique id, used for types minted at configure
+    /// time (see `ConstraintBuilder::new_dynamic_cell_type`). Types created
+    /// from different ids have to be different, so the id needs to take part
+    /// in `Eq`, `Ord` and `Hash`, e.g. by returning `Self::Dynamic(id)`.
     fn create_type(id: usize) -> Self;
 
     /// Returns the table type of the lookup (if it's a lookup)
@@ -165,6 +331,76 @@ pub trait CellType:
     }
 }
 
+/// Defines a cell type enum and implements `CellType` for it. Next to the
+/// listed variants the enum gets a `Dynamic(usize)` variant for the types
+/// created with `create_type`. The storage types are listed by phase, the
+/// first one is the default type.
+///
+/// ```ignore
+/// cell_type! {
+///     pub enum MyCellType: MyTableTag {
+///         storage: [StoragePhase1, StoragePhase2],
+///         byte: StoragePhase1,
+///         lookups: [LookupByte => MyTableTag::Byte],
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! cell_type {
+    (@byte_type) => {
+        None
+    };
+    (@byte_type $byte:ident) => {
+        Some(Self::$byte)
+    };
+    (
+        $vis:vis enum $name:ident: $table:ty {
+            storage: [$first:ident $(, $storage:ident)* $(,)?],
+            $(byte: $byte:ident,)?
+            $(lookups: [$($lookup:ident => $tag:expr),* $(,)?],)?
+        }
+    ) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        $vis enum $name {
+            $first,
+            $($storage,)*
+            $($($lookup,)*)?
+            Dynamic(usize),
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::$first
+            }
+        }
+
+        impl $crate::cell_manager::CellType for $name {
+            type TableType = $table;
+
+            fn byte_type() -> Option<Self> {
+                $crate::cell_type!(@byte_type $($byte)?)
+            }
+
+            fn storage_for_phase(phase: u8) -> Self {
+                *[Self::$first, $(Self::$storage),*]
+                    .get(phase as usize)
+                    .unwrap_or_else(|| panic!("no storage type for phase {}", phase))
+            }
+
+            fn create_type(id: usize) -> Self {
+                Self::Dynamic(id)
+            }
+
+            fn lookup_table_type(&self) -> Option<Self::TableType> {
+                match self {
+                    $($(Self::$lookup => Some($tag),)*)?
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum DefaultCellType {
     StoragePhase1,
@@ -214,6 +450,20 @@ pub(crate) struct CellColumn<F, C: CellType> {
     pub(crate) expr: Expression<F>,
     pub(super) height: usize,
     pub(super) index: usize,
+    /// Rows at the start of the column used by something else, like a
+    /// memory bank sharing the column
+    pub(super) reserved: usize,
+    /// Rows of cells kept alive across resets, never allocated again
+    pub(super) pinned: BTreeSet<usize>,
+}
+
+impl<F, C: CellType> CellColumn<F, C> {
+    /// Moves the height past the pinned rows
+    fn skip_pinned(&mut self) {
+        while self.pinned.contains(&self.height) {
+            self.height += 1;
+        }
+    }
 }
 
 impl<F: Field, C: CellType> PartialEq for CellColumn<F, C> {
@@ -244,6 +494,13 @@ impl<F: Field, C: CellType> Expr<F> for CellColumn<F, C> {
     }
 }
 
+/// Allocation state of a cell manager, see `CellManager::snapshot`
+#[derive(Clone, Debug)]
+pub(crate) struct CellManagerSnapshot {
+    heights: Vec<usize>,
+    height_limit: usize,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct CellManager<F, C: CellType> {
     configs: Vec<CellConfig<C>>,
@@ -251,6 +508,12 @@ pub struct CellManager<F, C: CellType> {
     height: usize,
     height_limit: usize,
     offset: usize,
+    /// Incremented on every reset, so cells from before can be told apart
+    generation: usize,
+    /// Generation in which each `(column index, row)` was last allocated
+    allocations: HashMap<(usize, usize), usize>,
+    /// Pairs of cell types whose columns are interleaved, see `prefer_adjacent`
+    adjacent: Vec<(C, C)>,
 }
 
 impl<F: Field, C: CellType> CellManager<F, C> {
@@ -261,13 +524,16 @@ impl<F: Field, C: CellType> CellManager<F, C> {
             height: max_height,
             height_limit: max_height,
             offset,
+            generation: 0,
+            allocations: HashMap::new(),
+            adjacent: Vec::new(),
         }
     }
 
-    pub(crate) fn add_columns(
+    pub(crate) fn add_columns<T: TableTag + From<C::TableType>>(
         &mut self,
-        meta: &mut ConstraintSystem<F>,
-        cb: &mut ConstraintBuilder<F, C>,
+        meta: &mut impl ColumnAllocator<F>,
+        cb: &mut ConstraintBuilder<F, C, T>,
         cell_type: C,
         phase: u8,
         permutable: bool,
@@ -277,10 +543,12 @@ impl<F: Field, C: CellType> CellManager<F, C> {
             // Add a column of the specified type
             let config = CellConfig::new(cell_type, phase, permutable);
             let col = config.init_column(meta);
+            let annotation = intern(&format!("{:?}", cell_type));
             let mut cells = Vec::new();
             for r in 0..self.height_limit {
-                query_expression(meta, |meta| {
-                    cells.push(Cell::new(meta, col, self.offset + r));
+                cells.push(Cell {
+                    annotation: Some(annotation),
+                    ..Cell::new(meta, col, self.offset + r)
                 });
             }
             let column_expr = cells[0].expr();
@@ -291,27 +559,157 @@ impl<F: Field, C: CellType> CellManager<F, C> {
                 height: 0,
                 expr: column_expr.expr(),
                 cells,
+                reserved: 0,
+                pinned: BTreeSet::new(),
             });
             self.configs.push(config);
 
             // For cell types that are lookups, generate the lookup here
-            if let Some(table) = cell_type.lookup_table_type() {
+            if let Some(table) = cb.cell_type_table(cell_type) {
                 cb.add_lookup(
                     format!("{:?}", table),
                     vec![column_expr.expr()],
                     vec![rlc::expr(
                         &cb.table(table),
-                        cb.lookup_challenge.clone().unwrap(),
+                        cb.lookup_challenge
+                            .clone()
+                            .expect("lookup cell types require a lookup challenge"),
                     )],
                 );
             }
         }
+        self.apply_adjacency();
+    }
+
+    /// Interleaves the columns of the two types (`a, b, a, b, ..`), so cells
+    /// copied between them can be in neighboring columns, i.e. columns next to
+    /// each other in the order of the cell manager. Also applies to columns
+    /// added later, needs to be called before cells are queried.
+    pub(crate) fn prefer_adjacent(&mut self, cell_type_a: C, cell_type_b: C) {
+        assert_ne!(cell_type_a, cell_type_b, "a cell type is always adjacent to itself");
+        self.adjacent.push((cell_type_a, cell_type_b));
+        self.apply_adjacency();
+    }
+
+    /// Orders the columns following the adjacency preferences, the
+    /// interleaved columns take the place of the first column of either type
+    fn apply_adjacency(&mut self) {
+        for (a, b) in self.adjacent.clone() {
+            let typed = |cell_type: C| {
+                self.columns
+                    .iter()
+                    .filter(|column| column.cell_type == cell_type)
+                    .map(|column| column.index)
+                    .collect::<Vec<_>>()
+            };
+            let mut interleaved = Some(typed(a).into_iter().interleave(typed(b)));
+            let mut order = Vec::with_capacity(self.columns.len());
+            for column in self.columns.iter() {
+                if column.cell_type != a && column.cell_type != b {
+                    order.push(column.index);
+                } else if let Some(interleaved) = interleaved.take() {
+                    order.extend(interleaved);
+                }
+            }
+            self.reorder(&order);
+        }
+    }
+
+    /// Puts the columns in the order of their current indices in `order`
+    fn reorder(&mut self, order: &[usize]) {
+        let mut new_index = vec![0; order.len()];
+        for (index, old_index) in order.iter().enumerate() {
+            new_index[*old_index] = index;
+        }
+        let mut columns = std::mem::take(&mut self.columns).into_iter().map(Some).collect::<Vec<_>>();
+        let mut configs = std::mem::take(&mut self.configs).into_iter().map(Some).collect::<Vec<_>>();
+        for (index, old_index) in order.iter().enumerate() {
+            let mut column = columns[*old_index].take().unwrap();
+            column.index = index;
+            self.columns.push(column);
+            self.configs.push(configs[*old_index].take().unwrap());
+        }
+        self.allocations = std::mem::take(&mut self.allocations)
+            .into_iter()
+            .map(|((index, row), generation)| ((new_index[index], row), generation))
+            .collect();
+    }
+
+    /// For every `prefer_adjacent` preference, the number of neighboring
+    /// column pairs of the two types that were requested (the number of
+    /// columns of the rarer type) and that the layout has
+    pub(crate) fn adjacency_stats(&self) -> Vec<((C, C), usize, usize)> {
+        self.adjacent
+            .iter()
+            .map(|&(a, b)| {
+                let count = |cell_type: C| {
+                    self.columns.iter().filter(|column| column.cell_type == cell_type).count()
+                };
+                let mut satisfied = 0;
+                let mut used = HashSet::new();
+                for (lhs, rhs) in self.columns.iter().tuple_windows() {
+                    let types = (lhs.cell_type, rhs.cell_type);
+                    if (types == (a, b) || types == (b, a))
+                        && !used.contains(&lhs.index)
+                        && !used.contains(&rhs.index)
+                    {
+                        used.extend([lhs.index, rhs.index]);
+                        satisfied += 1;
+                    }
+                }
+                ((a, b), count(a).min(count(b)), satisfied)
+            })
+            .collect()
+    }
+
+    /// Queries a cell of each type, in neighboring columns at the same
+    /// rotation, for gadgets copying between the two every row. Rows skipped
+    /// to line up the cells are left unused.
+    pub(crate) fn query_adjacent_pair(&mut self, cell_type_a: C, cell_type_b: C) -> (Cell<F>, Cell<F>) {
+        let free_row = |a: &CellColumn<F, C>, b: &CellColumn<F, C>| {
+            let mut row = a.height.max(b.height);
+            while a.pinned.contains(&row) || b.pinned.contains(&row) {
+                row += 1;
+            }
+            row
+        };
+        let (a, b, row) = self
+            .columns
+            .iter()
+            .tuple_windows()
+            .filter_map(|(lhs, rhs)| match (lhs.cell_type, rhs.cell_type) {
+                types if types == (cell_type_a, cell_type_b) => Some((lhs, rhs)),
+                types if types == (cell_type_b, cell_type_a) => Some((rhs, lhs)),
+                _ => None,
+            })
+            .map(|(a, b)| (a.index, b.index, free_row(a, b)))
+            .filter(|(_, _, row)| *row < self.height_limit)
+            .min_by_key(|(_, _, row)| *row)
+            .unwrap_or_else(|| {
+                panic!(
+                    "no neighboring columns of types {:?} and {:?} with a free row",
+                    cell_type_a, cell_type_b
+                )
+            });
+        let [a, b] = [a, b].map(|index| {
+            let column = &mut self.columns[index];
+            let mut cell = column.cells[row].clone();
+            cell.generation = self.generation;
+            self.allocations.insert((index, row), self.generation);
+            column.height = row + 1;
+            column.skip_pinned();
+            cell
+        });
+        (a, b)
     }
 
     pub(crate) fn restart(&mut self) {
         self.height = self.height_limit;
+        self.generation += 1;
+        self.allocations.clear();
         for col in self.columns.iter_mut() {
-            col.height = 0;
+            col.pinned.clear();
+            col.height = col.reserved;
         }
     }
 
@@ -320,8 +718,12 @@ impl<F: Field, C: CellType> CellManager<F, C> {
         while cells.len() < count {
             let column_idx = self.next_column(cell_type);
             let column = &mut self.columns[column_idx];
-            cells.push(column.cells[column.height].clone());
+            let mut cell = column.cells[column.height].clone();
+            cell.generation = self.generation;
+            self.allocations.insert((column.index, column.height), self.generation);
+            cells.push(cell);
             column.height += 1;
+            column.skip_pinned();
         }
         cells
     }
@@ -333,11 +735,94 @@ impl<F: Field, C: CellType> CellManager<F, C> {
     pub(crate) fn reset(&mut self, height_limit: usize) {
         assert!(height_limit <= self.height);
         self.height_limit = height_limit;
+        self.generation += 1;
         for column in self.columns.iter_mut() {
-            column.height = 0;
+            column.height = column.reserved;
+            column.skip_pinned();
         }
     }
 
+    /// Like `reset`, but keeps the cells allocated when the snapshot was
+    /// taken, so they can't collide with the cells allocated afterwards
+    pub(crate) fn reset_after(&mut self, snapshot: &CellManagerSnapshot, height_limit: usize) {
+        self.reset(height_limit);
+        for (column, height) in self.columns.iter_mut().zip(snapshot.heights.iter()) {
+            column.height = column.height.max(*height);
+            column.skip_pinned();
+        }
+    }
+
+    /// The allocation cursors of all columns, to go back to with `restore`
+    pub(crate) fn snapshot(&self) -> CellManagerSnapshot {
+        CellManagerSnapshot {
+            heights: self.columns.iter().map(|column| column.height).collect(),
+            height_limit: self.height_limit,
+        }
+    }
+
+    /// Goes back to the allocation cursors of the snapshot: the cells
+    /// allocated since are freed, the ones allocated before stay allocated
+    pub(crate) fn restore(&mut self, snapshot: &CellManagerSnapshot) {
+        self.height_limit = snapshot.height_limit;
+        self.generation += 1;
+        for (column, height) in self.columns.iter_mut().zip(snapshot.heights.iter()) {
+            column.height = column.reserved.max(*height);
+            column.skip_pinned();
+        }
+    }
+
+    /// The column index and row of a cell allocated by this cell manager
+    fn slot(&self, cell: &Cell<F>) -> Option<(usize, usize)> {
+        let column = self
+            .columns
+            .iter()
+            .find(|column| Some(column.column) == cell.column)?;
+        Some((column.index, cell.rotation.checked_sub(self.offset)?))
+    }
+
+    /// Keeps the cell alive across resets: its row is never allocated again
+    /// until `restart`
+    pub(crate) fn pin(&mut self, cell: &Cell<F>) {
+        let (index, row) = self.slot(cell).expect("cell not allocated by this cell manager");
+        let column = &mut self.columns[index];
+        column.pinned.insert(row);
+        column.skip_pinned();
+    }
+
+    /// Returns true if the cell was queried before the last reset and its
+    /// slot was allocated again since, so using the cell aliases a newer
+    /// cell, or its slot was freed by the reset and will be handed out again
+    pub(crate) fn is_stale(&self, cell: &Cell<F>) -> bool {
+        let (index, row) = match self.slot(cell) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let column = &self.columns[index];
+        match self.allocations.get(&(index, row)) {
+            Some(generation) if *generation > cell.generation => true,
+            Some(_) => row >= column.height && !column.pinned.contains(&row),
+            None => false,
+        }
+    }
+
+    /// Reserves the first `rows` rows of a column of the given type for use
+    /// outside of the cell manager, returning the column. Cells are only
+    /// allocated below the reserved rows of the region, so they start at
+    /// absolute row `rows` or later and never double book the first `rows`
+    /// rows of the circuit.
+    pub(crate) fn reserve_rows(&mut self, cell_type: C, rows: usize) -> Column<Advice> {
+        let column = self
+            .columns
+            .iter_mut()
+            .find(|column| column.cell_type == cell_type && column.reserved == 0)
+            .unwrap_or_else(|| panic!("no column of type {:?} left to share", cell_type));
+        assert_eq!(column.height, 0, "cells were already allocated in the shared column");
+        assert!(rows <= self.height_limit, "not enough rows to reserve");
+        column.reserved = rows;
+        column.height = rows;
+        column.column
+    }
+
     fn next_column(&self, cell_type: C) -> usize {
         let mut best_index: Option<usize> = None;
         let mut best_height = self.height;
@@ -364,7 +849,8 @@ impl<F: Field, C: CellType> CellManager<F, C> {
             .unwrap()
     }
 
-    /// Returns a map of CellType -> (width, height, num_cells)
+    /// Returns a map of CellType -> (width, height, num_cells), reserved rows
+    /// count as used cells
     pub(crate) fn get_stats(&self) -> BTreeMap<C, (usize, usize, usize)> {
         let mut data = BTreeMap::new();
         for column in self.columns.iter() {
@@ -1,21 +1,45 @@
 //! Circuit utilities
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
     marker::PhantomData,
-    ops::{Add, Mul},
+    ops::{Add, Deref, DerefMut, Mul, Range},
+    panic::Location,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
     vec,
 };
-use zkevm_circuits::table::LookupTable;
-use crate::util::{rlc, query_expression, and, sum, Expr, Scalar};
+use zkevm_circuits::{table::LookupTable, util::word::Word};
+#[cfg(feature = "serde")]
+use crate::util::expr_to_json;
+use crate::util::{
+    advice_queries, and, column_name, expr_to_string, fnv1a, fnv1a_str, from_bytes, has_challenge,
+    intern, linear_combine, padding_sentinel, queried_columns, query_expression, rlc,
+    strip_preamble, sum, term_degrees, Challenges, DeterministicRng, Expr, QueryCache, Scalar,
+};
 use eth_types::{Field};
-use halo2_proofs::plonk::{ConstraintSystem, Expression, Column, Advice};
+use halo2_proofs::{
+    circuit::Value,
+    plonk::{ConstraintSystem, Expression, Column, Advice, Any, Fixed, Instance, TableColumn},
+    poly::Rotation,
+};
 use itertools::Itertools;
 
 use super::{
-    cached_region::StoredExpression,
-    cell_manager::{Cell, CellManager, CellType},
+    cached_region::{RangeDecomposition, StoredExpression},
+    cell_manager::{Cell, CellManager, CellManagerSnapshot, CellType, TableTag, WordCell},
+    gadgets::BinarySelector,
+    table::{rotated_column_name, RangeTable},
 };
 
+/// Contiguous sets larger than this are checked with the fixed range table
+/// (when loaded) instead of a product of differences
+const RANGE_LOOKUP_THRESHOLD: usize = 4;
+
+/// Tuples longer than this are compressed with the shared challenge power
+/// cells (see `ConstraintBuilder::challenge_power`)
+const TUPLE_POWER_THRESHOLD: usize = 4;
+
 fn get_condition_expr<F: Field>(conditions: &Vec<Expression<F>>) -> Expression<F> {
     if conditions.is_empty() {
         1.expr()
@@ -27,54 +51,523 @@ fn get_condition_expr<F: Field>(conditions: &Vec<Expression<F>>) -> Expression<F
 /// Table data
 #[derive(Clone, Debug)]
 pub struct TableData<F> {
-    /// Desciption
-    pub description: &'static str,
-    /// Condition under which the lookup needs to be done
-    pub regional_condition: Expression<F>,
-    /// Need to store local condition for dyn table checks
-    pub local_condition: Expression<F>,
-    /// The values to lookup
-    pub values: Vec<Expression<F>>,
-    /// region
-    pub region_id: usize,
+    description: &'static str,
+    regional_condition: Expression<F>,
+    local_condition: Expression<F>,
+    values: Vec<Expression<F>>,
+    region_id: usize,
 }
 
 /// Lookup data
 #[derive(Clone, Debug)]
 pub struct LookupData<F> {
-    /// Desciption
-    pub description: String,
-    /// Condition under which the lookup needs to be done
-    pub regional_condition: Expression<F>,
-    /// Need to store local condition for dyn table checks
-    pub local_condition: Expression<F>,
-    /// The values to lookup
-    pub values: Vec<Expression<F>>,
-    /// The values to lookup
-    pub table: Vec<Expression<F>>,
-    /// region
-    pub region_id: usize,
+    description: String,
+    regional_condition: Expression<F>,
+    local_condition: Expression<F>,
+    values: Vec<Expression<F>>,
+    table: Vec<Expression<F>>,
+    region_id: usize,
+}
+
+/// A lookup into table columns registered under a tag, built with
+/// `meta.lookup` (see `ConstraintBuilder::add_static_lookup`)
+#[derive(Clone, Debug)]
+struct StaticLookupData<F, T> {
+    tag: T,
+    lookup: LookupData<F>,
+}
+
+/// Conditions are products of booleans, so a constant condition can only be
+/// 0 or 1
+fn debug_assert_boolean_condition<F: Field>(description: &str, condition: &Expression<F>) {
+    if let Expression::Constant(value) = condition {
+        debug_assert!(
+            *value == F::ZERO || *value == F::ONE,
+            "{}: condition is not boolean",
+            description
+        );
+    }
 }
 
 impl<F: Field> TableData<F> {
-    fn condition(&self) -> Expression<F> {
+    /// Creates the table data, checking the values and conditions in debug
+    /// builds
+    pub fn new(
+        description: &'static str,
+        values: Vec<Expression<F>>,
+        local_condition: Expression<F>,
+        regional_condition: Expression<F>,
+        region_id: usize,
+    ) -> Self {
+        debug_assert!(!values.is_empty(), "{}: table without values", description);
+        debug_assert_boolean_condition(description, &local_condition);
+        debug_assert_boolean_condition(description, &regional_condition);
+        Self {
+            description,
+            regional_condition,
+            local_condition,
+            values,
+            region_id,
+        }
+    }
+
+    /// Creates the table data without any checks
+    #[deprecated(note = "use `TableData::new`")]
+    pub fn from_raw_parts(
+        description: &'static str,
+        values: Vec<Expression<F>>,
+        local_condition: Expression<F>,
+        regional_condition: Expression<F>,
+        region_id: usize,
+    ) -> Self {
+        Self {
+            description,
+            regional_condition,
+            local_condition,
+            values,
+            region_id,
+        }
+    }
+
+    /// Desciption
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    /// The values stored in the table
+    pub fn values(&self) -> &[Expression<F>] {
+        &self.values
+    }
+
+    /// Condition of the region the table is stored in
+    pub fn regional_condition(&self) -> &Expression<F> {
+        &self.regional_condition
+    }
+
+    /// Condition the table is stored under within the region
+    pub fn local_condition(&self) -> &Expression<F> {
+        &self.local_condition
+    }
+
+    /// The region the table is stored in
+    pub fn region_id(&self) -> usize {
+        self.region_id
+    }
+
+    /// Condition under which the values are stored
+    pub fn condition(&self) -> Expression<F> {
         self.regional_condition.expr() * self.local_condition.expr()
     }
 }
 
 impl<F: Field> LookupData<F> {
-    fn condition(&self) -> Expression<F> {
+    /// Creates the lookup data, checking the values and conditions in debug
+    /// builds
+    pub fn new(
+        description: String,
+        values: Vec<Expression<F>>,
+        table: Vec<Expression<F>>,
+        local_condition: Expression<F>,
+        regional_condition: Expression<F>,
+        region_id: usize,
+    ) -> Self {
+        debug_assert!(!values.is_empty(), "{}: lookup without values", description);
+        debug_assert_boolean_condition(&description, &local_condition);
+        debug_assert_boolean_condition(&description, &regional_condition);
+        Self {
+            description,
+            regional_condition,
+            local_condition,
+            values,
+            table,
+            region_id,
+        }
+    }
+
+    /// Creates the lookup data without any checks
+    #[deprecated(note = "use `LookupData::new`")]
+    pub fn from_raw_parts(
+        description: String,
+        values: Vec<Expression<F>>,
+        table: Vec<Expression<F>>,
+        local_condition: Expression<F>,
+        regional_condition: Expression<F>,
+        region_id: usize,
+    ) -> Self {
+        Self {
+            description,
+            regional_condition,
+            local_condition,
+            values,
+            table,
+            region_id,
+        }
+    }
+
+    /// Desciption
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The values to lookup
+    pub fn values(&self) -> &[Expression<F>] {
+        &self.values
+    }
+
+    /// The table to lookup into
+    pub fn table(&self) -> &[Expression<F>] {
+        &self.table
+    }
+
+    /// Condition of the region the lookup is done in
+    pub fn regional_condition(&self) -> &Expression<F> {
+        &self.regional_condition
+    }
+
+    /// Condition the lookup is done under within the region
+    pub fn local_condition(&self) -> &Expression<F> {
+        &self.local_condition
+    }
+
+    /// The region the lookup is done in
+    pub fn region_id(&self) -> usize {
+        self.region_id
+    }
+
+    /// Condition under which the lookup needs to be done
+    pub fn condition(&self) -> Expression<F> {
         self.regional_condition.expr() * self.local_condition.expr()
     }
 }
 
+impl<F: Field> fmt::Display for TableData<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (width {}, condition degree {}, region {})",
+            self.description,
+            self.values.len(),
+            self.condition().degree(),
+            self.region_id,
+        )
+    }
+}
+
+impl<F: Field> fmt::Display for LookupData<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} -> {}, condition degree {}, region {})",
+            self.description,
+            self.values.len(),
+            self.table.len(),
+            self.condition().degree(),
+            self.region_id,
+        )
+    }
+}
+
+/// Lookup into a dynamic table by tag, resolved in `finalize`. The table
+/// columns are referenced by name against the table schema, or are the whole
+/// table when `columns` is `None`.
+#[derive(Clone, Debug)]
+struct NamedLookupData<F, T> {
+    description: String,
+    tag: T,
+    columns: Option<Vec<&'static str>>,
+    values: Vec<Expression<F>>,
+    regional_condition: Expression<F>,
+    local_condition: Expression<F>,
+    region_id: usize,
+}
+
+/// Errors found while finalizing the constraint builder
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConstraintBuilderError {
+    /// A lookup references a table by tag, but no table was stored with it
+    MissingTable {
+        /// The lookup
+        lookup: String,
+        /// The table tag
+        table: String,
+    },
+    /// A lookup references a column by name on a table without a schema
+    MissingTableSchema {
+        /// The lookup
+        lookup: String,
+        /// The table tag
+        table: String,
+    },
+    /// A lookup references a column name the table schema doesn't have
+    MissingTableColumn {
+        /// The lookup
+        lookup: String,
+        /// The table tag
+        table: String,
+        /// The missing column
+        column: &'static str,
+    },
+    /// Cells are queried, or an expression needs to be split into cells, but
+    /// the builder has no cell manager
+    CellManagerNotSet {
+        /// Name of the constraint or cell
+        name: String,
+        /// The region the cells are queried in
+        region_id: usize,
+    },
+    /// Byte cells are queried, but the cell type has no byte type
+    NoByteType {
+        /// Name of the constraint or cell
+        name: String,
+        /// The region the cells are queried in
+        region_id: usize,
+    },
+    /// An expression has a higher degree than allowed in the region
+    DegreeExceeded {
+        /// Name of the constraint
+        name: String,
+        /// The region of the expression
+        region_id: usize,
+        /// Degree of the expression
+        degree: usize,
+        /// Max degree in the region
+        max_degree: usize,
+    },
+    /// A tuple is compressed, but the builder has no lookup challenge
+    MissingLookupChallenge {
+        /// Name of the tuple
+        name: String,
+    },
+    /// A cell is imported that no region exported
+    MissingExport {
+        /// Name of the cell
+        name: String,
+        /// The region importing the cell
+        region_id: usize,
+    },
+    /// A cell from an earlier region is used after its slot was freed or
+    /// allocated again
+    StaleCell {
+        /// The cell
+        cell: String,
+        /// The region using the cell
+        region_id: usize,
+    },
+    /// In strict mode, constraints use a column that isn't owned by the cell
+    /// manager and wasn't registered as an external column
+    UnregisteredColumn {
+        /// The column, e.g. `advice[3]`
+        column: String,
+        /// The constraints using the column
+        constraints: Vec<String>,
+        /// Whether the column was queried with the `circuit!` macros
+        tracked: bool,
+    },
+    /// A lookup has more values than its table has columns
+    LookupTooWide {
+        /// The lookup
+        lookup: String,
+        /// The region of the lookup
+        region_id: usize,
+        /// Number of values
+        values: usize,
+        /// Number of table columns
+        columns: usize,
+    },
+    /// A table schema is registered with different column names than before
+    ConflictingSchema {
+        /// The table tag
+        table: String,
+        /// The registered column names
+        schema: Vec<&'static str>,
+        /// The conflicting column names
+        columns: Vec<&'static str>,
+    },
+    /// Several errors, in the order they were found
+    Multiple(Vec<ConstraintBuilderError>),
+}
+
+impl fmt::Display for ConstraintBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingTable { lookup, table } => {
+                write!(f, "lookup '{}': table {} was never stored", lookup, table)
+            }
+            Self::MissingTableSchema { lookup, table } => {
+                write!(f, "lookup '{}': table {} has no schema", lookup, table)
+            }
+            Self::MissingTableColumn { lookup, table, column } => {
+                write!(f, "lookup '{}': table {} has no column '{}'", lookup, table, column)
+            }
+            Self::CellManagerNotSet { name, region_id } => {
+                write!(f, "'{}' in region {}: cell manager not set", name, region_id)
+            }
+            Self::NoByteType { name, region_id } => {
+                write!(f, "'{}' in region {}: no byte type for this cell manager", name, region_id)
+            }
+            Self::DegreeExceeded { name, region_id, degree, max_degree } => write!(
+                f,
+                "'{}' in region {}: degree too high: {} > {}",
+                name, region_id, degree, max_degree
+            ),
+            Self::MissingLookupChallenge { name } => write!(
+                f,
+                "store_tuple requires a lookup challenge; construct the builder with \
+                `Some(challenge)` or call set_lookup_challenge ('{}')",
+                name
+            ),
+            Self::MissingExport { name, region_id } => {
+                write!(f, "'{}' imported in region {} but never exported", name, region_id)
+            }
+            Self::StaleCell { cell, region_id } => write!(
+                f,
+                "{} used in region {} was freed or allocated again, export it to keep it alive",
+                cell, region_id
+            ),
+            Self::UnregisteredColumn { column, constraints, tracked } => write!(
+                f,
+                "{}{} used by '{}' is neither owned by the cell manager nor registered as external",
+                column,
+                if *tracked { "" } else { " (raw meta query)" },
+                constraints.join("', '")
+            ),
+            Self::LookupTooWide { lookup, region_id, values, columns } => write!(
+                f,
+                "lookup '{}' in region {}: {} values for a table of {} columns",
+                lookup, region_id, values, columns
+            ),
+            Self::ConflictingSchema { table, schema, columns } => write!(
+                f,
+                "conflicting schemas for table {}: {:?} and {:?}",
+                table, schema, columns
+            ),
+            Self::Multiple(errors) => {
+                write!(f, "{} errors: {}", errors.len(), errors.iter().join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConstraintBuilderError {}
+
+/// Errors getting a dynamic table by tag
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TableError {
+    /// No table was stored with the tag
+    NotFound {
+        /// The table tag
+        tag: String,
+    },
+    /// The table was stored in multiple fragments, which need to be merged
+    MultipleFragments {
+        /// The table tag
+        tag: String,
+        /// Number of fragments
+        fragments: usize,
+    },
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { tag } => write!(f, "Dynamic table {} not found", tag),
+            Self::MultipleFragments { tag, fragments } => {
+                write!(f, "Dynamic table {} is stored in {} fragments", tag, fragments)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+/// How `ConstraintBuilder::merge` renumbered the regions of the merged
+/// builder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionRemap {
+    offset: usize,
+}
+
+impl RegionRemap {
+    /// The offset added to the region ids
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The new id of a region of the builder passed to `merge`. Region 0
+    /// stays region 0.
+    pub fn region_id(&self, region_id: usize) -> usize {
+        if region_id == 0 {
+            0
+        } else {
+            region_id + self.offset
+        }
+    }
+}
+
+/// A dynamic table exported by one builder (see
+/// `ConstraintBuilder::export_table`) to be imported by another builder
+/// configuring the same constraint system, which can use its own table tags.
+/// The expressions only make sense in that constraint system, so only the
+/// metadata can be serialized.
+#[derive(Clone, Debug)]
+pub struct ExportedTable<F> {
+    description: String,
+    fragments: Vec<TableData<F>>,
+    values: Vec<Expression<F>>,
+    selector: Expression<F>,
+    schema: Option<Vec<&'static str>>,
+    seed: u64,
+}
+
+impl<F: Field> ExportedTable<F> {
+    /// Description of the table in the exporting builder
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The merged table values, not multiplied with the selector
+    pub fn values(&self) -> &[Expression<F>] {
+        &self.values
+    }
+
+    /// Selector of the merged table, checked to be boolean by the exporter
+    pub fn selector(&self) -> &Expression<F> {
+        &self.selector
+    }
+
+    /// The column names, if registered in the exporting builder
+    pub fn schema(&self) -> Option<&[&'static str]> {
+        self.schema.as_deref()
+    }
+
+    /// The seed of the exporting builder, to reproduce its padding
+    /// (see `util::padding_sentinel`)
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+/// Serializes the description, width, schema and seed of the table
+#[cfg(feature = "serde")]
+impl<F: Field> serde::Serialize for ExportedTable<F> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("ExportedTable", 4)?;
+        state.serialize_field("description", &self.description)?;
+        state.serialize_field("width", &self.values.len())?;
+        state.serialize_field("schema", &self.schema)?;
+        state.serialize_field("seed", &self.seed)?;
+        state.end()
+    }
+}
+
 struct TableMerger<F, C> {
     data: Vec<TableData<F>>,
     _phantom: PhantomData<C>,
 }
 
 impl<F: Field, C: CellType> TableMerger<F, C> {
-    fn merge_check(&self, cb: &mut ConstraintBuilder<F, C>) {
+    fn merge_check<T: TableTag>(&self, cb: &mut ConstraintBuilder<F, C, T>) {
         let selector = sum::expr(self.data.iter().map(|t| t.condition()));
         crate::circuit!([meta, cb], {
             require!(selector => bool);
@@ -82,6 +575,12 @@ impl<F: Field, C: CellType> TableMerger<F, C> {
     }
 
     fn merge_unsafe(&self) -> (Expression<F>, Vec<Expression<F>>) {
+        self.merge_with_default(0.expr())
+    }
+
+    /// Merges the tables, padding the tables shorter than the longest one
+    /// with `default`
+    fn merge_with_default(&self, default: Expression<F>) -> (Expression<F>, Vec<Expression<F>>) {
         if self.data.is_empty() {
             return (0.expr(), Vec::new());
         }
@@ -89,191 +588,2365 @@ impl<F: Field, C: CellType> TableMerger<F, C> {
         // Merge
         let max_length = self.data.iter().map(|t| t.values.len()).max().unwrap();
         let mut merged_values = vec![0.expr(); max_length];
-        let default_value = 0.expr();
         merged_values.iter_mut().enumerate().for_each(|(idx, v)| {
             *v = sum::expr(
                 self.data
                     .iter()
-                    .map(|t| t.condition() * t.values.get(idx).unwrap_or(&default_value).expr()),
+                    .map(|t| t.condition() * t.values.get(idx).unwrap_or(&default).expr()),
             );
         });
         (selector, merged_values)
     }
 
-    fn check_and_merge(
+    fn check_and_merge<T: TableTag>(
         &self,
-        cb: &mut ConstraintBuilder<F, C>,
+        cb: &mut ConstraintBuilder<F, C, T>,
+        default: Expression<F>,
     ) -> (Expression<F>, Vec<Expression<F>>) {
         self.merge_check(cb);
-        self.merge_unsafe()
+        self.merge_with_default(default)
     }
 
-    fn merge_and_select(&self, _cb: &mut ConstraintBuilder<F, C>) -> Vec<Expression<F>> {
-        let (selector, v) = self.merge_unsafe();
+    fn merge_and_select<T: TableTag>(
+        &self,
+        _cb: &mut ConstraintBuilder<F, C, T>,
+        default: Expression<F>,
+    ) -> Vec<Expression<F>> {
+        let (selector, v) = self.merge_with_default(default);
         v.iter().map(|v| selector.expr() * v.expr()).collect()
     }
 }
 
-/// Constraint builder
-#[derive(Clone)]
-pub struct ConstraintBuilder<F, C: CellType> {
-    /// Constraints to be returned to meta
-    constraints: Vec<(&'static str, Expression<F>)>,
-    /// Max global degree of constraints
-    max_global_degree: usize,
-    /// Max local degree of constraints inside the current region
-    max_degree: usize,
-    /// conditions for constraints
-    conditions: Vec<Expression<F>>,
-    /// Columns whoes equality constraints needed to be enable
-    equalities: Vec<Column<Advice>>,
-    /// The tables
-    pub tables: HashMap<C::TableType, Vec<TableData<F>>>,
-    /// Lookups
-    pub lookups: Vec<LookupData<F>>,
-    /// All stored expressions
-    pub stored_expressions: HashMap<usize, Vec<StoredExpression<F, C>>>,
-    /// CellManager
-    pub cell_manager: Option<CellManager<F, C>>,
-    /// Disable macro-generated description for constraints & lookups
-    /// for graph display
-    pub disable_description: bool,
-    /// region id
-    pub region_id: usize,
-    /// lookup input challenge
-    pub lookup_challenge: Option<Expression<F>>,
-    /// state contect
-    pub state_context: Vec<Expression<F>>,
-    /// state constraints start
-    pub region_constraints_start: usize,
+/// Returns the first value of the set if it consists of consecutive constants
+fn contiguous_start<F: Field>(set: &[Expression<F>]) -> Option<F> {
+    let values = set
+        .iter()
+        .map(|item| match item {
+            Expression::Constant(value) => Some(*value),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let start = *values.first()?;
+    values
+        .iter()
+        .enumerate()
+        .all(|(idx, value)| *value == start + F::from(idx as u64))
+        .then_some(start)
 }
 
-impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
-    pub(crate) fn new(
-        max_degree: usize,
-        cell_manager: Option<CellManager<F, C>>,
-        lookup_challenge: Option<Expression<F>>,
-    ) -> Self {
-        ConstraintBuilder {
-            constraints: Vec::new(),
-            max_global_degree: max_degree,
-            max_degree,
-            conditions: Vec::new(),
-            equalities: Vec::new(),
-            tables: HashMap::new(),
-            lookups: Vec::new(),
-            cell_manager,
-            disable_description: false,
-            stored_expressions: HashMap::new(),
-            region_id: 0,
-            lookup_challenge,
-            state_context: Vec::new(),
-            region_constraints_start: 0,
-        }
+/// Value shared between the builder and the guards it hands out. Kept behind
+/// a mutex so the builder stays `Send`.
+struct Shared<T>(Arc<Mutex<T>>);
+
+impl<T> Shared<T> {
+    fn new(value: T) -> Self {
+        Self(Arc::new(Mutex::new(value)))
     }
 
-    pub(crate) fn restart(&mut self) {
-        self.constraints.clear();
-        self.conditions.clear();
-        self.equalities.clear();
-        self.tables.clear();
-        self.lookups.clear();
-        self.stored_expressions.clear();
-        self.region_id = 0;
-        self.state_context.clear();
-        self.region_constraints_start = 0;
-        if let Some(cell_manager) = &mut self.cell_manager {
-            cell_manager.restart();
-        }
+    // A guard dropped while unwinding still has to restore the value
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
     }
+}
 
-    pub(crate) fn set_cell_manager(&mut self, cell_manager: CellManager<F, C>) {
-        self.cell_manager = Some(cell_manager);
+impl<T: Default> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
     }
+}
 
-    pub(crate) fn set_max_degree(&mut self, max_degree: usize) {
-        self.max_global_degree = max_degree;
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
     }
+}
 
-    pub(crate) fn push_region(&mut self, region_id: usize, height: usize) {
-        assert!(region_id != 0);
-        self.region_id = region_id;
-        self.state_context = self.conditions.clone();
-        self.max_degree = self.max_global_degree - self.get_condition_expr().degree();
-        self.conditions.clear();
-        self.region_constraints_start = self.constraints.len();
+/// Stack of active conditions, shared with the `ConditionGuard`s so they can
+/// restore it without holding a borrow on the builder
+struct ConditionStack<F>(Shared<Vec<Expression<F>>>);
 
-        // Simply resets the cell manager for now, so all previously allocated cells will be freed
-        self.cell_manager.as_mut().unwrap().reset(height);
+impl<F> Default for ConditionStack<F> {
+    fn default() -> Self {
+        Self(Shared::new(Vec::new()))
     }
+}
 
-    pub(crate) fn pop_region(&mut self) {
-        // Apply the region condition to all contraints added in this region
-        let condition = get_condition_expr(&self.state_context);
-        for idx in self.region_constraints_start..self.constraints.len() {
-            self.constraints[idx].1 = condition.expr() * self.constraints[idx].1.clone();
-        }
-        self.conditions = self.state_context.clone();
-        self.max_degree = self.max_global_degree - self.get_condition_expr().degree();
-        self.region_id = 0;
-        self.state_context.clear();
+impl<F: Clone> Clone for ConditionStack<F> {
+    // A cloned builder gets its own stack
+    fn clone(&self) -> Self {
+        Self(Shared::new(self.0.lock().clone()))
     }
+}
 
-    pub(crate) fn set_disable_description(&mut self, disable_description: bool) {
-        self.disable_description = disable_description;
+/// Operations recorded by the builder, shared with the `OpLogGuard`s and
+/// `ConditionGuard`s
+#[derive(Default)]
+struct OpLog {
+    enabled: bool,
+    ops: Shared<Vec<BuilderOp>>,
+}
+
+impl Clone for OpLog {
+    // A cloned builder gets its own log
+    fn clone(&self) -> Self {
+        Self {
+            enabled: self.enabled,
+            ops: Shared::new(self.ops.lock().clone()),
+        }
     }
+}
 
-    pub(crate) fn require_zero(&mut self, name: &'static str, constraint: Expression<F>) {
-        self.add_constraint(name, constraint);
+/// Restores the condition stack to the state it had when it was created.
+/// This happens when it is dropped, so also when returning early with `?` or
+/// when unwinding from a panic.
+#[must_use = "the conditions are restored when this is dropped"]
+pub struct ConditionGuard<F> {
+    stack: Shared<Vec<Expression<F>>>,
+    saved: Vec<Expression<F>>,
+    /// The operation log with the degrees of the saved conditions, when
+    /// recording
+    oplog: Option<(Shared<Vec<BuilderOp>>, Vec<usize>)>,
+}
+
+impl<F> Drop for ConditionGuard<F> {
+    fn drop(&mut self) {
+        if let Some((ops, degrees)) = self.oplog.take() {
+            ops.lock().push(BuilderOp::SetConditions { degrees });
+        }
+        *self.stack.lock() = std::mem::take(&mut self.saved);
     }
+}
 
-    pub(crate) fn require_equal(
-        &mut self,
-        name: &'static str,
+/// Builder with an extra active condition, which is removed again when the
+/// guard is dropped. Derefs to the builder so constraints can be added
+/// through it.
+#[must_use = "the condition is removed when the guard is dropped"]
+pub struct ConditionScope<'a, F, C: CellType, T = <C as CellType>::TableType> {
+    cb: &'a mut ConstraintBuilder<F, C, T>,
+    _restore: ConditionGuard<F>,
+}
+
+impl<F, C: CellType, T> Deref for ConditionScope<'_, F, C, T> {
+    type Target = ConstraintBuilder<F, C, T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.cb
+    }
+}
+
+impl<F, C: CellType, T> DerefMut for ConditionScope<'_, F, C, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.cb
+    }
+}
+
+/// Context passed to constraint and lookup hooks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HookCtx {
+    /// The region the constraint or lookup is added in
+    pub region_id: usize,
+    /// Degree of the active condition
+    pub condition_degree: usize,
+}
+
+/// Number of constraints, lookups and queried cells of a region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionReport {
+    /// The region id
+    pub region_id: usize,
+    /// The label the region was pushed with, if any
+    pub label: Option<&'static str>,
+    /// Number of constraints
+    pub constraints: usize,
+    /// Number of lookups
+    pub lookups: usize,
+    /// Number of cells queried from the cell manager
+    pub cells: usize,
+}
+
+/// A cell queried from the cell manager, see `assignment_checklist`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CellInfo {
+    /// Name the cell was queried with, indexed when several cells were
+    /// queried at once
+    pub name: String,
+    /// The cell type, in debug format
+    pub cell_type: String,
+    /// The advice column of the cell
+    pub column: Column<Advice>,
+    /// Rotation of the cell relative to the region offset
+    pub rotation: usize,
+}
+
+impl fmt::Display for CellInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' ({}, advice[{}]@{})",
+            self.name,
+            self.cell_type,
+            self.column.index(),
+            self.rotation
+        )
+    }
+}
+
+/// A builder operation recorded in the operation log, see `record_ops`. The
+/// arguments are summarized: names, degrees and counts instead of expressions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuilderOp {
+    /// A constraint, with its degree including the active conditions
+    AddConstraint { name: String, degree: usize },
+    /// The equality constraint of a stored expression
+    StoreExpression { name: String, degree: usize },
+    /// A condition pushed on the condition stack
+    PushCondition { degree: usize },
+    /// The innermost condition popped
+    PopCondition,
+    /// The condition stack replaced by conditions of the given degrees, also
+    /// when restored by a condition guard
+    SetConditions { degrees: Vec<usize> },
+    /// A region entered
+    PushRegion { region_id: usize, height: usize },
+    /// The current region left
+    PopRegion,
+    /// Cells queried from the cell manager
+    QueryCells { cell_type: String, count: usize },
+    /// A row stored in a dynamic table
+    StoreTable { description: String, tag: String, width: usize },
+    /// A lookup
+    AddLookup { description: String, width: usize },
+}
+
+impl fmt::Display for BuilderOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuilderOp::AddConstraint { name, degree } => {
+                write!(f, "add_constraint '{}' (degree {})", name, degree)
+            }
+            BuilderOp::StoreExpression { name, degree } => {
+                write!(f, "store_expression '{}' (degree {})", name, degree)
+            }
+            BuilderOp::PushCondition { degree } => write!(f, "push_condition (degree {})", degree),
+            BuilderOp::PopCondition => write!(f, "pop_condition"),
+            BuilderOp::SetConditions { degrees } => write!(f, "set_conditions (degrees {:?})", degrees),
+            BuilderOp::PushRegion { region_id, height } => {
+                write!(f, "push_region {} (height {})", region_id, height)
+            }
+            BuilderOp::PopRegion => write!(f, "pop_region"),
+            BuilderOp::QueryCells { cell_type, count } => write!(f, "query_cells {} × {}", count, cell_type),
+            BuilderOp::StoreTable { description, tag, width } => {
+                write!(f, "store_table '{}' into {} (width {})", description, tag, width)
+            }
+            BuilderOp::AddLookup { description, width } => {
+                write!(f, "add_lookup '{}' (width {})", description, width)
+            }
+        }
+    }
+}
+
+/// Formats the operations, one per line prefixed with their index
+pub fn format_oplog(ops: &[BuilderOp]) -> String {
+    ops.iter()
+        .enumerate()
+        .map(|(idx, op)| format!("#{}: {}\n", idx, op))
+        .collect()
+}
+
+/// Prints the operation log of the builder it was created by when dropped
+/// while panicking, see `record_ops`
+#[must_use = "the operation log is only printed when this is dropped"]
+pub struct OpLogGuard {
+    ops: Shared<Vec<BuilderOp>>,
+}
+
+impl Drop for OpLogGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!("constraint builder operations:\n{}", format_oplog(&self.ops.lock()));
+        }
+    }
+}
+
+/// The conditions a constraint was added under, see `set_record_provenance`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConstraintProvenance {
+    /// `(identifier, rendered expression)` of every condition, outermost
+    /// first, starting with the conditions of the region
+    pub conditions: Vec<(&'static str, &'static str)>,
+    /// Name of the region the constraint was added in
+    pub region: &'static str,
+}
+
+impl fmt::Display for ConstraintProvenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {}", self.region)?;
+        if self.conditions.is_empty() {
+            write!(f, ", unconditional")?;
+        }
+        for (idx, (_, condition)) in self.conditions.iter().enumerate() {
+            write!(f, "\n  {}. {}", idx + 1, condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// The construction parameters of a gadget
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GadgetRecord {
+    /// Name of the gadget
+    pub gadget: &'static str,
+    /// The region the gadget was constructed in
+    pub region_id: usize,
+    /// The parameters, as a JSON object
+    pub params: String,
+}
+
+/// How `require_in_set` checks that a value is in a set
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SetMembership {
+    /// Range lookups for large contiguous sets when the fixed range table is
+    /// loaded, `Product` otherwise
+    #[default]
+    Auto,
+    /// The product of the differences with all elements, degree `set.len()`
+    Product,
+    /// Range lookups, needs a contiguous set and the fixed range table
+    Lookup,
+    /// An equality indicator per element, summing to 1. Degree 2 for any
+    /// set size, but needs two cells per element.
+    Indicators,
+}
+
+/// A cell exposed as a public input
+#[derive(Clone, Debug)]
+pub struct PublicInput<F> {
+    /// Name of the public input
+    pub(crate) name: &'static str,
+    /// The cell holding the value
+    pub(crate) cell: Cell<F>,
+    /// The instance column the value is copied to
+    pub(crate) instance: Column<Instance>,
+    /// The row in the instance column
+    pub(crate) row: usize,
+}
+
+/// A lookup argument emitted by `build_lookups`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LookupSummary<T> {
+    /// Description of the lookup
+    pub description: String,
+    /// Tag of the table, when the table is a stored table of the builder
+    pub table: Option<T>,
+    /// Number of table columns
+    pub columns: usize,
+    /// Max degree of the inputs, including the conditions
+    pub max_input_degree: usize,
+}
+
+/// What the condition cells of `set_condition_cell_threshold` saved
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConditionCellStats {
+    /// Number of conditions stored in a cell
+    pub stored: usize,
+    /// Number of constraints multiplied by a condition cell instead of the
+    /// condition
+    pub rewired: usize,
+    /// Total degree the rewired constraints would have had with the condition
+    pub degree_before: usize,
+    /// Total degree of the rewired constraints
+    pub degree_after: usize,
+}
+
+/// What `build_lookups` emitted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LookupBuildReport<T> {
+    /// The lookup arguments, in the order they were emitted
+    pub emitted: Vec<LookupSummary<T>>,
+    /// Lookups left out because an identical lookup was emitted
+    pub deduped: usize,
+    /// Lookups packed together with other lookups into a single argument
+    pub packed: usize,
+    /// Lookups into a table containing a challenge, i.e. tuples compressed
+    /// into a single column
+    pub compressed: usize,
+}
+
+impl<T> Default for LookupBuildReport<T> {
+    fn default() -> Self {
+        Self {
+            emitted: Vec::new(),
+            deduped: 0,
+            packed: 0,
+            compressed: 0,
+        }
+    }
+}
+
+/// What a builder created with `ConstraintBuilder::counting` would have
+/// added, see `ConstraintBuilder::counts`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuilderCounts {
+    /// Number of constraints, including the ones of stored expressions
+    pub constraints: usize,
+    /// Number of cells requested by cell type in debug format, including the
+    /// cells of stored expressions
+    pub cells: BTreeMap<String, usize>,
+    /// Number of lookups by the tag of the table in debug format, `untagged`
+    /// for lookups into tables given as expressions
+    pub lookups: BTreeMap<String, usize>,
+}
+
+/// Statistics of a group of lookups, see `LookupStats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LookupGroupStats {
+    /// Number of lookups
+    pub count: usize,
+    /// Most values looked up by a single lookup
+    pub max_width: usize,
+    /// Highest degree of a value multiplied with the lookup condition
+    pub max_degree: usize,
+}
+
+impl LookupGroupStats {
+    fn add<F: Field>(&mut self, lookup: &LookupData<F>) {
+        let condition_degree = lookup.condition().degree();
+        self.count += 1;
+        self.max_width = self.max_width.max(lookup.values.len());
+        self.max_degree = lookup
+            .values
+            .iter()
+            .map(|value| value.degree() + condition_degree)
+            .fold(self.max_degree, usize::max);
+    }
+}
+
+impl fmt::Display for LookupGroupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} lookups, max width {}, max degree {}",
+            self.count, self.max_width, self.max_degree
+        )
+    }
+}
+
+/// Where the lookups of a builder go, see `ConstraintBuilder::lookup_stats`.
+/// Displays as a table sorted by prefix, region id and tag.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Lookups by description prefix: the description without the
+    /// `concat_with_preamble!` preamble, up to a ` (` suffix, so
+    /// `x (range)` and `x (range max)` are both counted under `x`
+    pub by_prefix: BTreeMap<String, LookupGroupStats>,
+    /// Lookups by region id
+    pub by_region: BTreeMap<usize, LookupGroupStats>,
+    /// Number of fragments of the dynamic tables by tag in debug format
+    pub table_fragments: BTreeMap<String, usize>,
+}
+
+impl fmt::Display for LookupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (prefix, stats) in self.by_prefix.iter() {
+            writeln!(f, "'{}': {}", prefix, stats)?;
+        }
+        for (region_id, stats) in self.by_region.iter() {
+            writeln!(f, "region {}: {}", region_id, stats)?;
+        }
+        for (tag, fragments) in self.table_fragments.iter() {
+            writeln!(f, "table {}: {} fragments", tag, fragments)?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics of a region, see `CircuitReport`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegionStats {
+    /// The region id
+    pub region_id: usize,
+    /// `region <id>`, followed by the label when the region has one
+    pub name: String,
+    /// Number of constraints
+    pub constraints: usize,
+    /// Number of constraints by degree
+    pub degrees: BTreeMap<usize, usize>,
+    /// Number of lookups by the tag of the table in debug format, `untagged`
+    /// for lookups into tables that aren't stored tables of the builder
+    pub lookups: BTreeMap<String, usize>,
+    /// Number of table rows stored
+    pub table_rows: usize,
+    /// Number of stored expressions
+    pub stored_expressions: usize,
+    /// Number of cells queried from the cell manager by cell type in debug
+    /// format
+    pub cells: BTreeMap<String, usize>,
+}
+
+impl RegionStats {
+    /// Total number of lookups
+    pub fn num_lookups(&self) -> usize {
+        self.lookups.values().sum()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for RegionStats {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("RegionStats", 8)?;
+        state.serialize_field("region_id", &self.region_id)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("constraints", &self.constraints)?;
+        state.serialize_field("degrees", &self.degrees)?;
+        state.serialize_field("lookups", &self.lookups)?;
+        state.serialize_field("table_rows", &self.table_rows)?;
+        state.serialize_field("stored_expressions", &self.stored_expressions)?;
+        state.serialize_field("cells", &self.cells)?;
+        state.end()
+    }
+}
+
+/// Statistics of the circuit built with a builder, see
+/// `ConstraintBuilder::report`. Displays as `print_stats` prints.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CircuitReport {
+    /// Name and degree of every constraint, sorted by degree
+    pub constraints: Vec<(&'static str, usize)>,
+    /// Statistics per region, sorted by region id
+    pub regions: Vec<RegionStats>,
+    /// What the last `build_lookups` emitted, with the table tags in debug
+    /// format
+    pub lookup_arguments: Option<LookupBuildReport<String>>,
+    /// Name and number of constraints of the deferred constraint groups
+    pub deferred: Vec<(&'static str, usize)>,
+    /// Rows at the end of the circuit that can't be used
+    pub unusable_rows: usize,
+    /// Problems that were worked around while building
+    pub diagnostics: Vec<String>,
+    /// Number of boolean constraints left out as duplicates
+    pub elided_booleans: usize,
+    /// Number of expression nodes stored in cells to split expressions
+    pub split_nodes: usize,
+    /// Threshold and statistics of the condition cells, when enabled
+    pub condition_cells: Option<(usize, ConditionCellStats)>,
+    /// Number of column queries and of distinct ones
+    pub column_queries: (usize, usize),
+    /// Neighboring column pairs requested with `prefer_adjacent` and the
+    /// number the layout has
+    pub adjacent_columns: (usize, usize),
+}
+
+impl CircuitReport {
+    /// The statistics of a region
+    pub fn region(&self, region_id: usize) -> Option<&RegionStats> {
+        self.regions.iter().find(|region| region.region_id == region_id)
+    }
+}
+
+impl fmt::Display for CircuitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, degree) in self.constraints.iter() {
+            writeln!(f, "'{}': {}", name, degree)?;
+        }
+        for region in self.regions.iter() {
+            if region.num_lookups() > 0 || region.table_rows > 0 {
+                writeln!(
+                    f,
+                    "{}: {} lookups, {} table rows",
+                    region.name,
+                    region.num_lookups(),
+                    region.table_rows
+                )?;
+            }
+        }
+        if let Some(report) = &self.lookup_arguments {
+            writeln!(
+                f,
+                "lookup arguments: {} ({} deduped, {} packed, {} compressed)",
+                report.emitted.len(),
+                report.deduped,
+                report.packed,
+                report.compressed
+            )?;
+        }
+        for (name, num_constraints) in self.deferred.iter() {
+            writeln!(f, "deferred '{}': {} constraints", name, num_constraints)?;
+        }
+        writeln!(f, "unusable rows: {}", self.unusable_rows)?;
+        for diagnostic in self.diagnostics.iter() {
+            writeln!(f, "diagnostic: {}", diagnostic)?;
+        }
+        if self.elided_booleans > 0 {
+            writeln!(f, "elided boolean constraints: {}", self.elided_booleans)?;
+        }
+        if self.split_nodes > 0 {
+            writeln!(f, "split expression nodes: {}", self.split_nodes)?;
+        }
+        if let Some((threshold, stats)) = &self.condition_cells {
+            writeln!(
+                f,
+                "condition cells (threshold {}): {} stored, {} constraints rewired, total degree {} -> {}",
+                threshold, stats.stored, stats.rewired, stats.degree_before, stats.degree_after
+            )?;
+        }
+        if self.column_queries.0 > 0 {
+            writeln!(
+                f,
+                "column queries: {} ({} distinct)",
+                self.column_queries.0, self.column_queries.1
+            )?;
+        }
+        if self.adjacent_columns.0 > 0 {
+            writeln!(
+                f,
+                "adjacent columns: {} of {} pairs",
+                self.adjacent_columns.1, self.adjacent_columns.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes all statistics, the lookup arguments and condition cells as
+/// their counts
+#[cfg(feature = "serde")]
+impl serde::Serialize for CircuitReport {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let lookup_arguments = self.lookup_arguments.as_ref().map(|report| {
+            BTreeMap::from([
+                ("emitted", report.emitted.len()),
+                ("deduped", report.deduped),
+                ("packed", report.packed),
+                ("compressed", report.compressed),
+            ])
+        });
+        let condition_cells = self.condition_cells.as_ref().map(|(threshold, stats)| {
+            BTreeMap::from([
+                ("threshold", *threshold),
+                ("stored", stats.stored),
+                ("rewired", stats.rewired),
+                ("degree_before", stats.degree_before),
+                ("degree_after", stats.degree_after),
+            ])
+        });
+        let mut state = serializer.serialize_struct("CircuitReport", 11)?;
+        state.serialize_field("constraints", &self.constraints)?;
+        state.serialize_field("regions", &self.regions)?;
+        state.serialize_field("lookup_arguments", &lookup_arguments)?;
+        state.serialize_field("deferred", &self.deferred)?;
+        state.serialize_field("unusable_rows", &self.unusable_rows)?;
+        state.serialize_field("diagnostics", &self.diagnostics)?;
+        state.serialize_field("elided_booleans", &self.elided_booleans)?;
+        state.serialize_field("split_nodes", &self.split_nodes)?;
+        state.serialize_field("condition_cells", &condition_cells)?;
+        state.serialize_field("column_queries", &self.column_queries)?;
+        state.serialize_field("adjacent_columns", &self.adjacent_columns)?;
+        state.end()
+    }
+}
+
+/// Depth up to which expressions are rendered in the audit report
+const AUDIT_DEPTH: usize = 6;
+
+/// A constraint in the audit report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditConstraint {
+    /// Name of the constraint
+    pub name: String,
+    /// Degree, including the conditions
+    pub degree: usize,
+    /// The expression, elided below a fixed depth
+    pub expression: String,
+    /// The conditions the constraint was added under, when recorded
+    pub provenance: Option<ConstraintProvenance>,
+}
+
+/// A lookup in the audit report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditLookup {
+    /// Description of the lookup
+    pub description: String,
+    /// Tag of the table in debug format, for lookups into a stored table
+    pub table: Option<String>,
+    /// Names of the table columns, `column <i>` without a schema
+    pub columns: Vec<String>,
+    /// The looked up expressions, one per column
+    pub values: Vec<String>,
+    /// Condition the lookup is done under, including the region condition
+    pub condition: String,
+}
+
+/// A row stored in a dynamic table in the audit report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditTableRow {
+    /// Description of the row
+    pub description: String,
+    /// Tag of the table in debug format
+    pub table: String,
+    /// The stored expressions, one per column
+    pub values: Vec<String>,
+    /// Condition the row is stored under, including the region condition
+    pub condition: String,
+}
+
+/// Everything a region adds to the circuit, in the order it was added
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRegion {
+    /// The region id
+    pub region_id: usize,
+    /// `region <id>`, followed by the label when the region has one
+    pub name: String,
+    /// The constraints
+    pub constraints: Vec<AuditConstraint>,
+    /// The lookups
+    pub lookups: Vec<AuditLookup>,
+    /// The rows stored in dynamic tables
+    pub table_rows: Vec<AuditTableRow>,
+    /// Name and expression of the stored expressions
+    pub stored_expressions: Vec<(String, String)>,
+    /// The cells queried from the cell manager
+    pub cells: Vec<CellInfo>,
+    /// The gadgets constructed
+    pub gadgets: Vec<GadgetRecord>,
+}
+
+/// Description of all constraints, lookups, tables and cells of a builder,
+/// readable without running the circuit. See `ConstraintBuilder::audit_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditReport {
+    /// The degree all constraints are limited to
+    pub max_degree: usize,
+    /// Tag (in debug format) and column names of the stored tables, sorted
+    /// by tag
+    pub tables: Vec<(String, Vec<String>)>,
+    /// The regions, sorted by region id
+    pub regions: Vec<AuditRegion>,
+}
+
+impl AuditReport {
+    /// The report of a region
+    pub fn region(&self, region_id: usize) -> Option<&AuditRegion> {
+        self.regions.iter().find(|region| region.region_id == region_id)
+    }
+
+    /// Renders the report as markdown, with a section per region
+    pub fn to_markdown(&self) -> String {
+        // Expressions and names can contain `|`, which ends a table cell
+        let cell = |text: &str| text.replace('|', "\\|");
+        let code = |text: &str| format!("`{}`", cell(text));
+        let mut md = String::new();
+        let mut line = |text: String| {
+            md.push_str(&text);
+            md.push('\n');
+        };
+        line("# Circuit audit report".to_string());
+        line(String::new());
+        line(format!("Maximum degree: {}", self.max_degree));
+        if !self.tables.is_empty() {
+            line(String::new());
+            line("## Tables".to_string());
+            line(String::new());
+            line("| Table | Columns |".to_string());
+            line("|---|---|".to_string());
+            for (tag, columns) in self.tables.iter() {
+                line(format!("| {} | {} |", cell(tag), cell(&columns.join(", "))));
+            }
+        }
+        for region in self.regions.iter() {
+            line(String::new());
+            line(format!("## {}", region.name));
+            if !region.constraints.is_empty() {
+                line(String::new());
+                line("### Constraints".to_string());
+                line(String::new());
+                line("| # | Name | Degree | Expression | Conditions |".to_string());
+                line("|---|---|---|---|---|".to_string());
+                for (idx, constraint) in region.constraints.iter().enumerate() {
+                    let conditions = match &constraint.provenance {
+                        Some(provenance) => provenance
+                            .conditions
+                            .iter()
+                            .map(|(_, condition)| code(condition))
+                            .join("<br>"),
+                        None => "-".to_string(),
+                    };
+                    line(format!(
+                        "| {} | {} | {} | {} | {} |",
+                        idx,
+                        cell(&constraint.name),
+                        constraint.degree,
+                        code(&constraint.expression),
+                        conditions
+                    ));
+                }
+            }
+            if !region.lookups.is_empty() {
+                line(String::new());
+                line("### Lookups".to_string());
+                line(String::new());
+                line("| # | Description | Table | Values | Condition |".to_string());
+                line("|---|---|---|---|---|".to_string());
+                for (idx, lookup) in region.lookups.iter().enumerate() {
+                    let values = lookup
+                        .columns
+                        .iter()
+                        .zip(lookup.values.iter())
+                        .map(|(column, value)| format!("{}: {}", column, code(value)))
+                        .join("<br>");
+                    line(format!(
+                        "| {} | {} | {} | {} | {} |",
+                        idx,
+                        cell(&lookup.description),
+                        cell(lookup.table.as_deref().unwrap_or("-")),
+                        values,
+                        code(&lookup.condition)
+                    ));
+                }
+            }
+            if !region.table_rows.is_empty() {
+                line(String::new());
+                line("### Table rows".to_string());
+                line(String::new());
+                line("| Description | Table | Values | Condition |".to_string());
+                line("|---|---|---|---|".to_string());
+                for row in region.table_rows.iter() {
+                    line(format!(
+                        "| {} | {} | {} | {} |",
+                        cell(&row.description),
+                        cell(&row.table),
+                        row.values.iter().map(|value| code(value)).join("<br>"),
+                        code(&row.condition)
+                    ));
+                }
+            }
+            if !region.stored_expressions.is_empty() {
+                line(String::new());
+                line("### Stored expressions".to_string());
+                line(String::new());
+                line("| Name | Expression |".to_string());
+                line("|---|---|".to_string());
+                for (name, expression) in region.stored_expressions.iter() {
+                    line(format!("| {} | {} |", cell(name), code(expression)));
+                }
+            }
+            if !region.cells.is_empty() {
+                line(String::new());
+                line("### Cells".to_string());
+                line(String::new());
+                line("| Name | Type | Column | Rotation |".to_string());
+                line("|---|---|---|---|".to_string());
+                for info in region.cells.iter() {
+                    line(format!(
+                        "| {} | {} | advice[{}] | {} |",
+                        cell(&info.name),
+                        cell(&info.cell_type),
+                        info.column.index(),
+                        info.rotation
+                    ));
+                }
+            }
+            if !region.gadgets.is_empty() {
+                line(String::new());
+                line("### Gadgets".to_string());
+                line(String::new());
+                line("| Gadget | Parameters |".to_string());
+                line("|---|---|".to_string());
+                for record in region.gadgets.iter() {
+                    line(format!("| {} | {} |", cell(record.gadget), code(&record.params)));
+                }
+            }
+        }
+        md
+    }
+}
+
+/// Handle of a registered hook, used to remove it again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HookHandle(usize);
+
+/// Expression that doesn't need to be split anymore, like the cell an
+/// expression was stored in
+#[derive(Clone, Debug)]
+pub struct Reduced<F>(Expression<F>);
+
+impl<F: Field> Expr<F> for Reduced<F> {
+    fn expr(&self) -> Expression<F> {
+        self.0.clone()
+    }
+}
+
+impl<F> From<Reduced<F>> for Expression<F> {
+    fn from(reduced: Reduced<F>) -> Self {
+        reduced.0
+    }
+}
+
+type ConstraintHook<F> = Arc<dyn Fn(&str, Expression<F>, HookCtx) -> Expression<F> + Send + Sync>;
+type LookupHook<F> = Arc<dyn Fn(&str, Vec<Expression<F>>, HookCtx) -> Vec<Expression<F>> + Send + Sync>;
+type DeferredGroup<F, C, T> = Arc<dyn Fn(&mut ConstraintBuilder<F, C, T>) + Send + Sync>;
+type DescriptionFormatter = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// What a name handed out while descriptions are disabled stands for
+#[derive(Clone, Copy, Debug)]
+enum OriginalDescription {
+    /// The name given directly
+    Name(&'static str),
+    /// Where the description that was never formatted was made
+    CalledAt(&'static Location<'static>),
+}
+
+/// Constraint builder. Tables are tagged with `T`, which defaults to the
+/// table type of the cell type. Circuits sharing a cell type can use their
+/// own enum of tags, convertible from the table type of the cell type to add
+/// columns and store tuples.
+#[derive(Clone)]
+pub struct ConstraintBuilder<F, C: CellType, T = <C as CellType>::TableType> {
+    /// Constraints to be returned to meta
+    constraints: Vec<(&'static str, Expression<F>)>,
+    /// Region each constraint was added in
+    constraint_regions: Vec<usize>,
+    /// Conditions each constraint was added under, when recorded
+    constraint_provenance: Vec<Option<ConstraintProvenance>>,
+    /// Record the provenance of the constraints
+    record_provenance: bool,
+    /// Max global degree of constraints
+    max_global_degree: usize,
+    /// Max local degree of constraints inside the current region
+    max_degree: usize,
+    /// conditions for constraints
+    conditions: ConditionStack<F>,
+    /// Columns whoes equality constraints needed to be enable
+    equalities: Vec<Column<Any>>,
+    /// The tables
+    pub tables: HashMap<T, Vec<TableData<F>>>,
+    /// Lookups
+    pub lookups: Vec<LookupData<F>>,
+    /// All stored expressions
+    pub stored_expressions: HashMap<usize, Vec<StoredExpression<F, C>>>,
+    /// CellManager
+    pub cell_manager: Option<CellManager<F, C>>,
+    /// Keep the cells queried outside of the regions allocated in the
+    /// regions, see `set_keep_shared_cells`
+    keep_shared_cells: bool,
+    /// Allocation state of the cell manager outside of the regions, restored
+    /// by `pop_region`
+    cell_manager_snapshot: Option<CellManagerSnapshot>,
+    /// Disable macro-generated description for constraints & lookups
+    /// for graph display. Constraints, lookups and stored expressions are
+    /// named `#id` instead, with `id` a counter.
+    pub disable_description: bool,
+    /// What the ids handed out while descriptions are disabled stand for,
+    /// indexed by id
+    original_descriptions: Vec<Option<OriginalDescription>>,
+    /// Where `description` was last called while descriptions are disabled
+    described_at: Option<&'static Location<'static>>,
+    /// region id
+    pub region_id: usize,
+    /// lookup input challenge
+    pub lookup_challenge: Option<Expression<F>>,
+    /// All challenges of the circuit, when constructed with `with_challenges`
+    pub challenges: Option<Challenges<Expression<F>>>,
+    /// state contect
+    pub state_context: Vec<Expression<F>>,
+    /// state constraints start
+    pub region_constraints_start: usize,
+    /// Index of the first lookup added in the current region
+    region_lookups_start: usize,
+    /// Skip boolean constraints on expressions already proven boolean
+    dedupe_booleans: bool,
+    /// Default strategy of `require_in_set`
+    set_membership: SetMembership,
+    /// Expressions proven boolean in the current region, with the condition
+    /// they were proven under (`None` when unconditional)
+    booleans: HashSet<(String, Option<String>)>,
+    /// Number of boolean constraints skipped by deduplication
+    num_elided_booleans: usize,
+    /// Number of lookups merged into an identical lookup by `dedup_lookups`
+    num_deduped_lookups: usize,
+    /// Compress the values of lookups wider than their table, see
+    /// `set_compress_wide_lookups`
+    compress_wide_lookups: bool,
+    /// The table columns registered with `register_table_columns`
+    static_tables: HashMap<T, Vec<TableColumn>>,
+    /// The lookups into the registered table columns
+    static_lookups: Vec<StaticLookupData<F, T>>,
+    /// Require pushed conditions to be boolean
+    check_condition_booleanness: bool,
+    /// Identifiers of the conditions required to be boolean in the current
+    /// region, with the identifier of the active condition they were checked
+    /// under
+    checked_conditions: HashSet<(String, String)>,
+    /// The conditions required to be boolean with their degree and region,
+    /// in order
+    condition_degrees: Vec<(usize, String, usize)>,
+    /// Column queries done through query caches, and how many were distinct
+    column_queries: (usize, usize),
+    /// Fixed table containing `0..size`, used for range checks
+    fixed_range: Option<(Expression<F>, usize)>,
+    /// Column names of the dynamic tables, in table order
+    schemas: HashMap<T, Vec<&'static str>>,
+    /// Lookups by column name, waiting for `finalize`
+    named_lookups: Vec<NamedLookupData<F, T>>,
+    /// Transformations applied to every constraint, in registration order
+    constraint_hooks: Vec<(HookHandle, ConstraintHook<F>)>,
+    /// Transformations applied to the values of every lookup
+    lookup_hooks: Vec<(HookHandle, LookupHook<F>)>,
+    /// Formats the names of constraints, lookups and stored expressions,
+    /// names are kept verbatim without one
+    description_formatter: Option<DescriptionFormatter>,
+    /// Id of the next registered hook
+    next_hook: usize,
+    /// Identifiers of the expressions known to be reduced, with the cell type
+    /// they are stored in (`None` when marked with `mark_reduced`)
+    reduced: HashMap<String, Option<C>>,
+    /// Number of expression nodes split_expression had to descend into
+    num_split_nodes: usize,
+    /// Store tuples without compressing them with the lookup challenge
+    uncompressed_tuples: bool,
+    /// Problems worked around while building
+    diagnostics: Vec<String>,
+    /// Build constraints and lookups in canonical order
+    canonical_order: bool,
+    /// Reuse stored expressions of any cell type when splitting
+    global_subexpression_sharing: bool,
+    /// Id of the next cell type minted with `new_dynamic_cell_type`
+    next_dynamic_type: usize,
+    /// Labels of the regions pushed with `push_named_region`
+    region_labels: HashMap<usize, &'static str>,
+    /// Cells queried per region
+    region_cells: HashMap<usize, Vec<CellInfo>>,
+    /// Every queried cell with its type, checked by `finalize_checks`
+    queried_cells: Vec<(C, Cell<F>)>,
+    /// Cells exported by a region for use in later regions
+    exported_cells: HashMap<String, Cell<F>>,
+    /// Cells holding the powers of the challenges by `(phase, exponent)`,
+    /// shared by all regions, with the regions using them
+    challenge_powers: BTreeMap<(u8, usize), (Cell<F>, BTreeSet<usize>)>,
+    /// Parameters of the gadgets constructed with this builder
+    gadget_records: Vec<GadgetRecord>,
+    /// Only allow constraints on cell manager and registered columns
+    strict_queries: bool,
+    /// Panic on degree violations also in release builds
+    strict_degree: bool,
+    /// Seed of the random looking values, see `set_seed`
+    seed: u64,
+    /// Selector multiplied into all constraints and lookups when they are
+    /// built, see `set_global_selector`
+    global_selector: Option<Expression<F>>,
+    /// Assign the stored expressions grouped by column
+    column_grouped_assignment: bool,
+    /// Columns queried with the `circuit!` macros in strict mode
+    tracked_columns: RefCell<HashSet<String>>,
+    /// Columns not owned by the cell manager usable in strict mode
+    external_columns: HashSet<String>,
+    /// Constraint groups added by `finalize`
+    deferred: Vec<(&'static str, DeferredGroup<F, C, T>)>,
+    /// Number of constraints added by every deferred group that ran
+    deferred_constraints: Vec<(&'static str, usize)>,
+    /// Cells exposed as public inputs
+    publics: Vec<PublicInput<F>>,
+    /// What the last `build_lookups` emitted
+    lookup_report: Option<LookupBuildReport<T>>,
+    /// Operations done on the builder, when enabled with `record_ops`
+    oplog: OpLog,
+    /// Number of constraints a condition is applied to in a region before
+    /// it's stored in a cell
+    condition_cell_threshold: Option<usize>,
+    /// Number of constraints each condition (by identifier) was applied to
+    /// in the current region
+    condition_uses: HashMap<String, usize>,
+    /// The cells holding the conditions stored in the current region
+    condition_cells: HashMap<String, Expression<F>>,
+    /// What the condition cells saved
+    condition_cell_stats: ConditionCellStats,
+    /// Values decomposed into byte cells by `require_in_range`, per region
+    range_decompositions: HashMap<usize, Vec<RangeDecomposition<F>>>,
+    /// The counts of a counting builder
+    counts: Option<BuilderCounts>,
+}
+
+impl<F: Field, C: CellType, T: TableTag> ConstraintBuilder<F, C, T> {
+    pub(crate) fn new(
+        max_degree: usize,
+        cell_manager: Option<CellManager<F, C>>,
+        lookup_challenge: Option<Expression<F>>,
+    ) -> Self {
+        ConstraintBuilder {
+            constraints: Vec::new(),
+            constraint_regions: Vec::new(),
+            constraint_provenance: Vec::new(),
+            record_provenance: false,
+            max_global_degree: max_degree,
+            max_degree,
+            conditions: ConditionStack::default(),
+            equalities: Vec::new(),
+            tables: HashMap::new(),
+            lookups: Vec::new(),
+            cell_manager,
+            keep_shared_cells: false,
+            cell_manager_snapshot: None,
+            disable_description: false,
+            original_descriptions: Vec::new(),
+            described_at: None,
+            stored_expressions: HashMap::new(),
+            region_id: 0,
+            lookup_challenge,
+            challenges: None,
+            state_context: Vec::new(),
+            region_constraints_start: 0,
+            region_lookups_start: 0,
+            dedupe_booleans: false,
+            set_membership: SetMembership::Auto,
+            booleans: HashSet::new(),
+            num_elided_booleans: 0,
+            num_deduped_lookups: 0,
+            compress_wide_lookups: false,
+            static_tables: HashMap::new(),
+            static_lookups: Vec::new(),
+            check_condition_booleanness: false,
+            checked_conditions: HashSet::new(),
+            condition_degrees: Vec::new(),
+            column_queries: (0, 0),
+            fixed_range: None,
+            schemas: HashMap::new(),
+            named_lookups: Vec::new(),
+            constraint_hooks: Vec::new(),
+            lookup_hooks: Vec::new(),
+            description_formatter: None,
+            next_hook: 0,
+            reduced: HashMap::new(),
+            num_split_nodes: 0,
+            uncompressed_tuples: false,
+            diagnostics: Vec::new(),
+            canonical_order: false,
+            global_subexpression_sharing: false,
+            next_dynamic_type: 0,
+            region_labels: HashMap::new(),
+            region_cells: HashMap::new(),
+            queried_cells: Vec::new(),
+            exported_cells: HashMap::new(),
+            challenge_powers: BTreeMap::new(),
+            gadget_records: Vec::new(),
+            strict_queries: false,
+            strict_degree: cfg!(feature = "strict-degree"),
+            seed: 0,
+            global_selector: None,
+            column_grouped_assignment: false,
+            tracked_columns: RefCell::new(HashSet::new()),
+            external_columns: HashSet::new(),
+            deferred: Vec::new(),
+            deferred_constraints: Vec::new(),
+            publics: Vec::new(),
+            lookup_report: None,
+            oplog: OpLog::default(),
+            condition_cell_threshold: None,
+            condition_uses: HashMap::new(),
+            condition_cells: HashMap::new(),
+            condition_cell_stats: ConditionCellStats::default(),
+            range_decompositions: HashMap::new(),
+            counts: None,
+        }
+    }
+
+    /// Creates a builder that compresses lookups with the first challenge
+    /// usable after the first phase, or after the second phase for tuples of
+    /// second phase values (see `phase_challenge`)
+    pub(crate) fn with_challenges(
+        max_degree: usize,
+        cell_manager: Option<CellManager<F, C>>,
+        challenges: Challenges<Expression<F>>,
+    ) -> Self {
+        let mut cb = Self::new(max_degree, cell_manager, challenges.lookup_input());
+        cb.challenges = Some(challenges);
+        cb
+    }
+
+    /// Creates a builder for running `circuit!` code only for its witness
+    /// side effects, see `_cb!`. Dummy builders have max degree 0 and record
+    /// nothing: constraints, lookups and tables are dropped, queries return
+    /// placeholder cells that are `0` and can't be assigned, and stored
+    /// expressions are returned as is.
+    pub fn dummy() -> Self {
+        Self::new(0, None, None)
+    }
+
+    /// Creates a dummy builder (see `dummy`) that counts what a real pass
+    /// would add: constraints, cells by type and lookups by table. Running
+    /// the configure code of a gadget against it sizes the cell manager
+    /// before the real pass. Expressions aren't split in this mode, so the
+    /// cells and constraints of degree reduction aren't counted. Queries
+    /// return placeholder cells that can't be told apart, so every stored
+    /// expression is counted, even when the real pass would reuse a cell.
+    pub fn counting() -> Self {
+        let mut cb = Self::dummy();
+        cb.counts = Some(BuilderCounts::default());
+        cb
+    }
+
+    /// Returns `true` for builders created with `counting`
+    pub fn is_counting(&self) -> bool {
+        self.counts.is_some()
+    }
+
+    /// What the counting builder counted so far
+    pub fn counts(&self) -> BuilderCounts {
+        self.counts
+            .clone()
+            .expect("counts requires a builder created with `counting`")
+    }
+
+    fn count(&mut self, f: impl FnOnce(&mut BuilderCounts)) {
+        if let Some(counts) = self.counts.as_mut() {
+            f(counts);
+        }
+    }
+
+    /// Counts the constraint of a stored expression, and its cell when it
+    /// isn't stored in a given cell
+    fn count_store(&mut self, cell_type: C, needs_cell: bool) {
+        self.count(|counts| {
+            counts.constraints += 1;
+            if needs_cell {
+                *counts.cells.entry(format!("{:?}", cell_type)).or_insert(0) += 1;
+            }
+        });
+    }
+
+    /// Returns `true` for builders created with `dummy` (or a max degree of
+    /// 0), so code shared with witness generation can skip constraint only
+    /// work
+    pub fn is_dummy(&self) -> bool {
+        self.max_global_degree == 0
+    }
+
+    pub(crate) fn restart(&mut self) {
+        self.constraints.clear();
+        self.constraint_regions.clear();
+        self.constraint_provenance.clear();
+        self.conditions.0.lock().clear();
+        self.equalities.clear();
+        self.tables.clear();
+        self.lookups.clear();
+        self.schemas.clear();
+        self.named_lookups.clear();
+        self.stored_expressions.clear();
+        self.region_id = 0;
+        self.state_context.clear();
+        self.region_constraints_start = 0;
+        self.region_lookups_start = 0;
+        self.booleans.clear();
+        self.num_elided_booleans = 0;
+        self.num_deduped_lookups = 0;
+        self.static_tables.clear();
+        self.static_lookups.clear();
+        self.checked_conditions.clear();
+        self.condition_degrees.clear();
+        self.column_queries = (0, 0);
+        self.reduced.clear();
+        self.num_split_nodes = 0;
+        self.diagnostics.clear();
+        self.region_labels.clear();
+        self.original_descriptions.clear();
+        self.described_at = None;
+        self.region_cells.clear();
+        self.queried_cells.clear();
+        self.exported_cells.clear();
+        self.challenge_powers.clear();
+        self.gadget_records.clear();
+        self.tracked_columns.borrow_mut().clear();
+        self.external_columns.clear();
+        self.deferred.clear();
+        self.deferred_constraints.clear();
+        self.publics.clear();
+        self.lookup_report = None;
+        self.cell_manager_snapshot = None;
+        self.oplog.ops.lock().clear();
+        self.condition_uses.clear();
+        self.condition_cells.clear();
+        self.condition_cell_stats = ConditionCellStats::default();
+        self.range_decompositions.clear();
+        if self.counts.is_some() {
+            self.counts = Some(BuilderCounts::default());
+        }
+        if let Some(cell_manager) = &mut self.cell_manager {
+            cell_manager.restart();
+        }
+    }
+
+    /// Like `restart`, but keeps the static tables: the tables stored outside
+    /// of any region without a condition, like the ones added with
+    /// `load_table`. They can be used without loading them again.
+    pub(crate) fn restart_keeping_tables(&mut self) {
+        let is_one = |expr: &Expression<F>| matches!(expr, Expression::Constant(value) if *value == F::ONE);
+        let mut tables = std::mem::take(&mut self.tables);
+        for data in tables.values_mut() {
+            data.retain(|table| {
+                table.region_id == 0
+                    && is_one(&table.local_condition)
+                    && is_one(&table.regional_condition)
+            });
+        }
+        tables.retain(|_, data| !data.is_empty());
+        self.restart();
+        self.tables = tables;
+    }
+
+    /// Mints a new cell type with `CellType::create_type`, e.g. for the
+    /// columns of a table discovered at configure time. Every call returns a
+    /// different type, also after `restart`.
+    pub(crate) fn new_dynamic_cell_type(&mut self) -> C {
+        let cell_type = C::create_type(self.next_dynamic_type);
+        self.next_dynamic_type += 1;
+        cell_type
+    }
+
+    pub(crate) fn set_cell_manager(&mut self, cell_manager: CellManager<F, C>) {
+        self.cell_manager = Some(cell_manager);
+    }
+
+    /// Merges a builder configured separately, e.g. for a gadget, into this
+    /// one. Constraints, lookups, equalities, stored expressions and tables
+    /// are concatenated. The regions of `other` are renumbered after the
+    /// regions of this builder, the returned `RegionRemap` gives their new
+    /// ids, e.g. for `get_stored_expressions`. The `#id` names handed out by
+    /// `other` while descriptions are disabled are renumbered the same way.
+    /// The maximum degree is the minimum of both, and all constraints have to
+    /// fit it. Hooks of `other` aren't carried over, so `other` can't have any.
+    /// When `other` has a cell manager it replaces the one of this builder:
+    /// builders sharing a cell manager pass it on, so the last one has seen
+    /// all allocations.
+    pub(crate) fn merge(&mut self, mut other: ConstraintBuilder<F, C, T>) -> RegionRemap {
+        assert!(
+            self.region_id == 0 && other.region_id == 0,
+            "builders can only be merged outside of regions"
+        );
+        assert!(
+            self.conditions.0.lock().is_empty() && other.conditions.0.lock().is_empty(),
+            "builders can only be merged without active conditions"
+        );
+        assert!(
+            other.constraint_hooks.is_empty() && other.lookup_hooks.is_empty(),
+            "the hooks of a merged builder would be dropped, register them on the merged builder"
+        );
+        let max_global_degree = self.max_global_degree.min(other.max_global_degree);
+        if max_global_degree > 0 {
+            // Only the constraints of the builder with the larger budget
+            // weren't checked against the new one
+            let builders = [
+                (self.max_global_degree, &self.constraints),
+                (other.max_global_degree, &other.constraints),
+            ];
+            let too_high = builders
+                .iter()
+                .filter(|(max_degree, _)| *max_degree > max_global_degree)
+                .flat_map(|(_, constraints)| constraints.iter())
+                .filter(|(_, constraint)| constraint.degree() > max_global_degree)
+                .map(|(name, constraint)| format!("{} ({})", name, constraint.degree()))
+                .collect::<Vec<_>>();
+            assert!(
+                too_high.is_empty(),
+                "constraints above the max degree {} of the merged builder: {}",
+                max_global_degree,
+                too_high.join(", ")
+            );
+        }
+        other.renumber_descriptions(self.original_descriptions.len());
+        let offset = self
+            .constraint_regions
+            .iter()
+            .copied()
+            .chain(self.lookups.iter().map(|lookup| lookup.region_id))
+            .chain(self.static_lookups.iter().map(|lookup| lookup.lookup.region_id))
+            .chain(self.stored_expressions.keys().copied())
+            .chain(self.tables.values().flatten().map(|table| table.region_id))
+            .chain(self.region_cells.keys().copied())
+            .chain(self.region_labels.keys().copied())
+            .max()
+            .unwrap_or(0);
+        let remap = |region_id: usize| RegionRemap { offset }.region_id(region_id);
+
+        self.original_descriptions.extend(other.original_descriptions);
+        self.constraints.extend(other.constraints);
+        self.constraint_regions
+            .extend(other.constraint_regions.into_iter().map(remap));
+        self.constraint_provenance.extend(other.constraint_provenance);
+        self.lookups.extend(other.lookups.into_iter().map(|mut lookup| {
+            lookup.region_id = remap(lookup.region_id);
+            lookup
+        }));
+        self.named_lookups
+            .extend(other.named_lookups.into_iter().map(|mut lookup| {
+                lookup.region_id = remap(lookup.region_id);
+                lookup
+            }));
+        self.static_lookups
+            .extend(other.static_lookups.into_iter().map(|mut lookup| {
+                lookup.lookup.region_id = remap(lookup.lookup.region_id);
+                lookup
+            }));
+        for (tag, columns) in other.static_tables {
+            self.register_table_columns(tag, columns);
+        }
+        for column in other.equalities {
+            self.enable_equality_any(column);
+        }
+        for (region_id, stored_expressions) in other.stored_expressions {
+            self.stored_expressions
+                .entry(remap(region_id))
+                .or_insert_with(Vec::new)
+                .extend(stored_expressions);
+        }
+        for (region_id, decompositions) in other.range_decompositions {
+            self.range_decompositions
+                .entry(remap(region_id))
+                .or_insert_with(Vec::new)
+                .extend(decompositions);
+        }
+        for (tag, tables) in other.tables {
+            self.tables
+                .entry(tag)
+                .or_insert_with(Vec::new)
+                .extend(tables.into_iter().map(|mut table| {
+                    table.region_id = remap(table.region_id);
+                    table
+                }));
+        }
+        for (tag, schema) in other.schemas {
+            self.set_table_schema(tag, &schema).unwrap_or_else(|err| panic!("{}", err));
+        }
+        for (region_id, label) in other.region_labels {
+            self.region_labels.insert(remap(region_id), label);
+        }
+        for (region_id, cells) in other.region_cells {
+            self.region_cells
+                .entry(remap(region_id))
+                .or_insert_with(Vec::new)
+                .extend(cells);
+        }
+        self.queried_cells.extend(other.queried_cells);
+        for (name, cell) in other.exported_cells {
+            assert!(
+                self.exported_cells.insert(name.clone(), cell).is_none(),
+                "cell '{}' exported by both builders",
+                name
+            );
+        }
+        for (key, (cell, region_ids)) in other.challenge_powers {
+            let region_ids = region_ids.into_iter().map(remap).collect();
+            assert!(
+                self.challenge_powers.insert(key, (cell, region_ids)).is_none(),
+                "challenge power {:?} cached by both builders",
+                key
+            );
+        }
+        self.gadget_records
+            .extend(other.gadget_records.into_iter().map(|mut record| {
+                record.region_id = remap(record.region_id);
+                record
+            }));
+        self.reduced.extend(other.reduced);
+        self.publics.extend(other.publics);
+        self.deferred.extend(other.deferred);
+        self.diagnostics.extend(other.diagnostics);
+        self.external_columns.extend(other.external_columns);
+        self.tracked_columns
+            .borrow_mut()
+            .extend(other.tracked_columns.into_inner());
+        self.num_split_nodes += other.num_split_nodes;
+        self.num_elided_booleans += other.num_elided_booleans;
+        self.num_deduped_lookups += other.num_deduped_lookups;
+        self.condition_degrees.extend(
+            other
+                .condition_degrees
+                .into_iter()
+                .map(|(region_id, identifier, degree)| (remap(region_id), identifier, degree)),
+        );
+        self.max_global_degree = max_global_degree;
+        self.max_degree = self.max_degree.min(other.max_degree);
+        if self.fixed_range.is_none() {
+            self.fixed_range = other.fixed_range;
+        }
+        if self.global_selector.is_none() {
+            self.global_selector = other.global_selector;
+        }
+        if other.cell_manager.is_some() {
+            self.cell_manager = other.cell_manager;
+        }
+        RegionRemap { offset }
+    }
+
+    /// Shifts the `#id` names handed out while descriptions are disabled by
+    /// `offset`, for `merge`
+    fn renumber_descriptions(&mut self, offset: usize) {
+        let num_ids = self.original_descriptions.len();
+        if offset == 0 || num_ids == 0 {
+            return;
+        }
+        let renumber = |name: &str| {
+            let id = name.strip_prefix('#')?.parse::<usize>().ok()?;
+            (id < num_ids).then(|| format!("#{}", id + offset))
+        };
+        for (name, _) in self.constraints.iter_mut() {
+            if let Some(renumbered) = renumber(name) {
+                *name = intern(&renumbered);
+            }
+        }
+        let lookups = self
+            .lookups
+            .iter_mut()
+            .chain(self.static_lookups.iter_mut().map(|lookup| &mut lookup.lookup));
+        for description in lookups
+            .map(|lookup| &mut lookup.description)
+            .chain(self.named_lookups.iter_mut().map(|lookup| &mut lookup.description))
+            .chain(self.stored_expressions.values_mut().flatten().map(|stored| &mut stored.name))
+        {
+            if let Some(renumbered) = renumber(description) {
+                *description = renumbered;
+            }
+        }
+    }
+
+    /// Sets the maximum degree of the constraints. Inside a region the budget
+    /// of the region is reduced by the degree of the region condition, as in
+    /// `push_region`.
+    pub(crate) fn set_max_degree(&mut self, max_degree: usize) {
+        self.max_global_degree = max_degree;
+        self.max_degree = if self.region_id == 0 {
+            max_degree
+        } else {
+            max_degree - get_condition_expr(&self.state_context).degree()
+        };
+    }
+
+    /// Keeps the cells queried outside of the regions, e.g. shared selector
+    /// cells, allocated inside the regions, and allocates outside of the
+    /// regions where it left off after `pop_region`. By default every region
+    /// starts from an empty cell manager.
+    pub(crate) fn set_keep_shared_cells(&mut self, keep_shared_cells: bool) {
+        assert!(self.region_id == 0, "set_keep_shared_cells called inside region {}", self.region_id);
+        self.keep_shared_cells = keep_shared_cells;
+    }
+
+    pub(crate) fn push_region(&mut self, region_id: usize, height: usize) {
+        assert!(region_id != 0);
+        assert!(
+            self.region_id == 0,
+            "push_region({}) while region {} is still open, call pop_region first",
+            region_id,
+            self.region_id
+        );
+        self.log(|| BuilderOp::PushRegion { region_id, height });
+        self.region_id = region_id;
+        self.state_context = self.conditions();
+        self.max_degree = self.max_global_degree.saturating_sub(self.get_condition_expr().degree());
+        self.conditions.0.lock().clear();
+        self.region_constraints_start = self.constraints.len();
+        self.region_lookups_start = self.lookups.len();
+        self.booleans.clear();
+        self.checked_conditions.clear();
+        self.condition_uses.clear();
+        self.condition_cells.clear();
+        // Cells of the previous region can be reused, so they aren't reduced
+        self.reduced.clear();
+
+        // Frees the cells of the previous region. With `keep_shared_cells`
+        // the cells queried outside of the regions stay allocated.
+        if let Some(cell_manager) = self.cell_manager.as_mut() {
+            if self.keep_shared_cells {
+                let snapshot = cell_manager.snapshot();
+                cell_manager.reset_after(&snapshot, height);
+                self.cell_manager_snapshot = Some(snapshot);
+            } else {
+                cell_manager.reset(height);
+            }
+        }
+    }
+
+    /// Like `push_region`, with a label that's shown in degree errors,
+    /// `print_stats` and `region_report`
+    pub(crate) fn push_named_region(&mut self, region_id: usize, label: &'static str, height: usize) {
+        self.region_labels.insert(region_id, label);
+        self.push_region(region_id, height);
+    }
+
+    /// The label of the region, if it was pushed with one
+    pub(crate) fn region_label(&self, region_id: usize) -> Option<&'static str> {
+        self.region_labels.get(&region_id).copied()
+    }
+
+    /// `region <id>`, followed by the label when the region has one
+    fn region_name(&self, region_id: usize) -> String {
+        match self.region_label(region_id) {
+            Some(label) => format!("region {} ({})", region_id, label),
+            None => format!("region {}", region_id),
+        }
+    }
+
+    /// Number of constraints, lookups and queried cells per region, sorted
+    /// by region id
+    pub(crate) fn region_report(&self) -> Vec<RegionReport> {
+        let region_ids = self
+            .constraint_regions
+            .iter()
+            .copied()
+            .chain(self.lookups.iter().map(|lookup| lookup.region_id))
+            .chain(self.region_cells.keys().copied())
+            .chain(self.region_labels.keys().copied())
+            .unique()
+            .sorted();
+        region_ids
+            .map(|region_id| RegionReport {
+                region_id,
+                label: self.region_label(region_id),
+                constraints: self.constraint_regions.iter().filter(|&&id| id == region_id).count(),
+                lookups: self.lookups_in_region(region_id).count(),
+                cells: self.region_cells.get(&region_id).map_or(0, |cells| cells.len()),
+            })
+            .collect()
+    }
+
+    /// All cells queried in the region, including the ones holding stored
+    /// expressions, to check the assignment of the region with
+    /// `CachedRegion::assert_checklist_complete`
+    pub(crate) fn assignment_checklist(&self, region_id: usize) -> Vec<CellInfo> {
+        self.region_cells.get(&region_id).cloned().unwrap_or_default()
+    }
+
+    /// Starts recording the operations done on the builder. The returned
+    /// guard prints the log when it's dropped during a panic, so keeping it
+    /// alive while configuring shows the operations leading up to a panic.
+    /// The log can be replayed with `mock::replay`.
+    pub(crate) fn record_ops(&mut self) -> OpLogGuard {
+        self.oplog.enabled = true;
+        OpLogGuard {
+            ops: self.oplog.ops.clone(),
+        }
+    }
+
+    /// Records the operation when the operation log is enabled
+    fn log(&self, op: impl FnOnce() -> BuilderOp) {
+        if self.oplog.enabled {
+            self.oplog.ops.lock().push(op());
+        }
+    }
+
+    /// The operations recorded since `record_ops` (or the last `restart`)
+    pub(crate) fn oplog(&self) -> Vec<BuilderOp> {
+        self.oplog.ops.lock().clone()
+    }
+
+    /// The recorded operations, one per line
+    pub(crate) fn dump_oplog(&self) -> String {
+        format_oplog(&self.oplog.ops.lock())
+    }
+
+    /// Records the construction parameters of a gadget, `params` being a JSON
+    /// object. The records are part of the fingerprint, so circuits built with
+    /// different gadget parameters never get the same fingerprint.
+    pub(crate) fn record_gadget(&mut self, gadget: &'static str, params: String) {
+        self.gadget_records.push(GadgetRecord {
+            gadget,
+            region_id: self.region_id,
+            params,
+        });
+    }
+
+    /// The parameters of all gadgets constructed, in construction order
+    pub(crate) fn gadget_records(&self) -> &[GadgetRecord] {
+        &self.gadget_records
+    }
+
+    pub(crate) fn pop_region(&mut self) {
+        self.log(|| BuilderOp::PopRegion);
+        // Apply the region condition to all contraints added in this region
+        let condition = get_condition_expr(&self.state_context);
+        // Lookups added without the region condition were not added through
+        // the builder and would be enabled outside of the region. Only the
+        // lookups of this push are checked, region ids can be reused.
+        if !self.state_context.is_empty() {
+            let condition_id = condition.identifier();
+            for lookup in self.lookups[self.region_lookups_start..].iter() {
+                assert_eq!(
+                    lookup.regional_condition.identifier(),
+                    condition_id,
+                    "lookup '{}' in conditional region {} is missing the region condition",
+                    lookup.description,
+                    self.region_id
+                );
+            }
+        }
+        for idx in self.region_constraints_start..self.constraints.len() {
+            self.constraints[idx].1 = condition.expr() * self.constraints[idx].1.clone();
+        }
+        *self.conditions.0.lock() = self.state_context.clone();
+        self.max_degree = self.max_global_degree.saturating_sub(self.get_condition_expr().degree());
+        if let (Some(cell_manager), Some(snapshot)) =
+            (self.cell_manager.as_mut(), self.cell_manager_snapshot.take())
+        {
+            cell_manager.restore(&snapshot);
+        }
+        self.region_id = 0;
+        self.state_context.clear();
+        self.booleans.clear();
+        self.checked_conditions.clear();
+        self.condition_uses.clear();
+        self.condition_cells.clear();
+    }
+
+    /// Makes `require_boolean` skip expressions that were already constrained
+    /// to be boolean in the current region, under the same (or no) condition
+    pub(crate) fn set_dedupe_booleans(&mut self, dedupe_booleans: bool) {
+        self.dedupe_booleans = dedupe_booleans;
+    }
+
+    /// Makes `push_condition` require the pushed condition to be boolean, once
+    /// per condition and region. A non boolean condition scales the
+    /// constraints it applies to instead of enabling them.
+    pub(crate) fn set_check_condition_booleanness(&mut self, check: bool) {
+        self.check_condition_booleanness = check;
+    }
+
+    /// The conditions required to be boolean by
+    /// `set_check_condition_booleanness`, as `(region_id, identifier, degree)`
+    pub(crate) fn condition_degrees(&self) -> &[(usize, String, usize)] {
+        &self.condition_degrees
+    }
+
+    /// Formats the degrees of the conditions required to be boolean, one per
+    /// line
+    pub(crate) fn format_condition_degrees(&self) -> String {
+        let mut out = "condition degrees:\n".to_string();
+        for (region_id, identifier, degree) in self.condition_degrees.iter() {
+            out += &format!("  region {}: degree {}: {}\n", region_id, degree, identifier);
+        }
+        out
+    }
+
+    /// Number of boolean constraints skipped because of `set_dedupe_booleans`
+    pub(crate) fn num_elided_booleans(&self) -> usize {
+        self.num_elided_booleans
+    }
+
+    /// Merges lookups with identical values into an identical table into a
+    /// single lookup under the sum of their conditions, so a lookup done in
+    /// several branches is only a single lookup argument. The summed condition
+    /// is required to be boolean, which holds when the conditions are
+    /// disjoint. Copies under the same condition are dropped. Lookups are only
+    /// merged when the boolean check fits in the degree budget. Needs to be
+    /// called outside of the regions and before `build_constraints`. Lookups
+    /// by column name are only added in `finalize`, call it first to include
+    /// them. Returns the number of lookups merged away.
+    pub(crate) fn dedup_lookups(&mut self) -> usize {
+        assert_eq!(self.region_id, 0, "lookups are deduplicated outside of the regions");
+        let mut groups: Vec<Vec<LookupData<F>>> = Vec::new();
+        let mut group_ids = HashMap::new();
+        for lookup in std::mem::take(&mut self.lookups) {
+            let key = format!(
+                "{}|{}",
+                lookup.values.iter().map(|value| value.identifier()).join(","),
+                lookup.table.iter().map(|value| value.identifier()).join(","),
+            );
+            match group_ids.get(&key) {
+                Some(&idx) => groups[idx].push(lookup),
+                None => {
+                    group_ids.insert(key, groups.len());
+                    groups.push(vec![lookup]);
+                }
+            }
+        }
+        let mut merged = 0;
+        for group in groups {
+            let conditions = group
+                .iter()
+                .map(|lookup| lookup.condition())
+                .unique_by(|condition| condition.identifier())
+                .collect::<Vec<_>>();
+            let condition = sum::expr(conditions.iter().cloned());
+            if group.len() == 1
+                || (conditions.len() > 1 && 2 * condition.degree() > self.max_global_degree)
+            {
+                self.lookups.extend(group);
+                continue;
+            }
+            merged += group.len() - 1;
+            let first = &group[0];
+            let description = format!("{} (merged {})", first.description, group.len());
+            let region_id = if group.iter().all(|lookup| lookup.region_id == first.region_id) {
+                first.region_id
+            } else {
+                0
+            };
+            if conditions.len() > 1 {
+                self.push_constraint(
+                    intern(&format!("{} (condition)", description)),
+                    condition.expr() * (1.expr() - condition.expr()),
+                );
+            }
+            let (regional_condition, local_condition) = if conditions.len() == 1 {
+                (first.regional_condition.clone(), first.local_condition.clone())
+            } else {
+                (1.expr(), condition)
+            };
+            self.lookups.push(LookupData::new(
+                description,
+                first.values.clone(),
+                first.table.clone(),
+                local_condition,
+                regional_condition,
+                region_id,
+            ));
+        }
+        self.num_deduped_lookups += merged;
+        merged
+    }
+
+    /// Adds the query statistics of a gate's query cache to the builder stats
+    pub(crate) fn record_query_cache(&mut self, cache: &QueryCache<'_, '_, F>) {
+        let (num_queries, num_distinct) = cache.stats();
+        self.column_queries.0 += num_queries;
+        self.column_queries.1 += num_distinct;
+    }
+
+    /// Column queries done through query caches, and how many were distinct
+    pub(crate) fn column_queries(&self) -> (usize, usize) {
+        self.column_queries
+    }
+
+    pub(crate) fn set_disable_description(&mut self, disable_description: bool) {
+        self.disable_description = disable_description;
+    }
+
+    /// Interns the description of a constraint or lookup. When descriptions
+    /// are disabled the description isn't formatted at all and an empty
+    /// string is returned, the builder names it with a counter instead and
+    /// only remembers where it was called from (the macro call site).
+    #[track_caller]
+    pub fn description(&mut self, description: impl fmt::Display) -> &'static str {
+        if self.disable_description {
+            self.described_at = Some(Location::caller());
+            ""
+        } else {
+            intern(&description.to_string())
+        }
+    }
+
+    /// Returns a `'static` copy of the string, leaked only once per distinct
+    /// string (see `util::intern`)
+    pub fn intern(&self, s: &str) -> &'static str {
+        intern(s)
+    }
+
+    /// Formats the names of all constraints, lookups and stored expressions
+    /// added from now on, e.g. with `util::strip_preamble` to remove the
+    /// `file:line` the macros put in front, which changes between builds
+    pub fn set_description_formatter(&mut self, formatter: Box<dyn Fn(&str) -> String + Send + Sync>) {
+        self.description_formatter = Some(Arc::from(formatter));
+    }
+
+    /// Keeps the names verbatim again
+    pub fn clear_description_formatter(&mut self) {
+        self.description_formatter = None;
+    }
+
+    /// The name after the description formatter
+    fn format_description(&self, name: &str) -> String {
+        match &self.description_formatter {
+            Some(formatter) => formatter(name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Short counter-based name, used when descriptions are disabled. The
+    /// given name, or where the empty name came from `description`, is kept
+    /// for `original_description`.
+    fn next_description(&mut self, original: &str) -> String {
+        let id = self.original_descriptions.len();
+        let original = if original.is_empty() {
+            self.described_at.map(OriginalDescription::CalledAt)
+        } else {
+            Some(OriginalDescription::Name(intern(original)))
+        };
+        self.original_descriptions.push(original);
+        format!("#{}", id)
+    }
+
+    /// What a constraint, lookup or stored expression named `#id` because
+    /// descriptions are disabled was added as: the name given directly, or
+    /// `file:line` of the macro call for descriptions the macros would have
+    /// generated, as those are never formatted.
+    pub fn original_description(&self, name: &str) -> Option<String> {
+        let id = name.strip_prefix('#')?.parse::<usize>().ok()?;
+        match (*self.original_descriptions.get(id)?)? {
+            OriginalDescription::Name(name) => Some(name.to_string()),
+            OriginalDescription::CalledAt(location) => {
+                Some(format!("{}:{}", location.file(), location.line()))
+            }
+        }
+    }
+
+    pub(crate) fn require_zero(&mut self, name: &'static str, constraint: Expression<F>) {
+        self.add_constraint(name, constraint);
+    }
+
+    /// Requires every expression to be zero, one constraint per expression
+    /// named `name[i]`
+    pub(crate) fn require_all_zero(&mut self, name: &str, exprs: Vec<Expression<F>>) {
+        for (idx, expr) in exprs.into_iter().enumerate() {
+            let description = self.description(format_args!("{}[{}]", name, idx));
+            self.require_zero(description, expr);
+        }
+    }
+
+    /// Requires every expression to be boolean, one constraint per expression
+    /// named `name[i]`
+    pub(crate) fn require_all_boolean(&mut self, name: &str, exprs: Vec<Expression<F>>) {
+        for (idx, expr) in exprs.into_iter().enumerate() {
+            let description = self.description(format_args!("{}[{}]", name, idx));
+            self.require_boolean(description, expr);
+        }
+    }
+
+    /// Requires every expression to be zero with a single constraint, the
+    /// random linear combination of the expressions with the lookup
+    /// challenge. Falls back to `require_all_zero` without a challenge.
+    pub(crate) fn require_all_zero_rlc(&mut self, name: &'static str, exprs: Vec<Expression<F>>) {
+        match self.lookup_challenge.clone() {
+            Some(challenge) => self.require_zero(name, rlc::expr(&exprs, challenge)),
+            None => self.require_all_zero(name, exprs),
+        }
+    }
+
+    /// Requires `lhs != rhs`. The inverse of `lhs - rhs` is stored in a cell,
+    /// which is assigned together with the stored expressions. Under a
+    /// condition the inverse only needs to exist when the condition is `1`.
+    pub(crate) fn require_different(
+        &mut self,
+        name: &'static str,
+        lhs: Expression<F>,
+        rhs: Expression<F>,
+    ) {
+        let diff = self.split_expression(name, lhs - rhs);
+        let inverse = self.store_inverse(name, diff.expr());
+        self.add_constraint(name, diff * inverse.expr() - 1.expr());
+    }
+
+    /// Stores the inverse of the expression (`0` when the expression is `0`)
+    /// in a cell without constraining it
+    fn store_inverse(&mut self, name: &str, diff: Expression<F>) -> Cell<F> {
+        let cell_type = C::storage_for_expr(&diff);
+        let inverse = self.query_one(cell_type);
+        let stored_name = if self.disable_description {
+            self.next_description(name)
+        } else {
+            format!("{} (inverse)", self.format_description(name))
+        };
+        self.stored_expressions
+            .entry(self.region_id)
+            .or_insert_with(Vec::new)
+            .push(StoredExpression {
+                name: stored_name,
+                cell: inverse.clone(),
+                cell_type,
+                expr_id: diff.identifier(),
+                expr: diff,
+                inverse: true,
+            });
+        inverse
+    }
+
+    pub(crate) fn require_equal(
+        &mut self,
+        name: &'static str,
         lhs: Expression<F>,
         rhs: Expression<F>,
     ) {
         self.add_constraint(name, lhs - rhs);
     }
 
+    /// Requires two words to be equal, limb by limb. The limb constraints are
+    /// named `name (lo)` and `name (hi)`, and each is conditioned and split on
+    /// its own.
+    pub(crate) fn require_equal_word(
+        &mut self,
+        name: &'static str,
+        lhs: Word<Expression<F>>,
+        rhs: Word<Expression<F>>,
+    ) {
+        let name_lo = self.description(format_args!("{} (lo)", name));
+        let name_hi = self.description(format_args!("{} (hi)", name));
+        let ((lhs_lo, lhs_hi), (rhs_lo, rhs_hi)) = (lhs.to_lo_hi(), rhs.to_lo_hi());
+        self.require_equal(name_lo, lhs_lo, rhs_lo);
+        self.require_equal(name_hi, lhs_hi, rhs_hi);
+    }
+
+    /// Requires `Σ coefficient ⋅ expression == rhs`
+    pub(crate) fn require_linear(
+        &mut self,
+        name: &'static str,
+        terms: &[(F, Expression<F>)],
+        rhs: Expression<F>,
+    ) {
+        self.add_constraint(name, linear_combine(terms) - rhs);
+    }
+
     pub(crate) fn require_boolean(&mut self, name: &'static str, value: Expression<F>) {
+        // The placeholder cells of dummy builders all look the same
+        if self.dedupe_booleans && !self.is_dummy() {
+            let id = value.identifier();
+            let condition = self.get_condition().map(|condition| condition.identifier());
+            if self.booleans.contains(&(id.clone(), None))
+                || self.booleans.contains(&(id.clone(), condition.clone()))
+            {
+                self.num_elided_booleans += 1;
+                return;
+            }
+            self.booleans.insert((id, condition));
+        }
         self.add_constraint(name, value.clone() * (1.expr() - value));
     }
 
+    /// Sets the strategy `require_in_set` uses
+    pub(crate) fn set_set_membership(&mut self, strategy: SetMembership) {
+        self.set_membership = strategy;
+    }
+
     pub(crate) fn require_in_set(
         &mut self,
         name: &'static str,
         value: Expression<F>,
         set: Vec<Expression<F>>,
     ) {
-        self.add_constraint(
+        self.require_in_set_with(name, value, set, self.set_membership);
+    }
+
+    /// Like `require_in_set`, with the strategy given for this call
+    pub(crate) fn require_in_set_with(
+        &mut self,
+        name: &'static str,
+        value: Expression<F>,
+        set: Vec<Expression<F>>,
+        strategy: SetMembership,
+    ) {
+        match strategy {
+            SetMembership::Auto => {
+                // Large contiguous sets are cheaper as range lookups
+                if set.len() > RANGE_LOOKUP_THRESHOLD && self.fixed_range.is_some() {
+                    if let Some(start) = contiguous_start(&set) {
+                        self.require_in_contiguous(name, value, Expression::Constant(start), set.len() - 1);
+                        return;
+                    }
+                }
+                self.require_in_set_with(name, value, set, SetMembership::Product);
+            }
+            SetMembership::Product => {
+                self.add_constraint(
+                    name,
+                    set.iter()
+                        .fold(1.expr(), |acc, item| acc * (value.clone() - item.clone())),
+                );
+            }
+            SetMembership::Lookup => {
+                let start = contiguous_start(&set)
+                    .unwrap_or_else(|| panic!("{}: set lookups need a contiguous set", name));
+                assert!(
+                    matches!(self.fixed_range, Some((_, size)) if set.len() <= size),
+                    "{}: set lookups need a large enough fixed range table",
+                    name
+                );
+                self.require_in_contiguous(name, value, Expression::Constant(start), set.len() - 1);
+            }
+            SetMembership::Indicators => {
+                self.require_in_set_indicators(name, value, set);
+            }
+        }
+    }
+
+    /// Requires the value to be in the set with an equality indicator per
+    /// element, returning the indicators (`1` for the element equal to the
+    /// value, `0` for the others). Each indicator is stored in a cell next to
+    /// the inverse of the difference, so all constraints are of degree 2 for
+    /// a value of degree 1. The cells are assigned with the stored
+    /// expressions.
+    pub(crate) fn require_in_set_indicators(
+        &mut self,
+        name: &'static str,
+        value: Expression<F>,
+        set: Vec<Expression<F>>,
+    ) -> Vec<Expression<F>> {
+        let indicators = set
+            .into_iter()
+            .map(|item| {
+                let diff = value.expr() - item;
+                let inverse = self.store_inverse(name, diff.expr());
+                let cell_type = C::storage_for_expr(&diff);
+                let is_equal: Expression<F> = self
+                    .store_expression(name, 1.expr() - diff.expr() * inverse.expr(), cell_type, None)
+                    .into();
+                // `diff != 0` => `is_equal == 0`, `diff == 0` => `is_equal == 1`
+                self.add_constraint(name, diff * is_equal.expr());
+                is_equal
+            })
+            .collect::<Vec<_>>();
+        self.add_constraint(name, sum::expr(&indicators) - 1.expr());
+        indicators
+    }
+
+    /// Requires `0 <= value <= max`
+    pub(crate) fn require_range_closed(&mut self, name: &'static str, value: Expression<F>, max: usize) {
+        self.require_in_contiguous(name, value, 0.expr(), max);
+    }
+
+    /// Selects one of `n_arms` arms with the index in binary, see
+    /// `BinarySelector`. Used with `matchx_binary!`.
+    pub fn binary_selector(&mut self, n_arms: usize) -> BinarySelector<F> {
+        BinarySelector::construct(self, n_arms)
+    }
+
+    /// Requires `0 <= value < 256^n_bytes` by decomposing the value into
+    /// `n_bytes` little-endian byte cells (of `C::byte_type()`, so each is
+    /// range checked by the cell type). The bytes are assigned together with
+    /// the stored expressions, and returned for inspection.
+    pub(crate) fn require_in_range(
+        &mut self,
+        name: &'static str,
+        value: Expression<F>,
+        n_bytes: usize,
+    ) -> Vec<Cell<F>> {
+        assert!(
+            n_bytes <= from_bytes::MAX_N_BYTES_INTEGER,
+            "require_in_range '{}': {} bytes can wrap around the field, at most {} are supported",
+            name,
+            n_bytes,
+            from_bytes::MAX_N_BYTES_INTEGER
+        );
+        let bytes = self
+            .try_query_bytes_dyn(name, n_bytes)
+            .unwrap_or_else(|err| panic!("{}", err));
+        self.require_equal(name, value.expr(), from_bytes::expr(&bytes));
+        self.range_decompositions
+            .entry(self.region_id)
+            .or_insert_with(Vec::new)
+            .push(RangeDecomposition { name, value, bytes: bytes.clone() });
+        bytes
+    }
+
+    /// The values decomposed by `require_in_range` in the region
+    pub(crate) fn range_decompositions(&self, region_id: usize) -> &[RangeDecomposition<F>] {
+        self.range_decompositions
+            .get(&region_id)
+            .map(|decompositions| decompositions.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Requires `start <= value <= start + max`, using the fixed range table
+    /// when it is large enough and falling back to the product form otherwise.
+    fn require_in_contiguous(
+        &mut self,
+        name: &'static str,
+        value: Expression<F>,
+        start: Expression<F>,
+        max: usize,
+    ) {
+        match self.fixed_range.clone() {
+            Some((table, size)) if max < size => {
+                let offset = value - start;
+                self.add_lookup(format!("{} (range)", name), vec![offset.expr()], vec![table.expr()]);
+                // The upper bound is implied when the set ends with the table
+                if max + 1 < size {
+                    self.add_lookup(format!("{} (range max)", name), vec![max.expr() - offset], vec![table]);
+                }
+            }
+            _ => {
+                if !self.is_dummy() {
+                    self.diagnostics.push(format!(
+                        "'{}': range of {} values checked with a product of degree {}, no large \
+                        enough fixed range table loaded",
+                        name,
+                        max + 1,
+                        (max + 1) * value.degree().max(1)
+                    ));
+                }
+                self.add_constraint(
+                    name,
+                    (0..=max).fold(1.expr(), |acc, item| acc * (value.expr() - start.expr() - item.expr())),
+                );
+            }
+        }
+    }
+
+    /// Looks up `[index, values..]` in a sparse table of `height` rows (see
+    /// `table::assign_sparse_fixed_table`). The index is range checked below
+    /// `height` with the fixed range table, so indices past the assigned
+    /// rows can't be looked up.
+    pub(crate) fn lookup_sparse(
+        &mut self,
+        name: &'static str,
+        index: Expression<F>,
+        values: Vec<Expression<F>>,
+        table: Vec<Expression<F>>,
+        height: usize,
+    ) {
+        assert!(height > 0, "'{}': sparse tables need at least one row", name);
+        assert!(
+            matches!(self.fixed_range, Some((_, size)) if height <= size),
+            "'{}': sparse table lookups need a fixed range table of at least {} rows",
             name,
-            set.iter()
-                .fold(1.expr(), |acc, item| acc * (value.clone() - item.clone())),
+            height
         );
+        self.require_in_contiguous(name, index.expr(), 0.expr(), height - 1);
+        self.add_lookup(name.to_string(), [vec![index], values].concat(), table);
+    }
+
+    /// Registers a fixed column containing `0..size` (see
+    /// `table::assign_fixed_range`) to be used for range checks
+    pub(crate) fn load_fixed_range(&mut self, meta: &mut ConstraintSystem<F>, column: Column<Fixed>, size: usize) {
+        let table = query_expression(meta, |meta| meta.query_fixed(column, Rotation::cur()));
+        self.fixed_range = Some((table, size));
+    }
+
+    pub(crate) fn enable_equality(&mut self, column: Column<Advice>){
+        self.enable_equality_any(column);
+    }
+
+    /// Enables equality on the column only when `cond` holds, for columns
+    /// of gadgets that are not used in every configuration
+    pub(crate) fn enable_equality_if(&mut self, cond: bool, column: Column<Advice>) {
+        if cond {
+            self.enable_equality(column);
+        }
+    }
+
+    /// Enables equality constraints on a column of any type, e.g. to copy a
+    /// cell into an instance column. Every column is only registered once.
+    pub(crate) fn enable_equality_any(&mut self, column: impl Into<Column<Any>>) {
+        let column = column.into();
+        if !self.equalities.contains(&column) {
+            self.equalities.push(column);
+        }
+    }
+
+    /// The columns equality is enabled on by `build_equalities`, in
+    /// registration order
+    pub fn equalities(&self) -> &[Column<Any>] {
+        &self.equalities
+    }
+
+    /// Exposes the cell as the public input at `row` of the instance column,
+    /// enabling equality on both columns. The copy is done with
+    /// `CachedRegion::public_cells` and `constrain_publics`.
+    pub(crate) fn expose_public(
+        &mut self,
+        name: &'static str,
+        cell: Cell<F>,
+        instance: Column<Instance>,
+        row: usize,
+    ) {
+        self.enable_equality(cell.column());
+        self.enable_equality_any(instance);
+        self.publics.push(PublicInput { name, cell, instance, row });
+    }
+
+    /// The cells exposed as public inputs
+    pub(crate) fn publics(&self) -> &[PublicInput<F>] {
+        &self.publics
+    }
+
+    pub fn condition<R>(
+        &mut self,
+        condition: Expression<F>,
+        constraint: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let _guard = self.condition_guard();
+        self.push_condition(condition);
+        constraint(self)
+    }
+
+    pub fn push_condition(&mut self, condition: Expression<F>) {
+        if self.check_condition_booleanness {
+            self.require_condition_boolean(condition.clone());
+        }
+        self.push_boolean_condition(condition);
+    }
+
+    /// Pushes a condition known to be boolean, which is not checked with
+    /// `set_check_condition_booleanness`
+    pub fn push_boolean_condition(&mut self, condition: Expression<F>) {
+        self.log(|| BuilderOp::PushCondition { degree: condition.degree() });
+        self.conditions.0.lock().push(condition);
+    }
+
+    /// Requires the condition to be boolean under the active conditions, once
+    /// per region and active condition. Constant conditions are only checked
+    /// in debug builds.
+    fn require_condition_boolean(&mut self, condition: Expression<F>) {
+        if let Expression::Constant(_) = condition {
+            debug_assert_boolean_condition("push_condition", &condition);
+            return;
+        }
+        let identifier = condition.identifier();
+        let key = (identifier.clone(), self.get_condition_expr().identifier());
+        if !self.checked_conditions.insert(key) {
+            return;
+        }
+        self.condition_degrees.push((self.region_id, identifier, condition.degree()));
+        self.require_boolean("condition is boolean", condition);
     }
 
-    pub(crate) fn enable_equality(&mut self, column: Column<Advice>){
-        self.equalities.push(column);
+    pub fn pop_condition(&mut self) {
+        self.log(|| BuilderOp::PopCondition);
+        self.conditions.0.lock().pop();
     }
 
-    pub(crate) fn condition<R>(
-        &mut self,
-        condition: Expression<F>,
-        constraint: impl FnOnce(&mut Self) -> R,
-    ) -> R {
+    /// The currently active conditions, outermost first
+    pub fn conditions(&self) -> Vec<Expression<F>> {
+        self.conditions.0.lock().clone()
+    }
+
+    /// Returns a token that restores the current conditions when dropped
+    pub fn condition_guard(&self) -> ConditionGuard<F> {
+        let saved = self.conditions();
+        let oplog = self.oplog.enabled.then(|| {
+            let degrees = saved.iter().map(|condition| condition.degree()).collect();
+            (self.oplog.ops.clone(), degrees)
+        });
+        ConditionGuard {
+            stack: self.conditions.0.clone(),
+            saved,
+            oplog,
+        }
+    }
+
+    /// Pushes the condition, which is popped again when the returned guard is
+    /// dropped. Constraints can be added through the guard.
+    pub fn guard(&mut self, condition: Expression<F>) -> ConditionScope<'_, F, C, T> {
+        let restore = self.condition_guard();
         self.push_condition(condition);
-        let ret = constraint(self);
-        self.pop_condition();
-        ret
+        ConditionScope {
+            cb: self,
+            _restore: restore,
+        }
     }
 
-    pub(crate) fn push_condition(&mut self, condition: Expression<F>) {
-        self.conditions.push(condition);
+    /// Runs `f` with `conditions` as the active conditions. The previous
+    /// conditions are restored afterwards, also when `f` panics.
+    pub fn with_conditions<R>(
+        &mut self,
+        conditions: Vec<Expression<F>>,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let _guard = self.condition_guard();
+        self.log(|| BuilderOp::SetConditions {
+            degrees: conditions.iter().map(|condition| condition.degree()).collect(),
+        });
+        *self.conditions.0.lock() = conditions;
+        f(self)
     }
 
-    pub(crate) fn pop_condition(&mut self) {
-        self.conditions.pop();
+    /// Identifier of the combined active condition, usable as a caching key
+    pub fn condition_identifier(&self) -> String {
+        self.get_condition_expr().identifier()
     }
 
     pub(crate) fn add_constraints(&mut self, constraints: Vec<(&'static str, Expression<F>)>) {
@@ -283,27 +2956,367 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
     }
 
     pub(crate) fn add_constraint(&mut self, name: &'static str, constraint: Expression<F>) {
-        if self.max_global_degree == 0 {
+        if self.is_dummy() {
+            self.count(|counts| counts.constraints += 1);
             return;
         }
-        let constraint = match self.get_condition() {
-            Some(condition) => condition * constraint,
-            None => constraint,
+        if cfg!(debug_assertions) {
+            self.debug_check_cells(name, &constraint);
+        }
+        let given_name = name;
+        let name: &'static str = if self.disable_description {
+            intern(&self.next_description(name))
+        } else if self.description_formatter.is_some() {
+            intern(&self.format_description(name))
+        } else {
+            name
+        };
+        let constraint = self.apply_condition(constraint);
+        self.log(|| BuilderOp::AddConstraint { name: name.to_string(), degree: constraint.degree() });
+        let constraint = self.finish_constraint(name, given_name, constraint);
+        self.push_constraint(name, constraint);
+    }
+
+    /// Runs the hooks on the conditioned constraint and splits it, `name`
+    /// being the name it's stored under and `given_name` the one it was added
+    /// with
+    fn finish_constraint(
+        &mut self,
+        name: &'static str,
+        given_name: &'static str,
+        constraint: Expression<F>,
+    ) -> Expression<F> {
+        let constraint = self.apply_constraint_hooks(name, constraint);
+        // The stored expressions format the given name themselves
+        let split_name = if self.disable_description { name } else { given_name };
+        let constraint = self.split_expression(split_name, constraint);
+        self.validate_split_degree(&constraint, name);
+        constraint
+    }
+
+    /// Multiplies the constraint with the active conditions. Once a condition
+    /// was applied to more constraints in the region than the condition cell
+    /// threshold, it's stored in a boolean cell used for the next ones.
+    fn apply_condition(&mut self, constraint: Expression<F>) -> Expression<F> {
+        let condition = match self.get_condition() {
+            Some(condition) => condition,
+            None => return constraint,
+        };
+        let threshold = match self.condition_cell_threshold {
+            Some(threshold) if self.region_id != 0 && self.cell_manager.is_some() => threshold,
+            _ => return condition * constraint,
+        };
+        let id = condition.identifier();
+        let cell = match self.condition_cells.get(&id) {
+            Some(cell) => cell.clone(),
+            None => {
+                let uses = self.condition_uses.entry(id.clone()).or_insert(0);
+                *uses += 1;
+                if *uses <= threshold || condition.degree() <= 1 {
+                    return condition * constraint;
+                }
+                // The stored expression is only multiplied by the region
+                // condition, so the cell holds the condition on every row
+                let cell_type = C::storage_for_expr(&condition);
+                let cell = self.store_expression("condition", condition.expr(), cell_type, None).0;
+                let name = "condition cell is boolean";
+                let boolean = self.apply_constraint_hooks(name, cell.expr() * (1.expr() - cell.expr()));
+                self.push_constraint(name, boolean);
+                self.condition_cells.insert(id, cell.expr());
+                self.condition_cell_stats.stored += 1;
+                cell
+            }
         };
-        let constraint = self.split_expression(name, constraint);
-        self.validate_degree(constraint.degree(), name);
+        self.condition_cell_stats.rewired += 1;
+        self.condition_cell_stats.degree_before += condition.degree() + constraint.degree();
+        self.condition_cell_stats.degree_after += cell.degree() + constraint.degree();
+        cell * constraint
+    }
+
+    fn push_constraint(&mut self, name: &'static str, constraint: Expression<F>) {
         self.constraints.push((name, constraint));
+        self.constraint_regions.push(self.region_id);
+        let provenance = self.record_provenance.then(|| self.provenance());
+        self.constraint_provenance.push(provenance);
     }
 
-    pub(crate) fn get_condition(&self) -> Option<Expression<F>> {
-        if self.conditions.is_empty() {
+    /// The current conditions, including the region conditions, with the
+    /// strings interned so recording them for many constraints stays cheap
+    fn provenance(&self) -> ConstraintProvenance {
+        let conditions = self
+            .state_context
+            .iter()
+            .chain(self.conditions.0.lock().iter())
+            .map(|condition| {
+                (
+                    intern(&condition.identifier()),
+                    intern(&expr_to_string(condition, AUDIT_DEPTH)),
+                )
+            })
+            .collect();
+        ConstraintProvenance {
+            conditions,
+            region: intern(&self.region_name(self.region_id)),
+        }
+    }
+
+    /// Records the conditions every constraint is added under from now on,
+    /// see `constraint_provenance` and `why_active`
+    pub(crate) fn set_record_provenance(&mut self, record: bool) {
+        self.record_provenance = record;
+    }
+
+    /// The conditions the constraint at `idx` (in `constraints`) was added
+    /// under, when recorded
+    pub fn constraint_provenance(&self, idx: usize) -> Option<&ConstraintProvenance> {
+        self.constraint_provenance.get(idx).and_then(|provenance| provenance.as_ref())
+    }
+
+    /// Describes the chain of conditions enabling every constraint with the
+    /// name, one block per constraint
+    pub(crate) fn why_active(&self, name: &str) -> String {
+        let mut out = String::new();
+        for (idx, (constraint_name, _)) in self.constraints.iter().enumerate() {
+            if *constraint_name != name {
+                continue;
+            }
+            match self.constraint_provenance(idx) {
+                Some(provenance) => out.push_str(&format!("'{}' (#{}) {}\n", name, idx, provenance)),
+                None => out.push_str(&format!(
+                    "'{}' (#{}): provenance not recorded, see `set_record_provenance`\n",
+                    name, idx
+                )),
+            }
+        }
+        if out.is_empty() {
+            out = format!("no constraint named '{}'\n", name);
+        }
+        out
+    }
+
+    /// Index of the last constraint with the name added in the current region
+    fn find_constraint(&self, name: &str) -> Option<usize> {
+        (0..self.constraints.len()).rev().find(|&idx| {
+            self.constraint_regions[idx] == self.region_id && self.constraints[idx].0 == name
+        })
+    }
+
+    /// Removes the last constraint with the name added in the current region
+    /// and returns it, so a gadget can relax a constraint of a lower layer.
+    /// Expressions stored while adding the constraint are left alone.
+    pub(crate) fn remove_constraint(&mut self, name: &str) -> Option<Expression<F>> {
+        let idx = self.find_constraint(name)?;
+        self.constraint_regions.remove(idx);
+        self.constraint_provenance.remove(idx);
+        if idx < self.region_constraints_start {
+            self.region_constraints_start -= 1;
+        }
+        Some(self.constraints.remove(idx).1)
+    }
+
+    /// Replaces the last constraint with the name added in the current region
+    /// and returns the old one. The constraint is found by the name it was
+    /// added with, formatted like in `add_constraint`. The new constraint
+    /// keeps the name and the position, and goes through the conditions,
+    /// hooks and splitting of `add_constraint` with the conditions active
+    /// now. Expressions stored while adding the old constraint are left
+    /// alone.
+    pub(crate) fn replace_constraint(
+        &mut self,
+        name: &'static str,
+        constraint: Expression<F>,
+    ) -> Option<Expression<F>> {
+        let stored_name = if self.disable_description {
+            name.to_string()
+        } else {
+            self.format_description(name)
+        };
+        let idx = self.find_constraint(&stored_name)?;
+        let stored_name = self.constraints[idx].0;
+        if cfg!(debug_assertions) {
+            self.debug_check_cells(stored_name, &constraint);
+        }
+        let constraint = self.apply_condition(constraint);
+        let constraint = self.finish_constraint(stored_name, name, constraint);
+        self.constraint_provenance[idx] = self.record_provenance.then(|| self.provenance());
+        Some(std::mem::replace(&mut self.constraints[idx].1, constraint))
+    }
+
+    /// Adds the constraint, or returns an error when it has a higher degree
+    /// than allowed and can't be split: outside of the regions, where
+    /// constraints aren't split, or without a cell manager to store the parts
+    /// in
+    pub(crate) fn try_add_constraint(
+        &mut self,
+        name: &'static str,
+        constraint: Expression<F>,
+    ) -> Result<(), ConstraintBuilderError> {
+        let degree = constraint.degree() + self.get_condition_expr().degree();
+        if self.max_global_degree > 0 && degree > self.max_degree {
+            if self.region_id == 0 {
+                return Err(ConstraintBuilderError::DegreeExceeded {
+                    name: name.to_string(),
+                    region_id: self.region_id,
+                    degree,
+                    max_degree: self.max_degree,
+                });
+            }
+            if self.cell_manager.is_none() {
+                return Err(ConstraintBuilderError::CellManagerNotSet {
+                    name: name.to_string(),
+                    region_id: self.region_id,
+                });
+            }
+        }
+        self.add_constraint(name, constraint);
+        Ok(())
+    }
+
+    /// The degree all constraints are limited to
+    pub(crate) fn max_global_degree(&self) -> usize {
+        self.max_global_degree
+    }
+
+    /// The constraints added so far, in order. The condition of a region is
+    /// only applied to its constraints when the region is popped.
+    pub fn constraints(&self) -> &[(&'static str, Expression<F>)] {
+        &self.constraints
+    }
+
+    /// The constraints added in the region, in order. Like `constraints`,
+    /// the region condition is only applied once the region is popped.
+    pub fn constraints_for_region(&self, region_id: usize) -> Vec<(&'static str, Expression<F>)> {
+        self.constraints
+            .iter()
+            .zip(self.constraint_regions.iter())
+            .filter(|(_, &id)| id == region_id)
+            .map(|(constraint, _)| constraint.clone())
+            .collect()
+    }
+
+    /// The lookups added in the region, in order
+    pub fn lookups_for_region(&self, region_id: usize) -> Vec<&LookupData<F>> {
+        self.lookups_in_region(region_id).collect()
+    }
+
+    /// The ids of all regions that added constraints, lookups, table rows,
+    /// stored expressions, cells or gadgets, or were labeled, sorted. Region
+    /// `0` is listed when anything was added outside of the regions.
+    pub fn regions(&self) -> Vec<usize> {
+        self.constraint_regions
+            .iter()
+            .copied()
+            .chain(self.lookups.iter().map(|lookup| lookup.region_id))
+            .chain(self.tables.values().flatten().map(|table| table.region_id))
+            .chain(self.stored_expressions.keys().copied())
+            .chain(self.region_cells.keys().copied())
+            .chain(self.region_labels.keys().copied())
+            .chain(self.gadget_records.iter().map(|record| record.region_id))
+            .unique()
+            .sorted()
+            .collect()
+    }
+
+    /// The names of the constraints that don't evaluate to zero with
+    /// `evaluate`, e.g. `MockMeta::evaluate`
+    pub fn unsatisfied_constraints(&self, evaluate: impl Fn(&Expression<F>) -> F) -> Vec<&'static str> {
+        self.constraints
+            .iter()
+            .filter(|(_, constraint)| evaluate(constraint) != F::ZERO)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// The descriptions of the lookups whose values, with the lookup
+    /// condition applied and evaluated with `evaluate`, aren't one of the
+    /// rows `table_rows` returns for the table expressions
+    pub fn unsatisfied_lookups(
+        &self,
+        evaluate: impl Fn(&Expression<F>) -> F,
+        table_rows: impl Fn(&[Expression<F>]) -> Vec<Vec<F>>,
+    ) -> Vec<String> {
+        self.lookups
+            .iter()
+            .filter(|lookup| {
+                let condition = lookup.condition();
+                let values = lookup
+                    .values
+                    .iter()
+                    .map(|value| evaluate(&(value.expr() * condition.expr())))
+                    .collect::<Vec<_>>();
+                !table_rows(&lookup.table).contains(&values)
+            })
+            .map(|lookup| lookup.description.clone())
+            .collect()
+    }
+
+    /// Problems that were worked around while building, like expressions that
+    /// couldn't be split
+    pub(crate) fn diagnostics(&self) -> &[String] {
+        &self.diagnostics
+    }
+
+    /// Registers a hook transforming every constraint after the active
+    /// condition is applied and before it is split. The equalities of stored
+    /// expressions, including the parts of split constraints, go through the
+    /// hooks as well. Hooks are applied in registration order.
+    pub fn add_constraint_hook(
+        &mut self,
+        hook: Box<dyn Fn(&str, Expression<F>, HookCtx) -> Expression<F> + Send + Sync>,
+    ) -> HookHandle {
+        let handle = self.new_hook_handle();
+        self.constraint_hooks.push((handle, Arc::from(hook)));
+        handle
+    }
+
+    /// Registers a hook transforming the values of every lookup
+    pub fn add_lookup_hook(
+        &mut self,
+        hook: Box<dyn Fn(&str, Vec<Expression<F>>, HookCtx) -> Vec<Expression<F>> + Send + Sync>,
+    ) -> HookHandle {
+        let handle = self.new_hook_handle();
+        self.lookup_hooks.push((handle, Arc::from(hook)));
+        handle
+    }
+
+    /// Removes a constraint or lookup hook, returns false if it wasn't registered
+    pub fn remove_hook(&mut self, handle: HookHandle) -> bool {
+        let num_hooks = self.constraint_hooks.len() + self.lookup_hooks.len();
+        self.constraint_hooks.retain(|(h, _)| *h != handle);
+        self.lookup_hooks.retain(|(h, _)| *h != handle);
+        num_hooks != self.constraint_hooks.len() + self.lookup_hooks.len()
+    }
+
+    fn new_hook_handle(&mut self) -> HookHandle {
+        self.next_hook += 1;
+        HookHandle(self.next_hook)
+    }
+
+    /// Runs the constraint through the constraint hooks, in registration order
+    fn apply_constraint_hooks(&self, name: &str, constraint: Expression<F>) -> Expression<F> {
+        let ctx = self.hook_ctx();
+        self.constraint_hooks
+            .iter()
+            .fold(constraint, |constraint, (_, hook)| hook(name, constraint, ctx))
+    }
+
+    fn hook_ctx(&self) -> HookCtx {
+        HookCtx {
+            region_id: self.region_id,
+            condition_degree: self.get_condition_expr().degree(),
+        }
+    }
+
+    pub fn get_condition(&self) -> Option<Expression<F>> {
+        let conditions = self.conditions.0.lock();
+        if conditions.is_empty() {
             None
         } else {
-            Some(and::expr(self.conditions.iter()))
+            Some(and::expr(conditions.iter()))
         }
     }
 
-    pub(crate) fn get_condition_expr(&self) -> Expression<F> {
+    pub fn get_condition_expr(&self) -> Expression<F> {
         self.get_condition().unwrap_or_else(|| 1.expr())
     }
 
@@ -322,71 +3335,731 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
             .clone()
     }
 
+    /// Queries the two limb cells of a 256-bit word
+    pub(crate) fn query_word(&mut self) -> WordCell<F> {
+        self.query_word_with_type(C::default())
+    }
+
+    pub(crate) fn query_word_with_type(&mut self, cell_type: C) -> WordCell<F> {
+        let cells = self.query_cells_dyn(cell_type, 2);
+        WordCell::new(cells[0].clone(), cells[1].clone())
+    }
+
+    /// Queries a cell of each type in neighboring columns at the same
+    /// rotation (see `CellManager::query_adjacent_pair`)
+    pub(crate) fn query_adjacent_pair(&mut self, cell_type_a: C, cell_type_b: C) -> (Cell<F>, Cell<F>) {
+        if self.is_dummy() {
+            for cell_type in [cell_type_a, cell_type_b] {
+                self.count(|counts| *counts.cells.entry(format!("{:?}", cell_type)).or_insert(0) += 1);
+            }
+            return (Cell::placeholder(), Cell::placeholder());
+        }
+        let (a, b) = self
+            .cell_manager
+            .as_mut()
+            .expect("query_adjacent_pair: cell manager not set")
+            .query_adjacent_pair(cell_type_a, cell_type_b);
+        let infos = self.region_cells.entry(self.region_id).or_insert_with(Vec::new);
+        for (idx, (cell, cell_type)) in [(&a, cell_type_a), (&b, cell_type_b)].into_iter().enumerate() {
+            infos.push(CellInfo {
+                name: format!("query_adjacent_pair[{}]", idx),
+                cell_type: format!("{:?}", cell_type),
+                column: cell.column(),
+                rotation: cell.rotation(),
+            });
+        }
+        (a, b)
+    }
+
     pub(crate) fn query_one(&mut self, cell_type: C) -> Cell<F> {
+        self.try_query_one("query_one", cell_type)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Queries a cell, or returns an error when no cell manager is set.
+    /// `name` is only used in the error.
+    pub(crate) fn try_query_one(
+        &mut self,
+        name: &str,
+        cell_type: C,
+    ) -> Result<Cell<F>, ConstraintBuilderError> {
+        Ok(self.try_query_cells_dyn(name, cell_type, 1)?.remove(0))
+    }
+
+    pub(crate) fn query_bytes<const N: usize>(&mut self) -> [Cell<F>; N] {
+        self.try_query_bytes("query_bytes")
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Queries byte cells, or returns an error when no cell manager is set
+    /// or the cell type has no byte type. `name` is only used in the error.
+    pub(crate) fn try_query_bytes<const N: usize>(
+        &mut self,
+        name: &str,
+    ) -> Result<[Cell<F>; N], ConstraintBuilderError> {
+        Ok(self.try_query_bytes_dyn(name, N)?.try_into().unwrap())
+    }
+
+    /// Queries `count` byte cells, with `count` only known at configure time
+    pub(crate) fn query_bytes_dyn(&mut self, count: usize) -> Vec<Cell<F>> {
+        self.try_query_bytes_dyn("query_bytes_dyn", count)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Queries `count` byte cells, or returns an error when no cell manager is
+    /// set or the cell type has no byte type. `name` is only used in the error.
+    pub(crate) fn try_query_bytes_dyn(
+        &mut self,
+        name: &str,
+        count: usize,
+    ) -> Result<Vec<Cell<F>>, ConstraintBuilderError> {
+        let byte_type = C::byte_type().ok_or_else(|| ConstraintBuilderError::NoByteType {
+            name: name.to_string(),
+            region_id: self.region_id,
+        })?;
+        self.try_query_cells_dyn(name, byte_type, count)
+    }
+
+    pub(crate) fn query_cells_dyn(&mut self, cell_type: C, count: usize) -> Vec<Cell<F>> {
+        self.try_query_cells_dyn("query_cells_dyn", cell_type, count)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Queries cells, or returns an error when no cell manager is set.
+    /// `name` is only used in the error.
+    pub(crate) fn try_query_cells_dyn(
+        &mut self,
+        name: &str,
+        cell_type: C,
+        count: usize,
+    ) -> Result<Vec<Cell<F>>, ConstraintBuilderError> {
+        if self.is_dummy() {
+            self.count(|counts| *counts.cells.entry(format!("{:?}", cell_type)).or_insert(0) += count);
+            return Ok(vec![Cell::placeholder(); count]);
+        }
+        let region_id = self.region_id;
+        let cell_manager = self.cell_manager.as_mut().ok_or_else(|| {
+            ConstraintBuilderError::CellManagerNotSet {
+                name: name.to_string(),
+                region_id,
+            }
+        })?;
+        let cells = cell_manager.query_cells(cell_type, count);
+        self.log(|| BuilderOp::QueryCells { cell_type: format!("{:?}", cell_type), count });
+        let infos = self.region_cells.entry(region_id).or_insert_with(Vec::new);
+        for (idx, cell) in cells.iter().enumerate() {
+            infos.push(CellInfo {
+                name: if count == 1 { name.to_string() } else { format!("{}[{}]", name, idx) },
+                cell_type: format!("{:?}", cell_type),
+                column: cell.column(),
+                rotation: cell.rotation(),
+            });
+        }
+        self.queried_cells.extend(cells.iter().map(|cell| (cell_type, cell.clone())));
+        Ok(cells)
+    }
+
+    /// Makes the cell available to later regions with `import_cell`. The
+    /// cell keeps its slot in the cell manager, so no later region can
+    /// allocate it again.
+    pub(crate) fn export_cell(&mut self, name: &str, cell: &Cell<F>) {
+        self.cell_manager
+            .as_mut()
+            .expect("exporting cells requires a cell manager")
+            .pin(cell);
+        assert!(
+            self.exported_cells.insert(name.to_string(), cell.clone()).is_none(),
+            "cell '{}' exported twice",
+            name
+        );
+    }
+
+    /// Returns the cell an earlier region exported under the name
+    pub(crate) fn import_cell(&self, name: &str) -> Result<Cell<F>, ConstraintBuilderError> {
+        self.exported_cells.get(name).cloned().ok_or_else(|| {
+            ConstraintBuilderError::MissingExport {
+                name: name.to_string(),
+                region_id: self.region_id,
+            }
+        })
+    }
+
+    /// The `i`-th power of the first challenge usable after `phase` (1 or 2).
+    /// The powers from `r^2` on are stored in cells that are shared by all
+    /// regions, each region only adds the constraints of the powers it uses.
+    /// The constraint is added without the active conditions, so it holds
+    /// for every use of the power in the region.
+    /// The cells are assigned with `CachedRegion::assign_challenge_powers`
+    /// in the regions using them.
+    pub(crate) fn challenge_power(&mut self, phase: u8, i: usize) -> Expression<F> {
+        if self.is_dummy() {
+            return 0.expr();
+        }
+        let challenges = self
+            .challenges
+            .as_ref()
+            .expect("challenge powers require a builder constructed with `with_challenges`");
+        let r = challenges
+            .first_after(phase)
+            .unwrap_or_else(|| panic!("no challenge usable after phase {}", phase));
+        let power = match i {
+            0 => return 1.expr(),
+            1 => return r,
+            _ => (1..i).fold(r.expr(), |power, _| power * r.expr()),
+        };
+        if !self.challenge_powers.contains_key(&(phase, i)) {
+            let cell = self
+                .try_query_one("challenge power", C::storage_for_phase(phase))
+                .unwrap_or_else(|err| panic!("{}", err));
+            self.cell_manager
+                .as_mut()
+                .expect("challenge powers require a cell manager")
+                .pin(&cell);
+            self.challenge_powers.insert((phase, i), (cell, BTreeSet::new()));
+        }
+        let region_id = self.region_id;
+        let (cell, region_ids) = self.challenge_powers.get_mut(&(phase, i)).unwrap();
+        let cell = cell.clone();
+        if region_ids.insert(region_id) {
+            let constraint = self.apply_constraint_hooks("challenge power", cell.expr() - power);
+            self.push_constraint("challenge power", constraint);
+        }
+        cell.expr()
+    }
+
+    /// The cells of the challenge powers by `(phase, exponent)`, with the
+    /// regions using them (`0` when used outside of the regions)
+    pub(crate) fn challenge_power_cells(&self) -> &BTreeMap<(u8, usize), (Cell<F>, BTreeSet<usize>)> {
+        &self.challenge_powers
+    }
+
+    /// Checks that the cell doesn't alias a cell allocated in a later region,
+    /// which happens when a cell is used across regions without being
+    /// exported
+    pub(crate) fn check_cell(&self, cell: &Cell<F>) -> Result<(), ConstraintBuilderError> {
+        match &self.cell_manager {
+            Some(cell_manager) if cell_manager.is_stale(cell) => {
+                Err(ConstraintBuilderError::StaleCell {
+                    cell: cell.to_string(),
+                    region_id: self.region_id,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Panics when the expression uses a cell of an earlier region that
+    /// wasn't exported, see `check_cell`. Every slot is checked with the last
+    /// cell queried in it, so a stale cell whose slot was allocated again in
+    /// this region can't be told apart from the new cell.
+    fn debug_check_cells(&self, name: &str, expr: &Expression<F>) {
+        if self.region_id == 0 || self.cell_manager.is_none() {
+            return;
+        }
+        let mut queries = HashSet::new();
+        advice_queries(expr, &mut queries);
+        for (column, rotation) in queries {
+            let cell = self.queried_cells.iter().rev().map(|(_, cell)| cell).find(|cell| {
+                cell.column.map(|column| column.index()) == Some(column) && cell.rotation as i32 == rotation
+            });
+            if let Some(Err(err)) = cell.map(|cell| self.check_cell(cell)) {
+                panic!("'{}': {}", name, err);
+            }
+        }
+    }
+
+    pub(crate) fn query_cell_with_type(&mut self, cell_type: C) -> Cell<F> {
         self.query_cells_dyn(cell_type, 1).first().unwrap().clone()
     }
 
-    pub(crate) fn query_bytes<const N: usize>(&mut self) -> [Cell<F>; N] {
-        self.query_cells_dyn(
-            C::byte_type().expect("No byte type for this CellManager"),
-            N,
-        )
-        .try_into()
-        .unwrap()
+    /// Checks the degree of the expression against the max degree of the
+    /// region. Only checked inside regions in debug builds, unless strict
+    /// degree checking is enabled, which checks everywhere.
+    pub(crate) fn validate_degree(&self, expr: &Expression<F>, name: &'static str) {
+        if self.max_global_degree > 0
+            && (self.strict_degree || (self.region_id != 0 && cfg!(debug_assertions)))
+            && expr.degree() > self.max_degree
+        {
+            panic!(
+                "Expression {} degree too high in {}: {} > {} (terms of degree {:?})",
+                name,
+                self.region_name(self.region_id),
+                expr.degree(),
+                self.max_degree,
+                term_degrees(expr),
+            );
+        }
+    }
+
+    /// Like `validate_degree`, for expressions that went through
+    /// `split_expression`. Without a cell manager expressions can't be split,
+    /// which is recorded in the diagnostics, so only a strict builder fails.
+    fn validate_split_degree(&self, expr: &Expression<F>, name: &'static str) {
+        if self.cell_manager.is_some() || self.strict_degree {
+            self.validate_degree(expr, name);
+        }
+    }
+
+    /// Sets the seed all random looking values of the builder are derived
+    /// from, like the padding sentinel. Values only change with the seed.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// The seed of the builder, `0` unless set
+    pub(crate) fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Random looking values for `domain`, reproducible from the seed
+    pub(crate) fn rng(&self, domain: &str) -> DeterministicRng {
+        DeterministicRng::with_domain(self.seed, domain)
+    }
+
+    /// Value assigned by `Padding::Sentinel`
+    pub(crate) fn padding_sentinel(&self) -> F {
+        padding_sentinel(self.seed)
+    }
+
+    /// Makes every degree violation a hard failure: constraints and lookups
+    /// above the max degree panic in release builds as well, outside of the
+    /// regions too, and also when they couldn't be split because there's no
+    /// cell manager. Enabled by default with the `strict-degree` feature.
+    pub fn set_strict_degree(&mut self, strict: bool) {
+        self.strict_degree = strict;
+    }
+
+    /// Makes `CachedRegion::assign_stored_expressions` write the cells
+    /// grouped by column instead of in the order the expressions were
+    /// stored in. The witness is the same, only the assignment order changes.
+    pub(crate) fn set_column_grouped_assignment(&mut self, grouped: bool) {
+        self.column_grouped_assignment = grouped;
+    }
+
+    /// Whether the stored expressions are assigned grouped by column
+    pub(crate) fn column_grouped_assignment(&self) -> bool {
+        self.column_grouped_assignment
+    }
+
+    /// Multiplies the selector into every constraint in `build_constraints`
+    /// and into the condition of every lookup in `build_lookups`, so nothing
+    /// is enabled on the rows the selector is off. The degree of the selector
+    /// is taken off the degree budget once, like the condition of a region.
+    /// Needs to be set before any constraint or lookup is added, those were
+    /// checked against the full budget.
+    pub(crate) fn set_global_selector(&mut self, selector: Expression<F>) {
+        assert!(self.global_selector.is_none(), "global selector set twice");
+        assert!(
+            self.constraints.is_empty() && self.lookups.is_empty(),
+            "global selector set after constraints or lookups were added"
+        );
+        let degree = selector.degree();
+        assert!(
+            self.is_dummy() || degree < self.max_global_degree,
+            "global selector of degree {} leaves no degree for the constraints",
+            degree
+        );
+        self.max_global_degree = self.max_global_degree.saturating_sub(degree);
+        self.max_degree = self.max_degree.saturating_sub(degree);
+        self.global_selector = Some(selector);
+    }
+
+    /// The selector set with `set_global_selector`
+    pub(crate) fn global_selector(&self) -> Option<&Expression<F>> {
+        self.global_selector.as_ref()
+    }
+
+    /// Multiplies the global selector into the expression, if there is one
+    fn apply_global_selector(&self, expr: Expression<F>) -> Expression<F> {
+        match &self.global_selector {
+            Some(selector) => selector.expr() * expr,
+            None => expr,
+        }
+    }
+
+    /// Makes `build_constraints` and `build_lookups` return the constraints
+    /// and lookups in an order that doesn't depend on the order they were
+    /// added in: constraints are sorted by (region id, name, identifier hash),
+    /// lookups by (table, description). Moving code around then no longer
+    /// changes the verifying key, but enabling it changes the key once.
+    pub(crate) fn set_canonical_order(&mut self, canonical_order: bool) {
+        self.canonical_order = canonical_order;
+    }
+
+    /// Makes `split_expression` reuse any cell of the current region that
+    /// already stores a subexpression, whatever its cell type, instead of only
+    /// the cells of the storage type of the subexpression. Saves a cell and an
+    /// equality for every term that's also stored somewhere else.
+    pub(crate) fn set_global_subexpression_sharing(&mut self, enabled: bool) {
+        self.global_subexpression_sharing = enabled;
+    }
+
+    /// The constraints for `create_gate`. Without constraints a single
+    /// `0` constraint is returned, as a gate needs one, except for dummy
+    /// builders which don't create gates and return no constraints at all.
+    pub(crate) fn build_constraints(&self) -> Vec<(&'static str, Expression<F>)> {
+        if self.is_dummy() {
+            return Vec::new();
+        }
+        debug_assert!(
+            self.conditions.0.lock().is_empty(),
+            "building constraints with {} unpopped conditions",
+            self.conditions.0.lock().len()
+        );
+        debug_assert!(
+            self.deferred.is_empty(),
+            "building constraints with {} deferred groups that didn't run, call finalize first",
+            self.deferred.len()
+        );
+        if self.constraints.is_empty() {
+            return vec![("No constraints", 0.expr())];
+        }
+        let constraints = if !self.canonical_order {
+            self.constraints.clone()
+        } else {
+            // The sort is stable, so equal constraints keep their order
+            self.constraints
+                .iter()
+                .zip(self.constraint_regions.iter())
+                .map(|((name, constraint), region_id)| {
+                    ((*region_id, *name, fnv1a_str(&constraint.identifier())), constraint)
+                })
+                .sorted_by(|a, b| a.0.cmp(&b.0))
+                .map(|((_, name, _), constraint)| (name, constraint.clone()))
+                .collect()
+        };
+        constraints
+            .into_iter()
+            .map(|(name, constraint)| (name, self.apply_global_selector(constraint)))
+            .collect()
+    }
+
+    /// The lookups in the order they are built in
+    fn ordered_lookups(&self) -> Vec<&LookupData<F>> {
+        let lookups = self.lookups.iter();
+        if !self.canonical_order {
+            return lookups.collect();
+        }
+        // Tables are identified by their expressions, they don't carry a tag
+        lookups
+            .sorted_by_cached_key(|lookup| {
+                let table = lookup.table.iter().map(|t| t.identifier()).join(",");
+                (table, lookup.description.clone())
+            })
+            .collect()
+    }
+
+    /// Hash of the names and expressions of the constraints and lookups, in the
+    /// order they are built in. Stable across runs and platforms, so it can be
+    /// used to detect changes to the verifying key.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut hash = fnv1a_str("");
+        let mut write = |s: &str| {
+            hash = fnv1a(hash, &(s.len() as u64).to_le_bytes());
+            hash = fnv1a(hash, s.as_bytes());
+        };
+        for (name, constraint) in self.build_constraints() {
+            write(name);
+            write(&constraint.identifier());
+        }
+        for lookup in self.ordered_lookups() {
+            write(&lookup.description);
+            write(&lookup.condition().identifier());
+            for expr in lookup.values.iter().chain(lookup.table.iter()) {
+                write(&expr.identifier());
+            }
+        }
+        for record in self.gadget_records.iter() {
+            write(record.gadget);
+            write(&record.params);
+        }
+        hash
+    }
+
+    /// Canonical JSON of the built constraints, lookups and stored tables (see
+    /// `util::expr_to_json`), in the order they are built in. Two builds of
+    /// the same circuit give byte-identical output, so it can be diffed to
+    /// check that a refactor didn't change the constraint system. Names and
+    /// descriptions are left out when `strip_descriptions` is set.
+    #[cfg(feature = "serde")]
+    pub(crate) fn to_summary_json(&self, strip_descriptions: bool) -> String {
+        use serde_json::{json, Map, Value};
+        let exprs = |exprs: &[Expression<F>]| json!(exprs.iter().map(expr_to_json).collect::<Vec<_>>());
+        // Inserted in sorted order, whether or not `serde_json` keeps the
+        // insertion order
+        let object = |mut entries: Vec<(&'static str, Value)>, key: &'static str, description: &str| {
+            if !strip_descriptions {
+                entries.push((key, json!(description)));
+            }
+            entries.sort_by_key(|(key, _)| *key);
+            let entries = entries.into_iter().map(|(key, value)| (key.to_string(), value));
+            Value::Object(entries.collect::<Map<_, _>>())
+        };
+        let constraints = self
+            .build_constraints()
+            .into_iter()
+            .map(|(name, constraint)| object(vec![("expr", expr_to_json(&constraint))], "name", name))
+            .collect::<Vec<_>>();
+        let lookups = self
+            .ordered_lookups()
+            .into_iter()
+            .map(|lookup| {
+                let entries = vec![
+                    ("condition", expr_to_json(&lookup.condition())),
+                    ("table", exprs(&lookup.table)),
+                    ("values", exprs(&lookup.values)),
+                ];
+                object(entries, "description", &lookup.description)
+            })
+            .collect::<Vec<_>>();
+        let tables = self
+            .tables
+            .keys()
+            .sorted()
+            .flat_map(|tag| self.tables[tag].iter().map(move |table| (tag, table)))
+            .map(|(tag, table)| {
+                let entries = vec![
+                    ("condition", expr_to_json(&table.condition())),
+                    ("tag", json!(format!("{:?}", tag))),
+                    ("values", exprs(&table.values)),
+                ];
+                object(entries, "description", table.description)
+            })
+            .collect::<Vec<_>>();
+        let summary = json!({
+            "constraints": constraints,
+            "lookups": lookups,
+            "tables": tables,
+        });
+        serde_json::to_string(&summary).expect("summaries only contain strings and numbers")
+    }
+
+    /// Enables equality on all registered columns, every column only once
+    pub(crate) fn build_equalities(&self, meta: &mut ConstraintSystem<F>) {
+        self.equalities
+            .iter()
+            .unique()
+            .for_each(|c| {
+                meta.enable_equality(*c)}
+            );
+    }
+    
+    /// In strict mode `finalize` requires all columns used by the constraints
+    /// to be owned by the cell manager or registered with
+    /// `register_external_column`, so no column is invisible to the analyses
+    /// working on cells.
+    pub(crate) fn set_strict_queries(&mut self, strict: bool) {
+        self.strict_queries = strict;
+    }
+
+    /// Allows constraints on a column not owned by the cell manager in strict
+    /// mode
+    pub(crate) fn register_external_column(&mut self, column: impl Into<Column<Any>>) {
+        self.external_columns.insert(column_name(column.into()));
+    }
+
+    /// Records a query done by the `circuit!` macros, returning the query
+    pub(crate) fn track_query(&self, column: impl Into<Column<Any>>, expr: Expression<F>) -> Expression<F> {
+        if self.strict_queries {
+            self.tracked_columns.borrow_mut().insert(column_name(column.into()));
+        }
+        expr
+    }
+
+    /// Checks that the constraints only use known columns in strict mode
+    fn check_columns(&self) -> Result<(), ConstraintBuilderError> {
+        if !self.strict_queries {
+            return Ok(());
+        }
+        let mut known = self.external_columns.clone();
+        if let Some(cell_manager) = &self.cell_manager {
+            known.extend(cell_manager.columns().iter().map(|column| column_name(column.column.into())));
+        }
+        let mut unknown: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, constraint) in self.constraints.iter() {
+            let mut columns = HashSet::new();
+            queried_columns(constraint, &mut columns);
+            for column in columns.into_iter().filter(|column| !known.contains(column)) {
+                unknown.entry(column).or_default().push(name.to_string());
+            }
+        }
+        match unknown.into_iter().sorted().next() {
+            Some((column, constraints)) => Err(ConstraintBuilderError::UnregisteredColumn {
+                tracked: self.tracked_columns.borrow().contains(&column),
+                column,
+                constraints,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a group of constraints that can only be written once all regions
+    /// exist, e.g. equalities between cells exported by different regions.
+    /// The group runs in `finalize`, outside of any region and without
+    /// conditions.
+    pub(crate) fn defer(&mut self, name: &'static str, group: Box<dyn Fn(&mut Self) + Send + Sync>) {
+        self.deferred.push((name, Arc::from(group)));
     }
 
-    pub(crate) fn query_cells_dyn(&mut self, cell_type: C, count: usize) -> Vec<Cell<F>> {
-        self.cell_manager
-            .as_mut()
-            .expect("Cell manager not set")
-            .query_cells(cell_type, count)
+    /// Number of constraints added by each deferred group, in order
+    pub(crate) fn deferred_constraints(&self) -> &[(&'static str, usize)] {
+        &self.deferred_constraints
     }
 
-    pub(crate) fn query_cell_with_type(&mut self, cell_type: C) -> Cell<F> {
-        self.query_cells_dyn(cell_type, 1).first().unwrap().clone()
+    fn run_deferred(&mut self) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        assert_eq!(self.region_id, 0, "deferred constraints run after all regions are popped");
+        let conditions = std::mem::take(&mut *self.conditions.0.lock());
+        self.log(|| BuilderOp::SetConditions { degrees: Vec::new() });
+        for (name, group) in std::mem::take(&mut self.deferred) {
+            let num_constraints = self.constraints.len();
+            group(self);
+            self.deferred_constraints
+                .push((name, self.constraints.len() - num_constraints));
+        }
+        self.log(|| BuilderOp::SetConditions {
+            degrees: conditions.iter().map(|condition| condition.degree()).collect(),
+        });
+        *self.conditions.0.lock() = conditions;
     }
 
-    pub(crate) fn validate_degree(&self, degree: usize, name: &'static str) {
-        if self.max_global_degree > 0 && self.region_id != 0 {
-            debug_assert!(
-                degree <= self.max_degree,
-                "Expression {} degree too high: {} > {}",
-                name,
-                degree,
-                self.max_degree,
-            );
+    /// Runs the deferred constraint groups and resolves all lookups done by
+    /// tag or column name against the stored tables and their schemas.
+    /// Called by `build_lookups`, but needs to be called before
+    /// `build_constraints` when constraints are deferred, and can be called
+    /// earlier to handle errors. All errors are returned (as `Multiple` when
+    /// there are several), and the lookups that couldn't be resolved are kept
+    /// so `finalize` can be called again once their tables are stored.
+    pub(crate) fn finalize(&mut self) -> Result<(), ConstraintBuilderError> {
+        self.run_deferred();
+        let mut errors = Vec::new();
+        if let Err(err) = self.check_columns() {
+            errors.push(err);
+        }
+        let mut unresolved = Vec::new();
+        for lookup in std::mem::take(&mut self.named_lookups) {
+            let table = match self.resolve_named_lookup(&lookup) {
+                Ok(table) => table,
+                Err(err) => {
+                    errors.push(err);
+                    unresolved.push(lookup);
+                    continue;
+                }
+            };
+            self.log(|| BuilderOp::AddLookup {
+                description: lookup.description.clone(),
+                width: lookup.values.len(),
+            });
+            self.lookups.push(LookupData::new(
+                lookup.description,
+                lookup.values,
+                table,
+                lookup.local_condition,
+                lookup.regional_condition,
+                lookup.region_id,
+            ));
+        }
+        self.named_lookups = unresolved;
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ConstraintBuilderError::Multiple(errors)),
         }
     }
 
-    pub(crate) fn build_constraints(&self) -> Vec<(&'static str, Expression<F>)> {
-        if self.constraints.is_empty() {
-            return vec![("No constraints", 0.expr())];
+    /// The table expressions a lookup by tag or column name looks up into
+    fn resolve_named_lookup(
+        &mut self,
+        lookup: &NamedLookupData<F, T>,
+    ) -> Result<Vec<Expression<F>>, ConstraintBuilderError> {
+        if !self.tables.contains_key(&lookup.tag) {
+            return Err(ConstraintBuilderError::MissingTable {
+                lookup: lookup.description.clone(),
+                table: format!("{:?}", lookup.tag),
+            });
         }
-        self.constraints.clone()
+        let indices = match &lookup.columns {
+            Some(columns) => {
+                let schema = self.schemas.get(&lookup.tag).ok_or_else(|| {
+                    ConstraintBuilderError::MissingTableSchema {
+                        lookup: lookup.description.clone(),
+                        table: format!("{:?}", lookup.tag),
+                    }
+                })?;
+                let indices = columns
+                    .iter()
+                    .map(|column| {
+                        schema.iter().position(|name| name == column).ok_or_else(|| {
+                            ConstraintBuilderError::MissingTableColumn {
+                                lookup: lookup.description.clone(),
+                                table: format!("{:?}", lookup.tag),
+                                column: *column,
+                            }
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(indices)
+            }
+            None => None,
+        };
+        let table = self.dynamic_table_merged(lookup.tag);
+        Ok(match indices {
+            Some(indices) => indices.into_iter().map(|idx| table[idx].expr()).collect(),
+            None => table,
+        })
     }
 
-    pub(crate) fn build_equalities(&self, meta: &mut ConstraintSystem<F>) {
-        self.equalities
-            .iter()
-            .for_each(|c| {
-                meta.enable_equality(*c)}
-            );
+    pub(crate) fn build_lookups(
+        &mut self,
+        meta: &mut ConstraintSystem<F>,
+    ) -> LookupBuildReport<T> {
+        self.try_build_lookups(meta).unwrap_or_else(|err| panic!("{}", err))
     }
-    
-    pub(crate) fn build_lookups(&mut self, meta: &mut ConstraintSystem<F>) {
-        for lookup in self.lookups.iter() {
+
+    /// Like `build_lookups`, but returns an error instead of panicking. No
+    /// lookup is emitted when a lookup has more values than its table has
+    /// columns.
+    pub(crate) fn try_build_lookups(
+        &mut self,
+        meta: &mut ConstraintSystem<F>,
+    ) -> Result<LookupBuildReport<T>, ConstraintBuilderError> {
+        self.finalize()?;
+        if let Some(lookup) = self.lookups.iter().find(|lookup| lookup.values.len() > lookup.table.len()) {
+            return Err(ConstraintBuilderError::LookupTooWide {
+                lookup: lookup.description.clone(),
+                region_id: lookup.region_id,
+                values: lookup.values.len(),
+                columns: lookup.table.len(),
+            });
+        }
+        let mut report = LookupBuildReport {
+            deduped: self.num_deduped_lookups,
+            ..Default::default()
+        };
+        for lookup in self.ordered_lookups() {
+            let condition = self.apply_global_selector(lookup.condition());
             let mut values: Vec<_> = lookup
                 .values
                 .iter()
-                .map(|value| value.expr() * lookup.condition())
+                .map(|value| value.expr() * condition.expr())
                 .collect();
             // Align the length of values and table
-            assert!(lookup.table.len() >= values.len());
             while values.len() < lookup.table.len() {
                 values.push(0.expr());
             }
+            if lookup.table.iter().any(has_challenge) {
+                report.compressed += 1;
+            }
+            report.emitted.push(LookupSummary {
+                description: lookup.description.clone(),
+                table: self.table_tag(&lookup.table),
+                columns: lookup.table.len(),
+                max_input_degree: values.iter().map(|value| value.degree()).max().unwrap_or(0),
+            });
             meta.lookup_any(
-                Box::leak(lookup.description.clone().into_boxed_str()),
+                intern(&lookup.description),
                 |_meta| {
                     values
                         .iter()
@@ -396,31 +4069,233 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
                 },
             );
         }
+        self.lookup_report = Some(report.clone());
+        Ok(report)
     }
 
+    /// What the last `build_lookups` emitted
+    pub(crate) fn lookup_report(&self) -> Option<&LookupBuildReport<T>> {
+        self.lookup_report.as_ref()
+    }
+
+    /// Tag of the table the cells of `cell_type` are looked up into, if it's
+    /// a lookup. Builders tagged with their own tags need to convert the
+    /// table type of the cell type into a tag.
+    pub(crate) fn cell_type_table(&self, cell_type: C) -> Option<T>
+    where
+        T: From<C::TableType>,
+    {
+        cell_type.lookup_table_type().map(T::from)
+    }
+
+    /// Tag of the stored table with exactly these expressions
+    fn table_tag(&self, table: &[Expression<F>]) -> Option<T> {
+        let table_ids = table.iter().map(|expr| expr.identifier()).collect::<Vec<_>>();
+        self.tables.keys().sorted().copied().find(|tag| {
+            let tables = &self.tables[tag];
+            tables.len() == 1
+                && tables[0].values.iter().map(|expr| expr.identifier()).collect::<Vec<_>>() == table_ids
+        })
+    }
+
+    /// Stores the tuple compressed with the lookup challenge in a cell of
+    /// `cell_type`, returning the cell. There's no such cell when tuples are
+    /// stored uncompressed, `None` is returned then, and dummy builders
+    /// return `0`. Long tuples are compressed with the shared challenge power
+    /// cells when the builder is constructed with `with_challenges`, those
+    /// need to be assigned with `CachedRegion::assign_challenge_powers`.
     pub(crate) fn store_tuple(
         &mut self,
         description: &'static str,
         cell_type: C,
         values: Vec<Expression<F>>,
-    ) -> Expression<F> {
-        let challenge = self.lookup_challenge.clone().unwrap();
-        let rlc = rlc::expr(&values, challenge.expr()) * self.get_condition_expr();
+    ) -> Option<Reduced<F>>
+    where
+        T: From<C::TableType>,
+    {
+        self.try_store_tuple(description, cell_type, values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `store_tuple`, but returns an error when the builder has no
+    /// lookup challenge to compress the tuple with, or when the compressed
+    /// tuple can't be stored
+    pub(crate) fn try_store_tuple(
+        &mut self,
+        description: &'static str,
+        cell_type: C,
+        values: Vec<Expression<F>>,
+    ) -> Result<Option<Reduced<F>>, ConstraintBuilderError>
+    where
+        T: From<C::TableType>,
+    {
+        self.store_tuple_inner(description, cell_type, values, true)
+    }
+
+    /// Like `store_tuple`, but the compressed tuple isn't multiplied with the
+    /// current condition, so the same tuple stored in different branches
+    /// shares a single cell and the degree of the condition isn't added.
+    /// This is only sound when the values are already gated, or when the
+    /// tuple has to be a valid table entry even when the condition is false:
+    /// the cell is looked up into the table on every row.
+    pub(crate) fn store_tuple_unconditional(
+        &mut self,
+        description: &'static str,
+        cell_type: C,
+        values: Vec<Expression<F>>,
+    ) -> Option<Reduced<F>>
+    where
+        T: From<C::TableType>,
+    {
+        self.try_store_tuple_unconditional(description, cell_type, values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `store_tuple_unconditional`, but returns an error like
+    /// `try_store_tuple`
+    pub(crate) fn try_store_tuple_unconditional(
+        &mut self,
+        description: &'static str,
+        cell_type: C,
+        values: Vec<Expression<F>>,
+    ) -> Result<Option<Reduced<F>>, ConstraintBuilderError>
+    where
+        T: From<C::TableType>,
+    {
+        self.store_tuple_inner(description, cell_type, values, false)
+    }
+
+    fn store_tuple_inner(
+        &mut self,
+        description: &'static str,
+        cell_type: C,
+        values: Vec<Expression<F>>,
+        conditional: bool,
+    ) -> Result<Option<Reduced<F>>, ConstraintBuilderError>
+    where
+        T: From<C::TableType>,
+    {
+        if self.is_dummy() {
+            if self.uncompressed_tuples {
+                for value in values.iter() {
+                    self.count_store(C::storage_for_expr(value), true);
+                }
+                let table = cell_type
+                    .lookup_table_type()
+                    .map_or("untagged".to_string(), |table| format!("{:?}", table));
+                self.count(|counts| *counts.lookups.entry(table).or_insert(0) += 1);
+                return Ok(None);
+            }
+            self.count_store(cell_type, true);
+            return Ok(Some(Reduced(0.expr())));
+        }
+        if self.uncompressed_tuples {
+            if conditional {
+                self.store_tuple_uncompressed(description, cell_type, values)?;
+            } else {
+                // Looked up on every row, like the cell of a compressed tuple
+                self.with_conditions(Vec::new(), |cb| {
+                    cb.store_tuple_uncompressed(description, cell_type, values)
+                })?;
+            }
+            return Ok(None);
+        }
+        let phase = values.iter().map(|value| C::expr_phase(value)).max().unwrap_or(0);
+        let challenge = self.phase_challenge(phase + 1).ok_or_else(|| {
+            ConstraintBuilderError::MissingLookupChallenge { name: description.to_string() }
+        })?;
+        let has_powers = self
+            .challenges
+            .as_ref()
+            .map_or(false, |challenges| challenges.first_after(phase + 1).is_some());
+        let rlc = if values.len() > TUPLE_POWER_THRESHOLD && has_powers {
+            let mut terms = vec![values[0].expr()];
+            for (i, value) in values.iter().enumerate().skip(1) {
+                terms.push(value.expr() * self.challenge_power(phase + 1, i));
+            }
+            sum::expr(terms)
+        } else {
+            rlc::expr(&values, challenge.expr())
+        };
+        let rlc = if conditional {
+            rlc * self.get_condition_expr()
+        } else {
+            rlc
+        };
         let reduced_rlc = self.split_expression("compression", rlc);
-        self.store_expression(description, reduced_rlc, cell_type, None)
+        self.try_store_expression(description, reduced_rlc, cell_type, None).map(Some)
+    }
+
+    /// Stores every value in its own cell and looks up the cells in the table
+    /// of the cell type, without compressing them with the lookup challenge
+    fn store_tuple_uncompressed(
+        &mut self,
+        description: &'static str,
+        cell_type: C,
+        values: Vec<Expression<F>>,
+    ) -> Result<(), ConstraintBuilderError>
+    where
+        T: From<C::TableType>,
+    {
+        let table_type = self
+            .cell_type_table(cell_type)
+            .unwrap_or_else(|| panic!("{:?} is not a lookup cell type", cell_type));
+        let cells = values
+            .into_iter()
+            .map(|value| {
+                let value = self.split_expression(description, value);
+                let storage = C::storage_for_expr(&value);
+                self.try_store_expression(description, value, storage, None).map(|cell| cell.into())
+            })
+            .collect::<Result<_, _>>()?;
+        let table = self.table(table_type);
+        self.add_lookup(description.to_string(), cells, table);
+        Ok(())
+    }
+
+    /// The challenge tuples of values known after `phase` are compressed
+    /// with: the first challenge usable after that phase when the builder was
+    /// constructed with `with_challenges`, the lookup challenge otherwise
+    pub(crate) fn phase_challenge(&self, phase: u8) -> Option<Expression<F>> {
+        self.challenges
+            .as_ref()
+            .and_then(|challenges| challenges.first_after(phase))
+            .or_else(|| self.lookup_challenge.clone())
+    }
+
+    /// Sets the challenge used to compress the tuples of lookups
+    pub(crate) fn set_lookup_challenge(&mut self, challenge: Expression<F>) {
+        self.lookup_challenge = Some(challenge);
+    }
+
+    /// Makes `add_lookup` compress the values past the last table column
+    /// together with the value of that column with the lookup challenge, when
+    /// there are more values than table columns. Only sound when the last
+    /// column of the table holds the same compression of the remaining
+    /// columns. Without it, such lookups make `build_lookups` fail.
+    pub(crate) fn set_compress_wide_lookups(&mut self, compress: bool) {
+        self.compress_wide_lookups = compress;
+    }
+
+    /// Makes tuple stores store every value in its own cell and do a
+    /// multi-column lookup into the table, instead of compressing the values
+    /// with the lookup challenge. Meant for small circuits without challenges:
+    /// cell types that are lookups can't be used with this enabled.
+    pub(crate) fn set_uncompressed_tuples(&mut self, uncompressed_tuples: bool) {
+        self.uncompressed_tuples = uncompressed_tuples;
     }
 
     /// Store the table as is without taking into account any active conditions
     pub(crate) fn load_table(
         &mut self,
         meta: &mut ConstraintSystem<F>,
-        table_type: C::TableType,
+        table_type: T,
         table: &dyn LookupTable<F>,
     ) {
         query_expression(meta, |meta| {
             let description = format!("{:?}", table_type);
             self.store_table_with_condition(
-                Box::leak(description.into_boxed_str()),
+                intern(&description),
                 table_type,
                 table.table_exprs(meta),
                 1.expr(),
@@ -429,11 +4304,69 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
         });
     }
 
+    /// Registers the range table under the tag, like `load_table`
+    pub(crate) fn load_range_table(
+        &mut self,
+        meta: &mut ConstraintSystem<F>,
+        table_type: T,
+        table: &RangeTable,
+    ) {
+        self.load_table(meta, table_type, table);
+    }
+
+    /// Requires `0 <= value < 2^bits` with a lookup into the range table
+    /// loaded under the tag (see `load_range_table`)
+    pub(crate) fn require_range_lookup(&mut self, name: &'static str, value: Expression<F>, table_type: T) {
+        let table = self.table(table_type);
+        assert!(self.is_dummy() || table.len() == 1, "'{}': {:?} is not a range table", name, table_type);
+        self.add_lookup(name.to_string(), vec![value], table);
+    }
+
+    /// Store the table as is, with every column queried at each of the
+    /// rotations, e.g. `[0, 1]` to look up pairs of adjacent rows. The values
+    /// are ordered by column and then by rotation, and the column annotations
+    /// are registered as the schema with the rotation added (`value@next`).
+    pub(crate) fn load_table_with_rotations(
+        &mut self,
+        meta: &mut ConstraintSystem<F>,
+        table_type: T,
+        table: &dyn LookupTable<F>,
+        rotations: &[i32],
+    ) {
+        let names = table
+            .annotations()
+            .iter()
+            .flat_map(|name| {
+                rotations
+                    .iter()
+                    .map(move |rotation| intern(&rotated_column_name(name, *rotation)))
+            })
+            .collect::<Vec<_>>();
+        self.set_table_schema(table_type, &names)
+            .unwrap_or_else(|err| panic!("{}", err));
+        query_expression(meta, |meta| {
+            let mut values = Vec::new();
+            for column in table.columns() {
+                for rotation in rotations {
+                    values.push(meta.query_any(column, Rotation(*rotation)));
+                }
+            }
+            let description = format!("{:?}", table_type);
+            self.store_table_with_condition(
+                intern(&description),
+                table_type,
+                values,
+                1.expr(),
+                1.expr(),
+            );
+        });
+    }
+
     /// Store the table taking into account the current active conditions
     pub(crate) fn store_table(
         &mut self,
         description: &'static str,
-        table_type: C::TableType,
+        table_type: T,
         values: Vec<Expression<F>>,
     ) {
         self.store_table_with_condition(
@@ -448,18 +4381,26 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
     pub(crate) fn store_table_with_condition(
         &mut self,
         description: &'static str,
-        table_type: C::TableType,
+        table_type: T,
         values: Vec<Expression<F>>,
         local_condition: Expression<F>,
         regional_condition: Expression<F>,
     ) {
-        let data = TableData {
+        if self.is_dummy() {
+            return;
+        }
+        self.log(|| BuilderOp::StoreTable {
+            description: description.to_string(),
+            tag: format!("{:?}", table_type),
+            width: values.len(),
+        });
+        let data = TableData::new(
             description,
+            values,
             local_condition,
             regional_condition,
-            values,
-            region_id: self.region_id,
-        };
+            self.region_id,
+        );
         if let Some(tables) = self.tables.get_mut(&table_type) {
             tables.push(data);
         } else {
@@ -467,30 +4408,408 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
         }
     }
 
-    pub(crate) fn table(&self, table_type: C::TableType) -> Vec<Expression<F>> {
-        let tables = self.tables.get(&table_type).unwrap();
-        assert!(tables.len() == 1);
-        tables[0].values.clone()
+    /// Store the table with named columns, registering the names as the
+    /// schema of the table so lookups can select columns by name
+    pub(crate) fn store_table_named(
+        &mut self,
+        description: &'static str,
+        table_type: T,
+        columns: Vec<(&'static str, Expression<F>)>,
+    ) {
+        let (names, values): (Vec<_>, Vec<_>) = columns.into_iter().unzip();
+        self.set_table_schema(table_type, &names)
+            .unwrap_or_else(|err| panic!("{}", err));
+        self.store_table(description, table_type, values);
+    }
+
+    /// Registers the column names of a table. All producers of the same table
+    /// need to use the same schema, registering a different one is an error.
+    pub(crate) fn set_table_schema(
+        &mut self,
+        table_type: T,
+        columns: &[&'static str],
+    ) -> Result<(), ConstraintBuilderError> {
+        let schema = self
+            .schemas
+            .entry(table_type)
+            .or_insert_with(|| columns.to_vec());
+        if schema.as_slice() != columns {
+            return Err(ConstraintBuilderError::ConflictingSchema {
+                table: format!("{:?}", table_type),
+                schema: schema.clone(),
+                columns: columns.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The column names of a table, if registered
+    pub(crate) fn table_schema(&self, table_type: T) -> Option<&[&'static str]> {
+        self.schemas.get(&table_type).map(|schema| schema.as_slice())
+    }
+
+    /// Lookup `values` into the given columns of a dynamic table. The columns
+    /// are resolved by name in `finalize`, so the table can be stored later
+    /// and in any column order.
+    pub(crate) fn lookup_columns(
+        &mut self,
+        description: &str,
+        table_type: T,
+        columns: &[&'static str],
+        values: Vec<Expression<F>>,
+    ) {
+        assert_eq!(columns.len(), values.len());
+        if self.is_dummy() {
+            self.count(|counts| *counts.lookups.entry(format!("{:?}", table_type)).or_insert(0) += 1);
+            return;
+        }
+        self.named_lookups.push(NamedLookupData {
+            description: description.to_string(),
+            tag: table_type,
+            columns: Some(columns.to_vec()),
+            values,
+            local_condition: self.get_condition_expr(),
+            regional_condition: get_condition_expr(&self.state_context),
+            region_id: self.region_id,
+        });
+    }
+
+    /// Lookup `values` into the dynamic table stored with `tag`. Only the tag
+    /// is recorded: the table is merged and the lookup emitted in `finalize`,
+    /// so the table can be stored after the lookup is added.
+    pub(crate) fn add_lookup_to_tag(
+        &mut self,
+        description: String,
+        values: Vec<Expression<F>>,
+        tag: T,
+    ) {
+        if self.is_dummy() {
+            self.count(|counts| *counts.lookups.entry(format!("{:?}", tag)).or_insert(0) += 1);
+            return;
+        }
+        self.named_lookups.push(NamedLookupData {
+            description,
+            tag,
+            columns: None,
+            values,
+            local_condition: self.get_condition_expr(),
+            regional_condition: get_condition_expr(&self.state_context),
+            region_id: self.region_id,
+        });
+    }
+
+    /// The values of the table stored with the tag. A table stored in
+    /// multiple fragments is merged, like `dynamic_table_merged` but without
+    /// requiring the selector to be boolean.
+    pub(crate) fn table(&self, table_type: T) -> Vec<Expression<F>> {
+        match self.try_table(table_type) {
+            Ok(values) => values,
+            Err(TableError::MultipleFragments { .. }) => {
+                let table_merger: TableMerger<F, C> = TableMerger {
+                    data: self.tables[&table_type].clone(),
+                    _phantom: PhantomData,
+                };
+                let (selector, values) = table_merger.merge_unsafe();
+                values.iter().map(|value| selector.expr() * value.expr()).collect()
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// The values of the table stored with the tag, if it's stored in a
+    /// single fragment
+    pub(crate) fn try_table(&self, table_type: T) -> Result<Vec<Expression<F>>, TableError> {
+        if self.is_dummy() {
+            return Ok(Vec::new());
+        }
+        match self.tables.get(&table_type).map(|tables| tables.as_slice()) {
+            None | Some([]) => Err(TableError::NotFound { tag: format!("{:?}", table_type) }),
+            Some([table]) => Ok(table.values.clone()),
+            Some(tables) => Err(TableError::MultipleFragments {
+                tag: format!("{:?}", table_type),
+                fragments: tables.len(),
+            }),
+        }
+    }
+
+    pub(crate) fn add_lookup(
+        &mut self,
+        description: String,
+        values: Vec<Expression<F>>,
+        table: Vec<Expression<F>>,
+    ) {
+        if self.is_dummy() {
+            self.count(|counts| *counts.lookups.entry("untagged".to_string()).or_insert(0) += 1);
+            return;
+        }
+        let description = if self.disable_description {
+            self.next_description(&description)
+        } else {
+            self.format_description(&description)
+        };
+        let ctx = self.hook_ctx();
+        let values = self
+            .lookup_hooks
+            .iter()
+            .fold(values, |values, (_, hook)| hook(&description, values, ctx));
+        let values = if self.compress_wide_lookups && !table.is_empty() && values.len() > table.len() {
+            let challenge = self.lookup_challenge.clone().unwrap_or_else(|| {
+                panic!("'{}': compressing the values requires a lookup challenge", description)
+            });
+            // The last table column holds the compressed remaining columns
+            let mut values = values;
+            let rest = values.split_off(table.len() - 1);
+            values.push(rlc::expr(&rest, challenge));
+            values
+        } else {
+            values
+        };
+        let values = self.split_lookup_values(&description, values);
+        self.log(|| BuilderOp::AddLookup { description: description.clone(), width: values.len() });
+        let data = LookupData::new(
+            description,
+            values,
+            table,
+            self.get_condition_expr(),
+            get_condition_expr(&self.state_context),
+            self.region_id,
+        );
+        self.lookups.push(data);
+    }
+
+    /// The values of lookups are multiplied with the condition when the
+    /// lookups are built, so they're split to what the condition leaves of
+    /// the budget
+    fn split_lookup_values(&mut self, description: &str, values: Vec<Expression<F>>) -> Vec<Expression<F>> {
+        let condition = self.get_condition_expr();
+        let max_degree = self.max_degree;
+        let budget = max_degree.saturating_sub(condition.degree());
+        if values.iter().all(|value| value.degree() <= budget) {
+            return values;
+        }
+        // Every part split off is stored with a constraint of at least degree
+        // 2, so splitting to less than that would never end
+        assert!(
+            budget >= 2,
+            "'{}': the condition of degree {} leaves a degree of {} to split the values to, \
+            at least 2 is needed",
+            description,
+            condition.degree(),
+            budget
+        );
+        let name = intern(description);
+        self.max_degree = budget;
+        let values = values
+            .into_iter()
+            .map(|value| self.split_expression(name, value))
+            .collect::<Vec<_>>();
+        self.max_degree = max_degree;
+        for value in values.iter() {
+            self.validate_split_degree(&(value.expr() * condition.expr()), name);
+        }
+        values
+    }
+
+    /// Registers the columns of a table assigned with `Layouter::assign_table`
+    /// (see `table::assign_table_rows`), to be looked up into with
+    /// `add_static_lookup`
+    pub(crate) fn register_table_columns(&mut self, table_type: T, columns: Vec<TableColumn>) {
+        assert!(
+            self.static_tables.insert(table_type, columns).is_none(),
+            "table columns of {:?} registered twice",
+            table_type
+        );
+    }
+
+    /// Lookup `values` into the table columns registered under the tag. The
+    /// lookups are built with `build_static_lookups` as native halo2 lookups,
+    /// which are cheaper than `lookup_any` for static tables. Lookups that
+    /// are disabled look up all zeros, so the table needs to contain the all
+    /// zero row.
+    pub(crate) fn add_static_lookup(
+        &mut self,
+        description: &str,
+        values: Vec<Expression<F>>,
+        table_type: T,
+    ) {
+        if self.is_dummy() {
+            self.count(|counts| *counts.lookups.entry(format!("{:?}", table_type)).or_insert(0) += 1);
+            return;
+        }
+        let description = if self.disable_description {
+            self.next_description(&description)
+        } else {
+            self.format_description(description)
+        };
+        let values = self.split_lookup_values(&description, values);
+        self.log(|| BuilderOp::AddLookup { description: description.clone(), width: values.len() });
+        self.static_lookups.push(StaticLookupData {
+            tag: table_type,
+            lookup: LookupData::new(
+                description,
+                values,
+                Vec::new(),
+                self.get_condition_expr(),
+                get_condition_expr(&self.state_context),
+                self.region_id,
+            ),
+        });
+    }
+
+    /// Emits the lookups added with `add_static_lookup` with `meta.lookup`,
+    /// pairing every value with a column of the table. Missing values are
+    /// looked up as zero.
+    pub(crate) fn build_static_lookups(&self, meta: &mut ConstraintSystem<F>) {
+        for StaticLookupData { tag, lookup } in self.static_lookups.iter() {
+            let columns = self
+                .static_tables
+                .get(tag)
+                .unwrap_or_else(|| panic!("'{}': no table columns registered for {:?}", lookup.description, tag));
+            assert!(
+                lookup.values.len() <= columns.len(),
+                "'{}': {} values for {} table columns",
+                lookup.description,
+                lookup.values.len(),
+                columns.len()
+            );
+            let condition = self.apply_global_selector(lookup.condition());
+            let values = lookup
+                .values
+                .iter()
+                .map(|value| value.expr() * condition.expr())
+                .chain(std::iter::repeat_with(|| 0.expr()))
+                .zip(columns.iter().copied())
+                .collect::<Vec<_>>();
+            meta.lookup(intern(&lookup.description), |_meta| values);
+        }
+    }
+
+    /// The lookups added in the given region
+    pub(crate) fn lookups_in_region(&self, region_id: usize) -> impl Iterator<Item = &LookupData<F>> {
+        self.lookups
+            .iter()
+            .filter(move |lookup| lookup.region_id == region_id)
+    }
+
+    /// The table rows stored in the given region, tables sorted by tag
+    pub(crate) fn tables_stored_in_region(
+        &self,
+        region_id: usize,
+    ) -> Vec<(T, &TableData<F>)> {
+        self.tables
+            .keys()
+            .sorted()
+            .flat_map(|tag| {
+                self.tables[tag]
+                    .iter()
+                    .filter(move |table| table.region_id == region_id)
+                    .map(move |table| (*tag, table))
+            })
+            .collect()
+    }
+
+    /// Merges all fragments of the table stored with the tag into a single
+    /// table, each row multiplied with the sum of the fragment conditions.
+    /// Only sound when at most one fragment is stored on any row: the
+    /// conditions aren't checked, and rows of overlapping fragments are
+    /// silently added together. Use `dynamic_table_merged_checked` when that
+    /// isn't guaranteed by the layout.
+    pub(crate) fn dynamic_table_merged(&mut self, tag: T) -> Vec<Expression<F>> {
+        self.try_dynamic_table_merged(tag).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Merges all fragments of the table stored with the tag, selected by the
+    /// sum of their conditions
+    pub(crate) fn try_dynamic_table_merged(
+        &mut self,
+        tag: T,
+    ) -> Result<Vec<Expression<F>>, TableError> {
+        self.merge_table(tag, 0.expr())
+    }
+
+    /// Like `dynamic_table_merged`, but the fragments with fewer columns are
+    /// padded with `default` instead of zero. Padding with zero lets a lookup
+    /// into the wider fragments match a row of a narrower fragment when the
+    /// looked up value is zero in the padded columns. Use a value that can't
+    /// be in those columns to rule that out.
+    pub(crate) fn dynamic_table_merged_with_default(
+        &mut self,
+        tag: T,
+        default: Expression<F>,
+    ) -> Vec<Expression<F>> {
+        self.merge_table(tag, default).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `dynamic_table_merged`, but requires the sum of the fragment
+    /// conditions to be boolean, so overlapping fragments make the circuit
+    /// fail instead of merging. Returns that sum as the selector, with the
+    /// merged values not yet multiplied with it so the caller can gate the
+    /// lookup on the selector as needed.
+    pub(crate) fn dynamic_table_merged_checked(
+        &mut self,
+        tag: T,
+    ) -> (Expression<F>, Vec<Expression<F>>) {
+        self.try_dynamic_table_merged_checked(tag).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `dynamic_table_merged_checked`, but returns an error when no
+    /// table was stored with the tag
+    pub(crate) fn try_dynamic_table_merged_checked(
+        &mut self,
+        tag: T,
+    ) -> Result<(Expression<F>, Vec<Expression<F>>), TableError> {
+        self.merge_table_checked(tag, 0.expr())
+    }
+
+    /// Like `dynamic_table_merged_checked`, but the fragments with fewer
+    /// columns are padded with `default` instead of zero, see
+    /// `dynamic_table_merged_with_default`
+    pub(crate) fn dynamic_table_merged_checked_with_default(
+        &mut self,
+        tag: T,
+        default: Expression<F>,
+    ) -> (Expression<F>, Vec<Expression<F>>) {
+        self.merge_table_checked(tag, default).unwrap_or_else(|err| panic!("{}", err))
     }
 
-    pub(crate) fn add_lookup(
+    fn merge_table_checked(
         &mut self,
-        description: String,
-        values: Vec<Expression<F>>,
-        table: Vec<Expression<F>>,
-    ) {
-        let data = LookupData {
-            description,
-            local_condition: self.get_condition_expr(),
-            regional_condition: get_condition_expr(&self.state_context),
-            values,
-            table,
-            region_id: self.region_id,
+        tag: T,
+        default: Expression<F>,
+    ) -> Result<(Expression<F>, Vec<Expression<F>>), TableError> {
+        let data = self
+            .tables
+            .get(&tag)
+            .ok_or_else(|| TableError::NotFound { tag: format!("{:?}", tag) })?
+            .clone();
+        let table_merger = TableMerger {
+            data,
+            _phantom: PhantomData,
         };
-        self.lookups.push(data);
+        Ok(table_merger.check_and_merge(self, default))
+    }
+
+    fn merge_table(
+        &mut self,
+        tag: T,
+        default: Expression<F>,
+    ) -> Result<Vec<Expression<F>>, TableError> {
+        let data = self
+            .tables
+            .get(&tag)
+            .ok_or_else(|| TableError::NotFound { tag: format!("{:?}", tag) })?
+            .clone();
+        let table_merger = TableMerger {
+            data,
+            _phantom: PhantomData,
+        };
+        Ok(table_merger.merge_and_select(self, default))
     }
 
-    pub(crate) fn dynamic_table_merged(&mut self, tag: C::TableType) -> Vec<Expression<F>> {
+    /// Exports the merged dynamic table so another builder can look up into
+    /// it with `import_table`. The selector of the merged table is required
+    /// to be boolean in this builder, so at most one producer is active.
+    pub(crate) fn export_table(&mut self, tag: T) -> ExportedTable<F> {
         let data = self
             .tables
             .get(&tag)
@@ -500,7 +4819,35 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
             data,
             _phantom: PhantomData,
         };
-        table_merger.merge_and_select(self)
+        let (selector, values) = table_merger.check_and_merge(self, 0.expr());
+        ExportedTable {
+            description: format!("{:?}", tag),
+            fragments: table_merger.data,
+            values,
+            selector,
+            schema: self.table_schema(tag).map(|schema| schema.to_vec()),
+            seed: self.seed,
+        }
+    }
+
+    /// Registers a table exported by another builder under a local tag, so
+    /// `table(local_tag)` and lookups by name can use it. The fragments are
+    /// stored as they were in the exporting builder, so the table is merged
+    /// exactly once.
+    pub(crate) fn import_table(&mut self, exported: ExportedTable<F>, local_tag: T) {
+        if let Some(schema) = &exported.schema {
+            self.set_table_schema(local_tag, schema)
+                .unwrap_or_else(|err| panic!("{}", err));
+        }
+        for fragment in exported.fragments {
+            self.store_table_with_condition(
+                fragment.description,
+                local_tag,
+                fragment.values,
+                fragment.local_condition,
+                fragment.regional_condition,
+            );
+        }
     }
 
     pub(crate) fn store_expression(
@@ -509,25 +4856,71 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
         expr: Expression<F>,
         cell_type: C,
         target_cell: Option<Cell<F>>,
-    ) -> Expression<F> {
+    ) -> Reduced<F> {
+        self.try_store_expression(name, expr, cell_type, target_cell)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Like `store_expression`, but returns an error when a cell is needed
+    /// and no cell manager is set, or when the expression has a higher
+    /// degree than allowed in the region
+    pub(crate) fn try_store_expression(
+        &mut self,
+        name: &str,
+        expr: Expression<F>,
+        cell_type: C,
+        target_cell: Option<Cell<F>>,
+    ) -> Result<Reduced<F>, ConstraintBuilderError> {
+        if self.max_global_degree > 0 && self.region_id != 0 && expr.degree() > self.max_degree {
+            return Err(ConstraintBuilderError::DegreeExceeded {
+                name: name.to_string(),
+                region_id: self.region_id,
+                degree: expr.degree(),
+                max_degree: self.max_degree,
+            });
+        }
+        self.store_expression_inner(name, expr, cell_type, target_cell)
+    }
+
+    fn store_expression_inner(
+        &mut self,
+        name: &str,
+        expr: Expression<F>,
+        cell_type: C,
+        target_cell: Option<Cell<F>>,
+    ) -> Result<Reduced<F>, ConstraintBuilderError> {
+        if self.is_dummy() {
+            self.count_store(cell_type, target_cell.is_none());
+            return Ok(Reduced(expr));
+        }
+        // Storing a stored cell again in the same kind of cell is pointless
+        if target_cell.is_none() && self.is_reduced_in(&expr, cell_type) {
+            return Ok(Reduced(expr));
+        }
         // Check if we already stored the expression somewhere
         let stored_expression = self.find_stored_expression(&expr, cell_type);
         match stored_expression {
-            Some(stored_expression) => stored_expression.cell.expr(),
+            Some(stored_expression) => Ok(Reduced(stored_expression.cell.expr())),
             None => {
                 // Require the stored value to equal the value of the expression
                 let cell = if let Some(tc) = target_cell {
                     tc
                 } else {
-                    self.query_one(cell_type)
+                    self.try_query_one(name, cell_type)?
+                };
+                let name = if self.disable_description {
+                    self.next_description(name)
+                } else {
+                    self.stored_expression_name(&self.format_description(name), &expr)
                 };
-                let name = format!("{} (stored expression)", name);
                 let equality = if self.region_id == 0 {
                     (cell.expr() - expr.clone()) * self.get_condition_expr()
                 } else {
                     cell.expr() - expr.clone()
                 };
-                self.constraints.push((Box::leak(name.clone().into_boxed_str()), equality));
+                self.log(|| BuilderOp::StoreExpression { name: name.clone(), degree: equality.degree() });
+                let equality = self.apply_constraint_hooks(&name, equality);
+                self.push_constraint(intern(&name), equality);
                 self.stored_expressions
                     .entry(self.region_id)
                     .or_insert_with(Vec::new)
@@ -537,12 +4930,161 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
                         cell_type,
                         expr_id: expr.identifier(),
                         expr,
+                        inverse: false,
                     });
-                cell.expr()
+                self.reduced.insert(cell.identifier(), Some(cell_type));
+                Ok(Reduced(cell.expr()))
             }
         }
     }
 
+    /// Marks the expression as reduced, so it's never split or stored again
+    /// in the same kind of cell. The expression is trusted to be of low enough
+    /// degree.
+    pub(crate) fn mark_reduced(&mut self, expr: Expression<F>) -> Reduced<F> {
+        self.reduced.entry(expr.identifier()).or_insert(None);
+        Reduced(expr)
+    }
+
+    /// Returns true if the expression was stored or marked as reduced
+    pub(crate) fn is_reduced(&self, expr: &Expression<F>) -> bool {
+        !self.reduced.is_empty() && self.reduced.contains_key(&expr.identifier())
+    }
+
+    /// Estimated number of rows at the end of the circuit that can't be used
+    /// because halo2 reserves them for blinding. Only the advice queries in
+    /// the constraints, tables and lookups of this builder are taken into
+    /// account.
+    pub(crate) fn unusable_rows(&self) -> usize {
+        let mut queries = HashSet::new();
+        for (_, constraint) in self.constraints.iter() {
+            advice_queries(constraint, &mut queries);
+        }
+        let tables = self.tables.values().flatten().map(|table| &table.values);
+        let lookups = self.lookups.iter().flat_map(|lookup| [&lookup.values, &lookup.table]);
+        for expr in tables.chain(lookups).flatten() {
+            advice_queries(expr, &mut queries);
+        }
+        let mut num_queries = HashMap::new();
+        for (column, _) in queries {
+            *num_queries.entry(column).or_insert(0) += 1;
+        }
+        // Every lookup argument queries its permuted input at two rotations
+        let lookup_queries = if self.lookups.is_empty() { 0 } else { 2 };
+        let max_queries = num_queries
+            .values()
+            .copied()
+            .chain([lookup_queries])
+            .max()
+            .unwrap_or(0);
+        // One blinding row per query (at least 3), two more blinding rows and
+        // the last row
+        max_queries.max(3) + 3
+    }
+
+    /// Returns the queried cells that don't appear in any constraint, lookup
+    /// or stored expression. Such a cell is a free witness, which is almost
+    /// always a soundness bug. A table row only constrains its cells when the
+    /// table is looked up into, lookups by tag or column names that aren't
+    /// resolved yet included. Cells of lookup cell types are constrained by
+    /// the lookup of their column and are never returned. Copy constraints
+    /// aren't seen, so cells only used in those are returned as well. Call it
+    /// once all constraints and lookups were added.
+    pub(crate) fn finalize_checks(&self) -> Vec<(C, Cell<F>)> {
+        let mut queries = HashSet::new();
+        for (_, constraint) in self.constraints.iter() {
+            advice_queries(constraint, &mut queries);
+        }
+        // The table of a lookup holds the rows of the tables looked up into
+        let lookups = self
+            .lookups
+            .iter()
+            .chain(self.static_lookups.iter().map(|lookup| &lookup.lookup))
+            .flat_map(|lookup| [&lookup.values, &lookup.table]);
+        let named_lookups = self.named_lookups.iter().flat_map(|lookup| {
+            let rows = self.tables.get(&lookup.tag).into_iter().flatten().map(|table| &table.values);
+            std::iter::once(&lookup.values).chain(rows)
+        });
+        for expr in lookups.chain(named_lookups).flatten() {
+            advice_queries(expr, &mut queries);
+        }
+        for stored in self.stored_expressions.values().flatten() {
+            advice_queries(&stored.expr, &mut queries);
+        }
+        self.queried_cells
+            .iter()
+            .filter(|(cell_type, cell)| {
+                if cell_type.lookup_table_type().is_some() {
+                    return false;
+                }
+                let mut cell_queries = HashSet::new();
+                advice_queries(&cell.expr(), &mut cell_queries);
+                cell_queries.is_disjoint(&queries)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The rows usable for witness data and tables in a circuit of `2^k` rows
+    pub(crate) fn usable_rows(&self, k: u32) -> Range<usize> {
+        0..(1 << k) - self.unusable_rows()
+    }
+
+    /// Stores a combined condition in a boolean cell once it was applied to
+    /// more than `threshold` constraints in a region, and multiplies the
+    /// later constraints by the cell instead. Saves degree when many
+    /// constraints share a high degree condition, at the cost of a cell and
+    /// two constraints. Conditions of degree 1 are never stored. The conditions
+    /// need to be boolean. `None` disables it.
+    pub(crate) fn set_condition_cell_threshold(&mut self, threshold: Option<usize>) {
+        self.condition_cell_threshold = threshold;
+    }
+
+    /// What the condition cells saved so far
+    pub(crate) fn condition_cell_stats(&self) -> &ConditionCellStats {
+        &self.condition_cell_stats
+    }
+
+    /// Number of expression nodes that had to be split
+    pub(crate) fn num_split_nodes(&self) -> usize {
+        self.num_split_nodes
+    }
+
+    fn is_reduced_in(&self, expr: &Expression<F>, cell_type: C) -> bool {
+        !self.reduced.is_empty()
+            && self.reduced.get(&expr.identifier()) == Some(&Some(cell_type))
+    }
+
+    /// `<name> (stored expression)`, with a suffix (`<name> #2 (stored
+    /// expression)`, `#3`, ..) when a different expression was already
+    /// stored with the name in the current region
+    fn stored_expression_name(&self, name: &str, expr: &Expression<F>) -> String {
+        let expr_id = expr.identifier();
+        let stored = self.stored_expressions.get(&self.region_id);
+        let taken = |candidate: &str| {
+            stored.map_or(false, |stored| {
+                stored
+                    .iter()
+                    .any(|stored| stored.name == candidate && stored.expr_id != expr_id)
+            })
+        };
+        let mut candidate = format!("{} (stored expression)", name);
+        let mut suffix = 1;
+        while taken(&candidate) {
+            suffix += 1;
+            candidate = format!("{} #{} (stored expression)", name, suffix);
+        }
+        candidate
+    }
+
+    /// The names of the expressions stored in the region, in order
+    pub(crate) fn stored_expression_names(&self, region_id: usize) -> Vec<String> {
+        self.stored_expressions
+            .get(&region_id)
+            .map(|stored| stored.iter().map(|stored| stored.name.clone()).collect())
+            .unwrap_or_default()
+    }
+
     pub(crate) fn get_stored_expressions(&self, region_id: usize) -> Vec<StoredExpression<F, C>> {
         self.stored_expressions
             .get(&region_id)
@@ -550,6 +5092,15 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
             .unwrap_or_default()
     }
 
+    /// The stored expressions of the region pushed with the label
+    pub(crate) fn get_stored_expressions_by_label(&self, label: &str) -> Vec<StoredExpression<F, C>> {
+        self.region_labels
+            .iter()
+            .find(|(_, region_label)| **region_label == label)
+            .map(|(region_id, _)| self.get_stored_expressions(*region_id))
+            .unwrap_or_default()
+    }
+
     pub(crate) fn find_stored_expression(
         &self,
         expr: &Expression<F>,
@@ -559,39 +5110,81 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
         if let Some(stored_expressions) = self.stored_expressions.get(&self.region_id) {
             stored_expressions
                 .iter()
-                .find(|&e| e.cell_type == cell_type && e.expr_id == expr_id)
+                .find(|&e| e.cell_type == cell_type && e.expr_id == expr_id && !e.inverse)
         } else {
             None
         }
     }
 
+    /// A stored expression of any cell type in the current region
+    fn find_shared_expression(&self, expr: &Expression<F>) -> Option<Expression<F>> {
+        let expr_id = expr.identifier();
+        self.stored_expressions
+            .get(&self.region_id)?
+            .iter()
+            .find(|e| e.expr_id == expr_id && !e.inverse)
+            .map(|e| e.cell.expr())
+    }
+
+    /// Splits the expression into parts of at most the maximum degree of the
+    /// region, storing the parts in cells. The result is checked against the
+    /// maximum degree like the constraints are.
     pub(crate) fn split_expression(
         &mut self,
         name: &'static str,
         expr: Expression<F>,
     ) -> Expression<F> {
+        let expr = self.split_expression_inner(name, expr);
+        self.validate_split_degree(&expr, name);
+        expr
+    }
+
+    fn split_expression_inner(
+        &mut self,
+        name: &'static str,
+        expr: Expression<F>,
+    ) -> Expression<F> {
+        if self.region_id != 0 && self.is_reduced(&expr) {
+            return expr;
+        }
         if expr.degree() > self.max_degree && self.region_id != 0 {
+            self.num_split_nodes += 1;
             match expr {
                 Expression::Negated(poly) => {
-                    Expression::Negated(Box::new(self.split_expression(name, *poly)))
+                    Expression::Negated(Box::new(self.split_expression_inner(name, *poly)))
                 }
                 Expression::Scaled(poly, v) => {
-                    Expression::Scaled(Box::new(self.split_expression(name, *poly)), v)
+                    Expression::Scaled(Box::new(self.split_expression_inner(name, *poly)), v)
                 }
                 Expression::Sum(a, b) => {
-                    let a = self.split_expression(name, *a);
-                    let b = self.split_expression(name, *b);
+                    let a = self.split_expression_inner(name, *a);
+                    let b = self.split_expression_inner(name, *b);
                     a + b
                 }
+                Expression::Product(a, b) if self.cell_manager.is_none() => {
+                    let expr = Expression::Product(a, b);
+                    self.diagnostics.push(format!(
+                        "'{}': degree {} not split, no cell manager set",
+                        name,
+                        expr.degree()
+                    ));
+                    expr
+                }
                 Expression::Product(a, b) => {
                     let (mut a, mut b) = (*a, *b);
                     while a.degree() + b.degree() > self.max_degree {
                         let mut split = |expr: Expression<F>| {
                             if expr.degree() > self.max_degree {
-                                self.split_expression(name, expr)
+                                self.split_expression_inner(name, expr)
+                            } else if let Some(cell) = self
+                                .global_subexpression_sharing
+                                .then(|| self.find_shared_expression(&expr))
+                                .flatten()
+                            {
+                                cell
                             } else {
                                 let cell_type = C::storage_for_expr(&expr);
-                                self.store_expression(name, expr, cell_type, None)
+                                self.store_expression(name, expr, cell_type, None).into()
                             }
                         };
                         if a.degree() >= b.degree() {
@@ -609,11 +5202,216 @@ impl<F: Field, C: CellType> ConstraintBuilder<F, C> {
         }
     }
 
+    /// One line per table and lookup, tables sorted by tag
+    pub(crate) fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        for tag in self.tables.keys().sorted() {
+            for table in self.tables[tag].iter() {
+                lines.push(format!("table {:?}: {}", tag, table));
+            }
+        }
+        for lookup in self.ordered_lookups() {
+            lines.push(format!("lookup: {}", lookup));
+        }
+        lines.join("\n")
+    }
+
+    /// Lookup counts, widths and degrees by description prefix and by region,
+    /// and the fragments of every dynamic table. Lookups by tag or column
+    /// name are only counted once resolved in `finalize`.
+    pub(crate) fn lookup_stats(&self) -> LookupStats {
+        let mut stats = LookupStats::default();
+        for lookup in self.lookups.iter() {
+            let description = strip_preamble(&lookup.description);
+            let prefix = description
+                .split_once(" (")
+                .map_or(description.as_str(), |(prefix, _)| prefix);
+            stats.by_prefix.entry(prefix.to_string()).or_default().add(lookup);
+            stats.by_region.entry(lookup.region_id).or_default().add(lookup);
+        }
+        stats.table_fragments = self
+            .tables
+            .iter()
+            .map(|(tag, tables)| (format!("{:?}", tag), tables.len()))
+            .collect();
+        stats
+    }
+
+    /// Statistics of the constraints, lookups and cells, per region
+    pub(crate) fn report(&self) -> CircuitReport {
+        let mut constraints = self
+            .constraints
+            .iter()
+            .map(|(name, expr)| (*name, expr.degree()))
+            .collect::<Vec<_>>();
+        constraints.sort_by(|a, b| a.1.cmp(&b.1));
+        let region_ids = self
+            .constraint_regions
+            .iter()
+            .copied()
+            .chain(self.lookups.iter().map(|lookup| lookup.region_id))
+            .chain(self.tables.values().flatten().map(|table| table.region_id))
+            .chain(self.stored_expressions.keys().copied())
+            .chain(self.region_cells.keys().copied())
+            .chain(self.region_labels.keys().copied())
+            .unique()
+            .sorted();
+        let regions = region_ids
+            .map(|region_id| {
+                let mut stats = RegionStats {
+                    region_id,
+                    name: self.region_name(region_id),
+                    table_rows: self.tables_stored_in_region(region_id).len(),
+                    stored_expressions: self
+                        .stored_expressions
+                        .get(&region_id)
+                        .map_or(0, |exprs| exprs.len()),
+                    ..Default::default()
+                };
+                for ((_, expr), _) in self
+                    .constraints
+                    .iter()
+                    .zip(self.constraint_regions.iter())
+                    .filter(|(_, &id)| id == region_id)
+                {
+                    stats.constraints += 1;
+                    *stats.degrees.entry(expr.degree()).or_insert(0) += 1;
+                }
+                for lookup in self.lookups_in_region(region_id) {
+                    let tag = self
+                        .table_tag(&lookup.table)
+                        .map_or("untagged".to_string(), |tag| format!("{:?}", tag));
+                    *stats.lookups.entry(tag).or_insert(0) += 1;
+                }
+                for cell in self.region_cells.get(&region_id).into_iter().flatten() {
+                    *stats.cells.entry(cell.cell_type.clone()).or_insert(0) += 1;
+                }
+                stats
+            })
+            .collect();
+        CircuitReport {
+            constraints,
+            regions,
+            lookup_arguments: self.lookup_report.as_ref().map(|report| LookupBuildReport {
+                emitted: report
+                    .emitted
+                    .iter()
+                    .map(|lookup| LookupSummary {
+                        description: lookup.description.clone(),
+                        table: lookup.table.map(|tag| format!("{:?}", tag)),
+                        columns: lookup.columns,
+                        max_input_degree: lookup.max_input_degree,
+                    })
+                    .collect(),
+                deduped: report.deduped,
+                packed: report.packed,
+                compressed: report.compressed,
+            }),
+            deferred: self.deferred_constraints.clone(),
+            unusable_rows: self.unusable_rows(),
+            diagnostics: self.diagnostics.clone(),
+            elided_booleans: self.num_elided_booleans,
+            split_nodes: self.num_split_nodes,
+            condition_cells: self
+                .condition_cell_threshold
+                .map(|threshold| (threshold, self.condition_cell_stats.clone())),
+            column_queries: self.column_queries,
+            adjacent_columns: self.cell_manager.as_ref().map_or((0, 0), |cm| {
+                cm.adjacency_stats()
+                    .iter()
+                    .fold((0, 0), |(requested, satisfied), (_, r, s)| (requested + r, satisfied + s))
+            }),
+        }
+    }
+
     pub(crate) fn print_stats(&self) {
-        let mut expressions = self.constraints.clone();
-        expressions.sort_by(|a, b| a.1.degree().cmp(&b.1.degree()));
-        for (name, expr) in expressions.iter() {
-            println!("'{}': {}", name, expr.degree());
+        print!("{}", self.report());
+    }
+
+    /// Describes every constraint, lookup, stored table row and cell by
+    /// region, for reviewing the circuit without running it
+    pub(crate) fn audit_report(&self) -> AuditReport {
+        let render = |expr: &Expression<F>| expr_to_string(expr, AUDIT_DEPTH);
+        let schema = |tag: Option<T>, width: usize| {
+            let names = tag.and_then(|tag| self.table_schema(tag));
+            (0..width)
+                .map(|idx| match names.and_then(|names| names.get(idx)) {
+                    Some(name) => name.to_string(),
+                    None => format!("column {}", idx),
+                })
+                .collect::<Vec<_>>()
+        };
+        let tables = self
+            .tables
+            .iter()
+            .sorted_by_key(|(tag, _)| **tag)
+            .map(|(tag, rows)| {
+                let width = rows.first().map_or(0, |row| row.values.len());
+                (format!("{:?}", tag), schema(Some(*tag), width))
+            })
+            .collect();
+        let regions = self
+            .regions()
+            .into_iter()
+            .map(|region_id| AuditRegion {
+                region_id,
+                name: self.region_name(region_id),
+                constraints: self
+                    .constraints
+                    .iter()
+                    .zip(self.constraint_regions.iter())
+                    .enumerate()
+                    .filter(|(_, (_, &id))| id == region_id)
+                    .map(|(idx, ((name, expr), _))| AuditConstraint {
+                        name: name.to_string(),
+                        degree: expr.degree(),
+                        expression: render(expr),
+                        provenance: self.constraint_provenance(idx).cloned(),
+                    })
+                    .collect(),
+                lookups: self
+                    .lookups_in_region(region_id)
+                    .map(|lookup| {
+                        let tag = self.table_tag(&lookup.table);
+                        AuditLookup {
+                            description: lookup.description.clone(),
+                            table: tag.map(|tag| format!("{:?}", tag)),
+                            columns: schema(tag, lookup.table.len()),
+                            values: lookup.values.iter().map(render).collect(),
+                            condition: render(&lookup.condition()),
+                        }
+                    })
+                    .collect(),
+                table_rows: self
+                    .tables_stored_in_region(region_id)
+                    .into_iter()
+                    .map(|(tag, row)| AuditTableRow {
+                        description: row.description.to_string(),
+                        table: format!("{:?}", tag),
+                        values: row.values.iter().map(render).collect(),
+                        condition: render(&row.condition()),
+                    })
+                    .collect(),
+                stored_expressions: self
+                    .stored_expressions
+                    .get(&region_id)
+                    .into_iter()
+                    .flatten()
+                    .map(|stored| (stored.name.clone(), render(&stored.expr)))
+                    .collect(),
+                cells: self.region_cells.get(&region_id).cloned().unwrap_or_default(),
+                gadgets: self
+                    .gadget_records
+                    .iter()
+                    .filter(|record| record.region_id == region_id)
+                    .cloned()
+                    .collect(),
+            })
+            .collect();
+        AuditReport {
+            max_degree: self.max_global_degree,
+            tables,
+            regions,
         }
     }
 }
@@ -935,47 +5733,53 @@ impl<F: Field> RLCChainableRev<F> for Expression<F> {
     }
 }
 
-/// Trait around RLC
+/// Trait around RLC. The challenge is unknown during keygen, the RLC is then
+/// unknown as well.
 pub trait RLCableValue<F> {
     /// Returns the RLC of itself
-    fn rlc_value(&self, r: F) -> F;
+    fn rlc_value(&self, r: Value<F>) -> Value<F>;
     /// Returns the RLC of the reverse of itself
-    fn rlc_value_rev(&self, r: F) -> F;
+    fn rlc_value_rev(&self, r: Value<F>) -> Value<F>;
 }
 
 impl<F: Field> RLCableValue<F> for Vec<u8> {
-    fn rlc_value(&self, r: F) -> F {
-        rlc::value(self, r)
+    fn rlc_value(&self, r: Value<F>) -> Value<F> {
+        self.as_slice().rlc_value(r)
     }
-    fn rlc_value_rev(&self, r: F) -> F {
-        rlc::value(&self.iter().rev().cloned().collect_vec(), r)
+    fn rlc_value_rev(&self, r: Value<F>) -> Value<F> {
+        self.as_slice().rlc_value_rev(r)
     }
 }
 
 impl<F: Field> RLCableValue<F> for [u8] {
-    fn rlc_value(&self, r: F) -> F {
-        rlc::value(self, r)
+    fn rlc_value(&self, r: Value<F>) -> Value<F> {
+        r.map(|r| rlc::value(self, r))
     }
-    fn rlc_value_rev(&self, r: F) -> F {
-        rlc::value(&self.iter().rev().cloned().collect_vec(), r)
+    fn rlc_value_rev(&self, r: Value<F>) -> Value<F> {
+        r.map(|r| rlc::value(&self.iter().rev().cloned().collect_vec(), r))
     }
 }
 
 /// Trait around RLC
 pub trait RLCChainableValue<F, S, I> {
-    /// Returns the RLC of itself with a starting rlc/multiplier
-    fn rlc_chain_value(&self, values: I, r: F) -> (F, F);
+    /// Returns the RLC of itself with a starting rlc/multiplier, unknown if
+    /// the challenge or the start is unknown
+    fn rlc_chain_value(&self, values: I, r: Value<F>) -> (Value<F>, Value<F>);
 }
 
-impl<F: Field, S: Scalar<F>, I: IntoIterator<Item = S>> RLCChainableValue<F, S, I> for (F, F) {
-    fn rlc_chain_value(&self, values: I, r: F) -> (F, F) {
-        let mut rlc = self.0;
-        let mut mult = self.1;
-        for value in values.into_iter().map(|byte| byte.scalar()) {
-            rlc += value * mult;
-            mult *= r;
-        }
-        (rlc, mult)
+impl<F: Field, S: Scalar<F>, I: IntoIterator<Item = S>> RLCChainableValue<F, S, I>
+    for (Value<F>, Value<F>)
+{
+    fn rlc_chain_value(&self, values: I, r: Value<F>) -> (Value<F>, Value<F>) {
+        let values = values.into_iter().map(|byte| byte.scalar()).collect::<Vec<F>>();
+        let state = self.0.zip(self.1).zip(r).map(|((mut rlc, mut mult), r)| {
+            for value in values.iter() {
+                rlc += *value * mult;
+                mult *= r;
+            }
+            (rlc, mult)
+        });
+        (state.map(|(rlc, _)| rlc), state.map(|(_, mult)| mult))
     }
 }
 /// require_parser
@@ -1019,12 +5823,13 @@ macro_rules! _require2 {
     }};
 }
 
-/// Creates a dummy constraint builder that cannot be used to add constraints.
+/// Creates a dummy constraint builder (see `ConstraintBuilder::dummy`), to run
+/// `circuit!` code only for its witness side effects.
 #[macro_export]
 macro_rules! _cb {
     () => {{
-        use $crate::circuit_tools::cell_manager::DefaultCellType;
-        ConstraintBuilder::<F, DefaultCellType>::new(0, None, None)
+        use $crate::cell_manager::DefaultCellType;
+        $crate::constraint_builder::ConstraintBuilder::<F, DefaultCellType>::dummy()
     }};
 }
 
@@ -1071,6 +5876,20 @@ macro_rules! _require {
         $cb.require_boolean(description, $lhs.expr());
     }};
 
+    // Words as `Word<Expression<F>>`
+    ($cb:expr, $lhs:expr => word $rhs:expr) => {{
+        let description = concat_with_preamble!(
+            stringify!($lhs),
+            " => word ",
+            stringify!($rhs)
+        );
+        _require!($cb, description, $lhs => word $rhs)
+    }};
+    ($cb:expr, $descr:expr, $lhs:expr => word $rhs:expr) => {{
+        let description = $cb.description($descr);
+        $cb.require_equal_word(description, $lhs, $rhs);
+    }};
+
     ($cb:expr, $lhs:expr => $rhs:expr) => {{
         let description = concat_with_preamble!(
             stringify!($lhs),
@@ -1085,15 +5904,17 @@ macro_rules! _require {
         let rhs = $rhs.to_expr_vec();
         if lhs.len() == rhs.len() {
             for (lhs, rhs) in lhs.iter().zip(rhs.iter()) {
+                let description = $cb.description($descr);
                 $cb.require_equal(
-                    Box::leak($descr.to_string().into_boxed_str()),
+                    description,
                     lhs.expr(),
                     rhs.expr(),
                 );
             }
         } else if lhs.len() == 1 && rhs.len() > 1 {
+            let description = $cb.description($descr);
             $cb.require_in_set(
-                Box::leak($descr.to_string().into_boxed_str()),
+                description,
                 lhs[0].expr(),
                 rhs.clone(),
             );
@@ -1102,6 +5923,28 @@ macro_rules! _require {
         }
     }};
 
+    // Store tuple without the condition
+    ($cb:expr, $values:expr =>>! @$tag:expr) => {{
+        let description = concat_with_preamble!(
+            stringify!($values),
+            " =>>! @",
+            stringify!($tag),
+        );
+        $cb.store_tuple_unconditional(
+            description,
+            $tag,
+            $values,
+        );
+    }};
+    ($cb:expr, $descr:expr, $values:expr =>>! @$tag:expr) => {{
+        let description = $cb.description($descr);
+        $cb.store_tuple_unconditional(
+            description,
+            $tag,
+            $values,
+        );
+    }};
+
     // Store tuple
     ($cb:expr, $values:expr =>> @$tag:expr) => {{
         let description = concat_with_preamble!(
@@ -1116,10 +5959,33 @@ macro_rules! _require {
         );
     }};
     ($cb:expr, $descr:expr, $values:expr =>> @$tag:expr) => {{
+        let description = $cb.description($descr);
         $cb.store_tuple(
-            Box::leak($descr.to_string().into_boxed_str()),
+            description,
+            $tag,
+            $values,
+        );
+    }};
+
+    // Do lookup into the table stored with the tag, resolved at build time
+    ($cb:expr, $values:expr => @@$tag:expr) => {{
+        let description = concat_with_preamble!(
+            stringify!($values),
+            " => @@",
+            stringify!($tag),
+        );
+        $cb.add_lookup_to_tag(
+            description.to_string(),
+            $values,
             $tag,
+        );
+    }};
+    ($cb:expr, $descr:expr, $values:expr => @@$tag:expr) => {{
+        let description = $cb.description($descr);
+        $cb.add_lookup_to_tag(
+            description.to_string(),
             $values,
+            $tag,
         );
     }};
 
@@ -1137,8 +6003,9 @@ macro_rules! _require {
         );
     }};
     ($cb:expr, $descr:expr, $values:expr => @$table:expr) => {{
+        let description = $cb.description($descr);
         $cb.add_lookup(
-            Box::leak($descr.into_boxed_str()),
+            description.to_string(),
             $values,
             $table,
         );
@@ -1172,18 +6039,20 @@ macro_rules! _matchx {
         let mut conditions = Vec::new();
         let mut cases = Vec::new();
         $(
-            $cb.push_condition($condition.expr());
+            let guard = $cb.condition_guard();
+            $cb.push_boolean_condition($condition.expr());
             let ret = $when.clone();
-            $cb.pop_condition();
+            drop(guard);
             cases.push(($condition.expr(), ret));
             conditions.push($condition.expr());
         )*
 
         $(
             let catch_all_condition = not::expr(sum::expr(&conditions));
-            $cb.push_condition(catch_all_condition.expr());
+            let guard = $cb.condition_guard();
+            $cb.push_boolean_condition(catch_all_condition.expr());
             let ret = $catch_all;
-            $cb.pop_condition();
+            drop(guard);
             cases.push((catch_all_condition.expr(), ret));
             conditions.push(catch_all_condition.expr());
         )*
@@ -1204,6 +6073,64 @@ macro_rules! _matchx {
     }};
 }
 
+/// matchx over the arms of a `BinarySelector`, with the arm indices as the
+/// conditions. The arm conditions are boolean and exclusive by construction,
+/// so unlike `matchx` no constraints are needed, except that one of the arms
+/// is selected when the match doesn't cover all arms and has no `_` arm.
+#[macro_export]
+macro_rules! _matchx_binary {
+    ($cb:expr, $selector:expr, ($($arm:expr => $when:expr),* $(, _ => $catch_all:expr)? $(,)?))  => {{
+        let selector = &$selector;
+        let mut arms: Vec<usize> = Vec::new();
+        let mut conditions = Vec::new();
+        let mut cases = Vec::new();
+        $(
+            let condition = selector.is_arm($arm);
+            let guard = $cb.condition_guard();
+            $cb.push_boolean_condition(condition.expr());
+            let ret = $when.clone();
+            drop(guard);
+            cases.push((condition.expr(), ret));
+            conditions.push(condition);
+            arms.push($arm);
+        )*
+
+        #[allow(unused_mut)]
+        let mut exhaustive = selector.covers(&arms);
+        $(
+            let catch_all_condition = not::expr(sum::expr(&conditions));
+            let guard = $cb.condition_guard();
+            $cb.push_boolean_condition(catch_all_condition.expr());
+            let ret = $catch_all;
+            drop(guard);
+            cases.push((catch_all_condition.expr(), ret));
+            conditions.push(catch_all_condition.expr());
+            exhaustive = true;
+        )*
+
+        if !exhaustive {
+            _require!($cb, sum::expr(&conditions) => 1);
+        }
+
+        // Apply the conditions to all corresponding values
+        let mut res = cases[0].1.mul(&cases[0].0.expr());
+        for pair in cases.iter().skip(1) {
+            res = <_ as ExprResult<F>>::add(&res, &pair.1.mul(&pair.0.expr()));
+        }
+        res
+    }};
+}
+
+/// Creates a `QueryCache` for the meta of a gate closure. Pass it to
+/// `circuit!` instead of meta so repeated `a!`/`f!`/`x!` queries of the same
+/// column and rotation share a single expression.
+#[macro_export]
+macro_rules! query_cache {
+    ($meta:expr) => {{
+        $crate::util::QueryCache::new($meta)
+    }};
+}
+
 #[macro_export]
 macro_rules! _to_and {
     (($($condition:expr),*)) => {
@@ -1216,26 +6143,34 @@ macro_rules! _to_and {
 /// ifx
 #[macro_export]
 macro_rules! _ifx {
-    ($cb:expr,$condition:tt => $when_true:block $(elsex $when_false:block)?)  => {{
+    ($cb:expr,$condition:tt => $when_true:block elsex $when_false:block)  => {{
         let condition = _to_and!($condition);
 
-        $cb.push_condition(condition.expr());
+        let guard = $cb.condition_guard();
+        $cb.push_boolean_condition(condition.expr());
         let ret_true = $when_true;
-        $cb.pop_condition();
+        drop(guard);
 
-        #[allow(unused_assignments, unused_mut)]
-        let mut ret = ret_true.mul(&condition.expr());
-        $(
-            // In if/else cases, the condition needs to be boolean
-            _require!($cb, condition => bool);
+        // In if/else cases, the condition needs to be boolean
+        _require!($cb, condition => bool);
 
-            $cb.push_condition(not::expr(condition.expr()));
-            let ret_false = $when_false;
-            $cb.pop_condition();
+        let guard = $cb.condition_guard();
+        $cb.push_boolean_condition(not::expr(condition.expr()));
+        let ret_false = $when_false;
+        drop(guard);
 
-            ret = <_ as ExprResult<F>>::add(&ret_true.mul(&condition), &ret_false.mul(&not::expr(condition.expr())));
-        )*
-        ret
+        <_ as ExprResult<F>>::add(&ret_true.mul(&condition), &ret_false.mul(&not::expr(condition.expr())))
+    }};
+    ($cb:expr,$condition:tt => $when_true:block)  => {{
+        let condition = _to_and!($condition);
+
+        // Only checked to be boolean with `set_check_condition_booleanness`
+        let guard = $cb.condition_guard();
+        $cb.push_condition(condition.expr());
+        let ret_true = $when_true;
+        drop(guard);
+
+        ret_true.mul(&condition.expr())
     }};
 }
 
@@ -1260,6 +6195,36 @@ macro_rules! matchw {
     }};
 }
 
+/// dispatch - Like matchw, but also assigns the selector cell of every branch:
+/// 1 to the cell of the branch taken, 0 to all others. Exactly one condition
+/// needs to be true. Returns the value of the branch taken, assignment errors
+/// are returned early with `?`.
+///
+/// `dispatch!(region, offset, { condition, selector_cell => when, ... })`
+#[macro_export]
+macro_rules! dispatch {
+    ($region:expr, $offset:expr, { $($condition:expr, $selector:expr => $when:expr),* $(,)? }) => {{
+        use $crate::util::Scalar;
+        let conditions = [$($condition),*];
+        let num_taken = conditions.iter().filter(|condition| **condition).count();
+        assert_eq!(
+            num_taken, 1,
+            "dispatch: {} of {} conditions are true, expected exactly one",
+            num_taken,
+            conditions.len()
+        );
+        for (condition, selector) in conditions.iter().zip([$(&$selector),*]) {
+            selector.assign($region, $offset, condition.scalar())?;
+        }
+        // The guards are tried in order, each takes the next condition
+        let mut conditions = conditions.into_iter();
+        match () {
+            $(_ if conditions.next().unwrap() => $when,)*
+            _ => unreachable!(),
+        }
+    }};
+}
+
 /// assign advice
 #[macro_export]
 macro_rules! assign {
@@ -1306,6 +6271,7 @@ macro_rules! assign {
 #[macro_export]
 macro_rules! assignf {
     ($region:expr, ($column:expr, $offset:expr) => $value:expr) => {{
+        use halo2_proofs::circuit::Value;
         let description =
             $crate::concat_with_preamble!(stringify!($column), " => ", stringify!($value));
         let value: F = $value;
@@ -1338,27 +6304,31 @@ macro_rules! circuit {
         use $crate::constraint_builder::{ExprResult, ExprVec};
         #[allow(unused_imports)]
         use $crate::{
-            _ifx, _matchx, _require, _to_and, _to_options_vec, _to_values_vec, _unreachablex,
+            _ifx, _matchx, _matchx_binary, _require, _to_and, _to_options_vec, _to_values_vec, _unreachablex,
             concat_with_preamble,
         };
 
         #[allow(unused_macros)]
         macro_rules! f {
             ($column:expr, $rot:expr) => {{
-                $meta.query_fixed($column.clone(), Rotation($rot as i32))
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_fixed(column, Rotation($rot as i32)))
             }};
             ($column:expr) => {{
-                $meta.query_fixed($column.clone(), Rotation::cur())
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_fixed(column, Rotation::cur()))
             }};
         }
 
         #[allow(unused_macros)]
         macro_rules! a {
             ($column:expr, $rot:expr) => {{
-                $meta.query_advice($column.clone(), Rotation($rot as i32))
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_advice(column, Rotation($rot as i32)))
             }};
             ($column:expr) => {{
-                $meta.query_advice($column.clone(), Rotation::cur())
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_advice(column, Rotation::cur()))
             }};
         }
 
@@ -1379,10 +6349,12 @@ macro_rules! circuit {
         #[allow(unused_macros)]
         macro_rules! x {
             ($column:expr, $rot:expr) => {{
-                $meta.query_any($column.clone(), Rotation($rot as i32))
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_any(column, Rotation($rot as i32)))
             }};
             ($column:expr) => {{
-                $meta.query_any($column.clone(), Rotation::cur())
+                let column = $column.clone();
+                $cb.track_query(column, $meta.query_any(column, Rotation::cur()))
             }};
         }
 
@@ -1405,12 +6377,27 @@ macro_rules! circuit {
             ($lhs:expr => bool) => {{
                 _require!($cb, $lhs => bool);
             }};
+            ($lhs:expr => word $rhs:expr) => {{
+                _require!($cb, $lhs => word $rhs);
+            }};
+            ($name:expr, $lhs:expr => word $rhs:expr) => {{
+                _require!($cb, $name, $lhs => word $rhs);
+            }};
             ($lhs:expr => $rhs:expr) => {{
                 _require!($cb, $lhs => $rhs);
             }};
             ($name:expr, $lhs:expr => $rhs:expr) => {{
                 _require!($cb, $name, $lhs => $rhs);
             }};
+            // Store tuple without the condition
+            ($values:tt =>>! @$tag:expr) => {{
+                let values = _to_values_vec!($values);
+                _require!($cb, values =>>! @$tag);
+            }};
+            ($descr:expr, $values:tt =>>! @$tag:expr) => {{
+                let values = _to_values_vec!($values);
+                _require!($cb, $descr, values =>>! @$tag);
+            }};
             // Store tuple
             ($values:tt =>> @$tag:expr) => {{
                 let values = _to_values_vec!($values);
@@ -1420,6 +6407,15 @@ macro_rules! circuit {
                 let values = _to_values_vec!($values);
                 _require!($cb, $descr, values =>> @$tag);
             }};
+            // Do lookups into the table stored with the tag
+            ($values:tt => @@$tag:expr) => {{
+                let values = _to_values_vec!($values);
+                _require!($cb, values => @@$tag);
+            }};
+            ($descr:expr, $values:tt => @@$tag:expr) => {{
+                let values = _to_values_vec!($values);
+                _require!($cb, $descr, values => @@$tag);
+            }};
             // Do lookups
             ($values:tt => @$table:expr) => {{
                 let values = _to_values_vec!($values);
@@ -1459,6 +6455,13 @@ macro_rules! circuit {
             }};
         }
 
+        #[allow(unused_macros)]
+        macro_rules! matchx_binary {
+            ($selector:expr, $arm_to_when:tt) => {{
+                _matchx_binary!($cb, $selector, $arm_to_when)
+            }};
+        }
+
         #[allow(unused_macros)]
         macro_rules! unreachablex {
             () => {{
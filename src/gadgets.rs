@@ -1,19 +1,22 @@
 //! Circuit gadgets
+use std::marker::PhantomData;
 use eth_types::Field;
 use zkevm_circuits::util::word::{Word, WordExpr};
-use zkevm_gadgets::util::{and, Expr};
+use zkevm_gadgets::util::{and, not, sum, Expr};
 use halo2_proofs::{
     circuit::Value,
     plonk::{Error, Expression}, 
 };
 
 
-use crate::util::{transpose_val_ret, pow_of_two, from_bytes};
+use itertools::Itertools;
+
+use crate::util::{transpose_val_ret, pow_of_two, from_bytes, rlc};
 
 use super::{
     cached_region::CachedRegion,
-    cell_manager::{Cell, CellType},
-    constraint_builder::ConstraintBuilder,
+    cell_manager::{Cell, CellType, TableTag},
+    constraint_builder::{ConstraintBuilder, RLCChainable, RLCChainableValue},
 };
 
 /// Returns `1` when `value == 0`, and returns `0` otherwise.
@@ -24,8 +27,8 @@ pub struct IsZeroGadget<F> {
 }
 
 impl<F: Field> IsZeroGadget<F> {
-    pub(crate) fn construct<C: CellType>(
-        cb: &mut ConstraintBuilder<F, C>,
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
         value: Expression<F>,
     ) -> Self {
         circuit!([meta, cb], {
@@ -74,8 +77,8 @@ pub struct IsEqualGadget<F> {
 }
 
 impl<F: Field> IsEqualGadget<F> {
-    pub(crate) fn construct<C: CellType>(
-        cb: &mut ConstraintBuilder<F, C>,
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
         lhs: Expression<F>,
         rhs: Expression<F>,
     ) -> Self {
@@ -107,8 +110,8 @@ pub struct IsEqualWordGadget<F> {
 }
 
 impl<F: Field> IsEqualWordGadget<F> {
-    pub(crate) fn construct<C: CellType>(
-        cb: &mut ConstraintBuilder<F, C>,
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
         lhs: &Word<Expression<F>>,
         rhs: &Word<Expression<F>>,
     ) -> Self {
@@ -165,6 +168,129 @@ impl<F: Field> IsEqualWordGadget<F> {
     }
 }
 
+/// Byte order of a sequence of bytes, used to mark byte expressions so
+/// the two orders can't be mixed up
+pub trait Endian: Clone + Copy + std::fmt::Debug {
+    /// The other byte order
+    type Reversed: Endian<Reversed = Self>;
+    /// Whether the least significant byte comes first
+    const LITTLE: bool;
+
+    /// The bytes of the word in this byte order
+    fn word_to_bytes(word: eth_types::Word) -> [u8; 32];
+
+    /// The word encoded by the bytes in this byte order
+    fn word_from_bytes(bytes: &[u8; 32]) -> eth_types::Word;
+}
+
+/// Least significant byte first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Le;
+
+/// Most significant byte first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Be;
+
+impl Endian for Le {
+    type Reversed = Be;
+    const LITTLE: bool = true;
+
+    fn word_to_bytes(word: eth_types::Word) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        word.to_little_endian(&mut bytes);
+        bytes
+    }
+
+    fn word_from_bytes(bytes: &[u8; 32]) -> eth_types::Word {
+        eth_types::Word::from_little_endian(bytes)
+    }
+}
+
+impl Endian for Be {
+    type Reversed = Le;
+    const LITTLE: bool = false;
+
+    fn word_to_bytes(word: eth_types::Word) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        word.to_big_endian(&mut bytes);
+        bytes
+    }
+
+    fn word_from_bytes(bytes: &[u8; 32]) -> eth_types::Word {
+        eth_types::Word::from_big_endian(bytes)
+    }
+}
+
+/// The 32 bytes of a word in the byte order `E`
+#[derive(Clone, Debug)]
+pub struct Bytes<F, E> {
+    bytes: [Expression<F>; 32],
+    _endian: PhantomData<E>,
+}
+
+impl<F: Field, E: Endian> Bytes<F, E> {
+    /// The bytes of a word, in the byte order `E`
+    pub(crate) fn new(bytes: [Expression<F>; 32]) -> Self {
+        Self {
+            bytes,
+            _endian: PhantomData,
+        }
+    }
+
+    /// The bytes of the cells, in the byte order `E`
+    pub(crate) fn from_cells(cells: &[Cell<F>; 32]) -> Self {
+        Self::new(cells.clone().map(|cell| cell.expr()))
+    }
+
+    /// The byte expressions
+    pub(crate) fn exprs(&self) -> &[Expression<F>; 32] {
+        &self.bytes
+    }
+
+    /// The same bytes in the other byte order
+    pub(crate) fn reverse(&self) -> Bytes<F, E::Reversed> {
+        let mut bytes = self.bytes.clone();
+        bytes.reverse();
+        Bytes::new(bytes)
+    }
+
+    /// The word encoded by the bytes
+    pub(crate) fn word(&self) -> Word<Expression<F>> {
+        // `from_bytes` takes the least significant byte first
+        let bytes = self.to_le();
+        Word::new([from_bytes::expr(&bytes[..16]), from_bytes::expr(&bytes[16..])])
+    }
+
+    /// RLC of the bytes in their byte order
+    pub(crate) fn rlc(&self, r: Expression<F>) -> Expression<F> {
+        rlc::expr(&self.bytes, r)
+    }
+
+    /// The bytes in little endian order
+    fn to_le(&self) -> [Expression<F>; 32] {
+        let mut bytes = self.bytes.clone();
+        if !E::LITTLE {
+            bytes.reverse();
+        }
+        bytes
+    }
+}
+
+/// Reverses the 32 little endian bytes of a word into its big endian bytes.
+/// The result is the same cells in reverse order, so no cells or
+/// constraints are added.
+#[derive(Clone, Debug)]
+pub struct ReverseBytesGadget;
+
+impl ReverseBytesGadget {
+    pub(crate) fn construct<F: Field, C: CellType, T: TableTag>(
+        _cb: &mut ConstraintBuilder<F, C, T>,
+        input: &[Cell<F>; 32],
+    ) -> Bytes<F, Be> {
+        Bytes::<F, Le>::from_cells(input).reverse()
+    }
+}
+
 /// Returns `1` when `lhs < rhs`, and returns `0` otherwise.
 /// lhs and rhs `< 256**N_BYTES`
 /// `N_BYTES` is required to be `<= MAX_N_BYTES_INTEGER` to prevent overflow:
@@ -183,11 +309,12 @@ pub struct LtGadget<F, const N_BYTES: usize> {
 }
 
 impl<F: Field, const N_BYTES: usize> LtGadget<F, N_BYTES> {
-    pub(crate) fn construct<C: CellType>(
-        cb: &mut ConstraintBuilder<F, C>,
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
         lhs: Expression<F>,
         rhs: Expression<F>,
     ) -> Self {
+        cb.record_gadget("LtGadget", format!("{{\"n_bytes\":{}}}", N_BYTES));
         let lt = cb.query_bool();
         let diff = cb.query_bytes();
         let range = pow_of_two(N_BYTES * 8);
@@ -237,3 +364,621 @@ impl<F: Field, const N_BYTES: usize> LtGadget<F, N_BYTES> {
         self.diff.as_ref().unwrap().to_vec()
     }
 }
+
+/// Subtraction saturating at zero: `result()` is `lhs - rhs` when `lhs >= rhs`,
+/// otherwise `result()` is `0` and `underflow()` is `1`. `lhs` and `rhs` need
+/// to fit in `N_BYTES` bytes.
+#[derive(Clone, Debug, Default)]
+pub struct SaturatingSubGadget<F, const N_BYTES: usize> {
+    lt: LtGadget<F, N_BYTES>,
+}
+
+impl<F: Field, const N_BYTES: usize> SaturatingSubGadget<F, N_BYTES> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        lhs: Expression<F>,
+        rhs: Expression<F>,
+    ) -> Self {
+        cb.record_gadget("SaturatingSubGadget", format!("{{\"n_bytes\":{}}}", N_BYTES));
+        let lt = LtGadget::construct(cb, lhs, rhs);
+        Self { lt }
+    }
+
+    /// `lhs - rhs`, or `0` on underflow
+    pub(crate) fn result(&self) -> Expression<F> {
+        // `diff` equals `lhs - rhs` when there's no underflow
+        not::expr(self.lt.expr()) * from_bytes::expr(&self.lt.diff_bytes())
+    }
+
+    /// `1` when `lhs < rhs`, `0` otherwise
+    pub(crate) fn underflow(&self) -> Expression<F> {
+        self.lt.expr()
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        lhs: u128,
+        rhs: u128,
+    ) -> Result<(u128, bool), Error> {
+        self.lt
+            .assign(region, offset, F::from_u128(lhs), F::from_u128(rhs))?;
+        Ok((lhs.saturating_sub(rhs), lhs < rhs))
+    }
+}
+
+/// One-hot selector over `0..=max`: `selected(idx)` is `1` when `value == idx`.
+/// `value` is required to be in `0..=max`.
+#[derive(Clone, Debug, Default)]
+pub struct DynamicSelectorGadget<F> {
+    is_equal: Vec<IsEqualGadget<F>>,
+}
+
+impl<F: Field> DynamicSelectorGadget<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        value: Expression<F>,
+        max: usize,
+    ) -> Self {
+        cb.record_gadget("DynamicSelectorGadget", format!("{{\"max\":{}}}", max));
+        let is_equal = (0..=max)
+            .map(|idx| IsEqualGadget::construct(cb, value.expr(), idx.expr()))
+            .collect::<Vec<_>>();
+        // Exactly one of the indicators is enabled, which also range checks `value`
+        cb.require_equal(
+            "dynamic selector is one-hot",
+            sum::expr(is_equal.iter().map(|is_equal| is_equal.expr())),
+            1.expr(),
+        );
+
+        Self { is_equal }
+    }
+
+    /// Returns `1` when `value == idx`
+    pub(crate) fn selected(&self, idx: usize) -> Expression<F> {
+        self.is_equal[idx].expr()
+    }
+
+    /// Returns `1` when `idx < value`
+    pub(crate) fn lt(&self, idx: usize) -> Expression<F> {
+        sum::expr(self.is_equal.iter().skip(idx + 1).map(|is_equal| is_equal.expr()))
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        value: usize,
+    ) -> Result<(), Error> {
+        for (idx, is_equal) in self.is_equal.iter().enumerate() {
+            is_equal.assign(region, offset, F::from(value as u64), F::from(idx as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects one of `n_arms` arms with the arm index stored in binary in
+/// `ceil(log2(n_arms))` boolean cells. Needs fewer cells than a condition per
+/// arm, but `is_arm` has degree `ceil(log2(n_arms))`.
+#[derive(Clone, Debug, Default)]
+pub struct BinarySelector<F> {
+    bits: Vec<Cell<F>>,
+    n_arms: usize,
+}
+
+impl<F: Field> BinarySelector<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        n_arms: usize,
+    ) -> Self {
+        assert!(n_arms > 0, "a binary selector needs at least one arm");
+        cb.record_gadget("BinarySelector", format!("{{\"n_arms\":{}}}", n_arms));
+        let n_bits = (usize::BITS - (n_arms - 1).leading_zeros()) as usize;
+        let bits = (0..n_bits).map(|_| cb.query_bool()).collect();
+        let selector = Self { bits, n_arms };
+        // Indices past the last arm are possible when the number of arms
+        // isn't a power of two
+        if !n_arms.is_power_of_two() {
+            cb.require_equal(
+                "binary selector index in range",
+                sum::expr((0..n_arms).map(|arm| selector.is_arm(arm))),
+                1.expr(),
+            );
+        }
+        selector
+    }
+
+    /// Returns `1` when the arm is selected, and returns `0` otherwise
+    pub fn is_arm(&self, arm: usize) -> Expression<F> {
+        assert!(arm < self.n_arms, "arm {} of a selector with {} arms", arm, self.n_arms);
+        and::expr(self.bits.iter().enumerate().map(|(idx, bit)| {
+            if (arm >> idx) & 1 == 1 {
+                bit.expr()
+            } else {
+                not::expr(bit.expr())
+            }
+        }))
+    }
+
+    /// The index of the selected arm
+    pub(crate) fn expr(&self) -> Expression<F> {
+        sum::expr(
+            self.bits
+                .iter()
+                .enumerate()
+                .map(|(idx, bit)| bit.expr() * (1u64 << idx).expr()),
+        )
+    }
+
+    /// Number of arms
+    pub(crate) fn n_arms(&self) -> usize {
+        self.n_arms
+    }
+
+    /// The boolean cells holding the index
+    pub(crate) fn bits(&self) -> &[Cell<F>] {
+        &self.bits
+    }
+
+    /// Returns `true` when the arms are all the arms of the selector
+    pub fn covers(&self, arms: &[usize]) -> bool {
+        (0..self.n_arms).all(|arm| arms.contains(&arm))
+    }
+
+    /// Assigns the bits of the index of the selected arm
+    pub fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        arm: usize,
+    ) -> Result<(), Error> {
+        assert!(arm < self.n_arms, "arm {} of a selector with {} arms", arm, self.n_arms);
+        for (idx, bit) in self.bits.iter().enumerate() {
+            bit.assign(region, offset, F::from(((arm >> idx) & 1) as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Repeats constraints a witness determined number of times, up to
+/// `max_iters`. Iteration `i` is enabled with a boolean cell, the enabled
+/// iterations are a prefix of all iterations and their number is `count`.
+#[derive(Clone, Debug, Default)]
+pub struct BoundedLoopGadget<F> {
+    enables: Vec<Cell<F>>,
+}
+
+impl<F: Field> BoundedLoopGadget<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        max_iters: usize,
+        count: Expression<F>,
+    ) -> Self {
+        cb.record_gadget("BoundedLoopGadget", format!("{{\"max_iters\":{}}}", max_iters));
+        let enables = (0..max_iters).map(|_| cb.query_bool()).collect::<Vec<_>>();
+        // An iteration can only be enabled when the previous one is
+        for (prev, enable) in enables.iter().tuple_windows() {
+            cb.require_zero(
+                "enable[i] ⋅ (1 - enable[i-1]) == 0",
+                enable.expr() * not::expr(prev.expr()),
+            );
+        }
+        cb.require_equal(
+            "sum(enable) == count",
+            sum::expr(enables.iter().map(|enable| enable.expr())),
+            count,
+        );
+        Self { enables }
+    }
+
+    /// Returns `1` when iteration `i` is enabled, and returns `0` otherwise
+    pub(crate) fn enabled(&self, i: usize) -> Expression<F> {
+        self.enables[i].expr()
+    }
+
+    /// The boolean cells enabling the iterations
+    pub(crate) fn enables(&self) -> &[Cell<F>] {
+        &self.enables
+    }
+
+    /// Maximum number of iterations
+    pub(crate) fn max_iters(&self) -> usize {
+        self.enables.len()
+    }
+
+    /// Calls `f` for every iteration with the index and the enable
+    /// expression, with the enable pushed as a condition
+    pub(crate) fn for_each<C: CellType, T: TableTag>(
+        &self,
+        cb: &mut ConstraintBuilder<F, C, T>,
+        mut f: impl FnMut(&mut ConstraintBuilder<F, C, T>, usize, Expression<F>),
+    ) {
+        for (i, enable) in self.enables.iter().enumerate() {
+            cb.condition(enable.expr(), |cb| f(cb, i, enable.expr()));
+        }
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        count: usize,
+    ) -> Result<(), Error> {
+        assert!(
+            count <= self.max_iters(),
+            "{} iterations of a loop with at most {}",
+            count,
+            self.max_iters()
+        );
+        for (i, enable) in self.enables.iter().enumerate() {
+            enable.assign(region, offset, F::from((i < count) as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns `1` when all `values` are `0`, and returns `0` otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct BatchedIsZeroGadget<F> {
+    is_zero: Option<Cell<F>>,
+    nonempty_witness: Vec<Cell<F>>,
+}
+
+impl<F: Field> BatchedIsZeroGadget<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        values: &[Expression<F>],
+    ) -> Self {
+        cb.record_gadget("BatchedIsZeroGadget", format!("{{\"values\":{}}}", values.len()));
+        let is_zero = cb.query_bool();
+        let nonempty_witness = values
+            .iter()
+            .map(|value| cb.query_cell_with_type(C::storage_for_expr(value)))
+            .collect::<Vec<_>>();
+
+        // `is_zero == 1` => all values need to be `0`
+        for value in values.iter() {
+            cb.require_zero("is_zero ⋅ value == 0", is_zero.expr() * value.expr());
+        }
+        // `is_zero == 0` => at least one value needs to be non-zero
+        cb.require_equal(
+            "1 - is_zero == sum(value ⋅ witness)",
+            1.expr() - is_zero.expr(),
+            sum::expr(
+                values
+                    .iter()
+                    .zip(nonempty_witness.iter())
+                    .map(|(value, witness)| value.expr() * witness.expr()),
+            ),
+        );
+
+        Self {
+            is_zero: Some(is_zero),
+            nonempty_witness,
+        }
+    }
+
+    pub(crate) fn expr(&self) -> Expression<F> {
+        self.is_zero.as_ref().unwrap().expr()
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        values: &[F],
+    ) -> Result<F, Error> {
+        // Only the first non-zero value needs a witness
+        let nonempty = values.iter().position(|value| !bool::from(value.is_zero()));
+        for (idx, witness) in self.nonempty_witness.iter().enumerate() {
+            let inverse = match nonempty {
+                Some(nonempty) if nonempty == idx => values[idx].invert().unwrap(),
+                _ => F::ZERO,
+            };
+            witness.assign(region, offset, inverse)?;
+        }
+        let is_zero = if nonempty.is_none() { F::ONE } else { F::ZERO };
+        self.is_zero
+            .as_ref()
+            .unwrap()
+            .assign(region, offset, is_zero)?;
+        Ok(is_zero)
+    }
+}
+
+/// Returns `1` when the first `len` bytes of `a` and `b` are equal, and
+/// returns `0` otherwise. Bytes at positions `>= len` are ignored.
+/// `len` is required to be in `0..=max_len`.
+#[derive(Clone, Debug, Default)]
+pub struct BytesEqualGadget<F> {
+    len: DynamicSelectorGadget<F>,
+    is_zero: BatchedIsZeroGadget<F>,
+    max_len: usize,
+}
+
+impl<F: Field> BytesEqualGadget<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        a_bytes: &[Expression<F>],
+        b_bytes: &[Expression<F>],
+        len: Expression<F>,
+        max_len: usize,
+    ) -> Self {
+        assert_eq!(a_bytes.len(), max_len, "a_bytes needs to be max_len long");
+        assert_eq!(b_bytes.len(), max_len, "b_bytes needs to be max_len long");
+        cb.record_gadget("BytesEqualGadget", format!("{{\"max_len\":{}}}", max_len));
+
+        let len = DynamicSelectorGadget::construct(cb, len, max_len);
+        // Only the differences of the bytes at `i < len` are taken into account
+        let diffs = a_bytes
+            .iter()
+            .zip(b_bytes.iter())
+            .enumerate()
+            .map(|(idx, (a, b))| len.lt(idx) * (a.expr() - b.expr()))
+            .collect::<Vec<_>>();
+        let is_zero = BatchedIsZeroGadget::construct(cb, &diffs);
+
+        Self {
+            len,
+            is_zero,
+            max_len,
+        }
+    }
+
+    pub(crate) fn is_equal(&self) -> Expression<F> {
+        self.is_zero.expr()
+    }
+
+    pub(crate) fn expr(&self) -> Expression<F> {
+        self.is_equal()
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        a: &[u8],
+        b: &[u8],
+        len: usize,
+    ) -> Result<F, Error> {
+        self.len.assign(region, offset, len)?;
+        let diffs = (0..self.max_len)
+            .map(|idx| {
+                if idx < len {
+                    F::from(a.get(idx).copied().unwrap_or(0) as u64)
+                        - F::from(b.get(idx).copied().unwrap_or(0) as u64)
+                } else {
+                    F::ZERO
+                }
+            })
+            .collect::<Vec<_>>();
+        self.is_zero.assign(region, offset, &diffs)
+    }
+}
+
+/// Decomposes bytes into nibbles: `byte == hi * 16 + lo`, with both nibbles
+/// range checked against the fixed range table, which needs to be loaded
+/// with `load_fixed_range` (ideally with a size of 16).
+///
+/// In hex-prefix mode the first byte is the MPT hex-prefix: its high nibble
+/// holds the flags `2 * is_leaf + is_odd`, and its low nibble is the first
+/// nibble of the path when the path has odd length and `0` otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct NibbleGadget<F> {
+    nibbles: Vec<(Cell<F>, Cell<F>)>,
+    hex_prefix: Option<(Cell<F>, Cell<F>)>,
+}
+
+impl<F: Field> NibbleGadget<F> {
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        bytes: &[Expression<F>],
+    ) -> Self {
+        let nibbles = bytes
+            .iter()
+            .map(|byte| {
+                let hi = cb.query_default();
+                let lo = cb.query_default();
+                cb.require_range_closed("high nibble range", hi.expr(), 15);
+                cb.require_range_closed("low nibble range", lo.expr(), 15);
+                cb.require_equal("byte == hi ⋅ 16 + lo", byte.expr(), hi.expr() * 16.expr() + lo.expr());
+                (hi, lo)
+            })
+            .collect();
+
+        Self {
+            nibbles,
+            hex_prefix: None,
+        }
+    }
+
+    /// Decomposes the bytes of a hex-prefix encoded path
+    pub(crate) fn construct_hex_prefix<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        bytes: &[Expression<F>],
+    ) -> Self {
+        assert!(!bytes.is_empty(), "the hex-prefix needs at least one byte");
+        let mut gadget = Self::construct(cb, bytes);
+        let is_odd = cb.query_bool();
+        let is_leaf = cb.query_bool();
+        let (flags, first) = &gadget.nibbles[0];
+        cb.require_equal(
+            "hex-prefix flags == 2 ⋅ is_leaf + is_odd",
+            flags.expr(),
+            2.expr() * is_leaf.expr() + is_odd.expr(),
+        );
+        // Even length paths are padded with a zero nibble
+        cb.require_zero(
+            "hex-prefix padding nibble is zero for even paths",
+            not::expr(is_odd.expr()) * first.expr(),
+        );
+        gadget.hex_prefix = Some((is_odd, is_leaf));
+        gadget
+    }
+
+    /// All nibbles, high nibble first
+    pub(crate) fn nibbles(&self) -> Vec<Expression<F>> {
+        self.nibbles
+            .iter()
+            .flat_map(|(hi, lo)| [hi.expr(), lo.expr()])
+            .collect()
+    }
+
+    /// `1` when the hex-prefix encoded path has odd length
+    pub(crate) fn is_odd(&self) -> Expression<F> {
+        self.hex_prefix.as_ref().expect("not in hex-prefix mode").0.expr()
+    }
+
+    /// `1` when the hex-prefix flags a leaf
+    pub(crate) fn is_leaf(&self) -> Expression<F> {
+        self.hex_prefix.as_ref().expect("not in hex-prefix mode").1.expr()
+    }
+
+    /// The number of nibbles of the path, excluding the hex-prefix flags and
+    /// padding in hex-prefix mode
+    pub(crate) fn num_nibbles(&self) -> Expression<F> {
+        match &self.hex_prefix {
+            Some((is_odd, _)) => (2 * (self.nibbles.len() - 1)).expr() + is_odd.expr(),
+            None => (2 * self.nibbles.len()).expr(),
+        }
+    }
+
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        for ((hi, lo), byte) in self.nibbles.iter().zip(bytes.iter()) {
+            hi.assign(region, offset, F::from((byte >> 4) as u64))?;
+            lo.assign(region, offset, F::from((byte & 0xf) as u64))?;
+        }
+        if let Some((is_odd, is_leaf)) = &self.hex_prefix {
+            let flags = bytes[0] >> 4;
+            is_odd.assign(region, offset, F::from((flags & 1) as u64))?;
+            is_leaf.assign(region, offset, F::from(((flags >> 1) & 1) as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Random linear combination built up from multiple segments of bytes. Keeps
+/// `(rlc, mult)` in cells after every segment, with `mult` the power of `r`
+/// to multiply the next byte with. The lengths of the segments can be dynamic:
+/// the power `r^len` is looked up in a `PowerTable` of `r`.
+#[derive(Clone, Debug)]
+pub struct RlcChainGadget<F> {
+    r: Expression<F>,
+    power_table: Vec<Expression<F>>,
+    /// `(rlc, mult)` before the first segment and after every segment
+    states: Vec<(Cell<F>, Cell<F>)>,
+    /// The length selector and `r^len` of every segment
+    segments: Vec<(DynamicSelectorGadget<F>, Cell<F>)>,
+}
+
+impl<F: Field> RlcChainGadget<F> {
+    /// Starts a chain from `(rlc, mult)`, `(0, 1)` for an empty chain.
+    /// `power_table` are the expressions of the `PowerTable` of `r`.
+    pub(crate) fn construct<C: CellType, T: TableTag>(
+        cb: &mut ConstraintBuilder<F, C, T>,
+        r: Expression<F>,
+        power_table: Vec<Expression<F>>,
+        (rlc, mult): (Expression<F>, Expression<F>),
+    ) -> Self {
+        let cell_type = C::storage_for_expr(&r);
+        let rlc_cell = cb.query_cell_with_type(cell_type);
+        let mult_cell = cb.query_cell_with_type(cell_type);
+        cb.require_equal("rlc chain initial rlc", rlc_cell.expr(), rlc);
+        cb.require_equal("rlc chain initial mult", mult_cell.expr(), mult);
+
+        Self {
+            r,
+            power_table,
+            states: vec![(rlc_cell, mult_cell)],
+            segments: Vec::new(),
+        }
+    }
+
+    /// Appends the first `len` bytes, `len <= bytes.len()`. The bytes at
+    /// `idx >= len` are required to be zero.
+    pub(crate) fn chain<C: CellType, T: TableTag>(
+        &mut self,
+        cb: &mut ConstraintBuilder<F, C, T>,
+        bytes: &[Expression<F>],
+        len: Expression<F>,
+    ) {
+        let cell_type = C::storage_for_expr(&self.r);
+        let (rlc, mult) = self.states.last().unwrap().clone();
+
+        let len_selector = DynamicSelectorGadget::construct(cb, len.expr(), bytes.len());
+        for (idx, byte) in bytes.iter().enumerate() {
+            cb.require_zero(
+                "rlc chain bytes after len are zero",
+                not::expr(len_selector.lt(idx)) * byte.expr(),
+            );
+        }
+
+        let power = cb.query_cell_with_type(cell_type);
+        cb.add_lookup(
+            "rlc chain r^len".to_string(),
+            vec![1.expr(), len.expr(), power.expr()],
+            self.power_table.clone(),
+        );
+
+        let next_rlc = cb.query_cell_with_type(cell_type);
+        let next_mult = cb.query_cell_with_type(cell_type);
+        cb.require_equal(
+            "rlc chain rlc",
+            next_rlc.expr(),
+            (rlc.expr(), mult.expr()).rlc_chain(rlc::expr(bytes, self.r.expr())),
+        );
+        cb.require_equal(
+            "rlc chain mult",
+            next_mult.expr(),
+            mult.expr() * power.expr(),
+        );
+
+        self.states.push((next_rlc, next_mult));
+        self.segments.push((len_selector, power));
+    }
+
+    /// The rlc after the last segment
+    pub(crate) fn rlc(&self) -> Expression<F> {
+        self.states.last().unwrap().0.expr()
+    }
+
+    /// The multiplier after the last segment
+    pub(crate) fn mult(&self) -> Expression<F> {
+        self.states.last().unwrap().1.expr()
+    }
+
+    /// Assigns the chain for the given segments, returns the final `(rlc, mult)`.
+    /// Everything depending on `r` stays unknown while `r` is unknown.
+    pub(crate) fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        offset: usize,
+        init: (Value<F>, Value<F>),
+        segments: &[&[u8]],
+        r: Value<F>,
+    ) -> Result<(Value<F>, Value<F>), Error> {
+        let mut state = init;
+        let (rlc, mult) = &self.states[0];
+        rlc.assign_value(region, offset, state.0)?;
+        mult.assign_value(region, offset, state.1)?;
+        for (((len_selector, power), (rlc, mult)), bytes) in self
+            .segments
+            .iter()
+            .zip(self.states.iter().skip(1))
+            .zip(segments.iter())
+        {
+            len_selector.assign(region, offset, bytes.len())?;
+            power.assign_value(region, offset, r.map(|r| r.pow([bytes.len() as u64, 0, 0, 0])))?;
+            state = state.rlc_chain_value(bytes.iter().copied(), r);
+            rlc.assign_value(region, offset, state.0)?;
+            mult.assign_value(region, offset, state.1)?;
+        }
+        Ok(state)
+    }
+}
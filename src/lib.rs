@@ -6,6 +6,10 @@ pub mod cell_manager;
 pub mod cached_region;
 pub mod gadgets;
 pub mod memory;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod mock;
+pub mod prelude;
+pub mod table;
 pub mod util;
 //  mod graph;
 
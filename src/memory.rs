@@ -22,7 +22,6 @@ use super::{
 pub(crate) struct Memory<F: Field, C: CellType, MB: MemoryBank<F, C>> {
     banks: HashMap<C, MB>,
     _phantom: PhantomData<F>,
-    tag_counter: usize,
 }
 
 impl<F: Field, C: CellType, MB: MemoryBank<F, C>> Index<C> for Memory<F, C, MB> {
@@ -52,7 +51,6 @@ impl<F: Field, C: CellType, MB: MemoryBank<F, C>> Memory<F, C, MB> {
         Self {
             banks: HashMap::new(),
             _phantom: PhantomData,
-            tag_counter: 0,
         }
     }
 
@@ -64,11 +62,33 @@ impl<F: Field, C: CellType, MB: MemoryBank<F, C>> Memory<F, C, MB> {
         tag: C,
         phase: u8,
     ) -> &MB {
-        let table_tag = self.allocate_tag();
+        let table_tag = cb.new_dynamic_cell_type();
         let bank = MB::new(meta, cb, cm, (tag, table_tag), phase);
         self.add(bank)
     }
 
+    /// Adds a bank that keeps its keys in the first `rows` rows of a cell
+    /// manager column of type `pool`, instead of in a column of its own. The
+    /// rows are reserved relative to every region, so the cells of the pool
+    /// never overlap the keys as long as the bank is assigned with a height
+    /// below `rows`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn add_rw_shared(
+        &mut self,
+        meta: &mut ConstraintSystem<F>,
+        cb: &mut ConstraintBuilder<F, C>,
+        cm: &mut CellManager<F, C>,
+        tag: C,
+        phase: u8,
+        pool: C,
+        rows: usize,
+    ) -> &MB {
+        let table_tag = cb.new_dynamic_cell_type();
+        let key = cm.reserve_rows(pool, rows);
+        let bank = MB::new_shared(meta, cb, cm, (tag, table_tag), phase, key, rows);
+        self.add(bank)
+    }
+
     pub(crate) fn add(&mut self, memory_bank: MB) -> &MB {
         let tag = memory_bank.tag();
         self.banks.insert(tag, memory_bank);
@@ -102,12 +122,6 @@ impl<F: Field, C: CellType, MB: MemoryBank<F, C>> Memory<F, C, MB> {
         }
         Ok(())
     }
-
-    pub(crate) fn allocate_tag(&mut self) -> C {
-        let tag = C::create_type(self.tag_counter);
-        self.tag_counter += 1;
-        tag
-    }
 }
 
 pub(crate) trait MemoryBank<F: Field, C: CellType>: Clone {
@@ -118,6 +132,17 @@ pub(crate) trait MemoryBank<F: Field, C: CellType>: Clone {
         tag: (C, C),
         phase: u8,
     ) -> Self;
+    /// Creates a bank using `key` for its keys, which it may only use in the
+    /// first `rows` rows
+    fn new_shared(
+        meta: &mut ConstraintSystem<F>,
+        cb: &mut ConstraintBuilder<F, C>,
+        cm: &mut CellManager<F, C>,
+        tag: (C, C),
+        phase: u8,
+        key: Column<Advice>,
+        rows: usize,
+    ) -> Self;
     fn store(
         &mut self,
         cb: &mut ConstraintBuilder<F, C>,
@@ -133,10 +158,79 @@ pub(crate) trait MemoryBank<F: Field, C: CellType>: Clone {
     fn tag(&self) -> C;
     fn witness_store(&mut self, offset: usize, values: &[F]);
     fn witness_load(&self, offset: usize) -> Vec<F>;
+    /// The latest values stored for the key (the first stored value)
+    fn get(&self, key: F) -> Option<&[F]>;
+    /// The latest values stored for each key, in the order the keys were
+    /// first stored
+    fn iter_latest(&self) -> Box<dyn Iterator<Item = (F, &[F])> + '_>;
+    /// All stores for the key, oldest first
+    fn history(&self, key: F) -> &[WriteRecord<F>];
+    /// Number of distinct keys stored
+    fn len(&self) -> usize;
+    /// Returns true if nothing was stored yet
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn build_constraints(&self, cb: &mut ConstraintBuilder<F, C>, q_start: Expression<F>);
     fn assign(&self, region: &mut CachedRegion<'_, '_, F>, height: usize) -> Result<(), Error>;
 }
 
+/// A witness store into a memory bank
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WriteRecord<F> {
+    /// The offset the values were stored at
+    pub(crate) offset: usize,
+    /// The stored values, without the key
+    pub(crate) values: Vec<F>,
+}
+
+/// Witness stores grouped by key (the first stored value), keys in the order
+/// they were first stored
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WriteLog<F> {
+    keys: HashMap<Vec<u8>, usize>,
+    records: Vec<(F, Vec<WriteRecord<F>>)>,
+}
+
+impl<F: Field> WriteLog<F> {
+    pub(crate) fn write(&mut self, offset: usize, values: &[F]) {
+        let (key, values) = match values.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        let records = &mut self.records;
+        let index = *self.keys.entry(key.to_repr().as_ref().to_vec()).or_insert_with(|| {
+            records.push((*key, Vec::new()));
+            records.len() - 1
+        });
+        self.records[index].1.push(WriteRecord {
+            offset,
+            values: values.to_vec(),
+        });
+    }
+
+    pub(crate) fn history(&self, key: F) -> &[WriteRecord<F>] {
+        self.keys
+            .get(key.to_repr().as_ref())
+            .map(|&index| self.records[index].1.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn get(&self, key: F) -> Option<&[F]> {
+        self.history(key).last().map(|record| record.values.as_slice())
+    }
+
+    pub(crate) fn iter_latest(&self) -> impl Iterator<Item = (F, &[F])> {
+        self.records
+            .iter()
+            .map(|(key, records)| (*key, records.last().unwrap().values.as_slice()))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+}
+
 pub(crate) fn insert_key<V: Clone>(key: V, values: &[V]) -> Vec<V> {
     [vec![key], values.to_owned()].concat().to_vec()
 }
@@ -149,35 +243,23 @@ pub(crate) struct RwBank<F, C> {
     writes: Column<Advice>,
     store_offsets: Vec<usize>,
     stored_values: Vec<Vec<F>>,
+    write_log: WriteLog<F>,
     cur: Expression<F>,
     next: Expression<F>,
     local_conditions: Vec<(usize, Expression<F>)>,
+    /// Number of rows the key column can be used in, when shared
+    rows: Option<usize>,
 }
 
 impl<F: Field, C: CellType> RwBank<F, C> {
-    pub(crate) fn key(&self) -> Expression<F> {
-        self.cur.expr()
-    }
-    pub(crate) fn prepend_key(&self, values: &[Expression<F>]) -> Vec<Expression<F>> {
-        [&[self.cur.expr() + 1.expr()], values].concat().to_vec()
-    }
-
-    pub(crate) fn prepend_offset(
-        &self,
-        values: &[Expression<F>],
-        offset: Expression<F>,
-    ) -> Vec<Expression<F>> {
-        [&[self.cur.expr() - offset], values].concat().to_vec()
-    }
-}
-
-impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
-    fn new(
+    fn with_key(
         meta: &mut ConstraintSystem<F>,
         cb: &mut ConstraintBuilder<F, C>,
         cm: &mut CellManager<F, C>,
         tag: (C, C),
         phase: u8,
+        key: Column<Advice>,
+        rows: Option<usize>,
     ) -> Self {
         let rw: Vec<Column<Advice>> = [tag.0, tag.1]
             .iter()
@@ -186,7 +268,6 @@ impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
                 cm.get_typed_columns(*t)[0].column
             })
             .collect();
-        let key = meta.advice_column();
         let (cur, next, input, table) = query_expression(meta, |meta| {
             (
                 meta.query_advice(key, Rotation::cur()),
@@ -208,12 +289,54 @@ impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
             writes: rw[1],
             store_offsets: Vec::new(),
             stored_values: Vec::new(),
+            write_log: WriteLog::default(),
             cur,
             next,
             local_conditions: Vec::new(),
+            rows,
         }
     }
 
+    pub(crate) fn key(&self) -> Expression<F> {
+        self.cur.expr()
+    }
+    pub(crate) fn prepend_key(&self, values: &[Expression<F>]) -> Vec<Expression<F>> {
+        [&[self.cur.expr() + 1.expr()], values].concat().to_vec()
+    }
+
+    pub(crate) fn prepend_offset(
+        &self,
+        values: &[Expression<F>],
+        offset: Expression<F>,
+    ) -> Vec<Expression<F>> {
+        [&[self.cur.expr() - offset], values].concat().to_vec()
+    }
+}
+
+impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
+    fn new(
+        meta: &mut ConstraintSystem<F>,
+        cb: &mut ConstraintBuilder<F, C>,
+        cm: &mut CellManager<F, C>,
+        tag: (C, C),
+        phase: u8,
+    ) -> Self {
+        let key = meta.advice_column();
+        Self::with_key(meta, cb, cm, tag, phase, key, None)
+    }
+
+    fn new_shared(
+        meta: &mut ConstraintSystem<F>,
+        cb: &mut ConstraintBuilder<F, C>,
+        cm: &mut CellManager<F, C>,
+        tag: (C, C),
+        phase: u8,
+        key: Column<Advice>,
+        rows: usize,
+    ) -> Self {
+        Self::with_key(meta, cb, cm, tag, phase, key, Some(rows))
+    }
+
     fn store(
         &mut self,
         cb: &mut ConstraintBuilder<F, C>,
@@ -221,7 +344,7 @@ impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
     ) -> Expression<F> {
         let key = self.key() + 1.expr();
         cb.store_tuple(
-            Box::leak(format!("{:?} store", self.tag.1).into_boxed_str()),
+            cb.intern(&format!("{:?} store", self.tag.1)),
             self.tag.1,
             insert_key(key.expr(), values),
         );
@@ -237,7 +360,7 @@ impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
         values: &[Expression<F>],
     ) {
         cb.store_tuple(
-            Box::leak(format!("{:?} load", self.tag.0).into_boxed_str()),
+            cb.intern(&format!("{:?} load", self.tag.0)),
             self.tag.0,
             insert_key(self.key() - load_offset.expr(), values),
         );
@@ -270,13 +393,42 @@ impl<F: Field, C: CellType> MemoryBank<F, C> for RwBank<F, C> {
     fn witness_store(&mut self, offset: usize, values: &[F]) {
         self.stored_values.push(values.to_vec());
         self.store_offsets.push(offset);
+        self.write_log.write(offset, values);
     }
 
     fn witness_load(&self, offset: usize) -> Vec<F> {
         self.stored_values[self.stored_values.len() - 1 - offset].clone()
     }
 
+    fn get(&self, key: F) -> Option<&[F]> {
+        self.write_log.get(key)
+    }
+
+    fn iter_latest(&self) -> Box<dyn Iterator<Item = (F, &[F])> + '_> {
+        Box::new(self.write_log.iter_latest())
+    }
+
+    fn history(&self, key: F) -> &[WriteRecord<F>] {
+        self.write_log.history(key)
+    }
+
+    fn len(&self) -> usize {
+        self.write_log.len()
+    }
+
     fn assign(&self, region: &mut CachedRegion<'_, '_, F>, height: usize) -> Result<(), Error> {
+        // A shared key column can only be used in the reserved rows. The
+        // keys are assigned in the absolute rows `0..=height`, the cells of a
+        // region are allocated from its offset plus the reserved rows on.
+        if let Some(rows) = self.rows {
+            assert!(
+                height < rows,
+                "memory bank {:?} uses {} rows but only {} are reserved",
+                self.tag.0,
+                height + 1,
+                rows
+            );
+        }
         // Pad to the full circuit (necessary for reads)
         let mut store_offsets = self.store_offsets.clone();
         store_offsets.push(height);
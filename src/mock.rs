@@ -0,0 +1,496 @@
+//! Configure-time emulation without a circuit
+use std::{
+    collections::HashMap,
+    ops::{Add, Mul},
+};
+use eth_types::Field;
+use halo2_proofs::{
+    plonk::{
+        Advice, Any, Challenge, Column, ConstraintSystem, Expression, Fixed, FirstPhase,
+        SecondPhase, ThirdPhase,
+    },
+    poly::Rotation,
+};
+use rand::Rng;
+
+use crate::{
+    cell_manager::{Cell, CellType, ColumnAllocator, TableTag},
+    constraint_builder::{BuilderOp, ConstraintBuilder},
+    util::{not, query_expression, sum, Expr},
+};
+
+/// Stand-in for the `meta` used in gate closures, so gadgets can be configured
+/// and their constraints evaluated without a `Circuit` implementation.
+///
+/// Columns are created on a scratch `ConstraintSystem` (halo2 doesn't allow
+/// building queries any other way), but no gates or lookups are ever added to
+/// it. All queries are recorded, and expressions can be evaluated against
+/// values set per column and rotation.
+#[derive(Debug, Default)]
+pub struct MockMeta<F: Field> {
+    cs: ConstraintSystem<F>,
+    queries: Vec<(Column<Any>, Rotation)>,
+    advice: HashMap<(usize, i32), F>,
+    fixed: HashMap<(usize, i32), F>,
+    challenges: HashMap<usize, F>,
+}
+
+impl<F: Field> MockMeta<F> {
+    /// Creates an empty mock
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new advice column in the given phase
+    pub fn advice_column_in(&mut self, phase: u8) -> Column<Advice> {
+        match phase {
+            0 => self.cs.advice_column_in(FirstPhase),
+            1 => self.cs.advice_column_in(SecondPhase),
+            2 => self.cs.advice_column_in(ThirdPhase),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Creates a new fixed column
+    pub fn fixed_column(&mut self) -> Column<Fixed> {
+        self.cs.fixed_column()
+    }
+
+    /// Creates a challenge usable after the given phase. An advice column in
+    /// that phase needs to exist.
+    pub fn challenge_usable_after(&mut self, phase: u8) -> Challenge {
+        match phase {
+            0 => self.cs.challenge_usable_after(FirstPhase),
+            1 => self.cs.challenge_usable_after(SecondPhase),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Queries an advice column
+    pub fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
+        self.queries.push((column.into(), at));
+        query_expression(&mut self.cs, |meta| meta.query_advice(column, at))
+    }
+
+    /// Queries a fixed column
+    pub fn query_fixed(&mut self, column: Column<Fixed>, at: Rotation) -> Expression<F> {
+        self.queries.push((column.into(), at));
+        query_expression(&mut self.cs, |meta| meta.query_fixed(column, at))
+    }
+
+    /// Queries a challenge
+    pub fn query_challenge(&mut self, challenge: Challenge) -> Expression<F> {
+        query_expression(&mut self.cs, |meta| meta.query_challenge(challenge))
+    }
+
+    /// All column queries done so far, in order
+    pub fn queries(&self) -> &[(Column<Any>, Rotation)] {
+        &self.queries
+    }
+
+    /// Sets the value of an advice column at the given rotation
+    pub fn set_advice(&mut self, column: Column<Advice>, at: Rotation, value: F) {
+        self.advice.insert((column.index(), at.0), value);
+    }
+
+    /// Sets the value of a fixed column at the given rotation
+    pub fn set_fixed(&mut self, column: Column<Fixed>, at: Rotation, value: F) {
+        self.fixed.insert((column.index(), at.0), value);
+    }
+
+    /// Sets the value of a challenge
+    pub fn set_challenge(&mut self, challenge: Challenge, value: F) {
+        self.challenges.insert(challenge.index(), value);
+    }
+
+    /// Evaluates the expression with the values set on the mock, all other
+    /// values are zero
+    pub fn evaluate(&self, expr: &Expression<F>) -> F {
+        let get = |values: &HashMap<(usize, i32), F>, index: usize, at: Rotation| {
+            values.get(&(index, at.0)).copied().unwrap_or(F::ZERO)
+        };
+        expr.evaluate(
+            &|scalar| scalar,
+            &|_| unimplemented!("selectors are not supported"),
+            &|query| get(&self.fixed, query.column_index(), query.rotation()),
+            &|query| get(&self.advice, query.column_index(), query.rotation()),
+            &|_| unimplemented!("instance columns are not supported"),
+            &|challenge| {
+                self.challenges
+                    .get(&challenge.index())
+                    .copied()
+                    .unwrap_or(F::ZERO)
+            },
+            &|a| -a,
+            &|a, b| a + b,
+            &|a, b| a * b,
+            &|a, scalar| a * scalar,
+        )
+    }
+}
+
+impl<F: Field> ColumnAllocator<F> for MockMeta<F> {
+    fn alloc_advice_column(&mut self, phase: u8) -> Column<Advice> {
+        self.advice_column_in(phase)
+    }
+
+    fn enable_equality(&mut self, column: Column<Advice>) {
+        self.cs.enable_equality(column);
+    }
+
+    fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
+        MockMeta::query_advice(self, column, at)
+    }
+}
+
+/// Builder state reconstructed from an operation log by `replay`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayState {
+    /// Number of constraints, including the ones of stored expressions
+    pub constraints: usize,
+    /// Number of lookups
+    pub lookups: usize,
+    /// Number of rows stored in dynamic tables
+    pub table_rows: usize,
+    /// Number of cells queried from the cell manager
+    pub cells: usize,
+    /// The current region, 0 outside of regions
+    pub region_id: usize,
+    /// Degrees of the active conditions, outermost first
+    pub conditions: Vec<usize>,
+    /// Degrees of the conditions active when the current region was entered
+    pub region_conditions: Vec<usize>,
+}
+
+/// Reconstructs the state of the builder from its operation log (see
+/// `ConstraintBuilder::record_ops`) right before operation `upto` was done,
+/// e.g. the operation that panicked. The whole log is replayed when `upto`
+/// is past its end.
+pub fn replay(oplog: &[BuilderOp], upto: usize) -> ReplayState {
+    let mut state = ReplayState::default();
+    for op in oplog.iter().take(upto) {
+        match op {
+            BuilderOp::AddConstraint { .. } | BuilderOp::StoreExpression { .. } => {
+                state.constraints += 1
+            }
+            BuilderOp::PushCondition { degree } => state.conditions.push(*degree),
+            BuilderOp::PopCondition => {
+                state.conditions.pop();
+            }
+            BuilderOp::SetConditions { degrees } => state.conditions = degrees.clone(),
+            BuilderOp::PushRegion { region_id, .. } => {
+                state.region_conditions = std::mem::take(&mut state.conditions);
+                state.region_id = *region_id;
+            }
+            BuilderOp::PopRegion => {
+                state.conditions = std::mem::take(&mut state.region_conditions);
+                state.region_id = 0;
+            }
+            BuilderOp::QueryCells { count, .. } => state.cells += count,
+            BuilderOp::StoreTable { .. } => state.table_rows += 1,
+            BuilderOp::AddLookup { .. } => state.lookups += 1,
+        }
+    }
+    state
+}
+
+/// Expression over the variables of a `DifferentialTest`
+#[derive(Clone, Debug)]
+pub enum Term {
+    /// A variable, by index
+    Var(usize),
+    /// A constant
+    Const(u64),
+    /// `1 - term`
+    Not(Box<Term>),
+    /// Sum of two terms
+    Add(Box<Term>, Box<Term>),
+    /// Product of two terms
+    Mul(Box<Term>, Box<Term>),
+}
+
+impl Term {
+    /// The expression with the variables replaced by the cells
+    pub fn expr<F: Field>(&self, vars: &[Cell<F>]) -> Expression<F> {
+        match self {
+            Term::Var(idx) => vars[*idx].expr(),
+            Term::Const(value) => value.expr(),
+            Term::Not(term) => not::expr(term.expr(vars)),
+            Term::Add(lhs, rhs) => lhs.expr(vars) + rhs.expr(vars),
+            Term::Mul(lhs, rhs) => lhs.expr(vars) * rhs.expr(vars),
+        }
+    }
+
+    /// The value of the term for the values of the variables
+    pub fn value<F: Field>(&self, values: &[F]) -> F {
+        match self {
+            Term::Var(idx) => values[*idx],
+            Term::Const(value) => F::from(*value),
+            Term::Not(term) => F::ONE - term.value(values),
+            Term::Add(lhs, rhs) => lhs.value(values) + rhs.value(values),
+            Term::Mul(lhs, rhs) => lhs.value(values) * rhs.value(values),
+        }
+    }
+}
+
+impl Add for Term {
+    type Output = Term;
+    fn add(self, rhs: Term) -> Term {
+        Term::Add(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl Mul for Term {
+    type Output = Term;
+    fn mul(self, rhs: Term) -> Term {
+        Term::Mul(Box::new(self), Box::new(rhs))
+    }
+}
+
+/// Intended constraint of a `DifferentialTest`. Conditions multiply the
+/// constraints inside them, like the builder does.
+#[derive(Clone, Debug)]
+pub enum Spec {
+    /// The terms are pairwise equal
+    Equal(Vec<Term>, Vec<Term>),
+    /// The term is `0` or `1`
+    Boolean(Term),
+    /// The term is one of the values
+    InSet(Term, Vec<u64>),
+    /// The terms are a row of the table with the index
+    Lookup(Vec<Term>, usize),
+    /// The first specs under the condition, the optional second ones under
+    /// its negation, which requires the condition to be boolean
+    If(Term, Vec<Spec>, Option<Vec<Spec>>),
+    /// The specs of every arm under its condition, and the optional catch-all
+    /// specs when no condition is set. The conditions are boolean and exactly
+    /// one arm is taken.
+    Match(Vec<(Term, Vec<Spec>)>, Option<Vec<Spec>>),
+}
+
+/// Checks constraints written with the `circuit!` macros against the same
+/// constraints added through the builder API and against a reference
+/// interpreter of the intended constraints (`Spec`s), on random witnesses.
+///
+/// The witness is a single row: the variables are advice cells at rotation
+/// 0. Tables are fixed columns, so next to their rows they always contain
+/// the all zero row of the unassigned rows.
+#[derive(Debug)]
+pub struct DifferentialTest<F: Field> {
+    meta: MockMeta<F>,
+    vars: Vec<Cell<F>>,
+    tables: Vec<(Vec<Expression<F>>, Vec<Vec<F>>)>,
+}
+
+impl<F: Field> DifferentialTest<F> {
+    /// Creates the variables and the tables with the given rows
+    pub fn new(num_vars: usize, tables: &[Vec<Vec<u64>>]) -> Self {
+        let mut meta = MockMeta::new();
+        let vars = (0..num_vars)
+            .map(|_| {
+                let column = meta.advice_column_in(0);
+                Cell::new(&mut meta, column, 0)
+            })
+            .collect();
+        let tables = tables
+            .iter()
+            .map(|rows| {
+                let width = rows[0].len();
+                let exprs = (0..width)
+                    .map(|_| {
+                        let column = meta.fixed_column();
+                        meta.query_fixed(column, Rotation::cur())
+                    })
+                    .collect();
+                let rows = rows
+                    .iter()
+                    .map(|row| row.iter().map(|value| F::from(*value)).collect())
+                    .chain(std::iter::once(vec![F::ZERO; width]))
+                    .collect();
+                (exprs, rows)
+            })
+            .collect();
+        Self { meta, vars, tables }
+    }
+
+    /// The cells of the variables
+    pub fn vars(&self) -> &[Cell<F>] {
+        &self.vars
+    }
+
+    /// The expressions of the table columns, for lookups
+    pub fn tables(&self) -> Vec<Vec<Expression<F>>> {
+        self.tables.iter().map(|(exprs, _)| exprs.clone()).collect()
+    }
+
+    /// Adds the constraints of the specs with the builder API
+    pub fn build<C: CellType, T: TableTag>(
+        &self,
+        cb: &mut ConstraintBuilder<F, C, T>,
+        specs: &[Spec],
+    ) {
+        let vars = &self.vars;
+        for spec in specs {
+            match spec {
+                Spec::Equal(lhs, rhs) => {
+                    assert_eq!(lhs.len(), rhs.len());
+                    for (lhs, rhs) in lhs.iter().zip(rhs.iter()) {
+                        cb.require_equal("spec equal", lhs.expr(vars), rhs.expr(vars));
+                    }
+                }
+                Spec::Boolean(term) => cb.require_boolean("spec boolean", term.expr(vars)),
+                Spec::InSet(term, set) => cb.require_in_set(
+                    "spec in set",
+                    term.expr(vars),
+                    set.iter().map(|value| value.expr()).collect(),
+                ),
+                Spec::Lookup(terms, table) => cb.add_lookup(
+                    "spec lookup".to_string(),
+                    terms.iter().map(|term| term.expr(vars)).collect(),
+                    self.tables[*table].0.clone(),
+                ),
+                Spec::If(condition, when_true, when_false) => {
+                    let condition = condition.expr(vars);
+                    cb.condition(condition.expr(), |cb| self.build(cb, when_true));
+                    if let Some(when_false) = when_false {
+                        cb.require_boolean("spec if condition", condition.expr());
+                        cb.condition(not::expr(condition), |cb| self.build(cb, when_false));
+                    }
+                }
+                Spec::Match(arms, catch_all) => {
+                    let mut conditions = Vec::new();
+                    for (condition, specs) in arms {
+                        let condition = condition.expr(vars);
+                        cb.condition(condition.expr(), |cb| self.build(cb, specs));
+                        conditions.push(condition);
+                    }
+                    if let Some(specs) = catch_all {
+                        let condition = not::expr(sum::expr(&conditions));
+                        cb.condition(condition.expr(), |cb| self.build(cb, specs));
+                        conditions.push(condition);
+                    }
+                    for condition in conditions.iter() {
+                        cb.require_boolean("spec match condition", condition.expr());
+                    }
+                    cb.require_equal("spec match one arm", sum::expr(&conditions), 1.expr());
+                }
+            }
+        }
+    }
+
+    /// Returns `true` when the specs hold for the values of the variables,
+    /// according to the reference interpreter
+    pub fn holds(&self, specs: &[Spec], values: &[F]) -> bool {
+        self.holds_under(specs, values, F::ONE)
+    }
+
+    fn holds_under(&self, specs: &[Spec], values: &[F], condition: F) -> bool {
+        let is_zero = |value: F| condition * value == F::ZERO;
+        specs.iter().all(|spec| match spec {
+            Spec::Equal(lhs, rhs) => lhs
+                .iter()
+                .zip(rhs.iter())
+                .all(|(lhs, rhs)| is_zero(lhs.value(values) - rhs.value(values))),
+            Spec::Boolean(term) => {
+                let value = term.value(values);
+                is_zero(value * (F::ONE - value))
+            }
+            Spec::InSet(term, set) => {
+                let value = term.value(values);
+                is_zero(set.iter().map(|item| value - F::from(*item)).product())
+            }
+            Spec::Lookup(terms, table) => {
+                let row = terms
+                    .iter()
+                    .map(|term| term.value(values) * condition)
+                    .collect::<Vec<_>>();
+                self.tables[*table].1.contains(&row)
+            }
+            Spec::If(term, when_true, when_false) => {
+                let value = term.value(values);
+                self.holds_under(when_true, values, condition * value)
+                    && when_false.as_ref().map_or(true, |when_false| {
+                        is_zero(value * (F::ONE - value))
+                            && self.holds_under(when_false, values, condition * (F::ONE - value))
+                    })
+            }
+            Spec::Match(arms, catch_all) => {
+                let mut conditions = arms
+                    .iter()
+                    .map(|(term, _)| term.value(values))
+                    .collect::<Vec<_>>();
+                let catch_all_condition = F::ONE - conditions.iter().copied().sum::<F>();
+                let arms_hold = arms
+                    .iter()
+                    .zip(conditions.iter())
+                    .all(|((_, specs), value)| self.holds_under(specs, values, condition * value));
+                let catch_all_holds = catch_all.as_ref().map_or(true, |specs| {
+                    conditions.push(catch_all_condition);
+                    self.holds_under(specs, values, condition * catch_all_condition)
+                });
+                arms_hold
+                    && catch_all_holds
+                    && conditions.iter().all(|value| is_zero(*value * (F::ONE - *value)))
+                    && is_zero(conditions.iter().copied().sum::<F>() - F::ONE)
+            }
+        })
+    }
+
+    /// Returns `true` when all constraints and lookups of the builder hold
+    /// for the values of the variables
+    pub fn satisfied<C: CellType, T: TableTag>(
+        &mut self,
+        cb: &ConstraintBuilder<F, C, T>,
+        values: &[F],
+    ) -> bool {
+        for (var, value) in self.vars.iter().zip(values.iter()) {
+            self.meta.set_advice(var.column(), Rotation::cur(), *value);
+        }
+        let meta = &self.meta;
+        let tables = &self.tables;
+        cb.unsatisfied_constraints(|expr| meta.evaluate(expr)).is_empty()
+            && cb
+                .unsatisfied_lookups(
+                    |expr| meta.evaluate(expr),
+                    |table| {
+                        let identifiers = |exprs: &[Expression<F>]| {
+                            exprs.iter().map(|expr| expr.identifier()).collect::<Vec<_>>()
+                        };
+                        tables
+                            .iter()
+                            .find(|(exprs, _)| identifiers(exprs) == identifiers(table))
+                            .map(|(_, rows)| rows.clone())
+                            .expect("lookup into an unknown table")
+                    },
+                )
+                .is_empty()
+    }
+
+    /// Checks on `samples` random witnesses, with every variable in
+    /// `0..domain`, that the reference interpreter, the builder API and the
+    /// builder the macros were used with (`macro_cb`) agree on whether the
+    /// specs are satisfied. Panics with the witness on a mismatch. Returns on
+    /// how many witnesses the specs held, to check both outcomes were seen.
+    pub fn check<C: CellType, T: TableTag>(
+        &mut self,
+        specs: &[Spec],
+        macro_cb: &ConstraintBuilder<F, C, T>,
+        samples: usize,
+        domain: u64,
+        rng: &mut impl Rng,
+    ) -> usize {
+        let mut api_cb =
+            ConstraintBuilder::<F, C, T>::new(macro_cb.max_global_degree(), None, None);
+        self.build(&mut api_cb, specs);
+        let mut held = 0;
+        for _ in 0..samples {
+            let witness = self.vars.iter().map(|_| rng.gen_range(0..domain)).collect::<Vec<_>>();
+            let values = witness.iter().map(|value| F::from(*value)).collect::<Vec<_>>();
+            let expected = self.holds(specs, &values);
+            assert_eq!(self.satisfied(&api_cb, &values), expected, "builder API on {:?}", witness);
+            assert_eq!(self.satisfied(macro_cb, &values), expected, "macros on {:?}", witness);
+            held += expected as usize;
+        }
+        held
+    }
+}
@@ -0,0 +1,16 @@
+//! The stable public surface of the crate. Circuits should import from here
+//! rather than from the individual modules, which can be reorganized. Items
+//! are only removed from the prelude in breaking releases, the list is
+//! checked by the `public_api` test.
+pub use crate::{
+    cached_region::{CachedRegion, ChallengeSet},
+    cell_manager::{
+        Cell, CellManager, CellType, ColumnAllocator, DefaultCellType, TableTag,
+    },
+    constraint_builder::{
+        ConstraintBuilder, ConstraintBuilderError, ExprVec, RLCChainable, RLCChainableRev,
+        RLCChainableValue, RLCable, RLCableValue, Reduced,
+    },
+    table::FixedTableRegistry,
+    util::{Challenges, Expr, Scalar},
+};
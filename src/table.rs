@@ -0,0 +1,384 @@
+//! Fixed tables
+use std::{collections::HashMap, fmt::Debug, hash::Hash};
+use eth_types::Field;
+use halo2_proofs::{
+    circuit::{Layouter, Value},
+    plonk::{Advice, Any, Column, ConstraintSystem, Error, Expression, Fixed, TableColumn},
+    poly::Rotation,
+};
+use zkevm_circuits::table::LookupTable;
+
+use crate::util::{query_expression, Expr};
+
+/// Name of a table column queried at a rotation: `name` at the current row,
+/// `name@next` and `name@prev` for the adjacent rows and `name@<rotation>`
+/// otherwise
+pub(crate) fn rotated_column_name(name: &str, rotation: i32) -> String {
+    match rotation {
+        0 => name.to_string(),
+        1 => format!("{}@next", name),
+        -1 => format!("{}@prev", name),
+        _ => format!("{}@{}", name, rotation),
+    }
+}
+
+/// The rows `[0], [1], .., [size - 1]` of a range table
+pub(crate) fn fixed_range_rows<F: Field>(size: usize) -> Vec<Vec<F>> {
+    (0..size).map(|value| vec![F::from(value as u64)]).collect()
+}
+
+/// Assigns the rows to the fixed columns, one value per column
+pub(crate) fn assign_fixed_rows<F: Field>(
+    layouter: &mut impl Layouter<F>,
+    name: &str,
+    columns: &[Column<Fixed>],
+    rows: &[Vec<F>],
+) -> Result<(), Error> {
+    layouter.assign_region(
+        || name,
+        |mut region| {
+            for (offset, row) in rows.iter().enumerate() {
+                assert_eq!(row.len(), columns.len());
+                for (column, value) in columns.iter().zip(row.iter()) {
+                    region.assign_fixed(|| name, *column, offset, || Value::known(*value))?;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Assigns the rows to the table columns with `Layouter::assign_table`, one
+/// value per column. Unassigned rows of the columns are filled with the first
+/// row by halo2.
+pub(crate) fn assign_table_rows<F: Field>(
+    layouter: &mut impl Layouter<F>,
+    name: &str,
+    columns: &[TableColumn],
+    rows: &[Vec<F>],
+) -> Result<(), Error> {
+    layouter.assign_table(
+        || name,
+        |mut table| {
+            for (offset, row) in rows.iter().enumerate() {
+                assert_eq!(row.len(), columns.len());
+                for (column, value) in columns.iter().zip(row.iter()) {
+                    table.assign_cell(|| name, *column, offset, || Value::known(*value))?;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Assigns `0..size` to the table column
+pub(crate) fn assign_table_range<F: Field>(
+    layouter: &mut impl Layouter<F>,
+    column: TableColumn,
+    size: usize,
+) -> Result<(), Error> {
+    assign_table_rows(layouter, "table range", &[column], &fixed_range_rows(size))
+}
+
+/// Assigns `0..size` to the fixed range table column
+pub(crate) fn assign_fixed_range<F: Field>(
+    layouter: &mut impl Layouter<F>,
+    column: Column<Fixed>,
+    size: usize,
+) -> Result<(), Error> {
+    assign_fixed_rows(layouter, "fixed range", &[column], &fixed_range_rows(size))
+}
+
+/// The rows `[index, values..]` for `index` in `0..height` of a sparse table.
+/// Indices without an entry get `fill` for all values. The width of the
+/// table is taken from the entries, so there has to be at least one.
+pub(crate) fn sparse_rows<F: Field>(
+    entries: &[(usize, Vec<F>)],
+    height: usize,
+    fill: F,
+) -> Vec<Vec<F>> {
+    let width = entries
+        .first()
+        .map(|(_, values)| values.len())
+        .expect("sparse tables need at least one entry");
+    assert!(width > 0, "sparse entries need at least one value");
+    let mut rows = (0..height)
+        .map(|index| [vec![F::from(index as u64)], vec![fill; width]].concat())
+        .collect::<Vec<_>>();
+    let mut present = vec![false; height];
+    for (index, values) in entries.iter() {
+        assert!(*index < height, "sparse entry {} out of the table height {}", index, height);
+        assert!(!present[*index], "sparse entry {} given twice", index);
+        assert_eq!(values.len(), width, "sparse entry {} has the wrong width", index);
+        present[*index] = true;
+        rows[*index][1..].copy_from_slice(values);
+    }
+    rows
+}
+
+/// Records the rows of a sparse table (see `sparse_rows`) and assigns them,
+/// the index to the first column and the values to the others. Lookups into
+/// the table need to range check the index with
+/// `ConstraintBuilder::lookup_sparse`.
+pub(crate) fn assign_sparse_fixed_table<F: Field, T: Copy + Debug + Eq + Hash + Ord>(
+    layouter: &mut impl Layouter<F>,
+    registry: &mut FixedTableRegistry<F, T>,
+    tag: T,
+    columns: &[Column<Fixed>],
+    entries: &[(usize, Vec<F>)],
+    height: usize,
+    fill: F,
+) -> Result<(), Error> {
+    registry.register_sparse(tag, entries, height, fill);
+    registry.assign(layouter, tag, columns)
+}
+
+/// Records the rows of the fixed tables by tag, so the assigned contents can
+/// also be exported (e.g. to precompute commitments). Tables are assigned
+/// from the recorded rows.
+#[derive(Clone, Debug)]
+pub struct FixedTableRegistry<F, T> {
+    tables: HashMap<T, Vec<Vec<F>>>,
+}
+
+impl<F, T> Default for FixedTableRegistry<F, T> {
+    fn default() -> Self {
+        Self {
+            tables: HashMap::new(),
+        }
+    }
+}
+
+impl<F: Field, T: Copy + Debug + Eq + Hash + Ord> FixedTableRegistry<F, T> {
+    /// Records the rows of a table
+    pub fn register(&mut self, tag: T, rows: Vec<Vec<F>>) {
+        assert!(
+            self.tables.insert(tag, rows).is_none(),
+            "fixed table {:?} registered twice",
+            tag
+        );
+    }
+
+    /// Records a table containing `0..size`
+    pub fn register_range(&mut self, tag: T, size: usize) {
+        self.register(tag, fixed_range_rows(size));
+    }
+
+    /// Records a sparse table of `height` rows (see `sparse_rows`)
+    pub fn register_sparse(&mut self, tag: T, entries: &[(usize, Vec<F>)], height: usize, fill: F) {
+        self.register(tag, sparse_rows(entries, height, fill));
+    }
+
+    /// Records a table containing all byte values
+    pub fn register_bytes(&mut self, tag: T) {
+        self.register_range(tag, 256);
+    }
+
+    /// The recorded rows of a table
+    pub fn fixed_table_contents(&self, tag: T) -> Option<&[Vec<F>]> {
+        self.tables.get(&tag).map(|rows| rows.as_slice())
+    }
+
+    /// The rows of a table as seen by a lookup into its columns queried at
+    /// each of the rotations (see `load_table_with_rotations`), one row per
+    /// recorded row. Rows outside the recorded ones read as zero, so the all
+    /// zero row is included as well.
+    pub fn rotated_contents(&self, tag: T, rotations: &[i32]) -> Option<Vec<Vec<F>>> {
+        let rows = self.fixed_table_contents(tag)?;
+        let width = rows.first().map(|row| row.len()).unwrap_or(0);
+        let value = |offset: i64, column: usize| {
+            usize::try_from(offset)
+                .ok()
+                .and_then(|offset| rows.get(offset))
+                .map(|row| row[column])
+                .unwrap_or(F::ZERO)
+        };
+        let mut contents = (0..rows.len() as i64)
+            .map(|offset| {
+                (0..width)
+                    .flat_map(|column| {
+                        rotations
+                            .iter()
+                            .map(move |rotation| value(offset + *rotation as i64, column))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        contents.push(vec![F::ZERO; width * rotations.len()]);
+        Some(contents)
+    }
+
+    /// The tags of all recorded tables, sorted
+    pub fn tags(&self) -> Vec<T> {
+        let mut tags = self.tables.keys().copied().collect::<Vec<_>>();
+        tags.sort();
+        tags
+    }
+
+    /// Assigns the recorded rows of a table to its columns
+    pub fn assign(
+        &self,
+        layouter: &mut impl Layouter<F>,
+        tag: T,
+        columns: &[Column<Fixed>],
+    ) -> Result<(), Error> {
+        let rows = self
+            .fixed_table_contents(tag)
+            .unwrap_or_else(|| panic!("fixed table {:?} not registered", tag));
+        assign_fixed_rows(layouter, &format!("{:?}", tag), columns, rows)
+    }
+}
+
+/// Serializes as a map from the tag (in debug format) to the rows, with the
+/// values as little-endian hex strings
+#[cfg(feature = "serde")]
+impl<F: Field, T: Copy + Debug + Eq + Hash + Ord> serde::Serialize for FixedTableRegistry<F, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        use crate::util::field_to_hex;
+        let mut map = serializer.serialize_map(Some(self.tables.len()))?;
+        for tag in self.tags() {
+            let rows = self.tables[&tag]
+                .iter()
+                .map(|row| row.iter().map(field_to_hex).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            map.serialize_entry(&format!("{:?}", tag), &rows)?;
+        }
+        map.end()
+    }
+}
+
+/// Fixed column containing `0..2^bits`, e.g. `bits = 8` for byte checks.
+/// Registered with `ConstraintBuilder::load_range_table` and checked against
+/// with `ConstraintBuilder::require_range_lookup`.
+#[derive(Clone, Copy, Debug)]
+pub struct RangeTable {
+    column: Column<Fixed>,
+    bits: usize,
+}
+
+impl RangeTable {
+    pub(crate) fn construct<F: Field>(meta: &mut ConstraintSystem<F>, bits: usize) -> Self {
+        Self {
+            column: meta.fixed_column(),
+            bits,
+        }
+    }
+
+    pub(crate) fn column(&self) -> Column<Fixed> {
+        self.column
+    }
+
+    pub(crate) fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// Number of rows, `2^bits`
+    pub(crate) fn size(&self) -> usize {
+        1 << self.bits
+    }
+
+    /// Assigns `0..2^bits` to the column
+    pub(crate) fn load_witness<F: Field>(&self, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_fixed_range(layouter, self.column, self.size())
+    }
+}
+
+impl<F: Field> LookupTable<F> for RangeTable {
+    fn columns(&self) -> Vec<Column<Any>> {
+        vec![self.column.into()]
+    }
+
+    fn annotations(&self) -> Vec<String> {
+        vec![format!("u{}", self.bits)]
+    }
+}
+
+/// Table of `(1, exponent, r^exponent)` for `exponent` in `0..size`, used to
+/// look up the powers of a challenge `r`. `power` needs to be an advice column
+/// in a phase where `r` is available.
+#[derive(Clone, Debug)]
+pub struct PowerTable {
+    q_enable: Column<Fixed>,
+    q_first: Column<Fixed>,
+    q_step: Column<Fixed>,
+    exponent: Column<Fixed>,
+    power: Column<Advice>,
+    size: usize,
+}
+
+impl PowerTable {
+    pub(crate) fn configure<F: Field>(
+        meta: &mut ConstraintSystem<F>,
+        power: Column<Advice>,
+        r: Expression<F>,
+        size: usize,
+    ) -> Self {
+        let q_enable = meta.fixed_column();
+        let q_first = meta.fixed_column();
+        let q_step = meta.fixed_column();
+        let exponent = meta.fixed_column();
+        meta.create_gate("power table", |meta| {
+            let q_first = meta.query_fixed(q_first, Rotation::cur());
+            let q_step = meta.query_fixed(q_step, Rotation::cur());
+            let power_cur = meta.query_advice(power, Rotation::cur());
+            let power_next = meta.query_advice(power, Rotation::next());
+            vec![
+                ("r^0 == 1", q_first * (power_cur.expr() - 1.expr())),
+                ("r^(i+1) == r^i ⋅ r", q_step * (power_next - power_cur * r)),
+            ]
+        });
+        Self {
+            q_enable,
+            q_first,
+            q_step,
+            exponent,
+            power,
+            size,
+        }
+    }
+
+    /// The table expressions `[q_enable, exponent, power]`
+    pub(crate) fn exprs<F: Field>(&self, meta: &mut ConstraintSystem<F>) -> Vec<Expression<F>> {
+        query_expression(meta, |meta| {
+            vec![
+                meta.query_fixed(self.q_enable, Rotation::cur()),
+                meta.query_fixed(self.exponent, Rotation::cur()),
+                meta.query_advice(self.power, Rotation::cur()),
+            ]
+        })
+    }
+
+    pub(crate) fn assign<F: Field>(
+        &self,
+        layouter: &mut impl Layouter<F>,
+        r: Value<F>,
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "power table",
+            |mut region| {
+                let mut power = Value::known(F::ONE);
+                for offset in 0..self.size {
+                    region.assign_fixed(|| "q_enable", self.q_enable, offset, || Value::known(F::ONE))?;
+                    region.assign_fixed(
+                        || "exponent",
+                        self.exponent,
+                        offset,
+                        || Value::known(F::from(offset as u64)),
+                    )?;
+                    if offset == 0 {
+                        region.assign_fixed(|| "q_first", self.q_first, offset, || Value::known(F::ONE))?;
+                    }
+                    if offset + 1 < self.size {
+                        region.assign_fixed(|| "q_step", self.q_step, offset, || Value::known(F::ONE))?;
+                    }
+                    region.assign_advice(|| "power", self.power, offset, || power)?;
+                    power = power * r;
+                }
+                // All zero row for disabled lookups
+                region.assign_advice(|| "power", self.power, self.size, || Value::known(F::ZERO))?;
+                Ok(())
+            },
+        )
+    }
+}
@@ -0,0 +1,2894 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+use itertools::Itertools;
+use eth_types::Word;
+use zkevm_circuits::util::word::Word as WordExpr;
+use halo2_proofs::{
+    plonk::{ConstraintSystem, Advice, Fixed, FirstPhase, Instance, Column, Error, Expression},
+    circuit::{Layouter, Value},
+    dev::{MockProver, VerifyFailure},
+    poly::Rotation,
+    halo2curves::bn256::Fr,
+};
+
+use crate::prelude::*;
+use crate::{
+    table::{assign_fixed_range, assign_fixed_rows},
+    util::{and, from_bytes, interned_count, query_expression, strip_preamble, sum},
+    cached_region::constrain_publics,
+    cell_manager::WordCell,
+    constraint_builder::{ConditionCellStats, ConditionGuard, HookCtx, LookupData, RegionReport, TableData, TableError},
+};
+use super::{gadgets::TestCellType, mock_prover, RegionCircuit, RegionTest};
+
+// The macros refer to the field as `F`
+type F = Fr;
+
+fn builder(meta: &mut ConstraintSystem<F>, max_degree: usize) -> ConstraintBuilder<F, TestCellType> {
+    let mut cb = ConstraintBuilder::new(max_degree, None, None);
+    let mut cm = CellManager::new(8, 0);
+    cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 4);
+    cb.set_cell_manager(cm);
+    cb
+}
+
+#[test]
+fn dedupe_booleans() {
+    let count = |dedupe: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 4);
+        cb.set_dedupe_booleans(dedupe);
+        circuit!([meta, cb], {
+            // The same cell is required to be boolean three times
+            let q = cb.query_bool();
+            require!(q => bool);
+            ifx!(q.expr() => {
+                require!(q.expr() => 1);
+            } elsex {
+                require!(q.expr() => 0);
+            });
+        });
+        (cb.build_constraints().len(), cb.num_elided_booleans())
+    };
+    assert_eq!(count(false), (5, 0));
+    assert_eq!(count(true), (3, 2));
+}
+
+#[test]
+fn conditions_restored_after_panic() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_condition(2.expr());
+    let identifier = cb.condition_identifier();
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        cb.with_conditions(vec![3.expr()], |cb| {
+            assert_eq!(cb.conditions().len(), 1);
+            cb.push_condition(5.expr());
+            panic!("failed while building constraints");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(cb.conditions().len(), 1);
+    assert_eq!(cb.condition_identifier(), identifier);
+
+    // Guards restore the conditions in the order they were created
+    {
+        let _outer = cb.condition_guard();
+        cb.push_condition(7.expr());
+        let inner = cb.condition_guard();
+        cb.push_condition(11.expr());
+        assert_eq!(cb.conditions().len(), 3);
+        drop(inner);
+        assert_eq!(cb.conditions().len(), 2);
+    }
+    assert_eq!(cb.condition_identifier(), identifier);
+}
+
+#[test]
+fn builder_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<ConstraintBuilder<F, TestCellType>>();
+    assert_send::<ConditionGuard<F>>();
+}
+
+#[test]
+fn lookup_columns_by_name() {
+    let lookup_table = |reorder: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 4);
+        // The consumer is configured before the producer
+        cb.lookup_columns("read", (), &["value", "address"], vec![1.expr(), 2.expr()]);
+        let mut columns = vec![("address", 10.expr()), ("value", 20.expr()), ("is_write", 30.expr())];
+        if reorder {
+            columns.reverse();
+        }
+        cb.store_table_named("memory", (), columns);
+        cb.finalize().unwrap();
+        cb.lookups[0].table().iter().map(|t| t.identifier()).collect::<Vec<_>>()
+    };
+    assert_eq!(lookup_table(false), lookup_table(true));
+}
+
+#[test]
+fn lookup_columns_missing_column() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.store_table_named("memory", (), vec![("address", 10.expr()), ("value", 20.expr())]);
+    cb.lookup_columns("read", (), &["address", "is_write"], vec![1.expr(), 2.expr()]);
+    assert_eq!(
+        cb.finalize(),
+        Err(ConstraintBuilderError::MissingTableColumn {
+            lookup: "read".to_string(),
+            table: "()".to_string(),
+            column: "is_write",
+        })
+    );
+
+    // All errors are returned, and the unresolved lookups are kept
+    cb.lookup_columns("write", (), &["value", "is_write"], vec![1.expr(), 2.expr()]);
+    cb.lookup_columns("load", (), &["value"], vec![1.expr()]);
+    let err = cb.finalize().unwrap_err();
+    assert!(matches!(&err, ConstraintBuilderError::Multiple(errors) if errors.len() == 2));
+    assert_eq!(cb.lookups.len(), 1);
+    assert_eq!(cb.lookups[0].description(), "load");
+    assert!(cb.finalize().is_err());
+}
+
+#[test]
+fn conflicting_schemas() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    assert_eq!(cb.set_table_schema((), &["address", "value"]), Ok(()));
+    assert_eq!(cb.set_table_schema((), &["address", "value"]), Ok(()));
+    assert_eq!(
+        cb.set_table_schema((), &["value", "address"]),
+        Err(ConstraintBuilderError::ConflictingSchema {
+            table: "()".to_string(),
+            schema: vec!["address", "value"],
+            columns: vec!["value", "address"],
+        })
+    );
+    assert_eq!(cb.table_schema(()), Some(&["address", "value"][..]));
+}
+
+#[test]
+fn lookup_to_tag() {
+    let lookup_table = |deferred: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 4);
+        if deferred {
+            // The lookup is added before the table is stored
+            circuit!([meta, cb], {
+                require!("read", (1.expr(), 2.expr()) => @@());
+            });
+            assert_eq!(
+                cb.finalize(),
+                Err(ConstraintBuilderError::MissingTable {
+                    lookup: "read".to_string(),
+                    table: "()".to_string(),
+                })
+            );
+            // The lookup is kept and resolved by the next finalize
+            assert!(cb.lookups.is_empty());
+            cb.store_table("memory", (), vec![10.expr(), 20.expr()]);
+        } else {
+            cb.store_table("memory", (), vec![10.expr(), 20.expr()]);
+            let table = cb.dynamic_table_merged(());
+            cb.add_lookup("read".to_string(), vec![1.expr(), 2.expr()], table);
+        }
+        cb.finalize().unwrap();
+        assert_eq!(cb.lookups.len(), 1);
+        assert_eq!(cb.lookups[0].description(), "read");
+        cb.lookups[0].table().iter().map(|t| t.identifier()).collect::<Vec<_>>()
+    };
+    assert_eq!(lookup_table(true), lookup_table(false));
+}
+
+#[test]
+fn table_fragments() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let identifiers = |table: Vec<Expression<F>>| table.iter().map(|t| t.identifier()).collect::<Vec<_>>();
+
+    assert_eq!(cb.try_table(()).unwrap_err(), TableError::NotFound { tag: "()".to_string() });
+    assert_eq!(cb.try_dynamic_table_merged(()).unwrap_err(), TableError::NotFound { tag: "()".to_string() });
+    assert_eq!(
+        cb.try_dynamic_table_merged_checked(()).unwrap_err(),
+        TableError::NotFound { tag: "()".to_string() }
+    );
+
+    cb.store_table("first", (), vec![10.expr(), 20.expr()]);
+    assert_eq!(identifiers(cb.try_table(()).unwrap()), identifiers(vec![10.expr(), 20.expr()]));
+
+    cb.push_condition(3.expr());
+    cb.store_table("second", (), vec![30.expr(), 40.expr()]);
+    cb.pop_condition();
+    assert_eq!(
+        cb.try_table(()).unwrap_err(),
+        TableError::MultipleFragments { tag: "()".to_string(), fragments: 2 }
+    );
+    // The fragments are merged instead
+    let merged = cb.try_dynamic_table_merged(()).unwrap();
+    assert_eq!(identifiers(cb.table(())), identifiers(merged));
+
+    // The checked merge pads a narrower fragment with the default as well,
+    // and requires the selector to be boolean
+    cb.store_table("third", (), vec![50.expr()]);
+    let padded = cb.dynamic_table_merged_with_default((), 7.expr());
+    let num_constraints = cb.constraints().len();
+    let (selector, values) = cb.dynamic_table_merged_checked_with_default((), 7.expr());
+    assert_eq!(cb.constraints().len(), num_constraints + 1);
+    let selected = values.iter().map(|value| selector.expr() * value.expr()).collect();
+    assert_eq!(identifiers(selected), identifiers(padded));
+}
+
+#[test]
+fn lookup_stats_by_prefix_and_region() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.store_table("first", (), vec![10.expr(), 20.expr()]);
+    cb.store_table("second", (), vec![30.expr(), 40.expr()]);
+    cb.push_region(1, 8);
+    let [x, y] = [0; 2].map(|_| cb.query_one(TestCellType::Storage));
+    cb.add_lookup("x (range)".to_string(), vec![x.expr()], vec![1.expr()]);
+    cb.add_lookup("x (range max)".to_string(), vec![x.expr() * x.expr()], vec![1.expr()]);
+    cb.pop_region();
+    cb.push_region(2, 8);
+    cb.push_condition(y.expr());
+    cb.add_lookup("pair".to_string(), vec![x.expr(), y.expr()], vec![1.expr(), 2.expr()]);
+    cb.pop_condition();
+    cb.pop_region();
+
+    let stats = cb.lookup_stats();
+    assert_eq!(
+        stats.by_prefix.iter().map(|(prefix, stats)| (prefix.as_str(), stats.count)).collect::<Vec<_>>(),
+        [("pair", 1), ("x", 2)]
+    );
+    assert_eq!((stats.by_prefix["x"].max_width, stats.by_prefix["x"].max_degree), (1, 2));
+    assert_eq!((stats.by_region[&2].max_width, stats.by_region[&2].max_degree), (2, 2));
+    assert_eq!(stats.table_fragments["()"], 2);
+    assert_eq!(
+        stats.to_string(),
+        "'pair': 1 lookups, max width 2, max degree 2\n\
+         'x': 2 lookups, max width 1, max degree 2\n\
+         region 1: 2 lookups, max width 1, max degree 2\n\
+         region 2: 1 lookups, max width 2, max degree 2\n\
+         table (): 2 fragments\n"
+    );
+}
+
+#[test]
+fn query_cache() {
+    let build = |cached: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let q_enable = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb = builder(&mut meta, 4);
+        macro_rules! constrain {
+            ($meta:expr) => {
+                circuit!([$meta, cb], {
+                    ifx!(f!(q_enable) => {
+                        require!(a!(x, 1) => a!(x) + a!(x) * a!(x));
+                        require!(a!(x) * f!(q_enable) => a!(x, 1));
+                    });
+                })
+            };
+        }
+        meta.create_gate("Test", |meta| {
+            if cached {
+                let mut qc = query_cache!(meta);
+                constrain!(qc);
+                cb.record_query_cache(&qc);
+            } else {
+                constrain!(meta);
+            }
+            cb.build_constraints()
+        });
+        let identifiers = cb
+            .build_constraints()
+            .iter()
+            .map(|(_, c)| c.identifier())
+            .collect::<Vec<_>>();
+        (identifiers, cb.column_queries())
+    };
+    let (uncached, stats) = build(false);
+    assert_eq!(stats, (0, 0));
+    let (cached, stats) = build(true);
+    assert_eq!(cached, uncached);
+    // q_enable, x and x at the next row are each queried only once
+    assert_eq!(stats, (8, 3));
+}
+
+#[derive(Clone)]
+struct HookConfig {
+    q_test: Column<Fixed>,
+    x: Column<Advice>,
+}
+
+/// Requires `x == 1` on every row, gated by `q_test` through a hook
+#[derive(Clone, Default)]
+struct HookCircuit<const HOOK: bool>;
+
+impl<const HOOK: bool> RegionTest<F> for HookCircuit<HOOK> {
+    type Config = HookConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        meta.create_gate("Test", |meta| {
+            if HOOK {
+                let q_test = meta.query_fixed(q_test, Rotation::cur());
+                cb.add_constraint_hook(Box::new(move |_: &str, constraint: Expression<F>, _: HookCtx| q_test.expr() * constraint));
+            }
+            circuit!([meta, cb], {
+                require!(a!(x) => 1);
+                require!(a!(x) * a!(x) => 1);
+            });
+            cb.build_constraints()
+        });
+        HookConfig { q_test, x }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        for offset in 0..4 {
+            assignf!(region, (config.q_test, offset) => true.scalar())?;
+            assign!(region, (config.x, offset) => 1.scalar())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn constraint_hooks() {
+    // Only the rows enabled by the selector are constrained
+    let prover = mock_prover(6, HookCircuit::<true>);
+    prover.assert_satisfied_par();
+    let prover = mock_prover(6, HookCircuit::<false>);
+    assert!(prover.verify_par().is_err());
+
+    // Hooks compose in registration order and can be removed
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let double = cb.add_constraint_hook(Box::new(|_: &str, constraint: Expression<F>, _: HookCtx| constraint * 2.expr()));
+    cb.add_constraint_hook(Box::new(|_: &str, constraint: Expression<F>, ctx: HookCtx| {
+        assert_eq!(ctx.condition_degree, 0);
+        constraint + 1.expr()
+    }));
+    cb.add_lookup_hook(Box::new(|_: &str, values: Vec<Expression<F>>, _: HookCtx| values.into_iter().rev().collect()));
+    cb.require_zero("a", 3.expr());
+    assert!(cb.remove_hook(double));
+    assert!(!cb.remove_hook(double));
+    cb.require_zero("b", 3.expr());
+    cb.add_lookup("lookup".to_string(), vec![1.expr(), 2.expr()], vec![3.expr(), 4.expr()]);
+
+    let constraints = cb.build_constraints();
+    let id = |expr: Expression<F>| expr.identifier();
+    assert_eq!(constraints[0].1.identifier(), id(3.expr() * 2.expr() + 1.expr()));
+    assert_eq!(constraints[1].1.identifier(), id(3.expr() + 1.expr()));
+    assert_eq!(cb.lookups[0].values()[0].identifier(), id(2.expr()));
+
+    // The equalities of stored expressions go through the hooks as well
+    let mut cb = builder(&mut meta, 4);
+    cb.add_constraint_hook(Box::new(|_: &str, constraint: Expression<F>, _: HookCtx| constraint * 5.expr()));
+    cb.push_region(1, 8);
+    let x = cb.query_default();
+    let stored: Expression<F> = cb.store_expression("x", x.expr() * x.expr(), TestCellType::Storage, None).into();
+    let (name, equality) = cb.constraints()[0].clone();
+    assert!(name.ends_with("(stored expression)"));
+    assert_eq!(equality.identifier(), id((stored - x.expr() * x.expr()) * 5.expr()));
+
+    // And the constraints of the condition cells
+    let hooked = Arc::new(Mutex::new(Vec::new()));
+    let mut cb = builder(&mut meta, 4);
+    let names = hooked.clone();
+    cb.add_constraint_hook(Box::new(move |name: &str, constraint: Expression<F>, _: HookCtx| {
+        names.lock().unwrap().push(name.to_string());
+        constraint
+    }));
+    cb.set_condition_cell_threshold(Some(0));
+    cb.push_region(1, 8);
+    let (a, b, c) = (cb.query_default(), cb.query_default(), cb.query_default());
+    cb.condition(a.expr() * b.expr(), |cb| cb.require_zero("c == 0", c.expr()));
+    cb.pop_region();
+    let hooked = hooked.lock().unwrap();
+    assert_eq!(hooked.len(), cb.constraints().len());
+    assert!(hooked.iter().any(|name| name == "condition cell is boolean"), "{:?}", hooked);
+}
+
+#[test]
+fn reduced_expressions() {
+    let build = |reuse: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 3);
+        cb.push_region(1, 8);
+        let cells = (0..4).map(|_| cb.query_default()).collect::<Vec<_>>();
+        let product = cells[0].expr() * cells[1].expr() * cells[2].expr();
+        let stored = cb.store_expression("product", product.clone(), TestCellType::Storage, None);
+        for _ in 0..4 {
+            let value = if reuse { stored.clone().into() } else { product.clone() };
+            cb.require_zero("reuse", value * cells[3].expr());
+        }
+        let identifiers = cb
+            .build_constraints()
+            .iter()
+            .map(|(_, c)| c.identifier())
+            .collect::<Vec<_>>();
+        (identifiers, cb.num_split_nodes())
+    };
+    let (reused, split_nodes) = build(true);
+    assert_eq!(split_nodes, 0);
+    let (unreduced, split_nodes) = build(false);
+    assert_eq!(split_nodes, 4);
+    assert_eq!(reused, unreduced);
+
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 3);
+    cb.push_region(1, 8);
+    let cells = (0..4).map(|_| cb.query_default()).collect::<Vec<_>>();
+    // Storing a stored cell again doesn't need a new cell
+    let stored = cb.store_expression("a", cells[0].expr() * cells[1].expr(), TestCellType::Storage, None);
+    let num_constraints = cb.build_constraints().len();
+    let again = cb.store_expression("again", stored.expr(), TestCellType::Storage, None);
+    assert_eq!(again.expr().identifier(), stored.expr().identifier());
+    assert_eq!(cb.build_constraints().len(), num_constraints);
+
+    // Marked expressions are never split
+    let product = cells.iter().fold(1.expr(), |acc, cell| acc * cell.expr());
+    let marked = cb.mark_reduced(product.clone());
+    cb.require_zero("marked", marked.into());
+    assert_eq!(cb.build_constraints().last().unwrap().1.identifier(), product.identifier());
+    assert_eq!(cb.num_split_nodes(), 0);
+
+    // The cells of a region can be reused by the next one, so they're no
+    // longer reduced there
+    cb.pop_region();
+    cb.push_region(2, 8);
+    assert!(!cb.is_reduced(&stored.expr()));
+    assert!(!cb.is_reduced(&product));
+}
+
+#[test]
+fn region_attribution() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_condition(2.expr());
+    cb.push_region(1, 8);
+    cb.store_table("table", (), vec![1.expr()]);
+    cb.add_lookup("first".to_string(), vec![1.expr()], vec![1.expr()]);
+    cb.pop_region();
+    cb.pop_condition();
+    cb.push_region(2, 8);
+    cb.add_lookup("second".to_string(), vec![2.expr()], vec![2.expr()]);
+    cb.add_lookup("third".to_string(), vec![3.expr()], vec![3.expr()]);
+    cb.pop_region();
+
+    let descriptions = |region_id: usize| {
+        cb.lookups_in_region(region_id)
+            .map(|lookup| lookup.description().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(descriptions(1), vec!["first"]);
+    assert_eq!(descriptions(2), vec!["second", "third"]);
+    assert_eq!(cb.tables_stored_in_region(1).len(), 1);
+    assert!(cb.tables_stored_in_region(2).is_empty());
+    assert_eq!(cb.lookups_in_region(1).next().unwrap().regional_condition().identifier(), 2.expr().identifier());
+
+    // Pushing region 2 again under a condition doesn't check the lookups of
+    // its earlier, unconditional push
+    cb.push_condition(3.expr());
+    cb.push_region(2, 8);
+    cb.add_lookup("fourth".to_string(), vec![4.expr()], vec![4.expr()]);
+    cb.pop_region();
+    cb.pop_condition();
+    assert_eq!(cb.lookups_in_region(2).count(), 3);
+}
+
+#[test]
+#[should_panic(expected = "missing the region condition")]
+fn region_lookup_without_condition() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_condition(2.expr());
+    cb.push_region(1, 8);
+    // Added directly instead of through the builder
+    #[allow(deprecated)]
+    let lookup = LookupData::from_raw_parts(
+        "lookup".to_string(),
+        vec![1.expr()],
+        vec![1.expr()],
+        1.expr(),
+        1.expr(),
+        1,
+    );
+    cb.lookups.push(lookup);
+    cb.pop_region();
+}
+
+#[test]
+fn lookup_data_constructors() {
+    let lookup = LookupData::<F>::new("lookup".to_string(), vec![1.expr()], vec![2.expr()], 1.expr(), 0.expr(), 3);
+    assert_eq!(lookup.description(), "lookup");
+    assert_eq!(lookup.table()[0].identifier(), 2.expr().identifier());
+    assert_eq!(lookup.region_id(), 3);
+    let table = TableData::<F>::new("table", vec![1.expr(), 2.expr()], 1.expr(), 1.expr(), 0);
+    assert_eq!(table.values().len(), 2);
+
+    // Invalid data is rejected in debug builds
+    if cfg!(debug_assertions) {
+        assert!(catch_unwind(|| TableData::<F>::new("table", vec![], 1.expr(), 1.expr(), 0)).is_err());
+        assert!(catch_unwind(|| TableData::<F>::new("table", vec![1.expr()], 2.expr(), 1.expr(), 0)).is_err());
+        assert!(catch_unwind(|| {
+            LookupData::<F>::new("lookup".to_string(), vec![], vec![], 1.expr(), 1.expr(), 0)
+        }).is_err());
+        assert!(catch_unwind(|| {
+            LookupData::<F>::new("lookup".to_string(), vec![1.expr()], vec![1.expr()], 1.expr(), 3.expr(), 0)
+        }).is_err());
+    }
+}
+
+const BLINDING_K: u32 = 4;
+
+#[derive(Clone)]
+struct BlindingConfig {
+    q_test: Column<Fixed>,
+    usable_rows: std::ops::Range<usize>,
+}
+
+/// Enables a gate on the first `ROWS` rows, with the blinding guard enabled
+#[derive(Clone, Default)]
+struct BlindingCircuit<const ROWS: usize>;
+
+impl<const ROWS: usize> RegionTest<F> for BlindingCircuit<ROWS> {
+    type Config = BlindingConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(4, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 1);
+        cb.set_cell_manager(cm);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                // Queries the only column at 4 rotations
+                let cells = cb.query_cells_dyn(TestCellType::Storage, 4);
+                ifx!(f!(q_test) => {
+                    require!(sum::expr(cells.iter().map(|cell| cell.expr())) => 0);
+                });
+            });
+            cb.build_constraints()
+        });
+        assert_eq!(cb.unusable_rows(), 7);
+        BlindingConfig { q_test, usable_rows: cb.usable_rows(BLINDING_K) }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        region.set_usable_rows(config.usable_rows.clone(), BLINDING_K);
+        for offset in 0..ROWS {
+            assignf!(region, (config.q_test, offset) => true.scalar())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn blinding_rows() {
+    assert!(MockProver::<F>::run(BLINDING_K, &RegionCircuit(BlindingCircuit::<9>), vec![]).is_ok());
+    assert!(matches!(
+        MockProver::<F>::run(BLINDING_K, &RegionCircuit(BlindingCircuit::<10>), vec![]),
+        Err(Error::NotEnoughRowsAvailable { current_k: BLINDING_K })
+    ));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum TupleCellType {
+    Storage,
+    Lookup,
+}
+impl CellType for TupleCellType {
+    type TableType = ();
+
+    fn lookup_table_type(&self) -> Option<Self::TableType> {
+        match self {
+            Self::Lookup => Some(()),
+            _ => None,
+        }
+    }
+    fn byte_type() -> Option<Self> {None}
+    fn create_type(_id: usize) -> Self {unreachable!()}
+    fn storage_for_phase(_phase: u8) -> Self {Self::Storage}
+}
+impl Default for TupleCellType {
+    fn default() -> Self {Self::Storage}
+}
+
+#[derive(Clone)]
+struct TupleConfig {
+    q_test: Column<Fixed>,
+    table: [Column<Fixed>; 2],
+    x: Column<Advice>,
+    cb: ConstraintBuilder<F, TupleCellType>,
+}
+
+/// Looks up `(x, x + 1)` into a table of `(i, i + 1)` without a lookup
+/// challenge
+#[derive(Clone, Default)]
+struct TupleCircuit {
+    x: u64,
+}
+
+impl RegionTest<F> for TupleCircuit {
+    type Config = TupleConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let table = [meta.fixed_column(), meta.fixed_column()];
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TupleCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_uncompressed_tuples(true);
+        let mut cm = CellManager::new(4, 0);
+        cm.add_columns(meta, &mut cb, TupleCellType::Storage, 0, false, 2);
+        cb.set_cell_manager(cm);
+        let table_exprs = query_expression(meta, |meta| {
+            table.iter().map(|column| meta.query_fixed(*column, Rotation::cur())).collect()
+        });
+        cb.store_table("table", (), table_exprs);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_test) => {
+                    require!((a!(x), a!(x) * a!(x) + 1.expr()) =>> @TupleCellType::Lookup);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        TupleConfig { q_test, table, x, cb }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        for i in 0..4 {
+            assignf!(region, (config.table[0], i) => F::from(i as u64))?;
+            assignf!(region, (config.table[1], i) => F::from((i * i + 1) as u64))?;
+        }
+        region.push_region(0, 0);
+        assignf!(region, (config.q_test, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => F::from(self.x))?;
+        region.assign_stored_expressions(&config.cb, &Vec::<Value<F>>::new())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn store_tuple_without_challenge() {
+    // Compressing requires a challenge
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let err = cb.try_store_tuple("tuple", TestCellType::Storage, vec![1.expr()]).unwrap_err();
+    assert_eq!(err, ConstraintBuilderError::MissingLookupChallenge { name: "tuple".to_string() });
+    assert!(err.to_string().starts_with("store_tuple requires a lookup challenge"));
+
+    // Uncompressed tuples have no cell, and the unconditional ones are looked
+    // up without the condition
+    let mut cb: ConstraintBuilder<F, TupleCellType> = ConstraintBuilder::new(4, None, None);
+    cb.set_uncompressed_tuples(true);
+    let mut cm = CellManager::new(4, 0);
+    cm.add_columns(&mut meta, &mut cb, TupleCellType::Storage, 0, false, 2);
+    cb.set_cell_manager(cm);
+    cb.store_table("table", (), vec![1.expr(), 2.expr()]);
+    let condition = cb.condition(3.expr(), |cb| {
+        assert!(cb.store_tuple("conditional", TupleCellType::Lookup, vec![1.expr(), 2.expr()]).is_none());
+        assert!(cb
+            .store_tuple_unconditional("unconditional", TupleCellType::Lookup, vec![1.expr(), 2.expr()])
+            .is_none());
+        cb.condition_identifier()
+    });
+    let conditions = cb
+        .lookups
+        .iter()
+        .map(|lookup| lookup.local_condition().identifier())
+        .collect::<Vec<_>>();
+    assert_eq!(conditions, [condition, 1.expr().identifier()]);
+
+    // Without compression every value is looked up in its own column
+    let prover = mock_prover(6, TupleCircuit { x: 3 });
+    prover.assert_satisfied_par();
+    let prover = mock_prover(6, TupleCircuit { x: 4 });
+    assert!(prover.verify_par().is_err());
+}
+
+#[test]
+fn range_check_without_range_table() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 16);
+    let nibble = cb.query_default();
+    cb.require_range_closed("nibble", nibble.expr(), 15);
+    assert_eq!(cb.constraints()[0].1.degree(), 16);
+    assert_eq!(
+        cb.diagnostics(),
+        ["'nibble': range of 16 values checked with a product of degree 16, no large enough fixed \
+        range table loaded"]
+    );
+}
+
+#[test]
+fn split_without_cell_manager() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let degree_6 = || (0..6).fold(1.expr(), |acc, _| acc * x.expr());
+
+    // Expressions that can't be split are kept as is
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+    cb.push_region(1, 8);
+    cb.require_zero("degree 6", degree_6());
+    assert_eq!(cb.build_constraints()[0].1.identifier(), degree_6().identifier());
+    assert_eq!(cb.diagnostics().len(), 1);
+    assert!(cb.diagnostics()[0].starts_with("'degree 6': degree 6 not split"));
+
+    // Or rejected
+    assert_eq!(
+        cb.try_add_constraint("degree 6", degree_6()),
+        Err(ConstraintBuilderError::CellManagerNotSet {
+            name: "degree 6".to_string(),
+            region_id: 1,
+        })
+    );
+    assert!(cb.try_add_constraint("degree 2", x.expr() * x.expr()).is_ok());
+    assert_eq!(cb.build_constraints().len(), 2);
+
+    // Outside of the regions constraints aren't split at all
+    cb.pop_region();
+    let mut cb: ConstraintBuilder<F, TestCellType> = builder(&mut meta, 4);
+    assert_eq!(
+        cb.try_add_constraint("degree 6", degree_6()),
+        Err(ConstraintBuilderError::DegreeExceeded {
+            name: "degree 6".to_string(),
+            region_id: 0,
+            degree: 6,
+            max_degree: 4,
+        })
+    );
+    assert!(cb.constraints().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Expression degree 6 degree too high in region 1: 6 > 4")]
+fn strict_split_without_cell_manager() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    // A strict builder doesn't keep expressions it can't split
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+    cb.set_strict_degree(true);
+    cb.push_region(1, 8);
+    cb.require_zero("degree 6", (0..6).fold(1.expr(), |acc, _| acc * x.expr()));
+}
+
+#[test]
+fn strict_degree_outside_regions() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let add = |strict: bool, lookup: bool| {
+        catch_unwind(AssertUnwindSafe(|| {
+            let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+            cb.set_strict_degree(strict);
+            let x6 = (0..6).fold(1.expr(), |acc, _| acc * x.expr());
+            if lookup {
+                cb.add_lookup("x⁶ in table".to_string(), vec![x6], vec![x.expr()]);
+            } else {
+                cb.require_zero("x⁶ == 0", x6);
+            }
+        }))
+        .map_err(|err| err.downcast_ref::<String>().cloned().unwrap())
+    };
+    // Only kept as a diagnostic when not strict
+    assert!(add(false, false).is_ok());
+    assert!(add(false, true).is_ok());
+    let err = add(true, false).unwrap_err();
+    assert!(err.contains("x⁶ == 0 degree too high in region 0: 6 > 4"), "{}", err);
+    let err = add(true, true).unwrap_err();
+    assert!(err.contains("x⁶ in table degree too high in region 0: 6 > 4"), "{}", err);
+}
+
+#[test]
+fn canonical_order() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let (a, b) = (meta.fixed_column(), meta.fixed_column());
+    let (a, b): (Expression<F>, Expression<F>) = query_expression(&mut meta, |meta| {
+        (meta.query_fixed(a, Rotation::cur()), meta.query_fixed(b, Rotation::cur()))
+    });
+    let fingerprint = |canonical: bool, swapped: bool| {
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_canonical_order(canonical);
+        let mut constraints = vec![("a", a.expr()), ("b", b.expr() - 1.expr())];
+        let mut lookups = vec![("x", a.expr()), ("y", b.expr())];
+        if swapped {
+            constraints.reverse();
+            lookups.reverse();
+        }
+        for (name, constraint) in constraints {
+            cb.require_zero(name, constraint);
+        }
+        for (description, value) in lookups {
+            cb.add_lookup(description.to_string(), vec![value], vec![a.expr()]);
+        }
+        cb.fingerprint()
+    };
+
+    // Reordering the code changes the fingerprint, unless the order is canonical
+    assert_eq!(fingerprint(false, false), fingerprint(false, false));
+    assert_ne!(fingerprint(false, false), fingerprint(false, true));
+    assert_eq!(fingerprint(true, false), fingerprint(true, true));
+    // Canonical order is the order of the names here
+    assert_eq!(fingerprint(true, false), fingerprint(false, false));
+}
+
+crate::cell_type! {
+    enum DynamicCellType: () {
+        storage: [StoragePhase1],
+        lookups: [Lookup => ()],
+    }
+}
+
+#[test]
+fn dynamic_cell_types() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb: ConstraintBuilder<F, DynamicCellType> = ConstraintBuilder::new(4, None, None);
+    let (t0, t1) = (cb.new_dynamic_cell_type(), cb.new_dynamic_cell_type());
+    assert_eq!((t0, t1), (DynamicCellType::Dynamic(0), DynamicCellType::Dynamic(1)));
+    assert_eq!(DynamicCellType::default(), DynamicCellType::StoragePhase1);
+    assert_eq!(DynamicCellType::byte_type(), None);
+    assert_eq!(DynamicCellType::Lookup.lookup_table_type(), Some(()));
+    assert_eq!(t0.lookup_table_type(), None);
+
+    let mut cm = CellManager::new(4, 0);
+    cm.add_columns(&mut meta, &mut cb, DynamicCellType::StoragePhase1, 0, false, 1);
+    cm.add_columns(&mut meta, &mut cb, t0, 0, false, 1);
+    cm.add_columns(&mut meta, &mut cb, t1, 0, false, 2);
+    let columns = |cm: &CellManager<F, DynamicCellType>, cell_type| {
+        cm.get_typed_columns(cell_type).iter().map(|column| column.column).collect::<Vec<_>>()
+    };
+    let (t0_columns, t1_columns) = (columns(&cm, t0), columns(&cm, t1));
+    assert_eq!((t0_columns.len(), t1_columns.len()), (1, 2));
+    cb.set_cell_manager(cm);
+
+    cb.push_region(1, 4);
+    let cells = cb.query_cells_dyn(t1, 2);
+    assert!(cells.iter().all(|cell| t1_columns.contains(&cell.column())));
+    assert_eq!(cb.query_one(t0).column(), t0_columns[0]);
+    assert_eq!(
+        cb.cell_manager.as_ref().unwrap().get_stats().keys().copied().collect::<Vec<_>>(),
+        vec![DynamicCellType::StoragePhase1, t0, t1]
+    );
+
+    // Types stay unique after restarting the builder
+    cb.restart();
+    assert_eq!(cb.new_dynamic_cell_type(), DynamicCellType::Dynamic(2));
+}
+
+#[test]
+fn adjacent_columns() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb: ConstraintBuilder<F, DynamicCellType> = ConstraintBuilder::new(4, None, None);
+    let (a, b) = (cb.new_dynamic_cell_type(), cb.new_dynamic_cell_type());
+    let storage = DynamicCellType::StoragePhase1;
+    let mut cm = CellManager::new(4, 0);
+    cm.add_columns(&mut meta, &mut cb, a, 0, true, 2);
+    cm.add_columns(&mut meta, &mut cb, storage, 0, false, 1);
+    cm.prefer_adjacent(a, b);
+    // Also interleaves the columns added after the preference
+    cm.add_columns(&mut meta, &mut cb, b, 0, true, 2);
+    let types = cm.columns().iter().map(|column| column.cell_type).collect::<Vec<_>>();
+    assert_eq!(types, vec![a, b, a, b, storage]);
+    assert_eq!(cm.adjacency_stats(), vec![((a, b), 2, 2)]);
+    cb.set_cell_manager(cm);
+
+    cb.push_region(1, 4);
+    let mut cells = vec![cb.query_one(a)];
+    let pairs = (0..3).map(|_| cb.query_adjacent_pair(a, b)).collect::<Vec<_>>();
+    cells.extend(cb.query_cells_dyn(b, 2));
+    let column_index = |cell: &Cell<F>| {
+        let cm = cb.cell_manager.as_ref().unwrap();
+        cm.columns().iter().position(|column| column.column == cell.column()).unwrap()
+    };
+    for (lhs, rhs) in pairs.iter() {
+        assert_eq!(lhs.rotation(), rhs.rotation());
+        assert_eq!(column_index(lhs).abs_diff(column_index(rhs)), 1);
+        assert_eq!(types[column_index(lhs)], a);
+        assert_eq!(types[column_index(rhs)], b);
+    }
+    // The pairs are lined up without reusing any slot
+    cells.extend(pairs.into_iter().flat_map(|(lhs, rhs)| [lhs, rhs]));
+    assert!(cells.iter().map(|cell| (cell.column(), cell.rotation())).all_unique());
+    cb.pop_region();
+
+    let report = cb.report();
+    assert_eq!(report.adjacent_columns, (2, 2));
+    assert!(report.to_string().contains("adjacent columns: 2 of 2 pairs\n"));
+}
+
+#[test]
+fn condition_guard() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let add = |cb: &mut ConstraintBuilder<F, TestCellType>, early: bool| -> Option<()> {
+        let mut cb = cb.guard(3.expr());
+        cb.require_zero("guarded", 1.expr());
+        if early {
+            return None;
+        }
+        let mut cb = cb.guard(5.expr());
+        cb.require_zero("nested", 1.expr());
+        assert_eq!(cb.conditions().len(), 2);
+        Some(())
+    };
+    // Returning early drops the guard, so the condition is popped
+    assert_eq!(add(&mut cb, true), None);
+    assert!(cb.conditions().is_empty());
+    assert_eq!(add(&mut cb, false), Some(()));
+    assert!(cb.conditions().is_empty());
+
+    let constraints = cb.build_constraints();
+    assert_eq!(constraints.len(), 3);
+    let guarded = |conditions: Vec<Expression<F>>| (and::expr(conditions) * 1.expr()).identifier();
+    assert_eq!(constraints[0].1.identifier(), guarded(vec![3.expr()]));
+    assert_eq!(constraints[2].1.identifier(), guarded(vec![3.expr(), 5.expr()]));
+}
+
+/// Total length of the constraint names of 50k constraints added with
+/// `require!`, and the names
+fn constraint_names(disable_description: bool) -> (usize, Vec<&'static str>) {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+    cb.set_disable_description(disable_description);
+    circuit!([meta, cb], {
+        for _ in 0..50_000 {
+            require!(x.expr() => 1.expr());
+        }
+    });
+    let names = cb.build_constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    (names.iter().map(|name| name.len()).sum(), names)
+}
+
+#[test]
+fn disabled_descriptions() {
+    let (described, _) = constraint_names(false);
+    let (counted, names) = constraint_names(true);
+    assert_eq!(names[0], "#0");
+    assert_eq!(names[49_999], "#49999");
+    assert!(counted * 5 < described, "{} vs {} bytes", counted, described);
+}
+
+#[derive(Clone)]
+struct DescriptionConfig {
+    q_test: Column<Fixed>,
+    x: Column<Advice>,
+}
+
+#[derive(Clone, Default)]
+struct DescriptionCircuit;
+
+impl RegionTest<F> for DescriptionCircuit {
+    type Config = DescriptionConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_disable_description(true);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_test) => {
+                    require!(a!(x) => 1.expr());
+                    require!(a!(x) => 2.expr());
+                });
+            });
+            cb.build_constraints()
+        });
+        DescriptionConfig { q_test, x }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_test, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => 1.scalar())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn disabled_descriptions_in_failures() {
+    let prover = mock_prover(4, DescriptionCircuit);
+    let failures = prover.verify_par().unwrap_err();
+    assert_eq!(failures.len(), 1);
+    // The failing constraint is the second one
+    assert!(failures[0].to_string().contains("#1"), "{}", failures[0]);
+}
+
+#[derive(Clone)]
+struct DifferentConfig {
+    q_test: Column<Fixed>,
+    a: Column<Advice>,
+    b: Column<Advice>,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Requires `a != b` on rows where `q_test` is enabled
+#[derive(Clone, Default)]
+struct DifferentCircuit {
+    rows: Vec<(bool, u64, u64)>,
+}
+
+impl RegionTest<F> for DifferentCircuit {
+    type Config = DifferentConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let (a, b) = (meta.advice_column(), meta.advice_column());
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 1);
+        cb.set_cell_manager(cm);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_test) => {
+                    cb.require_different("a != b", a!(a), a!(b));
+                });
+            });
+            cb.build_constraints()
+        });
+        DifferentConfig { q_test, a, b, cb }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        for (offset, (enabled, a, b)) in self.rows.iter().enumerate() {
+            assignf!(region, (config.q_test, offset) => (*enabled).scalar())?;
+            assign!(region, (config.a, offset) => F::from(*a))?;
+            assign!(region, (config.b, offset) => F::from(*b))?;
+            region.assign_stored_expressions_at(&config.cb, 0, offset, &Vec::<Value<F>>::new())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn require_different() {
+    let verify = |rows: Vec<(bool, u64, u64)>| {
+        mock_prover(5, DifferentCircuit { rows }).verify_par()
+    };
+    assert!(verify(vec![(true, 1, 2), (true, 7, 3)]).is_ok());
+    assert!(verify(vec![(true, 1, 2), (true, 3, 3)]).is_err());
+    // Equal values are fine when the condition is off
+    assert!(verify(vec![(true, 1, 2), (false, 3, 3)]).is_ok());
+}
+
+#[test]
+fn named_regions() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_named_region(1, "header", 8);
+    let cells = cb.query_cells_dyn(TestCellType::Storage, 3);
+    cb.require_zero("header", cells[0].expr());
+    cb.store_expression("stored", cells[1].expr() * cells[2].expr(), TestCellType::Storage, None);
+    cb.add_lookup("header lookup".to_string(), vec![cells[0].expr()], vec![1.expr()]);
+    cb.pop_region();
+    cb.push_named_region(2, "body", 8);
+    let cell = cb.query_one(TestCellType::Storage);
+    cb.require_zero("body", cell.expr());
+    cb.pop_region();
+
+    assert_eq!(cb.region_label(1), Some("header"));
+    assert_eq!(cb.region_label(3), None);
+    // The stored expression is a constraint and a cell of the first region
+    assert_eq!(
+        cb.region_report(),
+        vec![
+            RegionReport { region_id: 1, label: Some("header"), constraints: 2, lookups: 1, cells: 4 },
+            RegionReport { region_id: 2, label: Some("body"), constraints: 1, lookups: 0, cells: 1 },
+        ]
+    );
+    assert_eq!(cb.get_stored_expressions_by_label("header").len(), 1);
+    assert!(cb.get_stored_expressions_by_label("body").is_empty());
+}
+
+#[test]
+fn exported_cells() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let digest = cb.query_one(TestCellType::Storage);
+    let scratch = cb.query_one(TestCellType::Storage);
+    cb.export_cell("digest", &digest);
+    cb.pop_region();
+
+    cb.push_region(2, 8);
+    let cells = cb.query_cells_dyn(TestCellType::Storage, 4);
+    let imported = cb.import_cell("digest").unwrap();
+    assert_eq!((imported.column, imported.rotation), (digest.column, digest.rotation));
+    // The exported slot is never handed out again
+    assert!(cells
+        .iter()
+        .all(|cell| (cell.column, cell.rotation) != (digest.column, digest.rotation)));
+    assert!(cb.check_cell(&imported).is_ok());
+    assert_eq!(
+        cb.import_cell("missing").unwrap_err(),
+        ConstraintBuilderError::MissingExport { name: "missing".to_string(), region_id: 2 }
+    );
+    // The slot of the cell that wasn't exported now belongs to a cell of
+    // this region
+    assert!(cells
+        .iter()
+        .any(|cell| (cell.column, cell.rotation) == (scratch.column, scratch.rotation)));
+    assert_eq!(
+        cb.check_cell(&scratch).unwrap_err(),
+        ConstraintBuilderError::StaleCell { cell: scratch.to_string(), region_id: 2 }
+    );
+    cb.pop_region();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "'a': advice[0]@0 (Storage) used in region 2 was freed or allocated again")]
+fn stale_cell_in_constraint() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let a = cb.query_one(TestCellType::Storage);
+    cb.pop_region();
+    cb.push_region(2, 8);
+    cb.require_zero("a", a.expr());
+}
+
+#[test]
+fn global_subexpression_sharing() {
+    let stored = |sharing: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb: ConstraintBuilder<F, DynamicCellType> = ConstraintBuilder::new(4, None, None);
+        let t0 = cb.new_dynamic_cell_type();
+        let mut cm = CellManager::new(8, 0);
+        cm.add_columns(&mut meta, &mut cb, DynamicCellType::StoragePhase1, 0, false, 2);
+        cm.add_columns(&mut meta, &mut cb, t0, 0, false, 1);
+        cb.set_cell_manager(cm);
+        cb.set_global_subexpression_sharing(sharing);
+
+        cb.push_region(1, 8);
+        let x = cb.query_cells_dyn(DynamicCellType::StoragePhase1, 5);
+        let x4 = x[0].expr() * x[1].expr() * x[2].expr() * x[3].expr();
+        cb.store_expression("x4", x4.clone(), t0, None);
+        // Two constraints sharing a degree 5 product
+        cb.require_zero("x5", x4.clone() * x[4].expr() - 1.expr());
+        cb.require_zero("x5 * x", x4 * x[4].expr() * x[0].expr());
+        cb.pop_region();
+        cb.get_stored_expressions(1).len()
+    };
+    // Without sharing the product is stored once more in a storage cell
+    assert_eq!(stored(false), 2);
+    assert_eq!(stored(true), 1);
+}
+
+#[test]
+fn restart_keeping_tables() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let columns: [Column<Fixed>; 2] = [meta.fixed_column(), meta.fixed_column()];
+    let mut cb = builder(&mut meta, 4);
+    cb.load_table(&mut meta, (), &columns);
+    let table = cb.table(());
+    cb.push_region(1, 8);
+    let cell = cb.query_one(TestCellType::Storage);
+    cb.store_table("dynamic", (), vec![cell.expr(), cell.expr()]);
+    cb.require_zero("cell", cell.expr());
+    cb.pop_region();
+    assert_eq!(cb.tables[&()].len(), 2);
+
+    cb.restart_keeping_tables();
+    assert!(cb.constraints().is_empty());
+    // Only the loaded table is kept
+    let ids = |exprs: Vec<Expression<F>>| exprs.iter().map(|expr| expr.identifier()).collect::<Vec<_>>();
+    assert_eq!(ids(cb.table(())), ids(table));
+
+    cb.restart();
+    assert!(cb.tables.is_empty());
+}
+
+#[test]
+fn strict_queries() {
+    let configure = |register: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 4);
+        let q_enable = meta.fixed_column();
+        let x = meta.advice_column();
+        cb.set_strict_queries(true);
+        cb.register_external_column(q_enable);
+        if register {
+            cb.register_external_column(x);
+        }
+        let cell = cb.query_one(TestCellType::Storage);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    require!(a!(x) => cell.expr());
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.finalize()
+    };
+    assert_eq!(configure(true), Ok(()));
+    // advice[0..4] belong to the cell manager
+    let err = configure(false).unwrap_err();
+    assert!(matches!(
+        &err,
+        ConstraintBuilderError::UnregisteredColumn { column, constraints, tracked: true }
+            if column == "advice[4]" && constraints.len() == 1
+    ));
+}
+
+#[derive(Clone)]
+struct InstanceConfig {
+    cell: Cell<F>,
+    instance: Column<Instance>,
+}
+
+#[derive(Clone, Default)]
+struct InstanceCircuit {
+    value: u64,
+}
+
+impl RegionTest<F> for InstanceCircuit {
+    type Config = InstanceConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let instance = meta.instance_column();
+        let mut cb = builder(meta, 4);
+        let cell = cb.query_one(TestCellType::Storage);
+        cb.enable_equality(cell.column());
+        // Registered by two gadgets, enabled once
+        cb.enable_equality_any(instance);
+        cb.enable_equality_any(instance);
+        cb.build_equalities(meta);
+        InstanceConfig { cell, instance }
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        let assigned = layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assign!(&mut region, config.cell, 0 => F::from(self.value))
+            }
+        )?;
+        layouter.constrain_instance(assigned.cell(), config.instance, 0)
+    }
+}
+
+#[test]
+fn equality_on_instance_column() {
+    let verify = |value: u64, public: u64| {
+        let circuit = RegionCircuit(InstanceCircuit { value });
+        MockProver::<F>::run(5, &circuit, vec![vec![F::from(public)]]).unwrap().verify_par()
+    };
+    assert!(verify(7, 7).is_ok());
+    assert!(verify(7, 8).is_err());
+}
+
+#[derive(Clone)]
+struct DeferredConfig {
+    q_enable: Column<Fixed>,
+    accumulators: (Cell<F>, Cell<F>),
+}
+
+#[derive(Clone, Default)]
+struct DeferredCircuit {
+    accumulators: (u64, u64),
+}
+
+impl RegionTest<F> for DeferredCircuit {
+    type Config = DeferredConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(2, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 1);
+        cb.set_cell_manager(cm);
+        let q = query_expression(meta, |meta| meta.query_fixed(q_enable, Rotation::cur()));
+
+        cb.defer("accumulators", Box::new(move |cb: &mut ConstraintBuilder<F, TestCellType>| {
+            let (a, b) = (cb.import_cell("a").unwrap(), cb.import_cell("b").unwrap());
+            cb.condition(q.expr(), |cb| cb.require_equal("a == b", a.expr(), b.expr()));
+        }));
+        for (region_id, name) in [(1, "a"), (2, "b")] {
+            cb.push_region(region_id, 2);
+            let accumulator = cb.query_one(TestCellType::Storage);
+            cb.export_cell(name, &accumulator);
+            cb.pop_region();
+        }
+        cb.finalize().unwrap();
+        assert_eq!(cb.deferred_constraints(), &[("accumulators", 1)]);
+        meta.create_gate("Test", |_| cb.build_constraints());
+
+        let accumulators = (cb.import_cell("a").unwrap(), cb.import_cell("b").unwrap());
+        DeferredConfig { q_enable, accumulators }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, config.accumulators.0, 0 => F::from(self.accumulators.0))?;
+        assign!(region, config.accumulators.1, 0 => F::from(self.accumulators.1))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn deferred_constraints() {
+    let verify = |accumulators: (u64, u64)| {
+        mock_prover(5, DeferredCircuit { accumulators }).verify_par()
+    };
+    assert!(verify((5, 5)).is_ok());
+    assert!(verify((5, 6)).is_err());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "building constraints with 1 deferred groups that didn't run")]
+fn deferred_constraints_not_run() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.defer("group", Box::new(|cb: &mut ConstraintBuilder<F, TestCellType>| cb.require_zero("zero", 0.expr())));
+    cb.build_constraints();
+}
+
+#[derive(Clone)]
+struct PublicConfig {
+    q_enable: Column<Fixed>,
+    cb: ConstraintBuilder<F, TestCellType>,
+    cells: (Cell<F>, Cell<F>),
+}
+
+#[derive(Clone, Default)]
+struct PublicCircuit {
+    value: u64,
+}
+
+impl RegionTest<F> for PublicCircuit {
+    type Config = PublicConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let instance = meta.instance_column();
+        let mut cb = builder(meta, 4);
+        let (x, x2) = (cb.query_one(TestCellType::Storage), cb.query_one(TestCellType::Storage));
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    require!(x2.expr() => x.expr() * x.expr());
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.expose_public("x", x.clone(), instance, 0);
+        cb.expose_public("x^2", x2.clone(), instance, 1);
+        cb.build_equalities(meta);
+        PublicConfig { q_enable, cb, cells: (x, x2) }
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        let publics = layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (config.q_enable, 0) => true.scalar())?;
+                assign!(&mut region, config.cells.0, 0 => F::from(self.value))?;
+                assign!(&mut region, config.cells.1, 0 => F::from(self.value * self.value))?;
+                region.public_cells(&config.cb, 0)
+            }
+        )?;
+        constrain_publics(layouter, &publics)
+    }
+}
+
+#[test]
+fn expose_public() {
+    let verify = |value: u64, publics: Vec<u64>| {
+        let instance = publics.into_iter().map(F::from).collect();
+        MockProver::<F>::run(5, &RegionCircuit(PublicCircuit { value }), vec![instance]).unwrap().verify_par()
+    };
+    assert!(verify(3, vec![3, 9]).is_ok());
+    assert!(verify(3, vec![3, 10]).is_err());
+    assert!(verify(3, vec![4, 9]).is_err());
+}
+
+#[derive(Clone)]
+struct WordConfig {
+    q_enable: Column<Fixed>,
+    bytes: [Cell<F>; 32],
+    words: (WordCell<F>, WordCell<F>),
+}
+
+#[derive(Clone, Default)]
+struct WordCircuit {
+    bytes: [u8; 32],
+    words: (Word, Word),
+}
+
+impl RegionTest<F> for WordCircuit {
+    type Config = WordConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(8, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 5);
+        cb.set_cell_manager(cm);
+        let bytes: [Cell<F>; 32] = cb.query_cells_dyn(TestCellType::Storage, 32).try_into().unwrap();
+        let words = (cb.query_word(), cb.query_word_with_type(TestCellType::Storage));
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let limbs = WordExpr::new([from_bytes::expr(&bytes[..16]), from_bytes::expr(&bytes[16..])]);
+                    cb.require_equal_word("word == bytes", words.0.expr(), limbs);
+                    require!("word == word", words.0.expr() => word words.1.expr());
+                });
+            });
+            cb.build_constraints()
+        });
+        WordConfig { q_enable, bytes, words }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        for (cell, byte) in config.bytes.iter().zip(self.bytes) {
+            cell.assign(region, 0, F::from(byte as u64))?;
+        }
+        config.words.0.assign(region, 0, self.words.0)?;
+        config.words.1.assign(region, 0, self.words.1)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn word_cells() {
+    let verify = |bytes: [u8; 32], words: (Word, Word)| {
+        mock_prover(5, WordCircuit { bytes, words }).verify_par()
+    };
+    let bytes: [u8; 32] = std::array::from_fn(|i| 0xff - i as u8);
+    let word = Word::from_little_endian(&bytes);
+    assert!(verify(bytes, (word, word)).is_ok());
+    assert!(verify(bytes, (word, word - 1)).is_err());
+    assert!(verify(bytes, (word + 1, word + 1)).is_err());
+    // Only the high limb differs
+    let high = word - (Word::one() << 128);
+    assert!(verify(bytes, (word, high)).is_err());
+}
+
+#[test]
+fn word_limb_failures() {
+    let failures = |words: (Word, Word)| {
+        let bytes: [u8; 32] = std::array::from_fn(|i| 0xff - i as u8);
+        assert_eq!(Word::from_little_endian(&bytes), words.0);
+        MockProver::<F>::run(5, &RegionCircuit(WordCircuit { bytes, words }), vec![])
+            .unwrap()
+            .verify_par()
+            .unwrap_err()
+            .iter()
+            .map(|failure| failure.to_string())
+            .collect::<Vec<_>>()
+    };
+    let word = Word::from_little_endian(&std::array::from_fn(|i| 0xff - i as u8));
+    for (other, limb) in [(word - 1, "(lo)"), (word - (Word::one() << 128), "(hi)")] {
+        let failures = failures((word, other));
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].contains(&format!("word == word {}", limb)), "{}", failures[0]);
+    }
+}
+
+#[test]
+fn word_limbs() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let [q, a, b, c] = [0; 4].map(|_| cb.query_default());
+    cb.push_region(1, 8);
+    circuit!([(), cb], {
+        ifx!(q => {
+            // Only the low limb is above the max degree
+            let lo = a.expr() * a.expr() * a.expr() * a.expr();
+            require!("w", (lo, b.expr()) => word (c.expr(), b.expr()));
+        });
+    });
+    cb.pop_region();
+
+    let limbs = cb
+        .constraints()
+        .iter()
+        .filter(|(name, _)| name.starts_with("w "))
+        .map(|(name, constraint)| (*name, constraint.degree()))
+        .collect::<Vec<_>>();
+    assert_eq!(limbs.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["w (lo)", "w (hi)"]);
+    assert!(limbs[0].1 <= 4);
+    // The high limb is only multiplied with the condition
+    assert_eq!(limbs[1].1, 2);
+    let stored = cb.get_stored_expressions(1);
+    assert!(!stored.is_empty());
+    assert!(stored.iter().all(|stored| stored.name.starts_with("w (lo) ")));
+}
+
+#[test]
+fn cell_operators() {
+    let constraints = |bare: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 4);
+        circuit!([meta, cb], {
+            let cells = cb.query_cells_dyn(TestCellType::Storage, 3);
+            let (a, b, c) = (&cells[0], &cells[1], &cells[2]);
+            if bare {
+                require!(a + b => c * 2.expr());
+                require!(a * b - c => -a);
+                require!(a * 3 => 1.expr() - b);
+            } else {
+                require!(a.expr() + b.expr() => c.expr() * 2.expr());
+                require!(a.expr() * b.expr() - c.expr() => -a.expr());
+                require!(a.expr() * 3.expr() => 1.expr() - b.expr());
+            }
+        });
+        cb.build_constraints()
+            .iter()
+            .map(|(_, constraint)| constraint.identifier())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(constraints(true), constraints(false));
+    assert_eq!(constraints(true).len(), 3);
+}
+
+#[derive(Clone)]
+struct AllZeroConfig {
+    q_test: Column<Fixed>,
+    x: Column<Advice>,
+    flag: Column<Advice>,
+}
+
+/// Requires the 4 `x` values to be zero, either one by one or folded with a
+/// challenge, and the 4 flags to be boolean
+#[derive(Clone, Default)]
+struct AllZeroCircuit<const RLC: bool> {
+    values: [u64; 4],
+    flags: [u64; 4],
+}
+
+impl<const RLC: bool> RegionTest<F> for AllZeroCircuit<RLC> {
+    type Config = AllZeroConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let (x, flag) = (meta.advice_column(), meta.advice_column());
+        let challenge = meta.challenge_usable_after(FirstPhase);
+        let r = query_expression(meta, |meta| meta.query_challenge(challenge));
+        let mut cb: ConstraintBuilder<F, TestCellType> =
+            ConstraintBuilder::new(4, None, if RLC { Some(r) } else { None });
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_test) => {
+                    let values = (0..4).map(|rot| a!(x, rot)).collect::<Vec<_>>();
+                    if RLC {
+                        cb.require_all_zero_rlc("x", values);
+                    } else {
+                        cb.require_all_zero("x", values);
+                    }
+                    cb.require_all_boolean("flag", (0..4).map(|rot| a!(flag, rot)).collect());
+                });
+            });
+            let names = cb.build_constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            if RLC {
+                assert_eq!(names, ["x", "flag[0]", "flag[1]", "flag[2]", "flag[3]"]);
+            } else {
+                assert_eq!(names[..4], ["x[0]", "x[1]", "x[2]", "x[3]"]);
+            }
+            cb.build_constraints()
+        });
+        AllZeroConfig { q_test, x, flag }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_test, 0) => true.scalar())?;
+        for offset in 0..4 {
+            assign!(region, (config.x, offset) => F::from(self.values[offset]))?;
+            assign!(region, (config.flag, offset) => F::from(self.flags[offset]))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn require_all() {
+    let verify = |values: [u64; 4], flags: [u64; 4], rlc: bool| {
+        let prover = if rlc {
+            MockProver::<F>::run(5, &RegionCircuit(AllZeroCircuit::<true> { values, flags }), vec![])
+        } else {
+            MockProver::<F>::run(5, &RegionCircuit(AllZeroCircuit::<false> { values, flags }), vec![])
+        };
+        prover.unwrap().verify_par().is_ok()
+    };
+    for rlc in [false, true] {
+        assert!(verify([0; 4], [0, 1, 1, 0], rlc));
+        // A single non-zero value is caught
+        assert!(!verify([0, 0, 3, 0], [0, 1, 1, 0], rlc));
+        // A single non-boolean flag is caught
+        assert!(!verify([0; 4], [0, 1, 2, 0], rlc));
+    }
+}
+
+#[test]
+fn interned_descriptions() {
+    let configure = || {
+        let mut meta = ConstraintSystem::<F>::default();
+        DifferentCircuit::configure(&mut meta);
+        AllZeroCircuit::<false>::configure(&mut meta);
+        meta
+    };
+    configure();
+    let interned = interned_count();
+    // Configuring again reuses the same descriptions
+    configure();
+    assert_eq!(interned_count(), interned);
+
+    let mut meta = ConstraintSystem::<F>::default();
+    let cb = builder(&mut meta, 4);
+    assert!(std::ptr::eq(cb.intern("x[0]"), cb.intern(&format!("x[{}]", 0))));
+}
+
+#[test]
+fn original_descriptions() {
+    let names = |disable_description: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let column = meta.fixed_column();
+        let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_disable_description(disable_description);
+        circuit!([meta, cb], {
+            cb.require_equal("x == 1", x.expr(), 1.expr());
+            require!(x.expr() => 2.expr());
+            cb.add_lookup("x in table".to_string(), vec![x.expr()], vec![x.expr()]);
+        });
+        let names = cb.build_constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        (cb, names)
+    };
+    let (_, described) = names(false);
+    assert_eq!(described[0], "x == 1");
+    assert!(described[1].ends_with("x.expr() => 2.expr()"), "{}", described[1]);
+
+    let (mut cb, counted) = names(true);
+    assert_eq!(counted, ["#0", "#1"]);
+    assert_eq!(cb.original_description("#0").as_deref(), Some("x == 1"));
+    // The macro description was never formatted, only the call site is known
+    let called_at = cb.original_description("#1").unwrap();
+    assert!(called_at.starts_with(concat!(file!(), ":")), "{}", called_at);
+    assert_eq!(cb.original_description("#2").as_deref(), Some("x in table"));
+    assert_eq!(cb.original_description("#3"), None);
+    assert_eq!(cb.original_description("x == 1"), None);
+
+    // The ids start over after a restart
+    cb.restart();
+    assert_eq!(cb.original_description("#0"), None);
+    cb.require_equal("x == 2", 2.expr(), 2.expr());
+    assert_eq!(cb.build_constraints()[0].0, "#0");
+    assert_eq!(cb.original_description("#0").as_deref(), Some("x == 2"));
+}
+
+#[derive(Clone)]
+struct ChecklistConfig {
+    cells: [Cell<F>; 3],
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Requires `c == a ⋅ b` with `a ⋅ b` stored, optionally leaving `c`
+/// unassigned
+#[derive(Clone, Default)]
+struct ChecklistCircuit {
+    skip_c: bool,
+}
+
+impl RegionTest<F> for ChecklistCircuit {
+    type Config = ChecklistConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let mut cb = builder(meta, 4);
+        let cells = ["a", "b", "c"].map(|name| cb.try_query_one(name, TestCellType::Storage).unwrap());
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                let [a, b, c] = &cells;
+                let ab = cb.store_expression("a⋅b", a.expr() * b.expr(), TestCellType::Storage, None);
+                require!(c => ab.expr());
+            });
+            cb.build_constraints()
+        });
+        ChecklistConfig { cells, cb }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        let [a, b, c] = &config.cells;
+        assign!(region, a, 0 => 2.scalar())?;
+        assign!(region, b, 0 => 3.scalar())?;
+        if !self.skip_c {
+            assign!(region, c, 0 => 6.scalar())?;
+        }
+        region.assign_stored_expressions_at(&config.cb, 0, 0, &Vec::<Value<F>>::new())?;
+        region.assert_checklist_complete(&config.cb.assignment_checklist(0), 0);
+        Ok(())
+    }
+}
+
+#[test]
+fn assignment_checklist() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let config = ChecklistCircuit::configure(&mut meta);
+    let names = config.cb.assignment_checklist(0).into_iter().map(|cell| cell.name).collect::<Vec<_>>();
+    assert_eq!(names, ["a", "b", "c", "a⋅b"]);
+
+    let prover = mock_prover(5, ChecklistCircuit { skip_c: false });
+    assert!(prover.verify_par().is_ok());
+
+    let err = catch_unwind(AssertUnwindSafe(|| {
+        MockProver::<F>::run(5, &RegionCircuit(ChecklistCircuit { skip_c: true }), vec![])
+    }))
+    .unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains("'c' (Storage, advice["), "{}", message);
+    assert!(!message.contains("'a'") && !message.contains("'a⋅b'"), "{}", message);
+}
+
+#[test]
+fn set_max_degree() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let pow = |n: usize| (1..n).fold(x.expr(), |acc, _| acc * x.expr());
+
+    // Raised before the first region
+    let mut cb = builder(&mut meta, 4);
+    cb.set_max_degree(9);
+    cb.require_zero("x^7", pow(7));
+    assert_eq!(cb.num_split_nodes(), 0);
+    assert_eq!(cb.build_constraints()[0].1.degree(), 7);
+
+    // Raised in a region with a condition of degree 1
+    let mut cb = builder(&mut meta, 4);
+    cb.push_condition(x.expr());
+    cb.push_region(1, 8);
+    cb.set_max_degree(9);
+    cb.require_zero("x^8", pow(8));
+    assert_eq!(cb.num_split_nodes(), 0);
+    cb.require_zero("x^9", pow(9));
+    assert!(cb.num_split_nodes() > 0);
+    cb.pop_region();
+}
+
+#[test]
+fn cell_manager_snapshot() {
+    let slots = |cells: &[Cell<F>]| {
+        cells.iter().map(|cell| (cell.column().index(), cell.rotation())).collect::<Vec<_>>()
+    };
+    let disjoint = |a: &[(usize, usize)], b: &[(usize, usize)]| a.iter().all(|slot| !b.contains(slot));
+
+    // By default every region starts from an empty cell manager
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let shared = slots(&cb.query_cells_dyn(TestCellType::Storage, 2));
+    cb.push_region(1, 8);
+    assert!(!disjoint(&shared, &slots(&cb.query_cells_dyn(TestCellType::Storage, 6))));
+    cb.pop_region();
+
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_keep_shared_cells(true);
+    let shared = slots(&cb.query_cells_dyn(TestCellType::Storage, 2));
+    cb.push_region(1, 8);
+    let first = slots(&cb.query_cells_dyn(TestCellType::Storage, 6));
+    assert!(disjoint(&shared, &first));
+    cb.pop_region();
+
+    // Back outside of the regions, the shared cells are still allocated
+    let more_shared = slots(&cb.query_cells_dyn(TestCellType::Storage, 2));
+    assert!(disjoint(&shared, &more_shared));
+    let shared = [shared, more_shared].concat();
+
+    // Regions reuse each others cells, but never the shared ones
+    cb.push_region(2, 8);
+    let second = slots(&cb.query_cells_dyn(TestCellType::Storage, 6));
+    assert!(disjoint(&shared, &second));
+    assert!(!disjoint(&first, &second));
+    cb.pop_region();
+    cb.push_region(3, 8);
+    let third = slots(&cb.query_cells_dyn(TestCellType::Storage, 6));
+    assert_eq!(second, third);
+    cb.pop_region();
+    assert!(disjoint(&shared, &slots(&cb.query_cells_dyn(TestCellType::Storage, 4))));
+}
+
+#[test]
+#[should_panic(expected = "region 1 is still open")]
+fn push_region_while_open() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_keep_shared_cells(true);
+    cb.push_region(1, 8);
+    cb.push_region(2, 8);
+}
+
+#[derive(Clone)]
+struct MergeConfig {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    cells: [Cell<F>; 4],
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Two gadgets configured with separate builders and merged: `c == a²` and
+/// `d == b + 1` with `b` in `0..8`
+#[derive(Clone, Default)]
+struct MergeCircuit {
+    values: [u64; 4],
+}
+
+impl RegionTest<F> for MergeCircuit {
+    type Config = MergeConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let (q, table) = query_expression(meta, |meta| {
+            (meta.query_fixed(q_enable, Rotation::cur()), meta.query_fixed(range, Rotation::cur()))
+        });
+
+        let mut square = builder(meta, 4);
+        let [a, c] = ["a", "c"].map(|name| square.try_query_one(name, TestCellType::Storage).unwrap());
+        square.condition(q.expr(), |cb| {
+            let a2 = cb.store_expression("a²", a.expr() * a.expr(), TestCellType::Storage, None);
+            cb.require_equal("c == a²", c.expr(), a2.expr());
+        });
+
+        let mut increment: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(3, None, None);
+        increment.set_cell_manager(square.cell_manager.clone().unwrap());
+        let [b, d] = ["b", "d"].map(|name| increment.try_query_one(name, TestCellType::Storage).unwrap());
+        increment.condition(q.expr(), |cb| {
+            cb.require_equal("d == b + 1", d.expr(), b.expr() + 1.expr());
+            cb.add_lookup("b in range".to_string(), vec![b.expr()], vec![table.expr()]);
+        });
+
+        square.merge(increment);
+        meta.create_gate("Test", |_| square.build_constraints());
+        square.build_lookups(meta);
+        MergeConfig { q_enable, range, cells: [a, b, c, d], cb: square }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_fixed_range(layouter, config.range, 8)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        for (cell, value) in config.cells.iter().zip(self.values) {
+            assign!(region, cell, 0 => F::from(value))?;
+        }
+        region.assign_stored_expressions_at(&config.cb, 0, 0, &Vec::<Value<F>>::new())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn merge() {
+    let verify = |values: [u64; 4]| {
+        mock_prover(6, MergeCircuit { values }).verify_par()
+    };
+    assert!(verify([3, 5, 9, 6]).is_ok());
+    assert!(verify([3, 5, 8, 6]).is_err());
+    assert!(verify([3, 9, 9, 10]).is_err());
+
+    // The cells of both builders don't overlap
+    let mut meta = ConstraintSystem::<F>::default();
+    let config = MergeCircuit::configure(&mut meta);
+    let slots = config.cells.iter().map(|cell| (cell.column().index(), cell.rotation())).unique().count();
+    assert_eq!(slots, 4);
+
+    // The smallest max degree wins
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let mut cb = config.cb;
+    cb.push_region(1, 8);
+    cb.require_zero("x^3", x.expr() * x.expr() * x.expr());
+    assert_eq!(cb.num_split_nodes(), 0);
+    cb.require_zero("x^4", x.expr() * x.expr() * x.expr() * x.expr());
+    assert!(cb.num_split_nodes() > 0);
+    cb.pop_region();
+
+    // Regions of the merged builder are renumbered
+    let with_region = |meta: &mut ConstraintSystem<F>| {
+        let mut cb = builder(meta, 4);
+        cb.push_region(1, 1);
+        cb.require_zero("x", x.expr());
+        cb.pop_region();
+        cb
+    };
+    let mut cb = with_region(&mut meta);
+    let remap = cb.merge(with_region(&mut meta));
+    assert_eq!((remap.offset(), remap.region_id(0), remap.region_id(1)), (1, 0, 2));
+    let regions = cb.region_report().into_iter().map(|report| (report.region_id, report.constraints)).collect::<Vec<_>>();
+    assert_eq!(regions, [(1, 1), (2, 1)]);
+
+    // The ids handed out while descriptions are disabled don't collide
+    let described = |meta: &mut ConstraintSystem<F>, name: &'static str| {
+        let mut cb = builder(meta, 4);
+        cb.set_disable_description(true);
+        cb.require_zero(name, x.expr());
+        cb
+    };
+    let mut cb = described(&mut meta, "first");
+    cb.merge(described(&mut meta, "second"));
+    let names = cb.build_constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names, ["#0", "#1"]);
+    assert_eq!(cb.original_description("#1").as_deref(), Some("second"));
+}
+
+#[test]
+#[should_panic(expected = "above the max degree 2 of the merged builder: x^3 (3)")]
+fn merge_lowering_max_degree() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let column = meta.fixed_column();
+    let x: Expression<F> = query_expression(&mut meta, |meta| meta.query_fixed(column, Rotation::cur()));
+    let mut cb = builder(&mut meta, 4);
+    cb.require_zero("x^3", x.expr() * x.expr() * x.expr());
+    cb.merge(builder(&mut meta, 2));
+}
+
+#[test]
+#[should_panic(expected = "hooks of a merged builder would be dropped")]
+fn merge_with_hooks() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let mut other = builder(&mut meta, 4);
+    other.add_constraint_hook(Box::new(|_: &str, constraint: Expression<F>, _: HookCtx| constraint));
+    cb.merge(other);
+}
+
+#[derive(Clone)]
+struct ConditionCellConfig {
+    q_enable: Column<Fixed>,
+    cells: [Cell<F>; 5],
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Requires `i ⋅ d == i ⋅ e` for `i` in `1..=20` under the degree 3 condition
+/// `a ⋅ b ⋅ c`
+#[derive(Clone, Default)]
+struct ConditionCellCircuit {
+    values: [u64; 5],
+}
+
+impl RegionTest<F> for ConditionCellCircuit {
+    type Config = ConditionCellConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let q = query_expression(meta, |meta| meta.query_fixed(q_enable, Rotation::cur()));
+        let mut cb = builder(meta, 5);
+        cb.set_condition_cell_threshold(Some(4));
+        cb.push_condition(q);
+        cb.push_region(1, 8);
+        let cells = ["a", "b", "c", "d", "e"].map(|name| cb.try_query_one(name, TestCellType::Storage).unwrap());
+        let [a, b, c, d, e] = &cells;
+        cb.condition(a.expr() * b.expr() * c.expr(), |cb| {
+            for i in 1..=20u64 {
+                cb.require_equal("i⋅d == i⋅e", d.expr() * i.expr(), e.expr() * i.expr());
+            }
+        });
+        cb.pop_region();
+        cb.pop_condition();
+        meta.create_gate("Test", |_| cb.build_constraints());
+        ConditionCellConfig { q_enable, cells, cb }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        for (cell, value) in config.cells.iter().zip(self.values) {
+            assign!(region, cell, 0 => F::from(value))?;
+        }
+        region.assign_stored_expressions_at(&config.cb, 1, 0, &Vec::<Value<F>>::new())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn condition_cells() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let config = ConditionCellCircuit::configure(&mut meta);
+    // The first 4 constraints use the condition, the other 16 the cell
+    assert_eq!(
+        config.cb.condition_cell_stats(),
+        &ConditionCellStats { stored: 1, rewired: 16, degree_before: 16 * 4, degree_after: 16 * 2 }
+    );
+    // Together with the region condition
+    let degrees = config.cb.clone().build_constraints().iter().map(|(_, expr)| expr.degree()).collect::<Vec<_>>();
+    assert_eq!(degrees.iter().filter(|&&degree| degree == 5).count(), 4);
+    assert_eq!(degrees.iter().filter(|&&degree| degree == 3).count(), 17);
+    assert_eq!(degrees.len(), 4 + 1 + 1 + 16);
+
+    let verify = |values: [u64; 5]| {
+        mock_prover(6, ConditionCellCircuit { values }).verify_par()
+    };
+    assert!(verify([1, 1, 1, 7, 7]).is_ok());
+    assert!(verify([1, 1, 1, 7, 8]).is_err());
+    // The condition is off
+    assert!(verify([1, 0, 1, 7, 8]).is_ok());
+}
+
+crate::cell_type! {
+    enum RangeCellType: () {
+        storage: [Storage],
+        byte: Byte,
+        lookups: [Byte => ()],
+    }
+}
+
+#[derive(Clone)]
+struct RangeConfig {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    x: Cell<F>,
+    cb: ConstraintBuilder<F, RangeCellType>,
+}
+
+/// Requires `x < 2^16`
+#[derive(Clone, Default)]
+struct InRangeCircuit {
+    x: u64,
+}
+
+impl RegionTest<F> for InRangeCircuit {
+    type Config = RangeConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        // A single column table, so the lookup challenge isn't used
+        let mut cb: ConstraintBuilder<F, RangeCellType> = ConstraintBuilder::new(4, None, Some(1.expr()));
+        cb.load_table(meta, (), &[range]);
+        let mut cm = CellManager::new(4, 0);
+        cm.add_columns(meta, &mut cb, RangeCellType::Storage, 0, false, 1);
+        cm.add_columns(meta, &mut cb, RangeCellType::Byte, 0, false, 2);
+        cb.set_cell_manager(cm);
+        let x = cb.query_one(RangeCellType::Storage);
+        let q = query_expression(meta, |meta| meta.query_fixed(q_enable, Rotation::cur()));
+        let bytes = cb.condition(q, |cb| cb.require_in_range("x < 2^16", x.expr(), 2));
+        assert!(bytes.iter().all(|byte| byte.column() != x.column()));
+        meta.create_gate("Test", |_| cb.build_constraints());
+        cb.build_lookups(meta);
+        RangeConfig { q_enable, range, x, cb }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_fixed_range(layouter, config.range, 256)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, config.x, 0 => F::from(self.x))?;
+        region.assign_stored_expressions_at(&config.cb, 0, 0, &Vec::<Value<F>>::new())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn require_in_range() {
+    let verify = |x: u64| mock_prover(9, InRangeCircuit { x }).verify_par();
+    assert!(verify(0).is_ok());
+    assert!(verify(0x1234).is_ok());
+    assert!(verify(0xffff).is_ok());
+    assert!(verify(0x10000).is_err());
+    assert!(verify(0x123456).is_err());
+
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb: ConstraintBuilder<F, RangeCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(32, 0);
+    cm.add_columns(&mut meta, &mut cb, RangeCellType::Storage, 0, false, 1);
+    cb.set_cell_manager(cm);
+    let err = catch_unwind(AssertUnwindSafe(|| cb.require_in_range("x", 0.expr(), 32))).unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains("at most 31 are supported"), "{}", message);
+}
+
+#[derive(Clone)]
+struct PowerLookupConfig {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    x: Cell<F>,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Looks up `x^7` in a byte table, with a max degree of 4
+#[derive(Clone, Default)]
+struct PowerLookupCircuit {
+    x: u64,
+}
+
+impl RegionTest<F> for PowerLookupCircuit {
+    type Config = PowerLookupConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let mut cb = builder(meta, 4);
+        cb.push_region(1, 8);
+        let x = cb.query_one(TestCellType::Storage);
+        let [q, table] = query_expression(meta, |meta| {
+            [meta.query_fixed(q_enable, Rotation::cur()), meta.query_fixed(range, Rotation::cur())]
+        });
+        let power = (1..7).fold(x.expr(), |power, _| power * x.expr());
+        assert_eq!(power.degree(), 7);
+        cb.condition(q, |cb| cb.add_lookup("x^7 in range".to_string(), vec![power], vec![table]));
+        assert!(!cb.get_stored_expressions(1).is_empty());
+        cb.pop_region();
+        meta.create_gate("Test", |_| cb.build_constraints());
+        let report = cb.build_lookups(meta);
+        assert!(report.emitted[0].max_input_degree <= 4);
+        PowerLookupConfig { q_enable, range, x, cb }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_fixed_range(layouter, config.range, 256)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        region.push_region(0, 1);
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, config.x, 0 => F::from(self.x))?;
+        region.assign_stored_expressions(&config.cb, &Vec::<Value<F>>::new())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn split_lookup_inputs() {
+    let verify = |x: u64| mock_prover(9, PowerLookupCircuit { x }).verify_par();
+    // 2^7 and 0 are bytes, 3^7 isn't
+    assert!(verify(2).is_ok());
+    assert!(verify(0).is_ok());
+    assert!(verify(3).is_err());
+
+    // A degree 3 condition leaves a degree of 1 with a max degree of 4, too
+    // little to split `a ⋅ b` to
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let [a, b, c] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+    cb.push_condition(c.expr() * c.expr() * c.expr());
+    cb.add_lookup("a in table".to_string(), vec![a.expr()], vec![1.expr()]);
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        cb.add_lookup("a ⋅ b in table".to_string(), vec![a.expr() * b.expr()], vec![1.expr()]);
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn dedup_lookups() {
+    let lookup_arguments = |dedup: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let mut cb = builder(&mut meta, 4);
+        cb.push_region(1, 8);
+        let x = cb.query_one(TestCellType::Storage);
+        let [q, table] = query_expression(&mut meta, |meta| {
+            [meta.query_fixed(q_enable, Rotation::cur()), meta.query_fixed(range, Rotation::cur())]
+        });
+        circuit!([meta, cb], {
+            // The same range check in both branches
+            ifx!(q => {
+                cb.add_lookup("x in range".to_string(), vec![x.expr()], vec![table.expr()]);
+            } elsex {
+                cb.add_lookup("x in range".to_string(), vec![x.expr()], vec![table.expr()]);
+            });
+        });
+        cb.pop_region();
+        if dedup {
+            assert_eq!(cb.dedup_lookups(), 1);
+            assert_eq!(cb.lookups_for_region(1)[0].description(), "x in range (merged 2)");
+        }
+        meta.create_gate("Test", |_| cb.build_constraints());
+        let report = cb.build_lookups(&mut meta);
+        assert_eq!(report.deduped, if dedup { 1 } else { 0 });
+        meta.lookups().len()
+    };
+    assert_eq!(lookup_arguments(false), 2);
+    assert_eq!(lookup_arguments(true), 1);
+}
+
+#[test]
+fn lookup_too_wide() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let table = [(); 2].map(|_| meta.fixed_column());
+    let mut cb = builder(&mut meta, 4);
+    let table = query_expression(&mut meta, |meta| {
+        table.map(|column| meta.query_fixed(column, Rotation::cur())).to_vec()
+    });
+    cb.push_region(1, 8);
+    let [x, y, z] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+    cb.add_lookup("xyz".to_string(), vec![x.expr(), y.expr(), z.expr()], table);
+    cb.pop_region();
+    let err = cb.try_build_lookups(&mut meta).unwrap_err();
+    assert_eq!(err.to_string(), "lookup 'xyz' in region 1: 3 values for a table of 2 columns");
+    assert!(meta.lookups().is_empty());
+}
+
+#[derive(Clone)]
+struct WideLookupConfig {
+    q_enable: Column<Fixed>,
+    table: [Column<Fixed>; 2],
+    values: [Column<Advice>; 3],
+}
+
+/// Looks up `(a, b, c)` in a table of `(a, b + 256 ⋅ c)`
+#[derive(Clone, Default)]
+struct WideLookupCircuit {
+    values: [u64; 3],
+}
+
+impl RegionTest<F> for WideLookupCircuit {
+    type Config = WideLookupConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let table = [(); 2].map(|_| meta.fixed_column());
+        let values = [(); 3].map(|_| meta.advice_column());
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, Some(256.expr()));
+        cb.set_compress_wide_lookups(true);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let values = values.iter().map(|column| a!(column)).collect();
+                    let table = table.iter().map(|column| f!(column)).collect();
+                    cb.add_lookup("wide".to_string(), values, table);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        WideLookupConfig { q_enable, table, values }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        // `(i, i + 1, i + 2)` for `i` in `1..3`, and the all zero row
+        let rows = [vec![F::from(0), F::from(0)]]
+            .into_iter()
+            .chain((1..3).map(|i| vec![F::from(i), F::from(i + 1) + F::from(256 * (i + 2))]))
+            .collect::<Vec<_>>();
+        assign_fixed_rows(layouter, "wide", &config.table, &rows)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        for (column, value) in config.values.iter().zip(self.values.iter()) {
+            assign!(region, (*column, 0) => F::from(*value))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn compress_wide_lookups() {
+    let verify = |values: [u64; 3]| mock_prover(5, WideLookupCircuit { values }).verify_par();
+    assert!(verify([1, 2, 3]).is_ok());
+    assert!(verify([2, 3, 4]).is_ok());
+    assert!(verify([1, 2, 4]).is_err());
+    assert!(verify([1, 3, 3]).is_err());
+}
+
+#[test]
+fn stored_expression_names() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let [a, b, c] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+    let ab = cb.store_expression("product", a.expr() * b.expr(), TestCellType::Storage, None);
+    // A different expression with the same name gets a suffix
+    cb.store_expression("product", a.expr() * c.expr(), TestCellType::Storage, None);
+    cb.store_expression("product", b.expr() * c.expr(), TestCellType::Storage, None);
+    // The same expression is only stored once
+    let again = cb.store_expression("product", a.expr() * b.expr(), TestCellType::Storage, None);
+    assert_eq!(again.expr().identifier(), ab.expr().identifier());
+    let names = [
+        "product (stored expression)",
+        "product #2 (stored expression)",
+        "product #3 (stored expression)",
+    ];
+    assert_eq!(cb.stored_expression_names(1), names);
+    for name in names {
+        assert_eq!(cb.constraints().iter().filter(|(constraint, _)| *constraint == name).count(), 1);
+    }
+    cb.pop_region();
+
+    // Names are only unique within a region
+    cb.push_region(2, 8);
+    cb.store_expression("product", b.expr() * b.expr(), TestCellType::Storage, None);
+    assert_eq!(cb.stored_expression_names(2), ["product (stored expression)"]);
+    cb.pop_region();
+}
+
+#[test]
+fn unconditional_tuples() {
+    let stored = |unconditional: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let q_enable = meta.fixed_column();
+        let mut cb = builder(&mut meta, 4);
+        cb.set_lookup_challenge(7.expr());
+        cb.push_region(1, 8);
+        let [a, b] = [0; 2].map(|_| cb.query_one(TestCellType::Storage));
+        circuit!([meta, cb], {
+            // The same tuple in both branches
+            ifx!(f!(q_enable) => {
+                if unconditional {
+                    require!((a.expr(), b.expr()) =>>! @TestCellType::Storage);
+                } else {
+                    require!((a.expr(), b.expr()) =>> @TestCellType::Storage);
+                }
+            } elsex {
+                if unconditional {
+                    require!((a.expr(), b.expr()) =>>! @TestCellType::Storage);
+                } else {
+                    require!((a.expr(), b.expr()) =>> @TestCellType::Storage);
+                }
+            });
+        });
+        let count = cb.get_stored_expressions(1).len();
+        cb.pop_region();
+        let degree = cb.constraints().iter().map(|(_, constraint)| constraint.degree()).max().unwrap();
+        (count, degree)
+    };
+    // Under the conditions the tuples differ, so each one gets a cell
+    assert_eq!(stored(false), (2, 2));
+    assert_eq!(stored(true), (1, 1));
+}
+
+#[test]
+fn unconstrained_cells() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let [a, b, c] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+    // `c` is queried but never used
+    circuit!([meta, cb], {
+        require!(a.expr() => b.expr());
+    });
+    cb.pop_region();
+    let unconstrained = cb.finalize_checks();
+    assert_eq!(unconstrained.len(), 1);
+    let (cell_type, cell) = &unconstrained[0];
+    assert_eq!(*cell_type, TestCellType::Storage);
+    assert_eq!(cell.identifier(), c.identifier());
+
+    // Using it in a stored expression constrains it
+    cb.push_region(2, 8);
+    cb.store_expression("c", c.expr() * a.expr(), TestCellType::Storage, None);
+    cb.pop_region();
+    assert!(cb.finalize_checks().is_empty());
+
+    // A cell only stored in a table isn't constrained until the table is
+    // looked up into, here by tag
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let [a, d] = [0; 2].map(|_| cb.query_one(TestCellType::Storage));
+    cb.require_zero("a == 0", a.expr());
+    cb.store_table("d", (), vec![d.expr()]);
+    cb.pop_region();
+    let unconstrained = cb.finalize_checks();
+    assert_eq!(unconstrained.iter().map(|(_, cell)| cell.identifier()).collect::<Vec<_>>(), vec![d.identifier()]);
+    cb.add_lookup_to_tag("a in d".to_string(), vec![a.expr()], ());
+    assert!(cb.finalize_checks().is_empty());
+}
+
+/// Configure code of a gadget, run against a counting and a real builder
+fn sized_gadget(cb: &mut ConstraintBuilder<F, TestCellType>) {
+    circuit!([(), cb], {
+        let [a, b, c] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+        let flag = cb.query_bool();
+        let ab = cb.store_expression("ab", a.expr() * b.expr(), TestCellType::Storage, None);
+        require!(c.expr() => ab.expr() + flag.expr());
+        cb.add_lookup("c in b".to_string(), vec![c.expr()], vec![b.expr()]);
+    });
+}
+
+#[test]
+fn counting_builder() {
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::counting();
+    assert!(cb.is_dummy() && cb.is_counting());
+    cb.push_region(1, 8);
+    sized_gadget(&mut cb);
+    cb.pop_region();
+    let counts = cb.counts();
+
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut real = builder(&mut meta, 4);
+    real.push_region(1, 8);
+    sized_gadget(&mut real);
+    real.pop_region();
+
+    // Three cells, the boolean and the stored product
+    assert_eq!(counts.cells.len(), 1);
+    assert_eq!(counts.cells["Storage"], 5);
+    assert_eq!(counts.cells["Storage"], real.assignment_checklist(1).len());
+    assert_eq!(counts.constraints, real.constraints().len());
+    assert_eq!(counts.lookups["untagged"], real.lookups_in_region(1).count());
+
+    // Plain dummy builders don't count
+    let cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::dummy();
+    assert!(!cb.is_counting());
+}
+
+/// A constraint, a lookup and a table on columns queried directly
+#[cfg(feature = "serde")]
+fn summary_builder() -> ConstraintBuilder<F, TestCellType> {
+    let mut meta = ConstraintSystem::<F>::default();
+    let q = meta.fixed_column();
+    let [a, b] = [(); 2].map(|_| meta.advice_column());
+    let t = meta.fixed_column();
+    let [q, a, b, t] = query_expression(&mut meta, |meta| {
+        [
+            meta.query_fixed(q, Rotation::cur()),
+            meta.query_advice(a, Rotation::cur()),
+            meta.query_advice(b, Rotation::cur()),
+            meta.query_fixed(t, Rotation::cur()),
+        ]
+    });
+    let mut cb = ConstraintBuilder::new(4, None, None);
+    cb.require_equal("a == b", q.expr() * a.expr(), q.expr() * b.expr());
+    cb.add_lookup("a in t".to_string(), vec![a.expr()], vec![t.expr()]);
+    cb.store_table("b", (), vec![b.expr()]);
+    cb
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn summary_json() {
+    let summary = summary_builder().to_summary_json(false);
+    assert_eq!(summary, summary_builder().to_summary_json(false));
+    assert_eq!(summary, include_str!("summary.json").trim_end());
+
+    let stripped = summary_builder().to_summary_json(true);
+    assert!(!stripped.contains("a == b") && !stripped.contains("description"));
+    assert!(stripped.contains("\"lookups\""));
+}
+
+#[test]
+fn remove_and_replace_constraints() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.push_region(1, 8);
+    let [a, b, c] = [0; 3].map(|_| cb.query_one(TestCellType::Storage));
+    cb.require_equal("a == b", a.expr(), b.expr());
+    cb.require_equal("b == c", b.expr(), c.expr());
+    cb.require_equal("a == b", a.expr() * 2.expr(), b.expr());
+    // The last one with the name is removed
+    let removed = cb.remove_constraint("a == b").unwrap();
+    assert_eq!(removed.identifier(), (a.expr() * 2.expr() - b.expr()).identifier());
+    // Relaxed to only hold when `a` is zero
+    let old = cb.replace_constraint("b == c", a.expr() * (b.expr() - c.expr())).unwrap();
+    assert_eq!(old.identifier(), (b.expr() - c.expr()).identifier());
+    assert!(cb.remove_constraint("c == a").is_none());
+    cb.pop_region();
+
+    // Only constraints of the current region can be removed
+    cb.push_region(2, 8);
+    assert!(cb.remove_constraint("a == b").is_none());
+    let c = cb.query_one(TestCellType::Storage);
+    cb.require_zero("c", c.expr());
+    cb.pop_region();
+
+    let names = cb.build_constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names, ["a == b", "b == c", "c"]);
+    let replaced = &cb.build_constraints()[1].1;
+    assert_eq!(replaced.degree(), 2);
+    assert_eq!(cb.constraints_for_region(1).len(), 2);
+    assert_eq!(cb.constraints_for_region(2).len(), 1);
+
+    // The replacement is found by the name it was added with and goes
+    // through the formatter and the hooks like the original
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_description_formatter(Box::new(|name| format!("gadget: {}", name)));
+    cb.add_constraint_hook(Box::new(|_: &str, constraint: Expression<F>, _: HookCtx| constraint * 2.expr()));
+    cb.push_region(1, 8);
+    let [a, b] = [0; 2].map(|_| cb.query_one(TestCellType::Storage));
+    cb.require_equal("a == b", a.expr(), b.expr());
+    let old = cb.replace_constraint("a == b", a.expr()).unwrap();
+    assert_eq!(old.identifier(), ((a.expr() - b.expr()) * 2.expr()).identifier());
+    assert_eq!(cb.constraints()[0].0, "gadget: a == b");
+    assert_eq!(cb.constraints()[0].1.identifier(), (a.expr() * 2.expr()).identifier());
+    cb.pop_region();
+}
+
+#[test]
+fn dummy_builder() {
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::dummy();
+    assert!(cb.is_dummy());
+    let mut witness = Vec::new();
+    circuit!([(), cb], {
+        let [a, b] = [0; 2].map(|_| cb.query_default());
+        let flag = cb.query_bool();
+        require!(a => b);
+        require!("a in set", a => vec![1.expr(), 2.expr()]);
+        require!(flag => bool);
+        ifx!(flag => {
+            require!((a, b) => @cb.table(()));
+            witness.push("flag");
+        } elsex {
+            require!((a.expr()) =>> @TestCellType::Storage);
+            cb.lookup_columns("named", (), &["value"], vec![b.expr()]);
+            require!((b) => @@());
+            witness.push("not flag");
+        });
+        matchx!((
+            a => {
+                cb.store_table("table", (), vec![a.expr()]);
+            },
+            _ => unreachablex!(),
+        ));
+        // Stored expressions are returned as is
+        let stored = cb.store_expression("stored", a.expr() * b.expr(), TestCellType::Storage, None);
+        assert_eq!(stored.expr().identifier(), (a.expr() * b.expr()).identifier());
+        assert_eq!(a.expr().identifier(), 0.expr().identifier());
+    });
+    // The blocks run for their side effects
+    assert_eq!(witness, ["flag", "not flag"]);
+    cb.finalize().unwrap();
+    assert!(cb.constraints().is_empty());
+    assert!(cb.build_constraints().is_empty());
+    let report = cb.report();
+    assert!(report.regions.iter().all(|region| region.num_lookups() == 0 && region.table_rows == 0));
+
+    let cb = _cb!();
+    assert!(cb.is_dummy());
+}
+
+#[test]
+#[should_panic(expected = "Expression x⁵ degree too high in region 1 (label): 5 > 4 (terms of degree [1, 1, 1, 1, 1])")]
+fn strict_degree() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_strict_degree(true);
+    cb.push_named_region(1, "label", 8);
+    let x = cb.query_default();
+    cb.validate_degree(&(0..5).map(|_| x.expr()).reduce(|acc, x| acc * x).unwrap(), "x⁵");
+}
+
+#[derive(Clone)]
+struct ConditionConfig {
+    q_test: Column<Fixed>,
+    c: Column<Advice>,
+    x: Column<Advice>,
+}
+
+/// Requires `x == 1` when `c`, with `x == 1` on the single enabled row
+#[derive(Clone, Default)]
+struct ConditionCircuit<const CHECK: bool> {
+    c: u64,
+}
+
+impl<const CHECK: bool> RegionTest<F> for ConditionCircuit<CHECK> {
+    type Config = ConditionConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_test = meta.fixed_column();
+        let c = meta.advice_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_check_condition_booleanness(CHECK);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_test) => {
+                    ifx!(a!(c) => {
+                        require!(a!(x) => 1);
+                    });
+                });
+            });
+            cb.build_constraints()
+        });
+        ConditionConfig { q_test, c, x }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_test, 0) => true.scalar())?;
+        assign!(region, (config.c, 0) => self.c.scalar())?;
+        assign!(region, (config.x, 0) => 1.scalar())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn condition_booleanness() {
+    // A non boolean condition only fails when checked
+    let verify = |check: bool, c: u64| {
+        if check {
+            mock_prover(4, ConditionCircuit::<true> { c }).verify_par()
+        } else {
+            mock_prover(4, ConditionCircuit::<false> { c }).verify_par()
+        }
+    };
+    assert!(verify(false, 2).is_ok());
+    assert!(verify(true, 2).is_err());
+    assert!(verify(true, 1).is_ok());
+    assert!(verify(true, 0).is_ok());
+
+    // Each condition is checked once per region, the if/else conditions are
+    // already required to be boolean
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_check_condition_booleanness(true);
+    circuit!([meta, cb], {
+        let a = cb.query_default();
+        let b = cb.query_default();
+        ifx!(a => {
+            require!(b => 1);
+        });
+        ifx!(a => {
+            require!(b => 2);
+        });
+        ifx!(b => {
+            require!(a => 1);
+        } elsex {
+            require!(a => 0);
+        });
+        ifx!(true.expr() => {
+            require!(a => 1);
+        });
+    });
+    let names = cb.constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names.iter().filter(|name| **name == "condition is boolean").count(), 1);
+    assert_eq!(cb.condition_degrees().len(), 1);
+    assert_eq!(cb.condition_degrees()[0].2, 1);
+    assert_eq!(cb.format_condition_degrees().lines().count(), 2);
+
+    // The check of a condition only covers the conditions it was pushed under,
+    // so the same condition nested under another one is checked again
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    cb.set_check_condition_booleanness(true);
+    circuit!([meta, cb], {
+        let a = cb.query_default();
+        let c = cb.query_default();
+        ifx!(a => {
+            require!(c => 1);
+        });
+        ifx!(c => {
+            ifx!(a => {
+                require!(c => 1);
+            });
+        });
+    });
+    let names = cb.constraints().iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names.iter().filter(|name| **name == "condition is boolean").count(), 3);
+}
+
+#[derive(Clone)]
+struct SelectorConfig {
+    q_enable: Column<Fixed>,
+    table: Column<Fixed>,
+    x: Column<Advice>,
+}
+
+/// Requires `x == 2` and looks `x` up in a table containing only 2, without
+/// any conditions. Row 0 is enabled, row 1 with `x == 5` only when
+/// `enable_second`.
+#[derive(Clone, Default)]
+struct SelectorCircuit {
+    enable_second: bool,
+}
+
+impl RegionTest<F> for SelectorCircuit {
+    type Config = SelectorConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let table = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, Some(1.expr()));
+        cb.load_table(meta, (), &[table]);
+        cb.set_global_selector(query_expression(meta, |meta| meta.query_fixed(q_enable, Rotation::cur())));
+        assert_eq!(cb.max_global_degree(), 3);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                require!(a!(x) => 2);
+                require!((a!(x)) => @cb.table(()));
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        SelectorConfig { q_enable, table, x }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.table, 0) => 2.scalar())?;
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => 2.scalar())?;
+        assignf!(region, (config.q_enable, 1) => self.enable_second.scalar())?;
+        assign!(region, (config.x, 1) => 5.scalar())?;
+        Ok(())
+    }
+}
+
+#[test]
+fn global_selector() {
+    // The lookup outside of any condition is still off on the disabled row
+    let verify = |enable_second: bool| {
+        mock_prover(4, SelectorCircuit { enable_second }).verify_par()
+    };
+    assert!(verify(false).is_ok());
+    let failures = verify(true).unwrap_err();
+    assert!(failures.iter().any(|failure| matches!(failure, VerifyFailure::Lookup { .. })));
+    assert!(failures.iter().any(|failure| matches!(failure, VerifyFailure::ConstraintNotSatisfied { .. })));
+
+    // The selector can't be set once constraints were checked against the
+    // full degree budget
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let x = cb.query_default();
+    cb.require_zero("x == 0", x.expr());
+    assert!(catch_unwind(AssertUnwindSafe(|| cb.set_global_selector(1.expr()))).is_err());
+}
+
+#[test]
+fn region_accessors() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 4);
+    let q = cb.query_default();
+    cb.require_boolean("q is boolean", q.expr());
+    cb.push_condition(q.expr());
+    cb.push_region(1, 8);
+    let a = cb.query_default();
+    cb.require_zero("a == 0", a.expr());
+    cb.add_lookup("a in table".to_string(), vec![a.expr()], vec![1.expr()]);
+    cb.pop_region();
+    cb.push_region(2, 8);
+    let b = cb.query_default();
+    cb.require_zero("b == 0", b.expr());
+    cb.require_zero("b ⋅ b == 0", b.expr() * b.expr());
+    cb.pop_region();
+    cb.pop_condition();
+
+    // The regions can be inspected after they were popped
+    assert_eq!(cb.regions(), vec![0, 1, 2]);
+    let names = |region_id: usize| {
+        cb.constraints_for_region(region_id).iter().map(|(name, _)| *name).collect::<Vec<_>>()
+    };
+    assert_eq!(names(0), vec!["q is boolean"]);
+    assert_eq!(names(1), vec!["a == 0"]);
+    assert_eq!(names(2), vec!["b == 0", "b ⋅ b == 0"]);
+    // The region condition was applied when popping the region
+    assert_eq!(cb.constraints_for_region(1)[0].1.degree(), 2);
+    let lookups = cb.lookups_for_region(1);
+    assert_eq!(lookups.len(), 1);
+    assert_eq!(lookups[0].description(), "a in table");
+    assert!(cb.lookups_for_region(2).is_empty());
+    assert!(cb.constraints_for_region(3).is_empty());
+}
+
+/// A gadget copying its cell somewhere else, only when `copy` is set
+fn copy_gadget(cb: &mut ConstraintBuilder<F, TestCellType>, copy: bool) -> Cell<F> {
+    let cell = cb.query_default();
+    cb.enable_equality_if(copy, cell.column());
+    cell
+}
+
+#[test]
+fn conditional_equality() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(8, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 1);
+    cb.set_cell_manager(cm);
+
+    // Unused gadgets don't add columns to the permutation argument
+    copy_gadget(&mut cb, false);
+    assert!(cb.equalities().is_empty());
+    // The same gadget configured twice registers its column once
+    let first = copy_gadget(&mut cb, true);
+    let second = copy_gadget(&mut cb, true);
+    assert_eq!(first.column(), second.column());
+    assert_eq!(cb.equalities(), &[first.column().into()]);
+    cb.build_equalities(&mut meta);
+}
+
+#[test]
+fn constraint_provenance() {
+    let mut meta = ConstraintSystem::<F>::default();
+    let mut cb = builder(&mut meta, 8);
+    cb.set_record_provenance(true);
+    let [q, a, b, c, d] = [0; 5].map(|_| cb.query_default());
+    cb.require_zero("unconditional", d.expr());
+    cb.push_condition(q.expr());
+    cb.push_named_region(1, "main", 8);
+    circuit!([(), cb], {
+        ifx!(a => {
+            matchx!((
+                b => {
+                    ifx!(c => {
+                        cb.require_zero("deep", d.expr());
+                    });
+                },
+            ));
+        });
+    });
+    cb.pop_region();
+    cb.pop_condition();
+
+    // The region condition comes first, then the nested conditions
+    let idx = cb.constraints().iter().position(|(name, _)| *name == "deep").unwrap();
+    let provenance = cb.constraint_provenance(idx).unwrap();
+    assert_eq!(provenance.region, "region 1 (main)");
+    assert_eq!(
+        provenance.conditions.iter().map(|(id, _)| id.to_string()).collect::<Vec<_>>(),
+        [&q, &a, &b, &c].map(|cell| cell.identifier()).to_vec()
+    );
+    let why = cb.why_active("deep");
+    assert!(why.starts_with(&format!("'deep' (#{}) in region 1 (main)\n", idx)), "{}", why);
+    assert_eq!(why.lines().count(), 5);
+    assert!(why.ends_with(&format!("  4. {}\n", provenance.conditions[3].1)), "{}", why);
+    assert!(cb.why_active("unconditional").contains("in region 0, unconditional"));
+    assert!(cb.why_active("missing").starts_with("no constraint named 'missing'"));
+
+    // Only recorded while enabled
+    cb.set_record_provenance(false);
+    cb.require_zero("late", d.expr());
+    assert!(cb.why_active("late").contains("provenance not recorded"));
+    let report = cb.audit_report();
+    assert!(report.region(1).unwrap().constraints.iter().all(|c| c.provenance.is_some()));
+    let recorded = report.region(0).unwrap().constraints.iter().map(|c| c.provenance.is_some()).collect::<Vec<_>>();
+    assert_eq!(recorded, vec![true, false]);
+}
+
+#[test]
+fn description_formatter() {
+    let names = |strip: bool| {
+        let mut meta = ConstraintSystem::<F>::default();
+        let mut cb = builder(&mut meta, 2);
+        if strip {
+            cb.set_description_formatter(Box::new(strip_preamble));
+        }
+        let [a, b, c] = [0; 3].map(|_| cb.query_default());
+        cb.push_region(1, 8);
+        circuit!([meta, cb], {
+            require!(a => bool);
+            // Split into a stored expression
+            require!(a.expr() * b.expr() * c.expr() => 1.expr());
+            require!((a.expr()) => @vec![b.expr()]);
+        });
+        cb.pop_region();
+        let lookups = cb
+            .lookups_for_region(1)
+            .iter()
+            .map(|lookup| lookup.description().to_string())
+            .collect::<Vec<_>>();
+        let stored = cb.stored_expression_names(1);
+        assert_eq!((lookups.len(), stored.len()), (1, 1));
+        cb.constraints()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(stored)
+            .chain(lookups)
+            .collect::<Vec<_>>()
+    };
+    assert!(names(false).iter().all(|name| name.contains(".rs:")));
+    let stripped = names(true);
+    assert!(stripped.iter().all(|name| !name.contains(':')), "{:?}", stripped);
+    assert!(stripped.contains(&"a => bool".to_string()));
+    assert!(stripped.iter().any(|name| name.ends_with("=> 1.expr() (stored expression)")), "{:?}", stripped);
+
+    assert_eq!(strip_preamble("src/lib.rs:12: a => b"), "a => b");
+    assert_eq!(strip_preamble("x: y"), "x: y");
+}
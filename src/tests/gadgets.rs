@@ -0,0 +1,585 @@
+use std::marker::PhantomData;
+use eth_types::Field;
+use halo2_proofs::{
+    plonk::{Circuit, ConstraintSystem, Fixed, Column, Error, Expression},
+    circuit::{SimpleFloorPlanner, Layouter, Value},
+    dev::{MockProver, VerifyFailure},
+    halo2curves::bn256::Fr,
+    poly::Rotation,
+};
+
+use rand_core::RngCore;
+use zkevm_circuits::util::word::Word;
+
+use crate::prelude::*;
+use crate::{
+    gadgets::{
+        Be, BoundedLoopGadget, Bytes, BytesEqualGadget, Endian, Le, NibbleGadget, ReverseBytesGadget,
+        RlcChainGadget, SaturatingSubGadget,
+    },
+    mock::MockMeta,
+    table::{assign_fixed_range, PowerTable},
+    util::{rlc, DeterministicRng},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TestCellType {
+    Storage,
+}
+impl CellType for TestCellType {
+    type TableType = ();
+
+    fn lookup_table_type(&self) -> Option<Self::TableType> {None}
+    fn byte_type() -> Option<Self> {Some(Self::Storage)}
+    fn create_type(_id: usize) -> Self {unreachable!()}
+    fn storage_for_phase(phase: u8) -> Self {
+        match phase {
+            0 => Self::Storage,
+            _ => unreachable!()
+        }
+    }
+}
+impl Default for TestCellType {
+    fn default() -> Self {Self::Storage}
+}
+
+/// A gadget under test, constructed in a gate enabled only on the first row
+trait GadgetTest<F: Field>: Clone {
+    type Witness: Clone;
+    /// Tables the gadget looks up into, configured before the gate
+    type Tables: Clone;
+
+    /// Size of the fixed range table to load, if any
+    const FIXED_RANGE: Option<usize> = None;
+
+    fn configure_tables(meta: &mut ConstraintSystem<F>) -> Self::Tables;
+
+    fn assign_tables(_tables: &Self::Tables, _layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, tables: &Self::Tables) -> Self;
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        witness: &Self::Witness,
+    ) -> Result<(), Error>;
+}
+
+#[derive(Clone)]
+struct GadgetConfig<F: Field, G: GadgetTest<F>> {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    tables: G::Tables,
+    gadget: G,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+struct GadgetCircuit<F: Field, G: GadgetTest<F>> {
+    witness: G::Witness,
+    _phantom: PhantomData<(F, G)>,
+}
+
+impl<F: Field, G: GadgetTest<F>> Circuit<F> for GadgetCircuit<F, G> {
+    type Config = GadgetConfig<F, G>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(5, None, None);
+        if let Some(size) = G::FIXED_RANGE {
+            cb.load_fixed_range(meta, range, size);
+        }
+        let tables = G::configure_tables(meta);
+        let mut cm = CellManager::new(8, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 8);
+        cb.set_cell_manager(cm);
+
+        let mut gadget = None;
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    gadget = Some(G::configure(&mut cb, &tables));
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        GadgetConfig {
+            q_enable,
+            range,
+            tables,
+            gadget: gadget.unwrap(),
+            cb,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        if let Some(size) = G::FIXED_RANGE {
+            assign_fixed_range(&mut layouter, config.range, size)?;
+        }
+        G::assign_tables(&config.tables, &mut layouter)?;
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                region.push_region(0, 0);
+                assignf!(&mut region, (config.q_enable, 0) => true.scalar())?;
+                config.gadget.assign(&mut region, &self.witness)?;
+                region.assign_stored_expressions(&config.cb, &Vec::<Value<F>>::new())?;
+                Ok(())
+            }
+        )
+    }
+}
+
+fn run<G: GadgetTest<Fr>>(witness: G::Witness) -> Result<(), Vec<VerifyFailure>> {
+    let circuit = GadgetCircuit::<Fr, G> {
+        witness,
+        _phantom: PhantomData,
+    };
+    MockProver::<Fr>::run(6, &circuit, vec![]).unwrap().verify_par()
+}
+
+const MAX_LEN: usize = 4;
+
+#[derive(Clone)]
+struct BytesEqualTest<F> {
+    a: Vec<Cell<F>>,
+    b: Vec<Cell<F>>,
+    len: Cell<F>,
+    is_equal: Cell<F>,
+    gadget: BytesEqualGadget<F>,
+}
+
+impl<F: Field> GadgetTest<F> for BytesEqualTest<F> {
+    // (a, b, len, is_equal)
+    type Witness = (Vec<u8>, Vec<u8>, usize, bool);
+    type Tables = ();
+
+    fn configure_tables(_meta: &mut ConstraintSystem<F>) {}
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, _tables: &()) -> Self {
+        let a = cb.query_cells_dyn(TestCellType::Storage, MAX_LEN);
+        let b = cb.query_cells_dyn(TestCellType::Storage, MAX_LEN);
+        let len = cb.query_default();
+        let is_equal = cb.query_default();
+        let gadget = BytesEqualGadget::construct(
+            cb,
+            &a.iter().map(|a| a.expr()).collect::<Vec<_>>(),
+            &b.iter().map(|b| b.expr()).collect::<Vec<_>>(),
+            len.expr(),
+            MAX_LEN,
+        );
+        cb.require_equal("is_equal", gadget.is_equal(), is_equal.expr());
+        Self { a, b, len, is_equal, gadget }
+    }
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        (a, b, len, is_equal): &Self::Witness,
+    ) -> Result<(), Error> {
+        for (cell, byte) in self.a.iter().zip(a.iter()) {
+            cell.assign(region, 0, F::from(*byte as u64))?;
+        }
+        for (cell, byte) in self.b.iter().zip(b.iter()) {
+            cell.assign(region, 0, F::from(*byte as u64))?;
+        }
+        self.len.assign(region, 0, F::from(*len as u64))?;
+        self.is_equal.assign(region, 0, is_equal.scalar())?;
+        self.gadget.assign(region, 0, a, b, *len)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn bytes_equal() {
+    let check = |a: [u8; MAX_LEN], b: [u8; MAX_LEN], len: usize, is_equal: bool| {
+        run::<BytesEqualTest<Fr>>((a.to_vec(), b.to_vec(), len, is_equal))
+    };
+    // Length 0 is always equal
+    assert!(check([1, 2, 3, 4], [5, 6, 7, 8], 0, true).is_ok());
+    assert!(check([1, 2, 3, 4], [5, 6, 7, 8], 0, false).is_err());
+    // Different trailing garbage is ignored
+    assert!(check([1, 2, 3, 4], [1, 2, 7, 8], 2, true).is_ok());
+    assert!(check([1, 2, 3, 4], [1, 2, 7, 8], 3, false).is_ok());
+    assert!(check([1, 2, 3, 4], [1, 2, 7, 8], 3, true).is_err());
+    // Full length
+    assert!(check([1, 2, 3, 4], [1, 2, 3, 4], MAX_LEN, true).is_ok());
+    assert!(check([1, 2, 3, 4], [1, 2, 3, 5], MAX_LEN, false).is_ok());
+    assert!(check([1, 2, 3, 4], [1, 2, 3, 5], MAX_LEN, true).is_err());
+}
+
+#[derive(Clone)]
+struct NibbleTest<F> {
+    bytes: Vec<Cell<F>>,
+    num_nibbles: Cell<F>,
+    gadget: NibbleGadget<F>,
+}
+
+impl<F: Field> GadgetTest<F> for NibbleTest<F> {
+    // (hex-prefix encoded path, number of nibbles in the path)
+    type Witness = (Vec<u8>, usize);
+    type Tables = ();
+
+    const FIXED_RANGE: Option<usize> = Some(16);
+
+    fn configure_tables(_meta: &mut ConstraintSystem<F>) {}
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, _tables: &()) -> Self {
+        let bytes = cb.query_cells_dyn(TestCellType::Storage, MAX_LEN);
+        let num_nibbles = cb.query_default();
+        let gadget = NibbleGadget::construct_hex_prefix(
+            cb,
+            &bytes.iter().map(|byte| byte.expr()).collect::<Vec<_>>(),
+        );
+        cb.require_equal("num_nibbles", gadget.num_nibbles(), num_nibbles.expr());
+        Self { bytes, num_nibbles, gadget }
+    }
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        (bytes, num_nibbles): &Self::Witness,
+    ) -> Result<(), Error> {
+        for (cell, byte) in self.bytes.iter().zip(bytes.iter()) {
+            cell.assign(region, 0, F::from(*byte as u64))?;
+        }
+        self.num_nibbles.assign(region, 0, F::from(*num_nibbles as u64))?;
+        self.gadget.assign(region, 0, bytes)
+    }
+}
+
+#[test]
+fn nibbles() {
+    let check = |bytes: [u8; MAX_LEN], num_nibbles: usize| {
+        run::<NibbleTest<Fr>>((bytes.to_vec(), num_nibbles))
+    };
+    // Odd extension and leaf paths
+    assert!(check([0x1a, 0xbc, 0xde, 0xf0], 7).is_ok());
+    assert!(check([0x3a, 0xbc, 0xde, 0xf0], 7).is_ok());
+    assert!(check([0x1a, 0xbc, 0xde, 0xf0], 6).is_err());
+    // Even extension and leaf paths
+    assert!(check([0x00, 0xbc, 0xde, 0xf0], 6).is_ok());
+    assert!(check([0x20, 0xbc, 0xde, 0xf0], 6).is_ok());
+    assert!(check([0x20, 0xbc, 0xde, 0xf0], 7).is_err());
+    // Even paths are padded with a zero nibble
+    assert!(check([0x2a, 0xbc, 0xde, 0xf0], 6).is_err());
+    // Invalid flags
+    assert!(check([0x4a, 0xbc, 0xde, 0xf0], 7).is_err());
+}
+
+const R: u64 = 7;
+const MAX_SEGMENT_LEN: usize = 3;
+
+#[derive(Clone)]
+struct RlcChainTest<F> {
+    segments: Vec<(Vec<Cell<F>>, Cell<F>)>,
+    rlc: Cell<F>,
+    gadget: RlcChainGadget<F>,
+}
+
+impl<F: Field> GadgetTest<F> for RlcChainTest<F> {
+    // (segments, rlc of all bytes)
+    type Witness = (Vec<Vec<u8>>, F);
+    type Tables = (PowerTable, Vec<Expression<F>>);
+
+    fn configure_tables(meta: &mut ConstraintSystem<F>) -> Self::Tables {
+        let power = meta.advice_column();
+        let table = PowerTable::configure(meta, power, R.expr(), MAX_SEGMENT_LEN + 1);
+        let exprs = table.exprs(meta);
+        (table, exprs)
+    }
+
+    fn assign_tables((table, _): &Self::Tables, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        table.assign(layouter, Value::known(F::from(R)))
+    }
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, (_, power_table): &Self::Tables) -> Self {
+        let mut gadget = RlcChainGadget::construct(cb, R.expr(), power_table.clone(), (0.expr(), 1.expr()));
+        let segments = (0..3)
+            .map(|_| {
+                let bytes = cb.query_cells_dyn(TestCellType::Storage, MAX_SEGMENT_LEN);
+                let len = cb.query_default();
+                gadget.chain(cb, &bytes.iter().map(|byte| byte.expr()).collect::<Vec<_>>(), len.expr());
+                (bytes, len)
+            })
+            .collect();
+        let rlc = cb.query_default();
+        cb.require_equal("rlc", gadget.rlc(), rlc.expr());
+        Self { segments, rlc, gadget }
+    }
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        (segments, rlc): &Self::Witness,
+    ) -> Result<(), Error> {
+        for ((cells, len), bytes) in self.segments.iter().zip(segments.iter()) {
+            for (idx, cell) in cells.iter().enumerate() {
+                cell.assign(region, 0, F::from(bytes.get(idx).copied().unwrap_or(0) as u64))?;
+            }
+            len.assign(region, 0, F::from(bytes.len() as u64))?;
+        }
+        self.rlc.assign(region, 0, *rlc)?;
+        let segments = segments.iter().map(|bytes| bytes.as_slice()).collect::<Vec<_>>();
+        self.gadget.assign(
+            region,
+            0,
+            (Value::known(F::ZERO), Value::known(F::ONE)),
+            &segments,
+            Value::known(F::from(R)),
+        )?;
+        Ok(())
+    }
+}
+
+#[test]
+fn rlc_chain() {
+    let segments = vec![vec![1, 2], vec![], vec![3, 4, 5]];
+    let rlc = rlc::value(&segments.concat(), Fr::from(R));
+    assert!(run::<RlcChainTest<Fr>>((segments.clone(), rlc)).is_ok());
+    // The rlc of the chain is the rlc of the concatenated bytes
+    assert!(run::<RlcChainTest<Fr>>((segments, rlc + Fr::from(1))).is_err());
+    let segments = vec![vec![1, 2, 3], vec![4], vec![5, 6]];
+    let rlc = rlc::value(&segments.concat(), Fr::from(R));
+    assert!(run::<RlcChainTest<Fr>>((segments, rlc)).is_ok());
+}
+
+const MAX_ITERS: usize = 4;
+const LOOP_SRC: [u64; MAX_ITERS] = [1, 2, 3, 4];
+
+/// Copies the first `count` of the source values to the destination
+#[derive(Clone)]
+struct LoopTest<F> {
+    count: Cell<F>,
+    src: Vec<Cell<F>>,
+    dst: Vec<Cell<F>>,
+    gadget: BoundedLoopGadget<F>,
+}
+
+impl<F: Field> GadgetTest<F> for LoopTest<F> {
+    // (count, enable bits overriding the ones of `count`, destination)
+    type Witness = (usize, Option<Vec<bool>>, Vec<u64>);
+    type Tables = ();
+
+    fn configure_tables(_meta: &mut ConstraintSystem<F>) {}
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, _tables: &()) -> Self {
+        let count = cb.query_default();
+        let src = cb.query_cells_dyn(TestCellType::Storage, MAX_ITERS);
+        let dst = cb.query_cells_dyn(TestCellType::Storage, MAX_ITERS);
+        let gadget = BoundedLoopGadget::construct(cb, MAX_ITERS, count.expr());
+        gadget.for_each(cb, |cb, i, _| {
+            cb.require_equal("dst[i] == src[i]", dst[i].expr(), src[i].expr());
+        });
+        Self { count, src, dst, gadget }
+    }
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        (count, enables, dst): &Self::Witness,
+    ) -> Result<(), Error> {
+        self.count.assign(region, 0, F::from(*count as u64))?;
+        for i in 0..MAX_ITERS {
+            self.src[i].assign(region, 0, F::from(LOOP_SRC[i]))?;
+            self.dst[i].assign(region, 0, F::from(dst[i]))?;
+        }
+        match enables {
+            Some(enables) => {
+                for (cell, enable) in self.gadget.enables().iter().zip(enables.iter()) {
+                    cell.assign(region, 0, enable.scalar())?;
+                }
+            }
+            None => self.gadget.assign(region, 0, *count)?,
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn bounded_loop() {
+    let check = |count: usize, dst: [u64; MAX_ITERS]| run::<LoopTest<Fr>>((count, None, dst.to_vec()));
+    // Nothing is copied
+    assert!(check(0, [9, 9, 9, 9]).is_ok());
+    assert!(check(2, [1, 2, 9, 9]).is_ok());
+    assert!(check(2, [1, 9, 9, 9]).is_err());
+    assert!(check(4, LOOP_SRC).is_ok());
+    assert!(check(4, [1, 2, 3, 9]).is_err());
+    // The enables need to add up to the count
+    assert!(run::<LoopTest<Fr>>((3, Some(vec![true, true, false, false]), vec![1, 2, 9, 9])).is_err());
+    // The enables need to be a prefix
+    let failures = run::<LoopTest<Fr>>((2, Some(vec![true, false, true, false]), vec![1, 9, 3, 9]))
+        .unwrap_err();
+    assert!(failures
+        .iter()
+        .all(|failure| failure.to_string().contains("enable[i] ⋅ (1 - enable[i-1]) == 0")));
+}
+
+const STEPS: usize = 4;
+
+/// A counter decreased by a cost on every step, with a flag that is raised on
+/// the first underflow and stays raised
+#[derive(Clone)]
+struct CounterTest<F> {
+    init: Cell<F>,
+    costs: Vec<Cell<F>>,
+    counters: Vec<Cell<F>>,
+    flags: Vec<Cell<F>>,
+    gadgets: Vec<SaturatingSubGadget<F, 2>>,
+}
+
+impl<F: Field> GadgetTest<F> for CounterTest<F> {
+    // (initial counter, costs, counters, flags)
+    type Witness = (u64, Vec<u64>, Vec<u64>, Vec<bool>);
+    type Tables = ();
+
+    fn configure_tables(_meta: &mut ConstraintSystem<F>) {}
+
+    fn configure(cb: &mut ConstraintBuilder<F, TestCellType>, _tables: &()) -> Self {
+        let init = cb.query_default();
+        let costs = cb.query_cells_dyn(TestCellType::Storage, STEPS);
+        let counters = cb.query_cells_dyn(TestCellType::Storage, STEPS);
+        let flags = cb.query_cells_dyn(TestCellType::Storage, STEPS);
+        let mut gadgets = Vec::new();
+        let (mut counter, mut flag) = (init.expr(), 0.expr());
+        for step in 0..STEPS {
+            let gadget = SaturatingSubGadget::construct(cb, counter, costs[step].expr());
+            cb.require_equal("counter", counters[step].expr(), gadget.result());
+            // Latch the underflow flag
+            cb.require_equal(
+                "flag",
+                flags[step].expr(),
+                flag.expr() + gadget.underflow() - flag.expr() * gadget.underflow(),
+            );
+            counter = counters[step].expr();
+            flag = flags[step].expr();
+            gadgets.push(gadget);
+        }
+        Self { init, costs, counters, flags, gadgets }
+    }
+
+    fn assign(
+        &self,
+        region: &mut CachedRegion<'_, '_, F>,
+        (init, costs, counters, flags): &Self::Witness,
+    ) -> Result<(), Error> {
+        self.init.assign(region, 0, F::from(*init))?;
+        let mut counter = *init as u128;
+        for step in 0..STEPS {
+            self.costs[step].assign(region, 0, F::from(costs[step]))?;
+            self.counters[step].assign(region, 0, F::from(counters[step]))?;
+            self.flags[step].assign(region, 0, flags[step].scalar())?;
+            counter = self.gadgets[step].assign(region, 0, counter, costs[step] as u128)?.0;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn saturating_counter() {
+    let check = |counters: [u64; STEPS], flags: [bool; STEPS]| {
+        run::<CounterTest<Fr>>((10, vec![3, 4, 5, 0], counters.to_vec(), flags.to_vec()))
+    };
+    // The third step underflows, the flag stays raised after it
+    assert!(check([7, 3, 0, 0], [false, false, true, true]).is_ok());
+    assert!(check([7, 3, 0, 0], [false, false, true, false]).is_err());
+    assert!(check([7, 3, 0, 0], [false, false, false, false]).is_err());
+    assert!(check([7, 3, 0, 0], [false, true, true, true]).is_err());
+    // No wraparound
+    assert!(check([7, 3, 65534, 65534], [false, false, true, true]).is_err());
+}
+
+#[test]
+fn gadget_records() {
+    use crate::{constraint_builder::GadgetRecord, gadgets::LtGadget};
+
+    fn configure<const N_BYTES: usize>() -> ConstraintBuilder<Fr, TestCellType> {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(8, 0);
+        cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 4);
+        cb.set_cell_manager(cm);
+        cb.push_region(1, 8);
+        let (lhs, rhs) = (cb.query_one(TestCellType::Storage), cb.query_one(TestCellType::Storage));
+        LtGadget::<Fr, N_BYTES>::construct(&mut cb, lhs.expr(), rhs.expr());
+        SaturatingSubGadget::<Fr, 3>::construct(&mut cb, lhs.expr(), rhs.expr());
+        cb.pop_region();
+        cb
+    }
+
+    let cb = configure::<2>();
+    let record = |gadget, params: &str| GadgetRecord { gadget, region_id: 1, params: params.to_string() };
+    assert_eq!(
+        cb.gadget_records(),
+        &[
+            record("LtGadget", "{\"n_bytes\":2}"),
+            record("SaturatingSubGadget", "{\"n_bytes\":3}"),
+            record("LtGadget", "{\"n_bytes\":3}"),
+        ]
+    );
+    // Constructed with other parameters
+    let other = configure::<4>();
+    assert_eq!(other.gadget_records()[0], record("LtGadget", "{\"n_bytes\":4}"));
+    assert_eq!(other.gadget_records()[1..], cb.gadget_records()[1..]);
+    assert_ne!(cb.fingerprint(), other.fingerprint());
+    assert_eq!(cb.fingerprint(), configure::<2>().fingerprint());
+}
+
+#[test]
+fn reverse_bytes_gadget() {
+    let mut rng = DeterministicRng::new(1);
+    for _ in 0..4 {
+        let mut meta = MockMeta::<Fr>::new();
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(32, 0);
+        cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 1);
+        cb.set_cell_manager(cm);
+        let cells: [Cell<Fr>; 32] = cb.query_cells_dyn(TestCellType::Storage, 32).try_into().unwrap();
+        let le = Bytes::<Fr, Le>::from_cells(&cells);
+        let be = ReverseBytesGadget::construct(&mut cb, &cells);
+
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let word = eth_types::Word::from_little_endian(&bytes);
+        let le_bytes = Le::word_to_bytes(word);
+        let be_bytes = Be::word_to_bytes(word);
+        assert_eq!(Le::word_from_bytes(&le_bytes), word);
+        assert_eq!(Be::word_from_bytes(&be_bytes), word);
+        for (cell, byte) in cells.iter().zip(le_bytes) {
+            meta.set_advice(cell.column(), Rotation(cell.rotation() as i32), Fr::from(byte as u64));
+        }
+
+        let evaluate = |word: Word<Expression<Fr>>| {
+            let (lo, hi) = word.to_lo_hi();
+            (meta.evaluate(&lo), meta.evaluate(&hi))
+        };
+        // Both orders encode the same word
+        assert_eq!(evaluate(le.word()), Word::<Fr>::from(word).to_lo_hi());
+        assert_eq!(evaluate(be.word()), Word::<Fr>::from(word).to_lo_hi());
+        // The big endian bytes read as little endian encode the byte-swapped word
+        let swapped = Le::word_from_bytes(&be_bytes);
+        assert_eq!(evaluate(Bytes::<Fr, Le>::new(be.exprs().clone()).word()), Word::<Fr>::from(swapped).to_lo_hi());
+        for (byte, expected) in be.exprs().iter().zip(be_bytes) {
+            assert_eq!(meta.evaluate(byte), Fr::from(expected as u64));
+        }
+
+        let r = Fr::from(123456);
+        assert_eq!(meta.evaluate(&le.rlc(r.expr())), rlc::value(&le_bytes, r));
+        assert_eq!(meta.evaluate(&be.rlc(r.expr())), rlc::value(&be_bytes, r));
+        assert_eq!(meta.evaluate(&be.reverse().rlc(r.expr())), rlc::value(&le_bytes, r));
+    }
+}
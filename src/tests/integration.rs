@@ -0,0 +1,297 @@
+use eth_types::Field;
+use halo2_proofs::{
+    plonk::{Circuit, ConstraintSystem, Advice, Fixed, Column, Error},
+    circuit::{SimpleFloorPlanner, Layouter, Value},
+    dev::{MockProver, VerifyFailure},
+    halo2curves::bn256::Fr,
+};
+
+use crate::prelude::*;
+use crate::{
+    gadgets::{IsZeroGadget, LtGadget},
+    memory::{Memory, MemoryBank, RwBank},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum StepCellType {
+    StoragePhase1,
+    StoragePhase2,
+    MemoryRead,
+    Dynamic(usize),
+}
+
+impl CellType for StepCellType {
+    type TableType = ();
+
+    fn lookup_table_type(&self) -> Option<Self::TableType> {None}
+    fn byte_type() -> Option<Self> {Some(Self::StoragePhase1)}
+    fn create_type(id: usize) -> Self {Self::Dynamic(id)}
+    fn storage_for_phase(phase: u8) -> Self {
+        match phase {
+            0 => Self::StoragePhase1,
+            1 => Self::StoragePhase2,
+            _ => unreachable!()
+        }
+    }
+}
+impl Default for StepCellType {
+    fn default() -> Self {Self::StoragePhase1}
+}
+
+/// A step of the state machine, one row each
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    /// Stores `(address, value)` in memory (region 1)
+    Write { address: u64, value: u64 },
+    /// Compares the operands: `0` when `lhs > rhs`, `1` when `lhs < rhs` and
+    /// `2` when they're equal (region 2)
+    Compare { lhs: u64, rhs: u64 },
+    /// Loads the `back`-th latest store from memory (region 3)
+    Read { back: usize },
+}
+
+const PROGRAM: [Step; 6] = [
+    Step::Write { address: 1, value: 5 },
+    Step::Write { address: 2, value: 9 },
+    Step::Compare { lhs: 5, rhs: 9 },
+    Step::Compare { lhs: 7, rhs: 7 },
+    Step::Read { back: 0 },
+    Step::Read { back: 1 },
+];
+
+#[derive(Clone)]
+struct CompareCells<F> {
+    lhs: Cell<F>,
+    rhs: Cell<F>,
+    result: Cell<F>,
+    lt: LtGadget<F, 2>,
+    is_equal: IsZeroGadget<F>,
+}
+
+/// Miniature state machine: the program counter runs over the steps, every
+/// step is one of three named regions. Memory is written in the first region
+/// and read back in the last one, with the tuples compressed with a second
+/// phase challenge.
+#[derive(Clone)]
+struct StateMachineConfig<F: Field> {
+    q_first: Column<Fixed>,
+    q_step: Column<Fixed>,
+    q_last: Column<Fixed>,
+    q_write: Column<Fixed>,
+    q_compare: Column<Fixed>,
+    q_read: Column<Fixed>,
+    pc: Column<Advice>,
+    write: (Cell<F>, Cell<F>),
+    compare: CompareCells<F>,
+    read: (Cell<F>, Cell<F>, Cell<F>),
+    memory: Memory<F, StepCellType, RwBank<F, StepCellType>>,
+    challenges: Challenges,
+    cb: ConstraintBuilder<F, StepCellType>,
+}
+
+/// Runs `PROGRAM`, with the value loaded by the read at `corrupt` changed
+#[derive(Clone, Debug, Default)]
+struct StateMachineCircuit {
+    corrupt: Option<usize>,
+}
+
+impl<F: Field> Circuit<F> for StateMachineCircuit {
+    type Config = StateMachineConfig<F>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let [q_first, q_step, q_last, q_write, q_compare, q_read] =
+            [(); 6].map(|_| meta.fixed_column());
+        let pc = meta.advice_column();
+        let challenges = Challenges::configure(meta, 1, 0);
+        let mut cb: ConstraintBuilder<F, StepCellType> =
+            ConstraintBuilder::with_challenges(5, None, challenges.exprs(meta));
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(meta, &mut cb, StepCellType::StoragePhase1, 0, false, 8);
+        let mut memory: Memory<F, StepCellType, RwBank<F, StepCellType>> = Memory::new();
+        memory.add_rw(meta, &mut cb, &mut cm, StepCellType::MemoryRead, 1);
+        cb.set_cell_manager(cm);
+
+        let (mut write, mut compare, mut read) = (None, None, None);
+        meta.create_gate("State machine", |meta| {
+            circuit!([meta, cb], {
+                // Boundary constraints
+                ifx!(f!(q_first) => {
+                    require!(a!(pc) => 0);
+                });
+                ifx!(f!(q_step) => {
+                    require!(a!(pc, 1) => a!(pc) + 1.expr());
+                });
+                ifx!(f!(q_last) => {
+                    require!(a!(pc) => PROGRAM.len() - 1);
+                });
+
+                ifx!(f!(q_write) => {
+                    cb.push_named_region(1, "write", 1);
+                    let address = cb.query_one(StepCellType::StoragePhase1);
+                    let value = cb.query_one(StepCellType::StoragePhase1);
+                    let values = [address.expr(), value.expr()];
+                    memory[StepCellType::MemoryRead].store(&mut cb, &values);
+                    memory.build_constraints(&mut cb, f!(q_first));
+                    write = Some((address, value));
+                    cb.pop_region();
+                });
+                ifx!(f!(q_compare) => {
+                    cb.push_named_region(2, "compare", 1);
+                    let lhs = cb.query_one(StepCellType::StoragePhase1);
+                    let rhs = cb.query_one(StepCellType::StoragePhase1);
+                    let result = cb.query_one(StepCellType::StoragePhase1);
+                    let lt = LtGadget::<F, 2>::construct(&mut cb, lhs.expr(), rhs.expr());
+                    let is_equal = IsZeroGadget::construct(&mut cb, lhs.expr() - rhs.expr());
+                    require!(result.expr() => lt.expr() + is_equal.expr() * 2.expr());
+                    memory.build_constraints(&mut cb, f!(q_first));
+                    compare = Some(CompareCells { lhs, rhs, result, lt, is_equal });
+                    cb.pop_region();
+                });
+                ifx!(f!(q_read) => {
+                    cb.push_named_region(3, "read", 1);
+                    let back = cb.query_one(StepCellType::StoragePhase1);
+                    let address = cb.query_one(StepCellType::StoragePhase1);
+                    let value = cb.query_one(StepCellType::StoragePhase1);
+                    let values = [address.expr(), value.expr()];
+                    memory[StepCellType::MemoryRead].load(&mut cb, back.expr(), &values);
+                    memory.build_constraints(&mut cb, f!(q_first));
+                    read = Some((back, address, value));
+                    cb.pop_region();
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+
+        StateMachineConfig {
+            q_first,
+            q_step,
+            q_last,
+            q_write,
+            q_compare,
+            q_read,
+            pc,
+            write: write.unwrap(),
+            compare: compare.unwrap(),
+            read: read.unwrap(),
+            memory,
+            challenges,
+            cb,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        let challenges = config.challenges.values(&layouter);
+
+        // The memory witness, outside of the region so it's only done once
+        let mut memory = config.memory.clone();
+        let mut loads = vec![Vec::new(); PROGRAM.len()];
+        for (offset, step) in PROGRAM.iter().enumerate() {
+            let bank = &mut memory[StepCellType::MemoryRead];
+            match *step {
+                Step::Write { address, value } => {
+                    bank.witness_store(offset, &[F::from(address), F::from(value)]);
+                }
+                Step::Read { back } => {
+                    loads[offset] = bank.witness_load(back);
+                    if self.corrupt == Some(offset) {
+                        loads[offset][1] += F::ONE;
+                    }
+                }
+                Step::Compare { .. } => {}
+            }
+        }
+
+        layouter.assign_region(
+            || "State machine",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                memory.assign(&mut region, PROGRAM.len())?;
+                for (offset, step) in PROGRAM.iter().enumerate() {
+                    if offset == 0 {
+                        assignf!(&mut region, (config.q_first, offset) => true.scalar())?;
+                    }
+                    if offset + 1 < PROGRAM.len() {
+                        assignf!(&mut region, (config.q_step, offset) => true.scalar())?;
+                    } else {
+                        assignf!(&mut region, (config.q_last, offset) => true.scalar())?;
+                    }
+                    assign!(&mut region, (config.pc, offset) => F::from(offset as u64))?;
+                    match *step {
+                        Step::Write { address, value } => {
+                            region.push_region(offset, 1);
+                            assignf!(&mut region, (config.q_write, offset) => true.scalar())?;
+                            assign!(&mut region, config.write.0, offset => F::from(address))?;
+                            assign!(&mut region, config.write.1, offset => F::from(value))?;
+                        }
+                        Step::Compare { lhs, rhs } => {
+                            region.push_region(offset, 2);
+                            assignf!(&mut region, (config.q_compare, offset) => true.scalar())?;
+                            let cells = &config.compare;
+                            let (lhs, rhs) = (F::from(lhs), F::from(rhs));
+                            assign!(&mut region, cells.lhs, offset => lhs)?;
+                            assign!(&mut region, cells.rhs, offset => rhs)?;
+                            let (lt, _) = cells.lt.assign(&mut region, offset, lhs, rhs)?;
+                            let is_equal = cells.is_equal.assign(&mut region, offset, lhs - rhs)?;
+                            let result = lt + is_equal * F::from(2);
+                            assign!(&mut region, cells.result, offset => result)?;
+                        }
+                        Step::Read { back } => {
+                            region.push_region(offset, 3);
+                            assignf!(&mut region, (config.q_read, offset) => true.scalar())?;
+                            assign!(&mut region, config.read.0, offset => F::from(back as u64))?;
+                            assign!(&mut region, config.read.1, offset => loads[offset][0])?;
+                            assign!(&mut region, config.read.2, offset => loads[offset][1])?;
+                        }
+                    }
+                }
+                // The memory tuples are stored expressions
+                region.assign_stored_expressions(&config.cb, &challenges)?;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn state_machine() {
+    let prover = MockProver::<Fr>::run(6, &StateMachineCircuit::default(), vec![]).unwrap();
+    prover.assert_satisfied_par();
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let config = StateMachineCircuit::configure(&mut meta);
+    let labels = config
+        .cb
+        .region_report()
+        .iter()
+        .filter(|report| report.region_id != 0)
+        .map(|report| report.label)
+        .collect::<Vec<_>>();
+    assert_eq!(labels, vec![Some("write"), Some("compare"), Some("read")]);
+}
+
+#[test]
+fn state_machine_corrupted_memory() {
+    // Loading a value that was never stored only fails the memory lookup
+    for offset in [4, 5] {
+        let circuit = StateMachineCircuit { corrupt: Some(offset) };
+        let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+        let failures = prover.verify_par().unwrap_err();
+        assert!(!failures.is_empty());
+        assert!(
+            failures.iter().all(|failure| matches!(failure, VerifyFailure::Lookup { .. })),
+            "{:?}",
+            failures
+        );
+    }
+}
@@ -1,21 +1,25 @@
+use std::{cell::RefCell, rc::Rc};
 use eth_types::Field;
-use zkevm_circuits::{util::query_expression};
 use zkevm_gadgets::impl_expr;
-use crate::{util::{Scalar, rlc}, cell_manager::{CellManager, Cell}, cached_region::CachedRegion};
+use itertools::Itertools;
+use crate::prelude::*;
+use crate::util::{query_expression, rlc, sum, DeterministicRng};
 use halo2_proofs::{
-    plonk::{Circuit, ConstraintSystem, Expression, Fixed, Column, FirstPhase, Challenge, Error}, 
+    plonk::{Circuit, ConstraintSystem, Advice, Expression, Fixed, Column, Error}, 
     circuit::{SimpleFloorPlanner, Layouter, Value},
     poly::Rotation,
 };
 
-use crate::{constraint_builder:: ConstraintBuilder, cell_manager::CellType};
+
+/// Height of the region
+const HEIGHT: usize = 5;
 
 #[derive(Clone)]
 pub struct TestConfig<F>{
     q_enable: Column<Fixed>,
     fixed_table: [Column<Fixed>; 2],
     cells: (Cell<F>, Cell<F>, Cell<F>, Cell<F>, Cell<F>),
-    rand: Challenge,
+    challenges: Challenges,
     cb: ConstraintBuilder<F, TestCellType>,
 }
 
@@ -56,29 +60,32 @@ impl Default for TestCellType {
 
 
 impl<F: Field> TestConfig<F> {
-    pub fn new(meta: &mut ConstraintSystem<F>, r0: Challenge) -> Self {
+    pub fn new(
+        meta: &mut ConstraintSystem<F>,
+        challenges: Challenges,
+    ) -> Result<Self, ConstraintBuilderError> {
         let q_enable = meta.fixed_column();
-        let r1 = meta.challenge_usable_after(FirstPhase);
         let fixed_table: [Column<Fixed>; 2] = (0..2)
             .map(|_| meta.fixed_column())
             .collect::<Vec<_>>()
             .try_into()
             .unwrap();
-        let r0 = query_expression(meta, |meta| meta.query_challenge(r0.clone()));
-        let mut cb: ConstraintBuilder<F, TestCellType> =  ConstraintBuilder::new(4,  None, Some(r0.clone()));
+        let exprs = challenges.exprs(meta);
+        let (r0, r1) = (exprs.phase1()[0].clone(), exprs.phase1()[1].clone());
+        let mut cb: ConstraintBuilder<F, TestCellType> =  ConstraintBuilder::with_challenges(4,  None, exprs);
         cb.load_table(meta, TableTag::Fixed, &fixed_table);
 
-        let mut cm = CellManager::new(5, 0);
+        let mut cm = CellManager::new(HEIGHT, 0);
         cm.add_columns(meta, &mut cb, TestCellType::StoragePhase1, 1, false, 1);
         cm.add_columns(meta, &mut cb, TestCellType::StoragePhase2, 2, false, 1);
         cm.add_columns(meta, &mut cb, TestCellType::Lookup, 2, false, 1);
         cb.set_cell_manager(cm);
         
-        let a = cb.query_default();
-        let b = cb.query_default();
-        let c = cb.query_default();
-        let d = cb.query_default();
-        let e = cb.query_cell_with_type(TestCellType::StoragePhase2);
+        let a = cb.try_query_one("a", TestCellType::StoragePhase1)?;
+        let b = cb.try_query_one("b", TestCellType::StoragePhase1)?;
+        let c = cb.try_query_one("c", TestCellType::StoragePhase1)?;
+        let d = cb.try_query_one("d", TestCellType::StoragePhase1)?;
+        let e = cb.try_query_one("e", TestCellType::StoragePhase2)?;
         
         meta.create_gate("Test", |meta| {
             circuit!([meta, cb], {
@@ -90,7 +97,7 @@ impl<F: Field> TestConfig<F> {
                     require!((combined) =>> @TestCellType::Lookup);
 
                     // Store random linear combination of c,d in a phase2 cell
-                    let rlc = c.expr() + d.expr() * c!(r1);
+                    let rlc = c.expr() + d.expr() * r1.expr();
                     // Correct store during assignment is garenteed by this equality constriant
                     require!(e.expr() => rlc);
 
@@ -103,37 +110,42 @@ impl<F: Field> TestConfig<F> {
             cb.build_constraints()
         });
         cb.build_lookups(meta);
-        TestConfig { 
+        Ok(TestConfig {
             q_enable,
-            rand: r1,
+            challenges,
             cells: (a, b, c, d, e),
             fixed_table,
             cb,
-        }
+        })
     }
 
+    /// Assigns `instances` copies of the region stacked vertically, logs every
+    /// rlc witness it computes
     pub fn assign(
         &self, 
         layouter: &mut impl Layouter<F>,
-        r0: Value<F>,
+        instances: usize,
+        rlcs: &RefCell<Vec<F>>,
     ) -> Result<(), Error> {
-        let mut r1 = F::ZERO;
-        layouter.get_challenge(self.rand).map(|r| r1 = r);
+        let challenges = self.challenges.values(&*layouter);
+        let r1 = challenges.phase1()[1];
         layouter.assign_region(
             || "Test", 
             |mut region| {
-                let mut region = CachedRegion::new(&mut region, 0.scalar());
-                region.push_region(0, 0);
-
-                assignf!(&mut region, (self.q_enable, 0) => true.scalar());
-                let (a, b, c, d,  e) = &self.cells;
-                assign!(&mut region, a, 0 => 1.scalar())?;
-                assign!(&mut region, b, 0 => 2.scalar())?;
-                assign!(&mut region, c, 0 => 3.scalar())?;
-                assign!(&mut region, d, 0 => 4.scalar())?;
-                let rlc = F::from(3) + F::from(4) * r1;
-                assign!(&mut region, e, 0 => rlc)?;
-                region.assign_stored_expressions(&self.cb, &[r0])?;
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                for instance in 0..instances {
+                    let offset = instance * HEIGHT;
+                    assignf!(&mut region, (self.q_enable, offset) => true.scalar())?;
+                    let (a, b, c, d,  e) = &self.cells;
+                    assign!(&mut region, a, offset => 1.scalar())?;
+                    assign!(&mut region, b, offset => 2.scalar())?;
+                    assign!(&mut region, c, offset => 3.scalar())?;
+                    assign!(&mut region, d, offset => 4.scalar())?;
+                    let rlc = r1.map(|r1| r1 * F::from(4) + F::from(3));
+                    rlc.map(|rlc| rlcs.borrow_mut().push(rlc));
+                    e.assign_value(&mut region, offset, rlc)?;
+                    region.assign_stored_expressions_at(&self.cb, 0, offset, &challenges)?;
+                }
                 Ok(())
             }
         )
@@ -142,11 +154,13 @@ impl<F: Field> TestConfig<F> {
 
 #[derive(Clone, Debug, Default)]
 struct TestCircuit<F> {
+    instances: usize,
+    rlcs: Rc<RefCell<Vec<F>>>,
     _phantom: F,
 }
 
 impl<F: Field> Circuit<F> for TestCircuit<F> {
-    type Config = (TestConfig<F>, Challenge);
+    type Config = TestConfig<F>;
     type FloorPlanner = SimpleFloorPlanner;
     type Params = ();
 
@@ -155,16 +169,13 @@ impl<F: Field> Circuit<F> for TestCircuit<F> {
     }
 
     fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
-        // dummy column for phase1 challange
-        meta.advice_column_in(FirstPhase);
-        let challenge = meta.challenge_usable_after(FirstPhase); 
-        
-        (TestConfig::new(meta, challenge), challenge)
+        let challenges = Challenges::configure(meta, 2, 0);
+        TestConfig::new(meta, challenges).unwrap()
     }
 
     fn synthesize(
         &self, 
-        (config, challenge): Self::Config, 
+        config: Self::Config, 
         mut layouter: impl Layouter<F>
     ) -> Result<(), Error> {
         layouter.assign_region(|| "fixed table", |mut region| {
@@ -172,8 +183,7 @@ impl<F: Field> Circuit<F> for TestCircuit<F> {
             assignf!(region, (config.fixed_table[1], 0) => (3 + 4).scalar())?;
             Ok(())
         });
-        let r0 =  layouter.get_challenge(challenge);
-        config.assign(&mut layouter, r0)?;
+        config.assign(&mut layouter, self.instances, &self.rlcs)?;
         Ok(())
     }
 }
@@ -183,7 +193,574 @@ fn test() {
 
     use halo2_proofs::{ dev::MockProver, halo2curves::bn256::Fr};
 
-    let circuit = TestCircuit::<Fr>::default();
+    let circuit = TestCircuit::<Fr> { instances: 1, ..Default::default() };
+    let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+    prover.assert_satisfied_par();
+}
+
+#[test]
+fn stacked_instances() {
+    use halo2_proofs::{ dev::MockProver, halo2curves::bn256::Fr};
+
+    let circuit = TestCircuit::<Fr> { instances: 3, ..Default::default() };
+    let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+    prover.assert_satisfied_par();
+}
+
+#[test]
+fn keygen_without_challenges() {
+    use halo2_proofs::{
+        dev::MockProver,
+        halo2curves::bn256::{Bn256, Fr},
+        plonk::{keygen_pk, keygen_vk},
+        poly::kzg::commitment::ParamsKZG,
+    };
+
+    // There are no challenges during keygen, so no rlc is computed. With the
+    // challenge defaulting to zero the rlc would have been 3.
+    let circuit = TestCircuit::<Fr> { instances: 2, ..Default::default() };
+    let params = ParamsKZG::<Bn256>::setup(6, DeterministicRng::new(0));
+    let vk = keygen_vk(&params, &circuit).unwrap();
+    keygen_pk(&params, vk, &circuit).unwrap();
+    assert!(circuit.rlcs.borrow().is_empty());
+
     let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
     prover.assert_satisfied_par();
-}
\ No newline at end of file
+    assert_eq!(circuit.rlcs.borrow().len(), 2);
+    assert!(!circuit.rlcs.borrow().contains(&Fr::from(3)));
+}
+
+#[test]
+fn display() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 0);
+    let config = TestConfig::new(&mut meta, challenges).unwrap();
+
+    // advice[0] is the dummy column for the challenges
+    let (a, _, _, d, e) = &config.cells;
+    assert_eq!(a.to_string(), "advice[1]@0 (StoragePhase1)");
+    assert_eq!(d.to_string(), "advice[1]@3 (StoragePhase1)");
+    assert_eq!(e.to_string(), "advice[2]@0 (StoragePhase2)");
+
+    let stored = config.cb.get_stored_expressions(0);
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].to_string().contains(" (stored expression): advice[3]@0 (Lookup) = "));
+    assert!(stored[0].to_string().ends_with(&format!(
+        "(degree 2, {} nodes)",
+        crate::util::expr_nodes(&stored[0].expr)
+    )));
+
+    let summary = config.cb.summary();
+    let lines = summary.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "table Fixed: Fixed (width 2, condition degree 0, region 0)");
+    assert_eq!(lines[1], "lookup: Fixed (1 -> 1, condition degree 0, region 0)");
+    assert!(lines[2].ends_with("(2 -> 2, condition degree 1, region 0)"));
+    assert!(lines[3].ends_with("(1 -> 1, condition degree 1, region 0)"));
+}
+
+#[test]
+fn lookup_report() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 0);
+    let config = TestConfig::new(&mut meta, challenges).unwrap();
+
+    let report = config.cb.lookup_report().unwrap();
+    assert_eq!(report.emitted.len(), 3);
+    assert_eq!((report.deduped, report.packed), (0, 0));
+    // The compressed lookup cells and the lookup into the rlc
+    assert_eq!(report.compressed, 2);
+
+    let emitted = &report.emitted;
+    assert_eq!(emitted[0].description, "Fixed");
+    assert_eq!((emitted[0].table, emitted[0].columns, emitted[0].max_input_degree), (None, 1, 1));
+    // Only the lookup using the stored table knows its tag
+    assert_eq!(
+        (emitted[1].table, emitted[1].columns, emitted[1].max_input_degree),
+        (Some(TableTag::Fixed), 2, 2)
+    );
+    assert_eq!((emitted[2].table, emitted[2].columns, emitted[2].max_input_degree), (None, 1, 2));
+}
+
+#[test]
+fn lookup_stats() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 0);
+    let config = TestConfig::new(&mut meta, challenges).unwrap();
+
+    let stats = config.cb.lookup_stats();
+    assert_eq!(stats.by_region.len(), 1);
+    assert_eq!(stats.table_fragments.len(), 1);
+    let output = stats.to_string();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "'Fixed': 1 lookups, max width 1, max degree 1");
+    // Both lookups in the gate are described by the same macro arm
+    assert!(lines[1].ends_with("': 2 lookups, max width 2, max degree 2"));
+    assert_eq!(lines[2], "region 0: 3 lookups, max width 2, max degree 2");
+    assert_eq!(lines[3], "table Fixed: 1 fragments");
+}
+
+#[test]
+fn circuit_report() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 0);
+    let config = TestConfig::new(&mut meta, challenges).unwrap();
+
+    let report = config.cb.report();
+    assert_eq!(report.regions.len(), 1);
+    let region = report.region(0).unwrap();
+    assert_eq!(region.name, "region 0");
+    assert_eq!(region.constraints, report.constraints.len());
+    assert_eq!(region.degrees.values().sum::<usize>(), region.constraints);
+    // The lookup using the stored table is the only one knowing its tag
+    assert_eq!(
+        region.lookups.iter().map(|(tag, count)| (tag.as_str(), *count)).collect::<Vec<_>>(),
+        vec![("Fixed", 1), ("untagged", 2)]
+    );
+    assert_eq!((region.table_rows, region.stored_expressions), (1, 1));
+    // The stored expression is in a lookup cell
+    assert_eq!(
+        region.cells.iter().map(|(cell_type, count)| (cell_type.as_str(), *count)).collect::<Vec<_>>(),
+        vec![("Lookup", 1), ("StoragePhase1", 4), ("StoragePhase2", 1)]
+    );
+    assert_eq!(report.lookup_arguments.as_ref().unwrap().emitted.len(), 3);
+
+    let printed = report.to_string();
+    assert!(printed.contains("region 0: 3 lookups, 1 table rows\n"), "{}", printed);
+    assert!(printed.contains("lookup arguments: 3 (0 deduped, 0 packed, 2 compressed)\n"), "{}", printed);
+}
+
+/// The audit report of `audit_builder`, rendered as markdown. Regenerated
+/// with `UPDATE_GOLDEN=1`, review the diff when it changes.
+const AUDIT_GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/lookup_and_cell_audit.md");
+
+/// A constraint, a lookup and a loaded table on columns queried directly, so
+/// the report doesn't depend on the source locations of the macros
+fn audit_builder() -> ConstraintBuilder<halo2_proofs::halo2curves::bn256::Fr, TestCellType> {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let q = meta.fixed_column();
+    let table: [Column<Fixed>; 2] = [meta.fixed_column(), meta.fixed_column()];
+    let [a, b] = [(); 2].map(|_| meta.advice_column());
+    let [q, a, b] = query_expression(&mut meta, |meta| {
+        [
+            meta.query_fixed(q, Rotation::cur()),
+            meta.query_advice(a, Rotation::cur()),
+            meta.query_advice(b, Rotation::cur()),
+        ]
+    });
+    let mut cb = ConstraintBuilder::new(4, None, None);
+    cb.load_table(&mut meta, TableTag::Fixed, &table);
+    cb.require_equal("a == b", q.expr() * a.expr(), q.expr() * b.expr());
+    let table = cb.table(TableTag::Fixed);
+    cb.add_lookup("(a, b) in fixed".to_string(), vec![q.expr() * a.expr(), q.expr() * b.expr()], table);
+    cb
+}
+
+#[test]
+fn audit_report() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 0);
+    let config = TestConfig::new(&mut meta, challenges).unwrap();
+
+    let report = config.cb.audit_report();
+    assert_eq!(report.max_degree, 4);
+    assert_eq!(report.tables, vec![("Fixed".to_string(), vec!["column 0".to_string(), "column 1".to_string()])]);
+    assert_eq!(report.regions.len(), 1);
+    let region = report.region(0).unwrap();
+    assert_eq!(region.constraints.len(), config.cb.constraints().len());
+    assert_eq!(region.lookups.len(), 3);
+    assert_eq!(region.lookups.iter().filter(|lookup| lookup.table.as_deref() == Some("Fixed")).count(), 1);
+    assert_eq!((region.table_rows.len(), region.stored_expressions.len(), region.cells.len()), (1, 1, 6));
+
+    assert!(report.to_markdown().starts_with("# Circuit audit report\n"));
+
+    let markdown = audit_builder().audit_report().to_markdown();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(AUDIT_GOLDEN, &markdown).unwrap();
+    }
+    assert_eq!(
+        markdown,
+        std::fs::read_to_string(AUDIT_GOLDEN).unwrap(),
+        "the audit report changed, rerun with UPDATE_GOLDEN=1 and review the diff of {}",
+        AUDIT_GOLDEN
+    );
+}
+
+#[test]
+fn missing_cell_manager() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let column = meta.fixed_column();
+    let x: Expression<Fr> = crate::util::query_expression(&mut meta, |meta| {
+        meta.query_fixed(column, Rotation::cur())
+    });
+    let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+    cb.push_region(2, HEIGHT);
+    assert_eq!(
+        cb.try_query_one("a", TestCellType::StoragePhase1).unwrap_err(),
+        ConstraintBuilderError::CellManagerNotSet { name: "a".to_string(), region_id: 2 }
+    );
+    assert_eq!(
+        cb.try_query_bytes::<4>("bytes").unwrap_err(),
+        ConstraintBuilderError::NoByteType { name: "bytes".to_string(), region_id: 2 }
+    );
+    assert_eq!(
+        cb.try_store_expression("x", x.expr() * x.expr(), TestCellType::StoragePhase1, None)
+            .unwrap_err(),
+        ConstraintBuilderError::CellManagerNotSet { name: "x".to_string(), region_id: 2 }
+    );
+    let x5 = (0..5).fold(x.expr(), |acc, _| acc * x.expr());
+    assert_eq!(
+        cb.try_store_expression("x6", x5, TestCellType::StoragePhase1, None).unwrap_err(),
+        ConstraintBuilderError::DegreeExceeded {
+            name: "x6".to_string(),
+            region_id: 2,
+            degree: 6,
+            max_degree: 4,
+        }
+    );
+}
+
+#[test]
+fn query_bytes_dyn() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+    use super::gadgets::TestCellType as ByteCellType;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let mut cb: ConstraintBuilder<Fr, ByteCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(HEIGHT * 2, 0);
+    cm.add_columns(&mut meta, &mut cb, ByteCellType::Storage, 0, false, 5);
+    let columns = cm.columns().iter().map(|column| column.column).collect::<Vec<_>>();
+    cb.set_cell_manager(cm);
+
+    assert!(cb.query_bytes_dyn(0).is_empty());
+    let byte = cb.query_bytes_dyn(1);
+    assert_eq!(byte.len(), 1);
+    assert_eq!((byte[0].column(), byte[0].rotation()), (columns[0], 0));
+    // The remaining bytes fill the other columns first, then row by row
+    let bytes = cb.query_bytes_dyn(40);
+    assert_eq!(bytes.len(), 40);
+    for (idx, cell) in bytes.iter().enumerate() {
+        let (column, rotation) = match idx {
+            0..=3 => (idx + 1, 0),
+            _ => ((idx - 4) % 5, (idx - 4) / 5 + 1),
+        };
+        assert_eq!((cell.column(), cell.rotation()), (columns[column], rotation));
+    }
+
+    // No byte type for this cell manager
+    let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(HEIGHT, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::StoragePhase1, 0, false, 1);
+    cb.set_cell_manager(cm);
+    assert_eq!(
+        cb.try_query_bytes_dyn("rlp", 2).unwrap_err(),
+        ConstraintBuilderError::NoByteType { name: "rlp".to_string(), region_id: 0 }
+    );
+}
+
+#[derive(Clone)]
+pub struct ExportConfig {
+    q_table: Column<Fixed>,
+    table: Column<Advice>,
+    q_lookup: Column<Fixed>,
+    x: Column<Advice>,
+}
+
+/// A table stored by one builder, looked up into by another builder with
+/// different table tags
+#[derive(Clone, Debug, Default)]
+struct ExportCircuit {
+    table: Vec<u64>,
+    lookups: Vec<u64>,
+}
+
+impl<F: Field> Circuit<F> for ExportCircuit {
+    type Config = ExportConfig;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        use super::gadgets::TestCellType as OtherCellType;
+
+        let (q_table, q_lookup) = (meta.fixed_column(), meta.fixed_column());
+        let (table, x) = (meta.advice_column(), meta.advice_column());
+
+        let mut producer: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut exported = None;
+        meta.create_gate("Producer", |meta| {
+            circuit!([meta, producer], {
+                ifx!(f!(q_table) => {
+                    producer.store_table_named("table", TableTag::Fixed, vec![("value", a!(table))]);
+                });
+            });
+            exported = Some(producer.export_table(TableTag::Fixed));
+            producer.build_constraints()
+        });
+        let exported = exported.unwrap();
+        assert_eq!((exported.description(), exported.schema()), ("Fixed", Some(&["value"][..])));
+
+        let mut consumer: ConstraintBuilder<F, OtherCellType> = ConstraintBuilder::new(4, None, None);
+        consumer.import_table(exported, ());
+        // The imported table isn't merged a second time
+        let identifiers =
+            |values: Vec<Expression<F>>| values.iter().map(|value| value.identifier()).collect_vec();
+        assert_eq!(identifiers(consumer.table(())), identifiers(producer.table(TableTag::Fixed)));
+        meta.create_gate("Consumer", |meta| {
+            circuit!([meta, consumer], {
+                ifx!(f!(q_lookup) => {
+                    require!((a!(x)) => @consumer.table(()));
+                });
+            });
+            consumer.build_constraints()
+        });
+        consumer.build_lookups(meta);
+        ExportConfig { q_table, table, q_lookup, x }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                for (offset, value) in self.table.iter().enumerate() {
+                    assignf!(&mut region, (config.q_table, offset) => true.scalar())?;
+                    assign!(&mut region, (config.table, offset) => F::from(*value))?;
+                }
+                for (offset, value) in self.lookups.iter().enumerate() {
+                    assignf!(&mut region, (config.q_lookup, offset) => true.scalar())?;
+                    assign!(&mut region, (config.x, offset) => F::from(*value))?;
+                }
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn exported_table() {
+    use halo2_proofs::{dev::MockProver, halo2curves::bn256::Fr};
+
+    let verify = |lookups: Vec<u64>| {
+        let circuit = ExportCircuit { table: vec![3, 5, 7], lookups };
+        MockProver::<Fr>::run(6, &circuit, vec![]).unwrap().verify_par().is_ok()
+    };
+    assert!(verify(vec![7, 3, 5, 5]));
+    assert!(!verify(vec![7, 4]));
+    assert!(!verify(vec![8]));
+}
+
+#[derive(Clone)]
+struct PowerConfig<F> {
+    q_first: Column<Fixed>,
+    q_second: Column<Fixed>,
+    x: [Cell<F>; 2],
+    challenges: Challenges,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+/// Two regions storing `x ⋅ r^16` and `x ⋅ (r^2 + .. + r^16)`, both using the
+/// shared challenge power cells
+#[derive(Clone, Debug, Default)]
+struct PowerCircuit<F> {
+    _phantom: F,
+}
+
+impl<F: Field> Circuit<F> for PowerCircuit<F> {
+    type Config = PowerConfig<F>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_first = meta.fixed_column();
+        let q_second = meta.fixed_column();
+        let challenges = Challenges::configure(meta, 1, 0);
+        let mut cb: ConstraintBuilder<F, TestCellType> =
+            ConstraintBuilder::with_challenges(4, None, challenges.exprs(meta));
+        let mut cm = CellManager::new(HEIGHT, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::StoragePhase1, 1, false, 8);
+        cb.set_cell_manager(cm);
+
+        let mut x = Vec::new();
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_first) => {
+                    cb.push_region(1, HEIGHT);
+                    let cell = cb.query_one(TestCellType::StoragePhase1);
+                    let power = cb.challenge_power(1, 16);
+                    cb.store_expression("x ⋅ r^16", cell.expr() * power, TestCellType::StoragePhase1, None);
+                    x.push(cell);
+                    cb.pop_region();
+                });
+                ifx!(f!(q_second) => {
+                    cb.push_region(2, HEIGHT);
+                    let cell = cb.query_one(TestCellType::StoragePhase1);
+                    let powers = (2..=16).map(|i| cb.challenge_power(1, i)).collect::<Vec<_>>();
+                    cb.store_expression("x ⋅ Σ r^i", cell.expr() * sum::expr(&powers), TestCellType::StoragePhase1, None);
+                    x.push(cell);
+                    cb.pop_region();
+                });
+            });
+            cb.build_constraints()
+        });
+        PowerConfig {
+            q_first,
+            q_second,
+            x: x.try_into().unwrap(),
+            challenges,
+            cb,
+        }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        let challenges = config.challenges.values(&layouter);
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                for (idx, (q, x)) in [config.q_first, config.q_second].iter().zip(config.x.iter()).enumerate() {
+                    let offset = idx * HEIGHT;
+                    region.push_region(offset, idx + 1);
+                    assignf!(&mut region, (*q, offset) => true.scalar())?;
+                    assign!(&mut region, x, offset => 3.scalar())?;
+                }
+                region.assign_challenge_powers(&config.cb, &challenges)?;
+                region.assign_stored_expressions(&config.cb, &challenges)?;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn challenge_powers() {
+    use halo2_proofs::{dev::MockProver, halo2curves::bn256::Fr};
+
+    let prover = MockProver::<Fr>::run(6, &PowerCircuit::<Fr>::default(), vec![]).unwrap();
+    prover.assert_satisfied_par();
+
+    // A single set of cells for r^2 up to r^16, constrained once per region
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let config = PowerCircuit::<Fr>::configure(&mut meta);
+    let cells = config.cb.challenge_power_cells();
+    assert_eq!(cells.keys().copied().collect::<Vec<_>>(), (2..=16).map(|i| (1, i)).collect::<Vec<_>>());
+    assert_eq!(cells.values().map(|(cell, _)| (cell.column(), cell.rotation())).unique().count(), 15);
+    assert_eq!(cells[&(1, 16)].1.iter().copied().collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(cells[&(1, 2)].1.iter().copied().collect::<Vec<_>>(), vec![2]);
+    let constraints = config.cb.constraints().iter().filter(|(name, _)| *name == "challenge power").count();
+    assert_eq!(constraints, 1 + 15);
+}
+
+#[test]
+fn challenge_power_under_condition() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    // The power is first used under a condition and then outside of it, the
+    // single constraint of the region can't depend on that condition
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 1, 0);
+    let mut cb: ConstraintBuilder<Fr, TestCellType> =
+        ConstraintBuilder::with_challenges(4, None, challenges.exprs(&mut meta));
+    let mut cm = CellManager::new(HEIGHT, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::StoragePhase1, 1, false, 8);
+    cb.set_cell_manager(cm);
+    cb.push_region(1, HEIGHT);
+    let c = cb.query_one(TestCellType::StoragePhase1);
+    cb.push_condition(c.expr());
+    let conditional = cb.challenge_power(1, 2);
+    cb.pop_condition();
+    let unconditional = cb.challenge_power(1, 2);
+    cb.pop_region();
+    assert_eq!(conditional.identifier(), unconditional.identifier());
+    let powers = cb.constraints().iter().filter(|(name, _)| *name == "challenge power").collect::<Vec<_>>();
+    assert_eq!(powers.len(), 1);
+    assert_eq!(powers[0].1.degree(), 1);
+}
+
+#[test]
+fn tuple_challenge_powers() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let q_enable = meta.fixed_column();
+    let challenges = Challenges::configure(&mut meta, 1, 0);
+    let mut cb: ConstraintBuilder<Fr, TestCellType> =
+        ConstraintBuilder::with_challenges(4, None, challenges.exprs(&mut meta));
+    let mut cm = CellManager::new(HEIGHT, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::StoragePhase1, 1, false, 8);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::StoragePhase2, 2, false, 1);
+    cb.set_cell_manager(cm);
+    cb.push_region(1, HEIGHT);
+    let q = query_expression(&mut meta, |meta| meta.query_fixed(q_enable, Rotation::cur()));
+    let short = [0; 4].map(|_| cb.query_one(TestCellType::StoragePhase1).expr());
+    let long = [0; 6].map(|_| cb.query_one(TestCellType::StoragePhase1).expr());
+    let other = [0; 5].map(|_| cb.query_one(TestCellType::StoragePhase1).expr());
+    cb.condition(q, |cb| {
+        // Short tuples are still compressed as a product of the challenge
+        cb.store_tuple("short", TestCellType::StoragePhase2, short.to_vec());
+        assert!(cb.challenge_power_cells().is_empty());
+        cb.store_tuple("long", TestCellType::StoragePhase2, long.to_vec());
+        // The power cells are shared between the tuples of the region
+        cb.store_tuple("other", TestCellType::StoragePhase2, other.to_vec());
+    });
+    cb.pop_region();
+
+    // r^2, r^3, r^4 and r^5, in the cells of `challenge_power`
+    let cells = cb.challenge_power_cells();
+    assert_eq!(cells.keys().copied().collect::<Vec<_>>(), (2..=5).map(|i| (1, i)).collect::<Vec<_>>());
+    let powers = cb.constraints().iter().filter(|(name, _)| *name == "challenge power").count();
+    assert_eq!(powers, 4);
+    assert!(cb.stored_expression_names(1).iter().all(|name| !name.contains("power")));
+}
+
+#[test]
+fn challenges_by_index() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let challenges = Challenges::configure(&mut meta, 2, 1).exprs(&mut meta);
+    for (idx, challenge) in challenges.indexed().into_iter().enumerate() {
+        match challenge {
+            Expression::Challenge(challenge) => assert_eq!(challenge.index(), idx),
+            _ => panic!("not a challenge query"),
+        }
+    }
+
+    // Every phase is wired into the builder
+    let cb = ConstraintBuilder::<Fr, TestCellType>::with_challenges(5, None, challenges.clone());
+    for phase in [1, 2] {
+        let expected = challenges.first_after(phase).unwrap().identifier();
+        assert_eq!(cb.phase_challenge(phase).unwrap().identifier(), expected);
+    }
+    assert_eq!(cb.lookup_challenge.unwrap().identifier(), challenges.phase1()[0].identifier());
+}
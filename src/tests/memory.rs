@@ -0,0 +1,178 @@
+use halo2_proofs::{
+    circuit::{Layouter, SimpleFloorPlanner, Value},
+    dev::MockProver,
+    plonk::{Circuit, Column, ConstraintSystem, Error, Fixed},
+    halo2curves::bn256::Fr,
+};
+
+use crate::prelude::*;
+use crate::{
+    memory::{Memory, MemoryBank, RwBank, WriteRecord},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum MemoryCellType {
+    Storage,
+    Pool,
+    Memory(usize),
+}
+
+impl CellType for MemoryCellType {
+    type TableType = ();
+
+    fn lookup_table_type(&self) -> Option<Self::TableType> {None}
+    fn byte_type() -> Option<Self> {None}
+    fn create_type(id: usize) -> Self {Self::Memory(id)}
+    fn storage_for_phase(_phase: u8) -> Self {Self::Storage}
+}
+impl Default for MemoryCellType {
+    fn default() -> Self {Self::Storage}
+}
+
+#[test]
+fn memory_bank_queries() {
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let mut cb: ConstraintBuilder<Fr, MemoryCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(8, 0);
+    let mut memory: Memory<Fr, MemoryCellType, RwBank<Fr, MemoryCellType>> = Memory::new();
+    let tag = memory.add_rw(&mut meta, &mut cb, &mut cm, MemoryCellType::Storage, 0).tag();
+    let bank = &mut memory[tag];
+    assert!(bank.is_empty());
+
+    let (a, b) = (Fr::from(10), Fr::from(20));
+    bank.witness_store(0, &[a, Fr::from(1)]);
+    bank.witness_store(1, &[b, Fr::from(2)]);
+    assert_eq!(bank.get(a), Some([Fr::from(1)].as_slice()));
+    bank.witness_store(2, &[a, Fr::from(3)]);
+    assert_eq!(bank.witness_load(0), vec![a, Fr::from(3)]);
+    bank.witness_store(3, &[b, Fr::from(4)]);
+
+    assert_eq!(bank.len(), 2);
+    assert_eq!(bank.get(a), Some([Fr::from(3)].as_slice()));
+    assert_eq!(bank.get(b), Some([Fr::from(4)].as_slice()));
+    assert_eq!(bank.get(Fr::from(30)), None);
+    assert_eq!(
+        bank.iter_latest().collect::<Vec<_>>(),
+        vec![(a, [Fr::from(3)].as_slice()), (b, [Fr::from(4)].as_slice())]
+    );
+    assert_eq!(
+        bank.history(a),
+        &[
+            WriteRecord { offset: 0, values: vec![Fr::from(1)] },
+            WriteRecord { offset: 2, values: vec![Fr::from(3)] },
+        ]
+    );
+    assert!(bank.history(Fr::from(30)).is_empty());
+}
+
+#[test]
+fn memory_bank_shared_column() {
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let mut cb: ConstraintBuilder<Fr, MemoryCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(8, 0);
+    cm.add_columns(&mut meta, &mut cb, MemoryCellType::Pool, 0, false, 1);
+    let pool = cm.get_typed_columns(MemoryCellType::Pool)[0].column;
+
+    let mut memory: Memory<Fr, MemoryCellType, RwBank<Fr, MemoryCellType>> = Memory::new();
+    let tag = memory
+        .add_rw_shared(&mut meta, &mut cb, &mut cm, MemoryCellType::Storage, 0, MemoryCellType::Pool, 4)
+        .tag();
+    // The bank keeps its keys in the pool column
+    assert_eq!(memory[tag].columns()[0], pool);
+
+    // Regular cells are only allocated below the reserved rows
+    let cells = cm.query_cells(MemoryCellType::Pool, 3);
+    for (idx, cell) in cells.iter().enumerate() {
+        assert_eq!(cell.column(), pool);
+        assert_eq!(cell.rotation(), 4 + idx);
+    }
+    assert_eq!(cm.get_stats()[&MemoryCellType::Pool], (1, 7, 7));
+
+    // The reservation survives restarting the cell manager
+    cm.restart();
+    assert_eq!(cm.query_cell(MemoryCellType::Pool).rotation(), 4);
+}
+
+#[derive(Clone)]
+struct SharedBankConfig {
+    q_first: Column<Fixed>,
+    q_write: Column<Fixed>,
+    value: Cell<Fr>,
+    memory: Memory<Fr, MemoryCellType, RwBank<Fr, MemoryCellType>>,
+    cb: ConstraintBuilder<Fr, MemoryCellType>,
+}
+
+/// Writes `VALUES` to a bank sharing its key column with the cells of the
+/// regions, one region per write
+#[derive(Clone, Default)]
+struct SharedBankCircuit;
+
+const VALUES: [u64; 2] = [5, 9];
+
+impl Circuit<Fr> for SharedBankCircuit {
+    type Config = SharedBankConfig;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        self.clone()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+        let q_first = meta.fixed_column();
+        let q_write = meta.fixed_column();
+        let mut cb: ConstraintBuilder<Fr, MemoryCellType> = ConstraintBuilder::new(4, None, Some(7.expr()));
+        let mut cm = CellManager::new(4, 0);
+        cm.add_columns(meta, &mut cb, MemoryCellType::Pool, 0, false, 1);
+        let mut memory: Memory<Fr, MemoryCellType, RwBank<Fr, MemoryCellType>> = Memory::new();
+        // The keys of the writes and the padding row stay in the reserved rows
+        memory.add_rw_shared(meta, &mut cb, &mut cm, MemoryCellType::Storage, 0, MemoryCellType::Pool, 3);
+        cb.set_cell_manager(cm);
+
+        let mut value = None;
+        meta.create_gate("Write", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_write) => {
+                    cb.push_region(1, 4);
+                    let cell = cb.query_one(MemoryCellType::Pool);
+                    memory[MemoryCellType::Storage].store(&mut cb, &[cell.expr()]);
+                    memory.build_constraints(&mut cb, f!(q_first));
+                    value = Some(cell);
+                    cb.pop_region();
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        SharedBankConfig { q_first, q_write, value: value.unwrap(), memory, cb }
+    }
+
+    fn synthesize(&self, config: Self::Config, mut layouter: impl Layouter<Fr>) -> Result<(), Error> {
+        let mut memory = config.memory.clone();
+        for (offset, value) in VALUES.iter().enumerate() {
+            memory[MemoryCellType::Storage].witness_store(offset, &[Fr::from(*value)]);
+        }
+        layouter.assign_region(
+            || "Shared bank",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                memory.assign(&mut region, VALUES.len())?;
+                assignf!(&mut region, (config.q_first, 0) => true.scalar())?;
+                for (offset, value) in VALUES.iter().enumerate() {
+                    region.push_region(offset, 1);
+                    assignf!(&mut region, (config.q_write, offset) => true.scalar())?;
+                    assign!(&mut region, config.value, offset => Fr::from(*value))?;
+                }
+                region.assign_stored_expressions(&config.cb, &Vec::<Value<Fr>>::new())?;
+                Ok(())
+            },
+        )
+    }
+}
+
+#[test]
+fn memory_bank_shared_column_regions() {
+    // The writes of both regions are in the pool column after the keys
+    let prover = MockProver::<Fr>::run(6, &SharedBankCircuit, vec![]).unwrap();
+    prover.assert_satisfied_par();
+}
@@ -0,0 +1,347 @@
+use halo2_proofs::{arithmetic::Field, halo2curves::bn256::Fr, plonk::Expression, poly::Rotation};
+
+use crate::prelude::*;
+use crate::{
+    util::{linear_combine, linear_combine_value, DeterministicRng},
+    gadgets::IsZeroGadget,
+    constraint_builder::BuilderOp,
+    mock::{replay, DifferentialTest, MockMeta, ReplayState, Spec, Term},
+};
+use super::gadgets::TestCellType;
+
+type F = Fr;
+
+/// Configures an `IsZeroGadget` on a single column and evaluates its
+/// constraints with the given witness
+fn is_zero(value: u64, inverse: F) -> (bool, F) {
+    let mut meta = MockMeta::<F>::new();
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+    let mut cm = CellManager::new(4, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 1);
+    cb.set_cell_manager(cm);
+    assert_eq!(meta.queries().len(), 4);
+
+    let value_cell = cb.query_default();
+    let gadget = IsZeroGadget::construct(&mut cb, value_cell.expr());
+
+    // Cells are handed out in order from the single column, so the inverse
+    // is stored right below the value
+    let column = value_cell.column();
+    meta.set_advice(column, Rotation(0), F::from(value));
+    meta.set_advice(column, Rotation(1), inverse);
+    let satisfied = cb
+        .build_constraints()
+        .iter()
+        .all(|(_, constraint)| meta.evaluate(constraint) == F::ZERO);
+    (satisfied, meta.evaluate(&gadget.expr()))
+}
+
+#[test]
+fn is_zero_gadget() {
+    assert_eq!(is_zero(0, F::ZERO), (true, F::ONE));
+    let value = F::from(123);
+    assert_eq!(is_zero(123, value.invert().unwrap()), (true, F::ZERO));
+
+    // Wrong inverses are rejected
+    assert!(!is_zero(123, F::ZERO).0);
+    assert!(!is_zero(123, F::ONE).0);
+    assert!(!is_zero(0, F::ONE).0);
+}
+
+fn depth(expr: &Expression<F>) -> usize {
+    match expr {
+        Expression::Negated(a) | Expression::Scaled(a, _) => 1 + depth(a),
+        Expression::Sum(a, b) | Expression::Product(a, b) => 1 + depth(a).max(depth(b)),
+        _ => 1,
+    }
+}
+
+#[test]
+fn linear_combination() {
+    let mut meta = MockMeta::<F>::new();
+    let columns = (0..8).map(|_| meta.advice_column_in(0)).collect::<Vec<_>>();
+    let queries = columns
+        .iter()
+        .map(|column| meta.query_advice(*column, Rotation::cur()))
+        .collect::<Vec<_>>();
+    for (idx, column) in columns.iter().enumerate() {
+        meta.set_advice(*column, Rotation::cur(), F::from(idx as u64 + 3));
+    }
+
+    // 1⋅q0 + 2⋅q1 + ... + 8⋅q7
+    let terms = queries
+        .iter()
+        .enumerate()
+        .map(|(idx, query)| (F::from(idx as u64 + 1), query.clone()))
+        .collect::<Vec<_>>();
+    let manual = queries
+        .iter()
+        .enumerate()
+        .fold(0.expr(), |acc, (idx, query)| acc + query.clone() * (idx as u64 + 1).expr());
+    let combined = linear_combine(&terms);
+    assert_eq!(meta.evaluate(&combined), meta.evaluate(&manual));
+    assert!(depth(&combined) < depth(&manual));
+    let values = terms
+        .iter()
+        .map(|(coefficient, query)| (*coefficient, meta.evaluate(query)))
+        .collect::<Vec<_>>();
+    assert_eq!(linear_combine_value(&values), meta.evaluate(&manual));
+
+    // Terms over the same query are merged, cancelled terms are dropped
+    let merged = linear_combine(&[
+        (F::from(2), queries[0].clone()),
+        (F::from(3), Expression::Scaled(Box::new(queries[0].clone()), F::from(5))),
+        (F::ONE, queries[1].clone()),
+        (-F::ONE, queries[1].clone()),
+    ]);
+    assert_eq!(
+        merged.identifier(),
+        Expression::Scaled(Box::new(queries[0].clone()), F::from(17)).identifier()
+    );
+}
+
+#[test]
+fn oplog_replay() {
+    let mut meta = MockMeta::<F>::new();
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(3, None, None);
+    let mut cm = CellManager::new(8, 0);
+    cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 2);
+    cb.set_cell_manager(cm);
+    let _guard = cb.record_ops();
+
+    let state = |cb: &ConstraintBuilder<F, TestCellType>| {
+        let report = cb.region_report();
+        ReplayState {
+            constraints: report.iter().map(|region| region.constraints).sum(),
+            lookups: report.iter().map(|region| region.lookups).sum(),
+            table_rows: cb.tables.values().map(Vec::len).sum(),
+            cells: report.iter().map(|region| region.cells).sum(),
+            region_id: cb.region_id,
+            conditions: cb.conditions().iter().map(|condition| condition.degree()).collect(),
+            region_conditions: cb.state_context.iter().map(|condition| condition.degree()).collect(),
+        }
+    };
+    let mut breakpoints = Vec::new();
+
+    let [a, b] = [0; 2].map(|_| cb.query_one(TestCellType::Storage));
+    cb.require_boolean("a is bool", a.expr());
+    breakpoints.push((cb.oplog().len(), state(&cb)));
+    cb.condition(a.expr(), |cb| {
+        cb.push_region(1, 4);
+        let c = cb.query_one(TestCellType::Storage);
+        // Split with the region condition, storing b²
+        cb.require_equal("c == b³", c.expr(), b.expr() * b.expr() * b.expr());
+        cb.push_condition(c.expr());
+        cb.store_table("c", (), vec![c.expr()]);
+        cb.add_lookup("b in c".to_string(), vec![b.expr()], vec![c.expr()]);
+        breakpoints.push((cb.oplog().len(), state(cb)));
+        cb.pop_condition();
+        cb.pop_region();
+        breakpoints.push((cb.oplog().len(), state(cb)));
+    });
+    cb.with_conditions(vec![a.expr(), b.expr() * b.expr()], |cb| {
+        breakpoints.push((cb.oplog().len(), state(cb)));
+    });
+    breakpoints.push((cb.oplog().len(), state(&cb)));
+
+    let oplog = cb.oplog();
+    assert!(oplog.contains(&BuilderOp::PushRegion { region_id: 1, height: 4 }));
+    assert!(oplog.iter().any(|op| matches!(op, BuilderOp::StoreExpression { .. })));
+    for (upto, expected) in breakpoints {
+        assert_eq!(replay(&oplog, upto), expected, "at #{}", upto);
+    }
+    assert_eq!(replay(&oplog, usize::MAX).conditions, Vec::<usize>::new());
+
+    let dump = cb.dump_oplog();
+    assert_eq!(dump.lines().count(), oplog.len());
+    assert!(dump.contains("push_region 1 (height 4)"), "{}", dump);
+
+    // Nothing is recorded unless enabled
+    let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(3, None, None);
+    cb.require_zero("zero", 0.expr());
+    assert!(cb.oplog().is_empty());
+}
+
+/// Builds the constraints with the `circuit!` macros in `build`, over the
+/// variables and tables of a `DifferentialTest`, and checks them against the
+/// specs. Both satisfying and violating witnesses need to be sampled.
+fn differential(
+    rng: &mut DeterministicRng,
+    specs: Vec<Spec>,
+    build: impl FnOnce(&mut ConstraintBuilder<F, TestCellType>, &[Cell<F>], &[Vec<Expression<F>>]),
+) {
+    const SAMPLES: usize = 200;
+    let mut test = DifferentialTest::<F>::new(
+        4,
+        &[vec![vec![0, 1], vec![1, 2], vec![2, 0]], vec![vec![1]]],
+    );
+    let mut cb = ConstraintBuilder::new(16, None, None);
+    build(&mut cb, test.vars(), &test.tables());
+    let held = test.check(&specs, &cb, SAMPLES, 3, rng);
+    assert!(held > 0 && held < SAMPLES, "{:?} held on {} of {} witnesses", specs, held, SAMPLES);
+}
+
+#[test]
+fn differential_macros() {
+    let rng = &mut DeterministicRng::new(0);
+    let [a, b, c, d] = [0, 1, 2, 3].map(Term::Var);
+    let one = || Term::Const(1);
+
+    differential(rng, vec![Spec::Equal(vec![a.clone()], vec![b.clone()])], |cb, v, _| {
+        circuit!([(), cb], {
+            require!(v[0] => v[1]);
+        });
+    });
+    differential(rng, vec![Spec::Equal(vec![a.clone()], vec![b.clone() + one()])], |cb, v, _| {
+        circuit!([(), cb], {
+            require!("a == b + 1", v[0] => v[1].expr() + 1.expr());
+        });
+    });
+    differential(
+        rng,
+        vec![Spec::Equal(vec![a.clone(), b.clone()], vec![c.clone(), d.clone()])],
+        |cb, v, _| {
+            circuit!([(), cb], {
+                require!(vec![v[0].expr(), v[1].expr()] => vec![v[2].expr(), v[3].expr()]);
+            });
+        },
+    );
+    differential(rng, vec![Spec::Boolean(a.clone() + b.clone())], |cb, v, _| {
+        circuit!([(), cb], {
+            require!(v[0].expr() + v[1].expr() => bool);
+        });
+    });
+    differential(rng, vec![Spec::InSet(a.clone() * b.clone(), vec![1, 2])], |cb, v, _| {
+        circuit!([(), cb], {
+            require!(v[0].expr() * v[1].expr() => vec![1.expr(), 2.expr()]);
+        });
+    });
+    differential(rng, vec![Spec::Lookup(vec![a.clone(), b.clone()], 0)], |cb, v, tables| {
+        circuit!([(), cb], {
+            require!((v[0], v[1]) => @tables[0].clone());
+        });
+    });
+    differential(rng, vec![Spec::Lookup(vec![c.clone() * d.clone()], 1)], |cb, v, tables| {
+        circuit!([(), cb], {
+            require!("c⋅d in table", (v[2].expr() * v[3].expr()) => @tables[1].clone());
+        });
+    });
+    differential(
+        rng,
+        vec![Spec::If(a.clone(), vec![Spec::Equal(vec![b.clone()], vec![c.clone()])], None)],
+        |cb, v, _| {
+            circuit!([(), cb], {
+                ifx!(v[0] => {
+                    require!(v[1] => v[2]);
+                });
+            });
+        },
+    );
+    differential(
+        rng,
+        vec![Spec::If(
+            a.clone(),
+            vec![Spec::Equal(vec![b.clone()], vec![c.clone()])],
+            Some(vec![Spec::Lookup(vec![b.clone(), d.clone()], 0)]),
+        )],
+        |cb, v, tables| {
+            circuit!([(), cb], {
+                ifx!(v[0] => {
+                    require!(v[1] => v[2]);
+                } elsex {
+                    require!((v[1], v[3]) => @tables[0].clone());
+                });
+            });
+        },
+    );
+    differential(
+        rng,
+        vec![Spec::If(a.clone() * b.clone(), vec![Spec::Equal(vec![c.clone()], vec![one()])], None)],
+        |cb, v, _| {
+            circuit!([(), cb], {
+                ifx!((v[0], v[1]) => {
+                    require!(v[2] => 1);
+                });
+            });
+        },
+    );
+    differential(
+        rng,
+        vec![Spec::Match(
+            vec![
+                (a.clone(), vec![Spec::Equal(vec![c.clone()], vec![d.clone()])]),
+                (b.clone(), vec![Spec::Boolean(c.clone())]),
+            ],
+            None,
+        )],
+        |cb, v, _| {
+            circuit!([(), cb], {
+                matchx!((
+                    v[0] => {
+                        require!(v[2] => v[3]);
+                    },
+                    v[1] => {
+                        require!(v[2] => bool);
+                    },
+                ));
+            });
+        },
+    );
+    differential(
+        rng,
+        vec![Spec::Match(
+            vec![
+                (a.clone(), vec![Spec::Equal(vec![c.clone()], vec![d.clone()])]),
+                (b.clone(), vec![Spec::InSet(c.clone(), vec![0, 2])]),
+            ],
+            Some(vec![Spec::Equal(vec![c.clone()], vec![d.clone() + one()])]),
+        )],
+        |cb, v, _| {
+            circuit!([(), cb], {
+                matchx!((
+                    v[0] => {
+                        require!(v[2] => v[3]);
+                    },
+                    v[1] => {
+                        require!(v[2] => vec![0.expr(), 2.expr()]);
+                    },
+                    _ => {
+                        require!(v[2] => v[3].expr() + 1.expr());
+                    },
+                ));
+            });
+        },
+    );
+    differential(
+        rng,
+        vec![Spec::Match(
+            vec![
+                (a.clone(), vec![Spec::Lookup(vec![b.clone(), c.clone()], 0)]),
+                (
+                    Term::Not(Box::new(a.clone())),
+                    vec![Spec::If(
+                        b.clone(),
+                        vec![Spec::Equal(vec![c.clone()], vec![d.clone()])],
+                        None,
+                    )],
+                ),
+            ],
+            Some(vec![Spec::Equal(vec![one()], vec![Term::Const(0)])]),
+        )],
+        |cb, v, tables| {
+            circuit!([(), cb], {
+                matchx!((
+                    v[0] => {
+                        require!((v[1], v[2]) => @tables[0].clone());
+                    },
+                    not!(v[0]) => {
+                        ifx!(v[1] => {
+                            require!(v[2] => v[3]);
+                        });
+                    },
+                    _ => unreachablex!(),
+                ));
+            });
+        },
+    );
+}
@@ -1,7 +1,83 @@
+use eth_types::Field;
+use halo2_proofs::{
+    circuit::{Layouter, SimpleFloorPlanner, Value},
+    dev::MockProver,
+    halo2curves::bn256::Fr,
+    plonk::{Circuit, ConstraintSystem, Error},
+};
+
+use crate::prelude::CachedRegion;
 
 mod query_and_branch;
 mod lookup_and_cell;
+mod gadgets;
+mod region;
+mod builder;
+mod table;
+mod mock;
+mod memory;
+mod integration;
+mod public_api;
 
 #[test]
 fn test() {
-}
\ No newline at end of file
+}
+
+/// A test circuit that assigns its tables and then a single region, run with
+/// `RegionCircuit`. Circuits needing more than that override `synthesize`.
+pub(crate) trait RegionTest<F: Field>: Default {
+    type Config: Clone;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config;
+
+    /// Assigns the tables looked up into, before the region
+    fn assign_tables(&self, _config: &Self::Config, _layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn assign(&self, _config: &Self::Config, _region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        self.assign_tables(config, layouter)?;
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                self.assign(config, &mut region)
+            }
+        )
+    }
+}
+
+/// The circuit of a `RegionTest`
+#[derive(Default)]
+pub(crate) struct RegionCircuit<T>(pub(crate) T);
+
+impl<F: Field, T: RegionTest<F>> Circuit<F> for RegionCircuit<T> {
+    type Config = T::Config;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        Self(T::default())
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        T::configure(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        self.0.synthesize(&config, &mut layouter)
+    }
+}
+
+/// Runs the test circuit in the mock prover with `2^k` rows
+pub(crate) fn mock_prover<T: RegionTest<Fr>>(k: u32, test: T) -> MockProver<Fr> {
+    MockProver::<Fr>::run(k, &RegionCircuit(test), vec![]).unwrap()
+}
@@ -0,0 +1,41 @@
+/// The items re-exported by the prelude as `module::Item`, in source order
+fn prelude_items(source: &str) -> Vec<String> {
+    let start = "pub use crate::{";
+    let source = &source[source.find(start).unwrap() + start.len()..];
+    let mut items = Vec::new();
+    // Module path, with whether the module opened a `{` group
+    let mut path: Vec<(String, bool)> = Vec::new();
+    let mut ident = String::new();
+    for c in source.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => ident.push(c),
+            ':' if !ident.is_empty() => path.push((std::mem::take(&mut ident), false)),
+            '{' => path.last_mut().unwrap().1 = true,
+            ',' | '}' => {
+                if !ident.is_empty() {
+                    let module = path.iter().map(|(module, _)| module.as_str()).collect::<Vec<_>>();
+                    items.push(format!("{}::{}", module.join("::"), std::mem::take(&mut ident)));
+                }
+                while matches!(path.last(), Some((_, false))) {
+                    path.pop();
+                }
+                if c == '}' && path.pop().is_none() {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    items
+}
+
+#[test]
+fn public_api() {
+    let mut items = prelude_items(include_str!("../prelude.rs"));
+    items.sort();
+    let expected = include_str!("public_api.txt").lines().collect::<Vec<_>>();
+    assert_eq!(
+        items, expected,
+        "the prelude changed, update src/tests/public_api.txt if this is intended"
+    );
+}
@@ -1,12 +1,12 @@
 use eth_types::Field;
-use crate::util::Scalar;
 use halo2_proofs::{
     plonk::{Circuit, ConstraintSystem, Advice, Fixed, Column, FirstPhase, Challenge, Error, SecondPhase}, 
     circuit::{SimpleFloorPlanner, Layouter, Value},
     poly::Rotation,
 };
 
-use crate::{constraint_builder:: ConstraintBuilder, cell_manager::CellType};
+use crate::prelude::*;
+use crate::gadgets::BinarySelector;
 
 #[derive(Clone)]
 pub struct TestConfig {
@@ -90,7 +90,7 @@ impl TestConfig {
     pub fn assign<F: Field>(
         &self, 
         layouter: &mut impl Layouter<F>,
-        r: F,
+        r: Value<F>,
     ) -> Result<(), Error> {
         layouter.assign_region(
             || "Test", 
@@ -105,7 +105,8 @@ impl TestConfig {
                     if is_even {
                         assign!(region, (self.res, offset) => (887766 + 112233).scalar());
                     } else {
-                        assign!(region, (self.res, offset) => Scalar::<F>::scalar(&887766) + r);
+                        let res = r.map(|r| r + Scalar::<F>::scalar(&887766));
+                        region.assign_advice(|| "res", self.res, offset, || res)?;
                     }
                 }
                 Ok(())
@@ -142,8 +143,7 @@ impl<F: Field> Circuit<F> for TestCircuit<F> {
         (config, randomness): Self::Config, 
         mut layouter: impl Layouter<F>
     ) -> Result<(), halo2_proofs::plonk::Error> {
-        let mut r = F::ZERO;
-        layouter.get_challenge(randomness).map(|randomness| r = randomness);
+        let r = layouter.get_challenge(randomness);
         config.assign(&mut layouter, r)?;
         Ok(())
     }
@@ -157,4 +157,234 @@ fn test() {
     let circuit = TestCircuit::<Fr>::default();
     let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
     prover.assert_satisfied_par();
-}
\ No newline at end of file
+}
+
+/// Selects one of three constants with `matchx!`, the witness side uses
+/// `dispatch!` to assign the selectors
+#[derive(Clone)]
+pub struct DispatchConfig<F> {
+    q_enable: Column<Fixed>,
+    selectors: [Cell<F>; 3],
+    res: Column<Advice>,
+}
+
+impl<F: Field> DispatchConfig<F> {
+    pub fn new(meta: &mut ConstraintSystem<F>) -> Self {
+        let q_enable = meta.fixed_column();
+        let res = meta.advice_column();
+        let selectors = [0, 1, 2].map(|_| {
+            let column = meta.advice_column();
+            Cell::new(meta, column, 0)
+        });
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        meta.create_gate("Dispatch", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let value = matchx!((
+                        selectors[0].expr() => 10.expr(),
+                        selectors[1].expr() => 20.expr(),
+                        selectors[2].expr() => 30.expr(),
+                    ));
+                    require!(a!(res) => value);
+                });
+            });
+            cb.build_constraints()
+        });
+        DispatchConfig { q_enable, selectors, res }
+    }
+
+    pub fn assign(&self, layouter: &mut impl Layouter<F>, inputs: &[u64]) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Dispatch",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                for (offset, x) in inputs.iter().enumerate() {
+                    assignf!(&mut region, (self.q_enable, offset) => true.scalar())?;
+                    let value: u64 = dispatch!(&mut region, offset, {
+                        *x < 10, self.selectors[0] => 10,
+                        (10..20).contains(x), self.selectors[1] => 20,
+                        (15..25).contains(x), self.selectors[2] => 30,
+                    });
+                    assign!(&mut region, (self.res, offset) => value.scalar())?;
+                }
+                Ok(())
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct DispatchCircuit<F> {
+    inputs: Vec<u64>,
+    _phantom: F,
+}
+
+impl<F: Field> Circuit<F> for DispatchCircuit<F> {
+    type Config = DispatchConfig<F>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        DispatchConfig::new(meta)
+    }
+
+    fn synthesize(&self, config: Self::Config, mut layouter: impl Layouter<F>) -> Result<(), Error> {
+        config.assign(&mut layouter, &self.inputs)
+    }
+}
+
+#[test]
+fn dispatch() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use halo2_proofs::{dev::MockProver, halo2curves::bn256::Fr};
+
+    // Only satisfied when the selectors match the branches taken
+    let circuit = DispatchCircuit::<Fr> { inputs: vec![0, 5, 10, 14, 24], ..Default::default() };
+    let prover = MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+    prover.assert_satisfied_par();
+
+    // 25 matches no branch, 15 matches two
+    for x in [25, 15] {
+        let circuit = DispatchCircuit::<Fr> { inputs: vec![x], ..Default::default() };
+        let result = catch_unwind(AssertUnwindSafe(|| MockProver::<Fr>::run(6, &circuit, vec![])));
+        let err = result.unwrap_err();
+        let message = err.downcast_ref::<String>().unwrap();
+        assert!(message.contains("conditions are true, expected exactly one"));
+    }
+}
+
+#[test]
+fn binary_selector_cost() {
+    use halo2_proofs::halo2curves::bn256::Fr;
+
+    // Returns the number of cells, the number of constraints and their max
+    // degree of an 8 arm match
+    let cost = |binary: bool| {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(8, None, None);
+        let mut cm = CellManager::new(8, 0);
+        cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 1);
+        cb.set_cell_manager(cm);
+        let res = meta.advice_column();
+        meta.create_gate("Match", |meta| {
+            circuit!([meta, cb], {
+                let value = if binary {
+                    let selector = cb.binary_selector(8);
+                    matchx_binary!(selector, (
+                        0 => 10.expr(), 1 => 20.expr(), 2 => 30.expr(), 3 => 40.expr(),
+                        4 => 50.expr(), 5 => 60.expr(), 6 => 70.expr(), 7 => 80.expr(),
+                    ))
+                } else {
+                    let c = (0..8).map(|_| cb.query_default()).collect::<Vec<_>>();
+                    matchx!((
+                        c[0] => 10.expr(), c[1] => 20.expr(), c[2] => 30.expr(), c[3] => 40.expr(),
+                        c[4] => 50.expr(), c[5] => 60.expr(), c[6] => 70.expr(), c[7] => 80.expr(),
+                    ))
+                };
+                require!(a!(res) => value);
+            });
+            cb.build_constraints()
+        });
+        let constraints = cb.build_constraints();
+        let degree = constraints.iter().map(|(_, expr)| expr.degree()).max().unwrap();
+        (cb.assignment_checklist(0).len(), constraints.len(), degree)
+    };
+    // One cell per arm, all boolean and summing to 1
+    assert_eq!(cost(false), (8, 8 + 1 + 1, 2));
+    // log2(8) boolean cells, `is_arm` has degree 3
+    assert_eq!(cost(true), (3, 3 + 1, 3));
+}
+
+/// Selects one of five constants with `matchx_binary!`
+#[derive(Clone)]
+pub struct BinarySelectorConfig<F> {
+    q_enable: Column<Fixed>,
+    selector: BinarySelector<F>,
+    res: Column<Advice>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct BinarySelectorCircuit<F> {
+    /// The index assigned to the selector bits, can be past the last arm
+    index: usize,
+    res: u64,
+    _phantom: F,
+}
+
+impl<F: Field> Circuit<F> for BinarySelectorCircuit<F> {
+    type Config = BinarySelectorConfig<F>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let res = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(5, None, None);
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 3);
+        cb.set_cell_manager(cm);
+        let mut selector = None;
+        meta.create_gate("Binary selector", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let binary = cb.binary_selector(5);
+                    let value = matchx_binary!(binary, (
+                        0 => 10.expr(),
+                        1 => 20.expr(),
+                        2 => 30.expr(),
+                        3 => 40.expr(),
+                        _ => 50.expr(),
+                    ));
+                    require!(a!(res) => value);
+                    selector = Some(binary);
+                });
+            });
+            cb.build_constraints()
+        });
+        BinarySelectorConfig { q_enable, selector: selector.unwrap(), res }
+    }
+
+    fn synthesize(&self, config: Self::Config, mut layouter: impl Layouter<F>) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Binary selector",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (config.q_enable, 0) => true.scalar())?;
+                if self.index < config.selector.n_arms() {
+                    config.selector.assign(&mut region, 0, self.index)?;
+                } else {
+                    for (idx, bit) in config.selector.bits().iter().enumerate() {
+                        assign!(&mut region, bit, 0 => ((self.index >> idx) & 1).scalar())?;
+                    }
+                }
+                assign!(&mut region, (config.res, 0) => self.res.scalar())?;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn binary_selector() {
+    use halo2_proofs::{dev::MockProver, halo2curves::bn256::Fr};
+
+    let verify = |index: usize, res: u64| {
+        let circuit = BinarySelectorCircuit::<Fr> { index, res, ..Default::default() };
+        MockProver::<Fr>::run(5, &circuit, vec![]).unwrap().verify_par()
+    };
+    for (index, res) in [(0, 10), (1, 20), (2, 30), (3, 40), (4, 50)] {
+        assert!(verify(index, res).is_ok());
+        assert!(verify(index, res + 1).is_err());
+    }
+    // Indices past the last arm select nothing
+    assert!(verify(5, 50).is_err());
+    assert!(verify(7, 0).is_err());
+}
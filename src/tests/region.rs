@@ -0,0 +1,350 @@
+use eth_types::Field;
+use halo2_proofs::{
+    plonk::{Circuit, ConstraintSystem, Advice, Fixed, Column, Error},
+    circuit::{SimpleFloorPlanner, Layouter, Value},
+    dev::MockProver,
+    halo2curves::bn256::Fr,
+    poly::Rotation,
+};
+
+use rand_core::RngCore;
+
+use crate::prelude::*;
+use crate::{
+    cached_region::{Padding, PaddingSpec},
+    util::padding_sentinel,
+};
+use super::gadgets::TestCellType;
+
+const HEIGHT: usize = 8;
+const USED: usize = 4;
+
+/// Accumulates `x` into `acc` on every enabled row: `acc_next == acc + x`
+#[derive(Clone)]
+pub struct PaddingConfig<F> {
+    q_enable: Column<Fixed>,
+    acc: Column<Advice>,
+    x: Column<Advice>,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+impl<F: Field> PaddingConfig<F> {
+    pub fn new(meta: &mut ConstraintSystem<F>) -> Self {
+        let q_enable = meta.fixed_column();
+        let acc = meta.advice_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    require!(a!(acc, 1) => a!(acc) + a!(x));
+                });
+            });
+            cb.build_constraints()
+        });
+        PaddingConfig { q_enable, acc, x, cb }
+    }
+
+    pub fn assign(
+        &self,
+        layouter: &mut impl Layouter<F>,
+        pad: bool,
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                region.push_region(0, 0);
+                // The region is declared with HEIGHT rows, but only USED rows have data
+                for offset in 0..HEIGHT - 1 {
+                    assignf!(&mut region, (self.q_enable, offset) => true.scalar())?;
+                }
+                let mut acc = 0u64;
+                for offset in 0..USED {
+                    assign!(&mut region, (self.acc, offset) => F::from(acc))?;
+                    assign!(&mut region, (self.x, offset) => F::from(offset as u64 + 1))?;
+                    acc += offset as u64 + 1;
+                }
+                assign!(&mut region, (self.acc, USED) => F::from(acc))?;
+                if pad {
+                    let spec = PaddingSpec::default()
+                        .column(self.acc, Padding::CopyPrevious)
+                        .column(self.x, Padding::Constant(F::ZERO));
+                    region.pad_region(&self.cb, USED, HEIGHT, &spec)?;
+                }
+                region.pop_region();
+                Ok(())
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct PaddingCircuit {
+    pad: bool,
+}
+
+impl<F: Field> Circuit<F> for PaddingCircuit {
+    type Config = PaddingConfig<F>;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        PaddingConfig::new(meta)
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        config.assign(&mut layouter, self.pad)
+    }
+}
+
+#[test]
+fn padding() {
+    let prover = MockProver::<Fr>::run(6, &PaddingCircuit { pad: true }, vec![]).unwrap();
+    prover.assert_satisfied_par();
+
+    // Without padding the accumulator drops to zero right after the used rows
+    let prover = MockProver::<Fr>::run(6, &PaddingCircuit { pad: false }, vec![]).unwrap();
+    assert!(prover.verify_par().is_err());
+}
+
+#[test]
+fn padding_sentinels() {
+    let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+    assert_eq!(cb.seed(), 0);
+    let sentinel = cb.padding_sentinel();
+    assert_eq!(sentinel, padding_sentinel(0));
+    // Only the seed changes the sentinel
+    cb.set_seed(7);
+    assert_ne!(cb.padding_sentinel(), sentinel);
+    assert_eq!(cb.padding_sentinel(), padding_sentinel(7));
+    assert_eq!(cb.rng("witness").next_u64(), cb.rng("witness").next_u64());
+    assert_ne!(cb.rng("witness").next_u64(), cb.rng("other").next_u64());
+
+    // The seed is exported with the table, to reproduce its padding
+    cb.store_table("table", (), vec![1.expr()]);
+    let exported = cb.export_table(());
+    assert_eq!(exported.seed(), 7);
+    assert_eq!(padding_sentinel::<Fr>(exported.seed()), cb.padding_sentinel());
+}
+
+/// Assigns `1..=USED` to the first rows of a column and pads the rest of the
+/// `HEIGHT` rows with the sentinel of a builder with seed 7, recording the
+/// values of the column
+#[derive(Default)]
+struct SentinelCircuit {
+    values: std::cell::RefCell<Vec<Fr>>,
+}
+
+impl Circuit<Fr> for SentinelCircuit {
+    type Config = (Column<Advice>, ConstraintBuilder<Fr, TestCellType>);
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        Self::default()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_seed(7);
+        (meta.advice_column(), cb)
+    }
+
+    fn synthesize(
+        &self,
+        (x, cb): Self::Config,
+        mut layouter: impl Layouter<Fr>
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                region.push_region(0, 0);
+                for offset in 0..USED {
+                    assign!(&mut region, (x, offset) => Fr::from(offset as u64 + 1))?;
+                }
+                let spec = PaddingSpec::default().column(x, Padding::Sentinel);
+                region.pad_region(&cb, USED, HEIGHT, &spec)?;
+                region.pop_region();
+                *self.values.borrow_mut() = (0..HEIGHT)
+                    .map(|offset| region.get_advice(offset, x.index(), Rotation::cur()))
+                    .collect();
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn sentinel_padding() {
+    let circuit = SentinelCircuit::default();
+    MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+    let values = circuit.values.borrow();
+    let expected = (1..=USED as u64)
+        .map(Fr::from)
+        .chain(std::iter::repeat(padding_sentinel(7)).take(HEIGHT - USED))
+        .collect::<Vec<_>>();
+    assert_eq!(*values, expected);
+}
+
+/// Assigns `values` in region 1 and a volatile value after them, recording
+/// the witness digest of the region
+#[cfg(feature = "sha2")]
+#[derive(Default)]
+struct DigestCircuit {
+    values: Vec<u64>,
+    volatile: u64,
+    digest: std::cell::RefCell<[u8; 32]>,
+}
+
+#[cfg(feature = "sha2")]
+impl<F: Field> Circuit<F> for DigestCircuit {
+    type Config = (Column<Fixed>, Column<Advice>);
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        unimplemented!()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        (meta.fixed_column(), meta.advice_column())
+    }
+
+    fn synthesize(
+        &self,
+        (q_enable, x): Self::Config,
+        mut layouter: impl Layouter<F>
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                region.push_region(0, 1);
+                for (offset, value) in self.values.iter().enumerate() {
+                    assignf!(&mut region, (q_enable, offset) => true.scalar())?;
+                    assign!(&mut region, (x, offset) => F::from(*value))?;
+                }
+                let offset = self.values.len();
+                assign!(&mut region, (x, offset) => F::from(self.volatile))?;
+                region.mark_volatile(x, offset);
+                region.pop_region();
+                // Not part of the region
+                assign!(&mut region, (x, offset + 1) => F::from(self.volatile))?;
+                *self.digest.borrow_mut() = region.witness_digest(1);
+                Ok(())
+            }
+        )
+    }
+}
+
+#[cfg(feature = "sha2")]
+#[test]
+fn witness_digest() {
+    let digest = |values: Vec<u64>, volatile: u64| {
+        let circuit = DigestCircuit { values, volatile, ..Default::default() };
+        MockProver::<Fr>::run(6, &circuit, vec![]).unwrap();
+        let digest = *circuit.digest.borrow();
+        digest
+    };
+    let values = vec![1, 2, 3, 4];
+    let reference = digest(values.clone(), 0);
+    assert_eq!(digest(values.clone(), 0), reference);
+    // Volatile values and values outside of the region are ignored
+    assert_eq!(digest(values.clone(), 7), reference);
+    // Any single value changes the digest
+    for idx in 0..values.len() {
+        let mut changed = values.clone();
+        changed[idx] += 1;
+        assert_ne!(digest(changed, 0), reference);
+    }
+}
+
+#[derive(Clone)]
+struct GroupedConfig {
+    q_enable: Column<Fixed>,
+    inputs: Vec<Cell<Fr>>,
+    cb: ConstraintBuilder<Fr, TestCellType>,
+}
+
+/// Stores products of the inputs, one of them using the cells of two others,
+/// and dumps the advice values after assigning them
+#[derive(Default)]
+struct GroupedCircuit<const GROUPED: bool> {
+    dump: std::cell::RefCell<Vec<((usize, usize), Fr)>>,
+}
+
+impl<const GROUPED: bool> Circuit<Fr> for GroupedCircuit<GROUPED> {
+    type Config = GroupedConfig;
+    type FloorPlanner = SimpleFloorPlanner;
+    type Params = ();
+
+    fn without_witnesses(&self) -> Self {
+        Self::default()
+    }
+
+    fn configure(meta: &mut ConstraintSystem<Fr>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.set_column_grouped_assignment(GROUPED);
+        let mut cm = CellManager::new(HEIGHT, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 3);
+        cb.set_cell_manager(cm);
+        let inputs = (0..4).map(|_| cb.query_default()).collect::<Vec<_>>();
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let [a, b, c, d] = [0, 1, 2, 3].map(|idx| inputs[idx].expr());
+                    let ab = cb.store_expression("ab", a.expr() * b.expr(), TestCellType::Storage, None);
+                    let cd = cb.store_expression("cd", c.expr() * d.expr(), TestCellType::Storage, None);
+                    cb.store_expression("abcd", ab.expr() * cd.expr(), TestCellType::Storage, None);
+                    cb.store_expression("a + d", a.expr() + d.expr(), TestCellType::Storage, None);
+                });
+            });
+            cb.build_constraints()
+        });
+        GroupedConfig { q_enable, inputs, cb }
+    }
+
+    fn synthesize(
+        &self,
+        config: Self::Config,
+        mut layouter: impl Layouter<Fr>
+    ) -> Result<(), Error> {
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (config.q_enable, 0) => true.scalar())?;
+                for (cell, value) in config.inputs.iter().zip([2u64, 3, 5, 7]) {
+                    assign!(&mut region, cell, 0 => Fr::from(value))?;
+                }
+                region.assign_stored_expressions_at(&config.cb, 0, 0, &Vec::<Value<Fr>>::new())?;
+                let mut dump = region.advice.iter().map(|(key, value)| (*key, *value)).collect::<Vec<_>>();
+                dump.sort_by_key(|(key, _)| *key);
+                *self.dump.borrow_mut() = dump;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[test]
+fn column_grouped_assignment() {
+    let ordered = GroupedCircuit::<false>::default();
+    MockProver::<Fr>::run(6, &ordered, vec![]).unwrap().assert_satisfied_par();
+    let grouped = GroupedCircuit::<true>::default();
+    MockProver::<Fr>::run(6, &grouped, vec![]).unwrap().assert_satisfied_par();
+    assert_eq!(ordered.dump.borrow().len(), 8);
+    assert_eq!(*ordered.dump.borrow(), *grouped.dump.borrow());
+}
@@ -0,0 +1,919 @@
+use eth_types::Field;
+use halo2_proofs::{
+    plonk::{ConstraintSystem, Advice, Any, Fixed, Column, Error, Expression, TableColumn},
+    circuit::{Layouter, Value},
+    dev::{MockProver, VerifyFailure},
+    halo2curves::bn256::Fr,
+    poly::Rotation,
+};
+
+use zkevm_circuits::table::LookupTable;
+
+use crate::prelude::*;
+use crate::{
+    constraint_builder::SetMembership,
+    mock::MockMeta,
+    table::{assign_fixed_range, assign_sparse_fixed_table, assign_table_range, RangeTable},
+};
+use super::{gadgets::TestCellType, mock_prover, RegionCircuit, RegionTest};
+
+const RANGE: usize = 256;
+
+/// Requires `x` to be in `0..10`, either with a product (no range table) or
+/// with range lookups
+#[derive(Clone)]
+pub struct RangeConfig {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    x: Column<Advice>,
+}
+
+impl RangeConfig {
+    pub fn new<F: Field>(meta: &mut ConstraintSystem<F>, use_table: bool) -> (Self, usize) {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        if use_table {
+            cb.load_fixed_range(meta, range, RANGE);
+        }
+
+        let mut num_constraints = 0;
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    require!(a!(x) => (0..10));
+                });
+            });
+            num_constraints = cb.build_constraints().iter().filter(|(_, c)| c.degree() > 0).count();
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        (RangeConfig { q_enable, range, x }, num_constraints)
+    }
+
+    pub fn assign<F: Field>(
+        &self,
+        layouter: &mut impl Layouter<F>,
+        x: u64,
+    ) -> Result<(), Error> {
+        assign_fixed_range(layouter, self.range, RANGE)?;
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (self.q_enable, 0) => true.scalar())?;
+                assign!(&mut region, (self.x, 0) => F::from(x))?;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct RangeCircuit<const TABLE: bool> {
+    x: u64,
+}
+
+impl<F: Field, const TABLE: bool> RegionTest<F> for RangeCircuit<TABLE> {
+    type Config = RangeConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        RangeConfig::new(meta, TABLE).0
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        config.assign(layouter, self.x)
+    }
+}
+
+#[test]
+fn require_in_set_with_range_table() {
+    let verify = |x: u64, table: bool| {
+        let prover = if table {
+            MockProver::<Fr>::run(9, &RegionCircuit(RangeCircuit::<true> { x }), vec![])
+        } else {
+            MockProver::<Fr>::run(9, &RegionCircuit(RangeCircuit::<false> { x }), vec![])
+        };
+        prover.unwrap().verify_par().is_ok()
+    };
+    // Both implementations accept and reject the same witnesses
+    for x in [0, 5, 9, 10, 11, 255, 300] {
+        assert_eq!(verify(x, true), x < 10);
+        assert_eq!(verify(x, false), x < 10);
+    }
+
+    // The lookup path doesn't add any constraints
+    let mut meta = ConstraintSystem::<Fr>::default();
+    assert_eq!(RangeConfig::new(&mut meta, true).1, 0);
+    let mut meta = ConstraintSystem::<Fr>::default();
+    assert_eq!(RangeConfig::new(&mut meta, false).1, 1);
+}
+
+#[test]
+fn fixed_table_registry() {
+    let mut registry = FixedTableRegistry::<Fr, &'static str>::default();
+    registry.register_bytes("bytes");
+    registry.register("pow2", (0..8).map(|i| vec![Fr::from(i), Fr::from(1 << i)]).collect());
+    assert_eq!(registry.tags(), vec!["bytes", "pow2"]);
+
+    let bytes = registry.fixed_table_contents("bytes").unwrap();
+    assert_eq!(bytes.len(), 256);
+    for (byte, row) in bytes.iter().enumerate() {
+        assert_eq!(row, &vec![Fr::from(byte as u64)]);
+    }
+    assert_eq!(registry.fixed_table_contents("pow2").unwrap()[3], vec![Fr::from(3), Fr::from(8)]);
+    assert!(registry.fixed_table_contents("missing").is_none());
+}
+
+/// Requires `x` to be in `3..7` with the given strategy
+#[derive(Clone)]
+pub struct SetConfig<F> {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    x: Column<Advice>,
+    cb: ConstraintBuilder<F, TestCellType>,
+}
+
+const STRATEGIES: [SetMembership; 3] =
+    [SetMembership::Product, SetMembership::Lookup, SetMembership::Indicators];
+
+impl<F: Field> SetConfig<F> {
+    pub fn new(meta: &mut ConstraintSystem<F>, strategy: SetMembership) -> Self {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let x = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 8);
+        cb.set_cell_manager(cm);
+        cb.load_fixed_range(meta, range, RANGE);
+        cb.set_set_membership(strategy);
+
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    require!(a!(x) => (3..7));
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        SetConfig { q_enable, range, x, cb }
+    }
+
+    pub fn assign(&self, layouter: &mut impl Layouter<F>, x: u64) -> Result<(), Error> {
+        assign_fixed_range(layouter, self.range, RANGE)?;
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (self.q_enable, 0) => true.scalar())?;
+                assign!(&mut region, (self.x, 0) => F::from(x))?;
+                region.assign_stored_expressions_at(&self.cb, 0, 0, &Vec::<Value<F>>::new())?;
+                Ok(())
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct SetCircuit<const STRATEGY: usize> {
+    x: u64,
+}
+
+impl<F: Field, const STRATEGY: usize> RegionTest<F> for SetCircuit<STRATEGY> {
+    type Config = SetConfig<F>;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        SetConfig::new(meta, STRATEGIES[STRATEGY])
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        config.assign(layouter, self.x)
+    }
+}
+
+#[test]
+fn set_membership_strategies() {
+    let verify = |x: u64, strategy: usize| {
+        let prover = match strategy {
+            0 => MockProver::<Fr>::run(9, &RegionCircuit(SetCircuit::<0> { x }), vec![]),
+            1 => MockProver::<Fr>::run(9, &RegionCircuit(SetCircuit::<1> { x }), vec![]),
+            _ => MockProver::<Fr>::run(9, &RegionCircuit(SetCircuit::<2> { x }), vec![]),
+        };
+        prover.unwrap().verify_par().is_ok()
+    };
+    // All strategies accept and reject the same witnesses
+    for x in [0, 2, 3, 4, 6, 7, 100] {
+        for strategy in 0..STRATEGIES.len() {
+            assert_eq!(verify(x, strategy), (3..7).contains(&x), "{:?} {}", STRATEGIES[strategy], x);
+        }
+    }
+
+    // The indicators are of degree 2 whatever the size of the set
+    for size in 2..=4 {
+        let mut meta = ConstraintSystem::<Fr>::default();
+        let x = meta.advice_column();
+        let x = crate::util::query_expression(&mut meta, |meta| meta.query_advice(x, Rotation::cur()));
+        let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(&mut meta, &mut cb, TestCellType::Storage, 0, false, 2 * size);
+        cb.set_cell_manager(cm);
+        let set = (0..size).map(|item| item.expr()).collect();
+        let indicators = cb.require_in_set_indicators("x in set", x.expr(), set);
+        assert_eq!(indicators.len(), size);
+        let degrees = cb.build_constraints().iter().map(|(_, c)| c.degree()).collect::<Vec<_>>();
+        assert_eq!(degrees.iter().max(), Some(&2));
+    }
+}
+
+const SPARSE_HEIGHT: usize = 16;
+
+fn sparse_entries<F: Field>() -> Vec<(usize, Vec<F>)> {
+    vec![(3, vec![F::from(30)]), (7, vec![F::from(70)]), (12, vec![F::from(120)])]
+}
+
+/// Looks up `(index, value)` in a sparse table with the entries above
+#[derive(Clone)]
+pub struct SparseConfig {
+    q_enable: Column<Fixed>,
+    range: Column<Fixed>,
+    table: [Column<Fixed>; 2],
+    index: Column<Advice>,
+    value: Column<Advice>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct SparseCircuit {
+    index: u64,
+    value: u64,
+}
+
+impl<F: Field> RegionTest<F> for SparseCircuit {
+    type Config = SparseConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let range = meta.fixed_column();
+        let table = [meta.fixed_column(), meta.fixed_column()];
+        let (index, value) = (meta.advice_column(), meta.advice_column());
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.load_fixed_range(meta, range, RANGE);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    let table = vec![f!(table[0]), f!(table[1])];
+                    cb.lookup_sparse("sparse", a!(index), vec![a!(value)], table, SPARSE_HEIGHT);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        SparseConfig { q_enable, range, table, index, value }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_fixed_range(layouter, config.range, RANGE)?;
+        let mut registry = FixedTableRegistry::default();
+        assign_sparse_fixed_table(
+            layouter,
+            &mut registry,
+            "sparse",
+            &config.table,
+            &sparse_entries(),
+            SPARSE_HEIGHT,
+            F::ZERO,
+        )?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, (config.index, 0) => F::from(self.index))?;
+        assign!(region, (config.value, 0) => F::from(self.value))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn sparse_fixed_table() {
+    let verify = |index: u64, value: u64| {
+        mock_prover(9, SparseCircuit { index, value }).verify_par()
+    };
+    // Present entries
+    assert!(verify(3, 30).is_ok());
+    assert!(verify(12, 120).is_ok());
+    assert!(verify(3, 70).is_err());
+    // Gaps only contain the fill value
+    assert!(verify(5, 50).is_err());
+    // Indices past the table height fail the range check
+    let failures = verify(SPARSE_HEIGHT as u64 + 4, 0).unwrap_err();
+    assert!(failures.iter().any(|failure| failure.to_string().contains("sparse (range max)")));
+
+    let mut registry = FixedTableRegistry::<Fr, &'static str>::default();
+    registry.register_sparse("sparse", &sparse_entries(), SPARSE_HEIGHT, Fr::from(0));
+    let rows = registry.fixed_table_contents("sparse").unwrap();
+    assert_eq!(rows.len(), SPARSE_HEIGHT);
+    assert_eq!(rows[5], vec![Fr::from(5), Fr::from(0)]);
+    assert_eq!(rows[7], vec![Fr::from(7), Fr::from(70)]);
+}
+
+#[test]
+#[should_panic(expected = "sparse tables need at least one entry")]
+fn sparse_fixed_table_without_entries() {
+    let mut registry = FixedTableRegistry::<Fr, &'static str>::default();
+    registry.register_sparse("sparse", &[], SPARSE_HEIGHT, Fr::from(0));
+}
+
+#[test]
+#[should_panic(expected = "'sparse': sparse tables need at least one row")]
+fn sparse_lookup_without_rows() {
+    let mut cb: ConstraintBuilder<Fr, TestCellType> = ConstraintBuilder::new(4, None, None);
+    cb.lookup_sparse("sparse", 1.expr(), vec![1.expr()], vec![1.expr(), 1.expr()], 0);
+}
+
+const CONTINUITY_VALUES: [u64; 5] = [2, 3, 5, 8, 13];
+
+/// Single column fixed table annotated as `value`
+#[derive(Clone, Copy)]
+struct ValueTable {
+    value: Column<Fixed>,
+}
+
+impl<F: Field> LookupTable<F> for ValueTable {
+    fn columns(&self) -> Vec<Column<Any>> {
+        vec![self.value.into()]
+    }
+
+    fn annotations(&self) -> Vec<String> {
+        vec!["value".to_string()]
+    }
+}
+
+/// Requires `(x, y)` to be adjacent values of the table, by looking up into
+/// the table queried at the current and the next row
+#[derive(Clone)]
+pub struct ContinuityConfig {
+    q_enable: Column<Fixed>,
+    table: Column<Fixed>,
+    x: Column<Advice>,
+    y: Column<Advice>,
+}
+
+impl ContinuityConfig {
+    fn new<F: Field>(meta: &mut ConstraintSystem<F>) -> (Self, ConstraintBuilder<F, TestCellType>) {
+        let x = meta.advice_column();
+        let y = meta.advice_column();
+        let q_enable = meta.fixed_column();
+        let table = meta.fixed_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.load_table_with_rotations(meta, (), &ValueTable { value: table }, &[0, 1]);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    cb.lookup_columns(
+                        "continuity",
+                        (),
+                        &["value", "value@next"],
+                        vec![a!(x), a!(y)],
+                    );
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        (ContinuityConfig { q_enable, table, x, y }, cb)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct ContinuityCircuit {
+    x: u64,
+    y: u64,
+}
+
+impl<F: Field> RegionTest<F> for ContinuityCircuit {
+    type Config = ContinuityConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        ContinuityConfig::new(meta).0
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        let mut registry = FixedTableRegistry::default();
+        registry.register(
+            "values",
+            CONTINUITY_VALUES.iter().map(|value| vec![F::from(*value)]).collect(),
+        );
+        registry.assign(layouter, "values", &[config.table])?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => F::from(self.x))?;
+        assign!(region, (config.y, 0) => F::from(self.y))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn rotated_table_lookup() {
+    let verify = |x: u64, y: u64| {
+        mock_prover(6, ContinuityCircuit { x, y }).verify_par().is_ok()
+    };
+    assert!(verify(2, 3));
+    assert!(verify(5, 8));
+    assert!(!verify(3, 8));
+    assert!(!verify(8, 5));
+    assert!(!verify(13, 2));
+
+    let mut registry = FixedTableRegistry::<Fr, &'static str>::default();
+    registry.register("values", CONTINUITY_VALUES.iter().map(|value| vec![Fr::from(*value)]).collect());
+    let rows = registry.rotated_contents("values", &[0, 1]).unwrap();
+    // The last row is followed by the unassigned rows, and the all zero row
+    // is added
+    assert_eq!(rows.len(), CONTINUITY_VALUES.len() + 1);
+    assert_eq!(rows[1], vec![Fr::from(3), Fr::from(5)]);
+    assert_eq!(rows[4], vec![Fr::from(13), Fr::from(0)]);
+    assert_eq!(rows[5], vec![Fr::from(0), Fr::from(0)]);
+    assert_eq!(registry.rotated_contents("values", &[-1]).unwrap()[0], vec![Fr::from(0)]);
+
+    // Checks witnesses against the recorded contents without a prover. The
+    // advice columns of the mock get the same indices as `x` and `y`.
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let (config, cb) = ContinuityConfig::new(&mut meta);
+    assert_eq!(cb.table_schema(()), Some(&["value", "value@next"][..]));
+    let mut mock = MockMeta::<Fr>::new();
+    let (x, y) = (mock.advice_column_in(0), mock.advice_column_in(0));
+    assert_eq!((x.index(), y.index()), (config.x.index(), config.y.index()));
+    mock.set_fixed(config.q_enable, Rotation::cur(), Fr::from(1));
+    let mut check = |x_value: u64, y_value: u64| {
+        mock.set_advice(x, Rotation::cur(), Fr::from(x_value));
+        mock.set_advice(y, Rotation::cur(), Fr::from(y_value));
+        cb.unsatisfied_lookups(|expr| mock.evaluate(expr), |_| rows.clone())
+    };
+    assert!(check(8, 13).is_empty());
+    assert_eq!(check(2, 5), vec!["continuity".to_string()]);
+}
+
+/// Table tags of a circuit: a fixed table of small values and a dynamic table
+/// stored in the circuit
+/// The tags of a circuit. `TestCellType` has no lookup cell types, so its
+/// table type `()` is never converted into a tag.
+trait CircuitTags: TableTag + From<()> {
+    const FIXED: Self;
+    const DYNAMIC: Self;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum FetchTag {
+    Rom,
+    Stack,
+}
+
+impl CircuitTags for FetchTag {
+    const FIXED: Self = FetchTag::Rom;
+    const DYNAMIC: Self = FetchTag::Stack;
+}
+
+impl From<()> for FetchTag {
+    fn from(_: ()) -> Self {
+        unreachable!()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ExecTag {
+    Opcode,
+    Trace,
+    Unused,
+}
+
+impl CircuitTags for ExecTag {
+    const FIXED: Self = ExecTag::Opcode;
+    const DYNAMIC: Self = ExecTag::Trace;
+}
+
+impl From<()> for ExecTag {
+    fn from(_: ()) -> Self {
+        unreachable!()
+    }
+}
+
+/// Requires `x` to be in the fixed table and `y` in the dynamic table, with
+/// the tags of `T`. The cells come from a cell manager of `TestCellType`,
+/// whatever the tags.
+#[derive(Clone)]
+pub struct TaggedConfig<F> {
+    q_table: Column<Fixed>,
+    q_lookup: Column<Fixed>,
+    fixed: Column<Fixed>,
+    dynamic: Column<Advice>,
+    x: Cell<F>,
+    y: Cell<F>,
+}
+
+impl<F: Field> TaggedConfig<F> {
+    fn new<T: CircuitTags>(
+        meta: &mut ConstraintSystem<F>,
+    ) -> (Self, ConstraintBuilder<F, TestCellType, T>) {
+        let (q_table, q_lookup) = (meta.fixed_column(), meta.fixed_column());
+        let fixed = meta.fixed_column();
+        let dynamic = meta.advice_column();
+        let mut cb: ConstraintBuilder<F, TestCellType, T> = ConstraintBuilder::new(4, None, None);
+        let mut cm = CellManager::new(1, 0);
+        cm.add_columns(meta, &mut cb, TestCellType::Storage, 0, false, 2);
+        cb.set_cell_manager(cm);
+        cb.load_table(meta, T::FIXED, &[fixed]);
+        let (x, y) = (cb.query_one(TestCellType::Storage), cb.query_one(TestCellType::Storage));
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_table) => {
+                    cb.store_table("dynamic", T::DYNAMIC, vec![a!(dynamic)]);
+                });
+                let stored = cb.dynamic_table_merged(T::DYNAMIC);
+                ifx!(f!(q_lookup) => {
+                    require!((x.expr()) => @cb.table(T::FIXED));
+                    require!((y.expr()) => @stored);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        (TaggedConfig { q_table, q_lookup, fixed, dynamic, x, y }, cb)
+    }
+
+    fn assign(&self, layouter: &mut impl Layouter<F>, x: u64, y: u64) -> Result<(), Error> {
+        assign_fixed_range(layouter, self.fixed, 4)?;
+        layouter.assign_region(
+            || "Test",
+            |mut region| {
+                let mut region = CachedRegion::new(&mut region, Value::unknown());
+                assignf!(&mut region, (self.q_table, 0) => true.scalar())?;
+                assign!(&mut region, (self.dynamic, 0) => F::from(10))?;
+                assignf!(&mut region, (self.q_table, 1) => true.scalar())?;
+                assign!(&mut region, (self.dynamic, 1) => F::from(20))?;
+                assignf!(&mut region, (self.q_lookup, 0) => true.scalar())?;
+                self.x.assign(&mut region, 0, F::from(x))?;
+                self.y.assign(&mut region, 0, F::from(y))?;
+                Ok(())
+            }
+        )
+    }
+}
+
+/// The same circuit, tagging its tables with `T`
+#[derive(Clone, Debug)]
+struct TaggedCircuit<T> {
+    x: u64,
+    y: u64,
+    _tags: std::marker::PhantomData<T>,
+}
+
+// Derived `Default` would require `T: Default`
+impl<T> Default for TaggedCircuit<T> {
+    fn default() -> Self {
+        Self { x: 0, y: 0, _tags: std::marker::PhantomData }
+    }
+}
+
+impl<F: Field, T: CircuitTags> RegionTest<F> for TaggedCircuit<T> {
+    type Config = TaggedConfig<F>;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        TaggedConfig::new::<T>(meta).0
+    }
+
+    fn synthesize(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        config.assign(layouter, self.x, self.y)
+    }
+}
+
+#[test]
+fn table_tags_independent_of_cell_type() {
+    fn verify<T: CircuitTags>(x: u64, y: u64) -> bool {
+        let circuit = RegionCircuit(TaggedCircuit::<T> { x, y, _tags: std::marker::PhantomData });
+        MockProver::<Fr>::run(6, &circuit, vec![]).unwrap().verify_par().is_ok()
+    }
+    // Both circuits share the cell type and accept the same witnesses
+    for (x, y, ok) in [(3, 10, true), (0, 20, true), (4, 10, false), (2, 15, false)] {
+        assert_eq!(verify::<FetchTag>(x, y), ok, "{} {}", x, y);
+        assert_eq!(verify::<ExecTag>(x, y), ok, "{} {}", x, y);
+    }
+
+    // The tables are tagged with the enum of each circuit
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let (_, cb) = TaggedConfig::new::<FetchTag>(&mut meta);
+    let mut tags = cb.tables.keys().copied().collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, vec![FetchTag::Rom, FetchTag::Stack]);
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let (_, cb) = TaggedConfig::new::<ExecTag>(&mut meta);
+    assert!(cb.tables.contains_key(&ExecTag::Trace));
+    assert!(!cb.tables.contains_key(&ExecTag::Unused));
+    assert_eq!(cb.table(ExecTag::Opcode).len(), 1);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum RangeTag {
+    U8,
+    U16,
+}
+
+#[derive(Clone)]
+pub struct RangeTablesConfig {
+    q_enable: Column<Fixed>,
+    u8_table: RangeTable,
+    u16_table: RangeTable,
+    byte: Column<Advice>,
+    word: Column<Advice>,
+}
+
+/// Requires `byte` to be a byte and `word` to fit in 16 bits
+#[derive(Clone, Debug, Default)]
+struct RangeTablesCircuit {
+    byte: u64,
+    word: u64,
+}
+
+impl<F: Field> RegionTest<F> for RangeTablesCircuit {
+    type Config = RangeTablesConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let (byte, word) = (meta.advice_column(), meta.advice_column());
+        let u8_table = RangeTable::construct(meta, 8);
+        let u16_table = RangeTable::construct(meta, 16);
+        let mut cb: ConstraintBuilder<F, TestCellType, RangeTag> = ConstraintBuilder::new(4, None, None);
+        cb.load_range_table(meta, RangeTag::U8, &u8_table);
+        cb.load_range_table(meta, RangeTag::U16, &u16_table);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    cb.require_range_lookup("byte", a!(byte), RangeTag::U8);
+                    cb.require_range_lookup("word", a!(word), RangeTag::U16);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        RangeTablesConfig { q_enable, u8_table, u16_table, byte, word }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        config.u8_table.load_witness(layouter)?;
+        config.u16_table.load_witness(layouter)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, (config.byte, 0) => F::from(self.byte))?;
+        assign!(region, (config.word, 0) => F::from(self.word))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn range_tables() {
+    let verify = |byte: u64, word: u64| {
+        mock_prover(17, RangeTablesCircuit { byte, word }).verify_par().is_ok()
+    };
+    assert!(verify(0, 0));
+    assert!(verify(255, 65535));
+    assert!(!verify(256, 65535));
+    assert!(!verify(255, 65536));
+
+    let mut meta = ConstraintSystem::<Fr>::default();
+    let table = RangeTable::construct(&mut meta, 10);
+    assert_eq!((table.bits(), table.size()), (10, 1024));
+    assert_eq!(LookupTable::<Fr>::annotations(&table), vec!["u10".to_string()]);
+    assert_eq!(LookupTable::<Fr>::columns(&table), vec![Column::<Any>::from(table.column())]);
+}
+
+#[derive(Clone)]
+pub struct StaticByteConfig {
+    q_enable: Column<Fixed>,
+    bytes: TableColumn,
+    x: Column<Advice>,
+}
+
+/// Requires `x` to be a byte with a native halo2 lookup
+#[derive(Clone, Debug, Default)]
+struct StaticByteCircuit {
+    x: u64,
+}
+
+impl<F: Field> RegionTest<F> for StaticByteCircuit {
+    type Config = StaticByteConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let q_enable = meta.fixed_column();
+        let x = meta.advice_column();
+        let bytes = meta.lookup_table_column();
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        cb.register_table_columns((), vec![bytes]);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_enable) => {
+                    cb.add_static_lookup("x is a byte", vec![a!(x)], ());
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        cb.build_static_lookups(meta);
+        StaticByteConfig { q_enable, bytes, x }
+    }
+
+    fn assign_tables(&self, config: &Self::Config, layouter: &mut impl Layouter<F>) -> Result<(), Error> {
+        assign_table_range(layouter, config.bytes, RANGE)?;
+        Ok(())
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_enable, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => F::from(self.x))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn static_table_lookup() {
+    let verify = |x: u64| mock_prover(9, StaticByteCircuit { x }).verify_par();
+    assert!(verify(0).is_ok());
+    assert!(verify(255).is_ok());
+    assert!(verify(256).is_err());
+
+    // A single native lookup, no `lookup_any`
+    let mut meta = ConstraintSystem::<Fr>::default();
+    StaticByteCircuit::configure(&mut meta);
+    assert_eq!(meta.lookups().len(), 1);
+}
+
+/// Requires `(x, y)` to be a row of the pairs stored in the dynamic table,
+/// which also has rows of a single column. With `SENTINEL` the single column
+/// rows are padded with a value that's never a pair.
+#[derive(Clone)]
+pub struct FragmentsConfig {
+    q_pair: Column<Fixed>,
+    q_single: Column<Fixed>,
+    q_lookup: Column<Fixed>,
+    pair: [Column<Advice>; 2],
+    single: Column<Advice>,
+    x: Column<Advice>,
+    y: Column<Advice>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct FragmentsCircuit<const SENTINEL: bool> {
+    x: u64,
+    y: u64,
+}
+
+impl<F: Field, const SENTINEL: bool> RegionTest<F> for FragmentsCircuit<SENTINEL> {
+    type Config = FragmentsConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let [q_pair, q_single, q_lookup] = [(); 3].map(|_| meta.fixed_column());
+        let pair = [(); 2].map(|_| meta.advice_column());
+        let [single, x, y] = [(); 3].map(|_| meta.advice_column());
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_pair) => {
+                    cb.store_table("pairs", (), vec![a!(pair[0]), a!(pair[1])]);
+                });
+                ifx!(f!(q_single) => {
+                    cb.store_table("singles", (), vec![a!(single)]);
+                });
+                let table = if SENTINEL {
+                    cb.dynamic_table_merged_with_default((), Expression::Constant(F::from(u64::MAX)))
+                } else {
+                    cb.dynamic_table_merged(())
+                };
+                ifx!(f!(q_lookup) => {
+                    require!((a!(x), a!(y)) => @table);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        FragmentsConfig { q_pair, q_single, q_lookup, pair, single, x, y }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        assignf!(region, (config.q_pair, 0) => true.scalar())?;
+        assign!(region, (config.pair[0], 0) => F::from(1))?;
+        assign!(region, (config.pair[1], 0) => F::from(2))?;
+        assignf!(region, (config.q_single, 1) => true.scalar())?;
+        assign!(region, (config.single, 1) => F::from(5))?;
+        assignf!(region, (config.q_lookup, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => F::from(self.x))?;
+        assign!(region, (config.y, 0) => F::from(self.y))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn merged_table_padding() {
+    fn verify<const SENTINEL: bool>(x: u64, y: u64) -> bool {
+        let circuit = RegionCircuit(FragmentsCircuit::<SENTINEL> { x, y });
+        MockProver::<Fr>::run(6, &circuit, vec![]).unwrap().verify_par().is_ok()
+    }
+    assert!(verify::<false>(1, 2));
+    assert!(verify::<true>(1, 2));
+    assert!(!verify::<true>(2, 1));
+    // `(5, 0)` was never stored as a pair, but matches the single value row
+    // padded with zero
+    assert!(verify::<false>(5, 0));
+    assert!(!verify::<true>(5, 0));
+}
+
+/// Requires `x` to be in the dynamic table stored in two fragments, merged
+/// with or without the check on the fragment conditions
+#[derive(Clone)]
+pub struct OverlapConfig {
+    q_a: Column<Fixed>,
+    q_b: Column<Fixed>,
+    q_lookup: Column<Fixed>,
+    a: Column<Advice>,
+    b: Column<Advice>,
+    x: Column<Advice>,
+}
+
+/// Stores `1` in the first fragment and `2` in the second, on the same row
+/// when `overlap` is set
+#[derive(Clone, Debug, Default)]
+struct OverlapCircuit<const CHECKED: bool> {
+    x: u64,
+    overlap: bool,
+}
+
+impl<F: Field, const CHECKED: bool> RegionTest<F> for OverlapCircuit<CHECKED> {
+    type Config = OverlapConfig;
+
+    fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+        let [q_a, q_b, q_lookup] = [(); 3].map(|_| meta.fixed_column());
+        let [a, b, x] = [(); 3].map(|_| meta.advice_column());
+        let mut cb: ConstraintBuilder<F, TestCellType> = ConstraintBuilder::new(4, None, None);
+        meta.create_gate("Test", |meta| {
+            circuit!([meta, cb], {
+                ifx!(f!(q_a) => {
+                    cb.store_table("a", (), vec![a!(a)]);
+                });
+                ifx!(f!(q_b) => {
+                    cb.store_table("b", (), vec![a!(b)]);
+                });
+                let table = if CHECKED {
+                    let (selector, values) = cb.dynamic_table_merged_checked(());
+                    values.iter().map(|value| selector.expr() * value.expr()).collect()
+                } else {
+                    cb.dynamic_table_merged(())
+                };
+                ifx!(f!(q_lookup) => {
+                    require!((a!(x)) => @table);
+                });
+            });
+            cb.build_constraints()
+        });
+        cb.build_lookups(meta);
+        OverlapConfig { q_a, q_b, q_lookup, a, b, x }
+    }
+
+    fn assign(&self, config: &Self::Config, region: &mut CachedRegion<'_, '_, F>) -> Result<(), Error> {
+        let b_offset = if self.overlap { 0 } else { 1 };
+        assignf!(region, (config.q_a, 0) => true.scalar())?;
+        assign!(region, (config.a, 0) => F::from(1))?;
+        assignf!(region, (config.q_b, b_offset) => true.scalar())?;
+        assign!(region, (config.b, b_offset) => F::from(2))?;
+        assignf!(region, (config.q_lookup, 0) => true.scalar())?;
+        assign!(region, (config.x, 0) => F::from(self.x))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn checked_table_merge() {
+    fn verify<const CHECKED: bool>(x: u64, overlap: bool) -> Result<(), Vec<VerifyFailure>> {
+        let circuit = RegionCircuit(OverlapCircuit::<CHECKED> { x, overlap });
+        MockProver::<Fr>::run(6, &circuit, vec![]).unwrap().verify_par()
+    }
+    // Without overlap both merge the same table
+    for x in [1, 2] {
+        assert!(verify::<false>(x, false).is_ok());
+        assert!(verify::<true>(x, false).is_ok());
+    }
+    assert!(verify::<false>(3, false).is_err());
+    assert!(verify::<true>(3, false).is_err());
+
+    // Overlapping rows are silently added together, `(1 + 1)⋅(1 + 2)` is in
+    // the table while neither value is
+    assert!(verify::<false>(6, true).is_ok());
+    assert!(verify::<false>(1, true).is_err());
+    // The check on the conditions rejects the overlap
+    let failures = verify::<true>(6, true).unwrap_err();
+    assert!(failures.iter().any(|failure| matches!(failure, VerifyFailure::ConstraintNotSatisfied { .. })));
+}
@@ -1,5 +1,13 @@
+use std::collections::{HashMap, HashSet};
 use eth_types::{Field};
-use halo2_proofs::circuit::Value;
+use halo2_proofs::{
+    circuit::{Layouter, Value},
+    plonk::{
+        Advice, Any, Challenge, Column, ConstraintSystem, Expression, FirstPhase, Fixed,
+        SecondPhase, Selector, VirtualCells,
+    },
+    poly::Rotation,
+};
 pub use zkevm_circuits::util::*;
 pub use zkevm_gadgets::util::*;
 
@@ -12,6 +20,477 @@ pub(crate) fn transpose_val_ret<F, E>(value: Value<Result<F, E>>) -> Result<Valu
     ret
 }
 
+/// Challenges of a multi-phase circuit, grouped by the phase after which they
+/// become usable. Holds `Challenge` handles at configure time, and their
+/// expressions or values after `exprs`/`values`. The `Challenge::index` of
+/// every challenge is kept, so they can be matched with challenge queries.
+#[derive(Clone, Debug)]
+pub struct Challenges<T = Challenge> {
+    phase1: Vec<T>,
+    phase2: Vec<T>,
+    indices: Vec<usize>,
+}
+
+impl Challenges {
+    /// Allocates `n_phase1` challenges usable after the first phase and
+    /// `n_phase2` challenges usable after the second phase, including the
+    /// dummy advice columns needed for those phases to exist.
+    pub(crate) fn configure<F: Field>(
+        meta: &mut ConstraintSystem<F>,
+        n_phase1: usize,
+        n_phase2: usize,
+    ) -> Self {
+        // Dummy columns for the phases the challenges are squeezed after
+        meta.advice_column_in(FirstPhase);
+        if n_phase2 > 0 {
+            meta.advice_column_in(SecondPhase);
+        }
+        let phase1 = (0..n_phase1)
+            .map(|_| meta.challenge_usable_after(FirstPhase))
+            .collect::<Vec<_>>();
+        let phase2 = (0..n_phase2)
+            .map(|_| meta.challenge_usable_after(SecondPhase))
+            .collect::<Vec<_>>();
+        let indices = phase1.iter().chain(phase2.iter()).map(|c| c.index()).collect();
+        Self { phase1, phase2, indices }
+    }
+
+    /// Queries the challenges as expressions (configure time)
+    pub(crate) fn exprs<F: Field>(&self, meta: &mut ConstraintSystem<F>) -> Challenges<Expression<F>> {
+        query_expression(meta, |meta| Challenges {
+            phase1: self.phase1.iter().map(|c| meta.query_challenge(*c)).collect(),
+            phase2: self.phase2.iter().map(|c| meta.query_challenge(*c)).collect(),
+            indices: self.indices.clone(),
+        })
+    }
+
+    /// Gets the challenge values from the layouter (synthesis time)
+    pub(crate) fn values<F: Field>(&self, layouter: &impl Layouter<F>) -> Challenges<Value<F>> {
+        Challenges {
+            phase1: self.phase1.iter().map(|c| layouter.get_challenge(*c)).collect(),
+            phase2: self.phase2.iter().map(|c| layouter.get_challenge(*c)).collect(),
+            indices: self.indices.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Challenges<T> {
+    /// Challenges usable after the first phase
+    pub(crate) fn phase1(&self) -> &[T] {
+        &self.phase1
+    }
+
+    /// Challenges usable after the second phase
+    pub(crate) fn phase2(&self) -> &[T] {
+        &self.phase2
+    }
+
+    /// The challenge used to compress lookup inputs
+    pub(crate) fn lookup_input(&self) -> Option<T> {
+        self.phase1.first().cloned()
+    }
+
+    /// The first challenge usable after `phase` (1 or 2), used to compress
+    /// the values that are only known in that phase
+    pub(crate) fn first_after(&self, phase: u8) -> Option<T> {
+        match phase {
+            1 => self.phase1.first().cloned(),
+            2 => self.phase2.first().cloned(),
+            _ => None,
+        }
+    }
+
+    /// All challenges, positioned at their `Challenge::index`. Panics when a
+    /// challenge of the circuit was allocated outside of this set, its value
+    /// isn't known here.
+    pub(crate) fn indexed(&self) -> Vec<&T> {
+        let mut indexed = vec![None; self.indices.len()];
+        for (challenge, &index) in self.phase1.iter().chain(self.phase2.iter()).zip(self.indices.iter()) {
+            assert!(
+                index < indexed.len(),
+                "challenge {} was allocated outside of this challenge set",
+                index
+            );
+            indexed[index] = Some(challenge);
+        }
+        indexed.into_iter().map(|challenge| challenge.unwrap()).collect()
+    }
+}
+
+/// Wraps the `meta` of a gate closure so every column/rotation is queried only
+/// once, later queries return a clone of the same expression. Has the same
+/// query methods as `VirtualCells`, so it can be passed to `circuit!` as meta.
+pub struct QueryCache<'m, 'a, F: Field> {
+    meta: &'m mut VirtualCells<'a, F>,
+    cache: HashMap<(Column<Any>, i32), Expression<F>>,
+    num_queries: usize,
+}
+
+impl<'m, 'a, F: Field> QueryCache<'m, 'a, F> {
+    pub fn new(meta: &'m mut VirtualCells<'a, F>) -> Self {
+        Self {
+            meta,
+            cache: HashMap::new(),
+            num_queries: 0,
+        }
+    }
+
+    pub fn query_advice(&mut self, column: Column<Advice>, at: Rotation) -> Expression<F> {
+        self.query_any(column, at)
+    }
+
+    pub fn query_fixed(&mut self, column: Column<Fixed>, at: Rotation) -> Expression<F> {
+        self.query_any(column, at)
+    }
+
+    pub fn query_any<C: Into<Column<Any>>>(&mut self, column: C, at: Rotation) -> Expression<F> {
+        let column = column.into();
+        self.num_queries += 1;
+        let meta = &mut self.meta;
+        self.cache
+            .entry((column, at.0))
+            .or_insert_with(|| meta.query_any(column, at))
+            .clone()
+    }
+
+    pub fn query_challenge(&mut self, challenge: Challenge) -> Expression<F> {
+        self.meta.query_challenge(challenge)
+    }
+
+    pub fn query_selector(&mut self, selector: Selector) -> Expression<F> {
+        self.meta.query_selector(selector)
+    }
+
+    /// Returns the number of column queries done and the number of distinct
+    /// queries forwarded to meta
+    pub fn stats(&self) -> (usize, usize) {
+        (self.num_queries, self.cache.len())
+    }
+}
+
+/// Number of nodes in the expression tree
+pub(crate) fn expr_nodes<F>(expr: &Expression<F>) -> usize {
+    match expr {
+        Expression::Negated(a) | Expression::Scaled(a, _) => 1 + expr_nodes(a),
+        Expression::Sum(a, b) | Expression::Product(a, b) => 1 + expr_nodes(a) + expr_nodes(b),
+        _ => 1,
+    }
+}
+
+/// Degrees of the summands of the expression, or of the factors when it isn't
+/// a sum, to show where the degree of an expression comes from
+pub(crate) fn term_degrees<F: Field>(expr: &Expression<F>) -> Vec<usize> {
+    fn flatten<'e, F>(expr: &'e Expression<F>, sum: bool, terms: &mut Vec<&'e Expression<F>>) {
+        match expr {
+            Expression::Negated(a) | Expression::Scaled(a, _) => flatten(a, sum, terms),
+            Expression::Sum(a, b) if sum => {
+                flatten(a, sum, terms);
+                flatten(b, sum, terms);
+            }
+            Expression::Product(a, b) if !sum => {
+                flatten(a, sum, terms);
+                flatten(b, sum, terms);
+            }
+            _ => terms.push(expr),
+        }
+    }
+    let mut terms = Vec::new();
+    flatten(expr, true, &mut terms);
+    if terms.len() == 1 {
+        terms.clear();
+        flatten(expr, false, &mut terms);
+    }
+    terms.iter().map(|term| term.degree()).collect()
+}
+
+/// Balanced sum of `coefficient ⋅ expression` terms. Terms over the same
+/// expression (including already scaled ones) are merged first, and terms
+/// with a zero coefficient are left out.
+pub(crate) fn linear_combine<F: Field>(terms: &[(F, Expression<F>)]) -> Expression<F> {
+    let mut merged: Vec<(F, Expression<F>)> = Vec::new();
+    let mut indices = HashMap::new();
+    for (coefficient, expr) in terms.iter() {
+        let (coefficient, expr) = match expr {
+            Expression::Scaled(expr, scale) => (*coefficient * *scale, expr.as_ref()),
+            _ => (*coefficient, expr),
+        };
+        let index = *indices.entry(expr.identifier()).or_insert_with(|| {
+            merged.push((F::ZERO, expr.clone()));
+            merged.len() - 1
+        });
+        merged[index].0 += coefficient;
+    }
+    let leaves = merged
+        .into_iter()
+        .filter(|(coefficient, _)| *coefficient != F::ZERO)
+        .map(|(coefficient, expr)| {
+            if coefficient == F::ONE {
+                expr
+            } else {
+                Expression::Scaled(Box::new(expr), coefficient)
+            }
+        })
+        .collect::<Vec<_>>();
+    balanced_sum(&leaves)
+}
+
+fn balanced_sum<F: Field>(exprs: &[Expression<F>]) -> Expression<F> {
+    match exprs.len() {
+        0 => 0.expr(),
+        1 => exprs[0].clone(),
+        n => balanced_sum(&exprs[..n / 2]) + balanced_sum(&exprs[n / 2..]),
+    }
+}
+
+/// Witness twin of `linear_combine`
+pub(crate) fn linear_combine_value<F: Field>(terms: &[(F, F)]) -> F {
+    terms
+        .iter()
+        .fold(F::ZERO, |acc, (coefficient, value)| acc + *coefficient * *value)
+}
+
+/// Collects the advice queries of the expression as `(column index, rotation)`
+pub(crate) fn advice_queries<F>(expr: &Expression<F>, queries: &mut HashSet<(usize, i32)>) {
+    match expr {
+        Expression::Advice(query) => {
+            queries.insert((query.column_index(), query.rotation().0));
+        }
+        Expression::Negated(a) | Expression::Scaled(a, _) => advice_queries(a, queries),
+        Expression::Sum(a, b) | Expression::Product(a, b) => {
+            advice_queries(a, queries);
+            advice_queries(b, queries);
+        }
+        _ => {}
+    }
+}
+
+/// Name of a column as in `advice[3]`, `fixed[0]` or `instance[1]`
+pub(crate) fn column_name(column: Column<Any>) -> String {
+    let kind = match column.column_type() {
+        Any::Advice(_) => "advice",
+        Any::Fixed => "fixed",
+        Any::Instance => "instance",
+    };
+    format!("{}[{}]", kind, column.index())
+}
+
+/// Collects the names (see `column_name`) of the columns queried in the
+/// expression
+pub(crate) fn queried_columns<F>(expr: &Expression<F>, columns: &mut HashSet<String>) {
+    match expr {
+        Expression::Advice(query) => {
+            columns.insert(format!("advice[{}]", query.column_index()));
+        }
+        Expression::Fixed(query) => {
+            columns.insert(format!("fixed[{}]", query.column_index()));
+        }
+        Expression::Instance(query) => {
+            columns.insert(format!("instance[{}]", query.column_index()));
+        }
+        Expression::Negated(a) | Expression::Scaled(a, _) => queried_columns(a, columns),
+        Expression::Sum(a, b) | Expression::Product(a, b) => {
+            queried_columns(a, columns);
+            queried_columns(b, columns);
+        }
+        _ => {}
+    }
+}
+
+/// Returns true if the expression queries a challenge
+pub(crate) fn has_challenge<F>(expr: &Expression<F>) -> bool {
+    match expr {
+        Expression::Challenge(_) => true,
+        Expression::Negated(a) | Expression::Scaled(a, _) => has_challenge(a),
+        Expression::Sum(a, b) | Expression::Product(a, b) => has_challenge(a) || has_challenge(b),
+        _ => false,
+    }
+}
+
+/// Continues the FNV-1a hash of `hash` with the bytes. Unlike the std
+/// hashers the result is stable across platforms and compiler versions.
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// FNV-1a hash of a string
+pub(crate) fn fnv1a_str(s: &str) -> u64 {
+    fnv1a(0xcbf29ce484222325, s.as_bytes())
+}
+
+/// Reproducible source of random looking values, e.g. for padding sentinels
+/// and witnesses in tests. The values only depend on the seed, also across
+/// platforms and versions (SplitMix64).
+#[derive(Clone, Debug)]
+pub struct DeterministicRng {
+    state: u64,
+}
+
+impl DeterministicRng {
+    /// Creates the generator for the seed
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Creates the generator for the seed combined with `domain`, so values
+    /// drawn for different purposes are independent
+    pub fn with_domain(seed: u64, domain: &str) -> Self {
+        Self::new(fnv1a(seed, domain.as_bytes()))
+    }
+
+    /// A field element
+    pub fn field<F: Field>(&mut self) -> F {
+        F::random(self)
+    }
+}
+
+impl rand_core::RngCore for DeterministicRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Removes the `file:line: ` preamble `concat_with_preamble!` puts in front
+/// of the descriptions generated by the macros, other descriptions are
+/// returned as is. Use with `ConstraintBuilder::set_description_formatter`.
+pub fn strip_preamble(description: &str) -> String {
+    let is_preamble = |preamble: &str| {
+        preamble.rsplit_once(':').map_or(false, |(file, line)| {
+            !file.is_empty() && !line.is_empty() && line.chars().all(|c| c.is_ascii_digit())
+        })
+    };
+    match description.split_once(": ") {
+        Some((preamble, rest)) if is_preamble(preamble) => rest.to_string(),
+        _ => description.to_string(),
+    }
+}
+
+/// Value padding with `Padding::Sentinel` assigns, derived from the seed of
+/// the builder
+pub fn padding_sentinel<F: Field>(seed: u64) -> F {
+    DeterministicRng::with_domain(seed, "padding sentinel").field()
+}
+
+thread_local! {
+    static INTERNED: std::cell::RefCell<HashSet<&'static str>> = std::cell::RefCell::new(HashSet::new());
+}
+
+/// Returns a `'static` copy of the string, as halo2 requires for constraint
+/// and lookup names. Every distinct string is only leaked once per thread, so
+/// configuring the same circuit again doesn't leak any more memory.
+pub(crate) fn intern(s: &str) -> &'static str {
+    INTERNED.with(|interned| {
+        let mut interned = interned.borrow_mut();
+        match interned.get(s) {
+            Some(s) => *s,
+            None => {
+                let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+                interned.insert(s);
+                s
+            }
+        }
+    })
+}
+
+/// Number of strings interned on this thread
+pub(crate) fn interned_count() -> usize {
+    INTERNED.with(|interned| interned.borrow().len())
+}
+
+/// Compact infix representation of an expression. Subexpressions nested
+/// deeper than `depth` are elided as `..`.
+pub(crate) fn expr_to_string<F: Field>(expr: &Expression<F>, depth: usize) -> String {
+    let constant = |value: &F| {
+        let lower = value.get_lower_128();
+        if lower <= u64::MAX as u128 && F::from(lower as u64) == *value {
+            format!("{}", lower)
+        } else if (-*value).get_lower_128() <= u64::MAX as u128
+            && F::from((-*value).get_lower_128() as u64) == -*value
+        {
+            format!("-{}", (-*value).get_lower_128())
+        } else {
+            format!("{:?}", value)
+        }
+    };
+    let nested = |expr: &Expression<F>| {
+        if depth == 0 {
+            "..".to_string()
+        } else {
+            expr_to_string(expr, depth - 1)
+        }
+    };
+    match expr {
+        Expression::Constant(value) => constant(value),
+        Expression::Selector(selector) => format!("selector[{}]", selector.index()),
+        Expression::Fixed(query) => format!("fixed[{}]@{}", query.column_index(), query.rotation().0),
+        Expression::Advice(query) => format!("advice[{}]@{}", query.column_index(), query.rotation().0),
+        Expression::Instance(query) => format!("instance[{}]@{}", query.column_index(), query.rotation().0),
+        Expression::Challenge(challenge) => format!("challenge[{}]", challenge.index()),
+        Expression::Negated(a) => format!("-{}", nested(a)),
+        Expression::Sum(a, b) => format!("({} + {})", nested(a), nested(b)),
+        Expression::Product(a, b) => format!("({} * {})", nested(a), nested(b)),
+        Expression::Scaled(a, value) => format!("({} * {})", nested(a), constant(value)),
+    }
+}
+
+/// The little-endian bytes of the value as a hex string
+#[cfg(feature = "serde")]
+pub(crate) fn field_to_hex<F: Field>(value: &F) -> String {
+    value
+        .to_repr()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Canonical JSON tree of the expression: queries as column index and
+/// rotation, constants in hex (see `field_to_hex`). The keys of every object
+/// are inserted in sorted order, so the output doesn't depend on how
+/// `serde_json` orders maps.
+#[cfg(feature = "serde")]
+pub(crate) fn expr_to_json<F: Field>(expr: &Expression<F>) -> serde_json::Value {
+    use serde_json::json;
+    match expr {
+        Expression::Constant(value) => json!({ "constant": field_to_hex(value) }),
+        Expression::Selector(selector) => json!({ "selector": selector.index() }),
+        Expression::Fixed(query) => json!({
+            "fixed": { "column": query.column_index(), "rotation": query.rotation().0 }
+        }),
+        Expression::Advice(query) => json!({
+            "advice": { "column": query.column_index(), "rotation": query.rotation().0 }
+        }),
+        Expression::Instance(query) => json!({
+            "instance": { "column": query.column_index(), "rotation": query.rotation().0 }
+        }),
+        Expression::Challenge(challenge) => json!({ "challenge": challenge.index() }),
+        Expression::Negated(a) => json!({ "neg": expr_to_json(a) }),
+        Expression::Sum(a, b) => json!({ "sum": [expr_to_json(a), expr_to_json(b)] }),
+        Expression::Product(a, b) => json!({ "product": [expr_to_json(a), expr_to_json(b)] }),
+        Expression::Scaled(a, value) => json!({
+            "scaled": { "expr": expr_to_json(a), "factor": field_to_hex(value) }
+        }),
+    }
+}
+
 /// Returns 2**by as Field
 pub(crate) fn pow_of_two<F: Field>(by: usize) -> F {
     F::from(2).pow([by as u64, 0, 0, 0])